@@ -1,13 +1,15 @@
 mod plugin;
 
 use bevy::prelude::*;
+use common::ShaderLibraryPlugin;
 use plugin::ApplierPlugin;
 
 fn main() {
     let mut app = App::new();
     app.add_plugins((
+        ShaderLibraryPlugin::default(),
         DefaultPlugins.set(ImagePlugin::default_nearest()),
         ApplierPlugin,
     ));
-    app.run();
+    common::run(&mut app);
 }
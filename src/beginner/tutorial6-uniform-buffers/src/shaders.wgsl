@@ -1,15 +1,10 @@
+#import applier::camera::CameraUniform
+#import applier::instancing::VertexInput
+
 // Vertex shader
-struct CameraUniform {
-    view_proj: mat4x4<f32>,
-};
 @group(1) @binding(0) // 1.
 var<uniform> camera: CameraUniform;
 
-struct VertexInput {
-    @location(0) position: vec3<f32>,
-    @location(1) tex_coords: vec2<f32>,
-}
-
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
     @location(0) tex_coords: vec2<f32>,
@@ -0,0 +1,21 @@
+#define_import_path applier::camera
+
+// Matches every tutorial's `CameraUniform`. The lighting tutorial's has the camera position
+// and the TAA jitter around the matrix, see `ShaderLibraryPlugin::shader_defs`.
+struct CameraUniform {
+#ifdef LIGHTING
+    view_position: vec4<f32>,
+#endif
+    view_proj: mat4x4<f32>,
+#ifdef LIGHTING
+    // Sub-pixel offset baked into view_proj, in NDC units.
+    jitter: vec2<f32>,
+#endif
+};
+
+#ifdef LIGHTING
+// Moves a clip space position back to where it would be without the TAA jitter.
+fn remove_jitter(clip_position: vec4<f32>, jitter: vec2<f32>) -> vec4<f32> {
+    return vec4<f32>(clip_position.xy - jitter * clip_position.w, clip_position.zw);
+}
+#endif
@@ -0,0 +1,7 @@
+#define_import_path applier::fullscreen
+
+// What the fullscreen vertex shader hands to the fragment stage of every fullscreen pass.
+struct FullscreenVertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+};
@@ -0,0 +1,77 @@
+#define_import_path applier::instancing
+
+// The lighting tutorial's picking and outlines, see `ShaderLibraryPlugin::shader_defs`.
+#ifdef LIGHTING
+// Written for picking where no instance got drawn. Matches `picking::NO_INSTANCE`.
+const NO_INSTANCE: u32 = 0xffffffffu;
+
+// Transparent materials discard texels with a lower alpha, so they neither mark the outline
+// stencil nor get picked. Matches `outline::TRANSPARENT_ALPHA_CUTOFF`.
+const TRANSPARENT_ALPHA_CUTOFF: f32 = 0.02;
+#endif
+
+// Matches `Vertex::desc()`. The lighting tutorial adds the normal and tangent.
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) tex_coords: vec2<f32>,
+#ifdef LIGHTING
+    @location(2) normal: vec3<f32>,
+    @location(3) tangent: vec4<f32>,
+#endif
+}
+
+// Matches `InstanceRaw::desc()`. The matrices come in as columns.
+struct InstanceInput {
+#ifdef LIGHTING
+    // The instance's rect of the image as offset and size, or its layer in x for texture arrays.
+    @location(4) texture: vec4<f32>,
+#endif
+    @location(5) model_matrix_x: vec4<f32>,
+    @location(6) model_matrix_y: vec4<f32>,
+    @location(7) model_matrix_z: vec4<f32>,
+    @location(8) model_matrix_w: vec4<f32>,
+#ifdef LIGHTING
+    @location(9) normal_matrix_x: vec3<f32>,
+    @location(10) normal_matrix_y: vec3<f32>,
+    @location(11) normal_matrix_z: vec3<f32>,
+#ifdef MOTION_VECTORS
+    @location(12) previous_model_matrix_x: vec4<f32>,
+    @location(13) previous_model_matrix_y: vec4<f32>,
+    @location(14) previous_model_matrix_z: vec4<f32>,
+    @location(15) previous_model_matrix_w: vec4<f32>,
+#endif
+    // The position in the instance buffer, which is what picking writes. The vertex attributes
+    // are all taken, so it can't be the index into `Instances` itself.
+    @builtin(instance_index) index: u32,
+#endif
+};
+
+fn model_matrix(instance: InstanceInput) -> mat4x4<f32> {
+    return mat4x4<f32>(
+        instance.model_matrix_x,
+        instance.model_matrix_y,
+        instance.model_matrix_z,
+        instance.model_matrix_w,
+    );
+}
+
+#ifdef LIGHTING
+fn normal_matrix(instance: InstanceInput) -> mat3x3<f32> {
+    return mat3x3<f32>(
+        instance.normal_matrix_x,
+        instance.normal_matrix_y,
+        instance.normal_matrix_z,
+    );
+}
+#endif
+
+#ifdef MOTION_VECTORS
+fn previous_model_matrix(instance: InstanceInput) -> mat4x4<f32> {
+    return mat4x4<f32>(
+        instance.previous_model_matrix_x,
+        instance.previous_model_matrix_y,
+        instance.previous_model_matrix_z,
+        instance.previous_model_matrix_w,
+    );
+}
+#endif
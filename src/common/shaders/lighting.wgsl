@@ -0,0 +1,82 @@
+#define_import_path applier::lighting
+
+const LIGHT_KIND_SPOT: u32 = 1u;
+const LIGHT_KIND_DIRECTIONAL: u32 = 2u;
+
+struct Light {
+    position: vec3<f32>,
+    range: f32,
+    color: vec3<f32>,
+    kind: u32,
+    direction: vec3<f32>,
+    spot_inner_cos: f32,
+    spot_outer_cos: f32,
+    ambient: f32,
+    diffuse: f32,
+    specular: f32,
+    shadow_layer: i32,
+};
+
+#ifdef LIGHTS_STORAGE_BUFFERS
+struct Lights {
+    data: array<Light>,
+};
+#else
+struct Lights {
+    data: array<Light, 128u>,
+};
+#endif
+
+const SHININESS: f32 = 32.0;
+
+struct LightSample {
+    // Towards the light.
+    direction: vec3<f32>,
+    // How much of the light reaches the surface, before shadows.
+    attenuation: f32,
+};
+
+fn sample_light(light: Light, world_position: vec3<f32>) -> LightSample {
+    var sample: LightSample;
+    sample.attenuation = 1.0;
+    if light.kind == LIGHT_KIND_DIRECTIONAL {
+        sample.direction = -light.direction;
+        return sample;
+    }
+    let to_light = light.position - world_position;
+    let distance = length(to_light);
+    sample.direction = to_light / distance;
+
+    // Smoothly fade the light out so it reaches zero at its range.
+    let falloff = saturate(1.0 - pow(distance / light.range, 4.0));
+    sample.attenuation = falloff * falloff;
+    if light.kind == LIGHT_KIND_SPOT {
+        sample.attenuation *= smoothstep(
+            light.spot_outer_cos,
+            light.spot_inner_cos,
+            dot(-sample.direction, light.direction),
+        );
+    }
+    return sample;
+}
+
+// Ambient plus diffuse and specular, with only the last two darkened by `shadow`.
+fn blinn_phong(
+    light: Light,
+    normal: vec3<f32>,
+    view_dir: vec3<f32>,
+    light_dir: vec3<f32>,
+    shadow: f32,
+) -> vec3<f32> {
+    let half_dir = normalize(view_dir + light_dir);
+
+    let ambient_color = light.color * light.ambient;
+
+    let diffuse_strength = max(dot(normal, light_dir), 0.0);
+    let diffuse_color = light.color * diffuse_strength * light.diffuse;
+
+    let specular_strength = pow(max(dot(normal, half_dir), 0.0), SHININESS);
+    let specular_color = light.color * specular_strength * light.specular;
+
+    return ambient_color + (diffuse_color + specular_color) * shadow;
+}
@@ -0,0 +1,134 @@
+//! Writing the render graph and the schedules out as Graphviz files.
+//!
+//! `--dump-graphs [DIR]` builds the app without running it and writes `render_graph.dot`,
+//! `ApplierSubgraph` included, and one file per schedule of the main and render worlds into
+//! `DIR`, `graphs` by default. When Graphviz is installed every file gets an `.svg` next to
+//! it. Handy for checking the node edges and system sets after touching `ApplierPlugin::build`.
+//!
+//! Every tutorial's `main` hands its app to [`crate::run`], which is where the flag gets
+//! checked.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use bevy::{
+    app::PluginsState,
+    ecs::schedule::{InternedScheduleLabel, ScheduleLabel},
+    prelude::*,
+    render::{Render, RenderApp},
+    tasks::tick_global_task_pools_on_main_thread,
+};
+use bevy_mod_debugdump::{render_graph, render_graph_dot, schedule_graph};
+
+const DEFAULT_DIR: &str = "graphs";
+
+/// Where `--dump-graphs` asked for the graphs to go, if it was passed.
+pub fn requested_dir() -> Option<PathBuf> {
+    let mut args = std::env::args().skip_while(|arg| arg != "--dump-graphs");
+    args.next()?;
+    let dir = args
+        .next()
+        .filter(|arg| !arg.starts_with("--"))
+        .unwrap_or_else(|| DEFAULT_DIR.to_owned());
+    Some(dir.into())
+}
+
+/// Finishes building `app` and writes its graphs into `dir`. The app can't be run afterwards.
+pub fn dump_graphs(app: &mut App, dir: &Path) {
+    // Plugins add some of their systems and render resources only once the renderer is
+    // ready, so wait for it like `App::run` does.
+    while app.plugins_state() == PluginsState::Adding {
+        tick_global_task_pools_on_main_thread();
+    }
+    app.finish();
+    app.cleanup();
+
+    if let Err(error) = fs::create_dir_all(dir) {
+        error!("couldn't create {}: {error}", dir.display());
+        return;
+    }
+    let mut files = vec![write_dot(
+        dir,
+        "render_graph",
+        &render_graph_dot(app, &render_graph::Settings::default()),
+    )];
+
+    let main_schedules = [
+        Startup.intern(),
+        First.intern(),
+        PreUpdate.intern(),
+        Update.intern(),
+        PostUpdate.intern(),
+        Last.intern(),
+    ];
+    for label in main_schedules {
+        files.push(dump_schedule(app.world_mut(), dir, "main", label));
+    }
+    if let Some(render_app) = app.get_sub_app_mut(RenderApp) {
+        for label in [ExtractSchedule.intern(), Render.intern()] {
+            files.push(dump_schedule(render_app.world_mut(), dir, "render", label));
+        }
+    }
+
+    let written: Vec<PathBuf> = files.into_iter().flatten().collect();
+    info!("wrote {} graphs to {}", written.len(), dir.display());
+    render_svgs(&written);
+}
+
+fn dump_schedule(
+    world: &mut World,
+    dir: &Path,
+    world_name: &str,
+    label: InternedScheduleLabel,
+) -> Option<PathBuf> {
+    let dot = world
+        .try_schedule_scope(label, |world, schedule| {
+            // Builds the dependency graph the dump is drawn from. A schedule that fails to
+            // build would fail the same way when run, so only warn.
+            if let Err(error) = schedule.initialize(world) {
+                warn!("{label:?} doesn't build: {error}");
+            }
+            schedule_graph::schedule_graph_dot(
+                schedule,
+                world,
+                &schedule_graph::Settings::default(),
+            )
+        })
+        .ok()?;
+    write_dot(dir, &format!("{world_name}_{label:?}"), &dot)
+}
+
+fn write_dot(dir: &Path, name: &str, dot: &str) -> Option<PathBuf> {
+    let path = dir.join(name).with_extension("dot");
+    match fs::write(&path, dot) {
+        Ok(()) => Some(path),
+        Err(error) => {
+            error!("couldn't write {}: {error}", path.display());
+            None
+        }
+    }
+}
+
+/// Leaves the `.dot` files alone when Graphviz isn't there.
+fn render_svgs(dots: &[PathBuf]) {
+    for dot in dots {
+        let svg = dot.with_extension("svg");
+        match Command::new("dot")
+            .arg("-Tsvg")
+            .arg(dot)
+            .arg("-o")
+            .arg(&svg)
+            .status()
+        {
+            Ok(status) if status.success() => {}
+            Ok(status) => warn!("dot failed on {}: {status}", dot.display()),
+            Err(error) => {
+                warn!("couldn't run Graphviz's dot, only wrote the .dot files: {error}");
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,18 @@
+//! What the tutorials share instead of each keeping a copy. Every tutorial on bevy 0.15
+//! depends on this crate.
+
+pub mod graph_dump;
+pub mod shader_library;
+
+use bevy::prelude::*;
+pub use shader_library::ShaderLibraryPlugin;
+
+/// Runs `app`, or only writes its graphs out when `--dump-graphs` was passed, see
+/// [`graph_dump`]. Every tutorial's `main` ends with this instead of `app.run()`.
+pub fn run(app: &mut App) -> AppExit {
+    if let Some(dir) = graph_dump::requested_dir() {
+        graph_dump::dump_graphs(app, &dir);
+        return AppExit::Success;
+    }
+    app.run()
+}
@@ -0,0 +1,140 @@
+//! WGSL modules the tutorials' shaders pull in with `#import applier::...`, so structs like
+//! `CameraUniform` and `InstanceInput` are only written down once.
+//!
+//! The modules live in `src/common/shaders`. Their structs start out the way the beginner
+//! tutorials lay out their buffers, and the `LIGHTING` shader def adds what the lighting
+//! tutorial's vertices, instances and camera carry on top. A tutorial binds the defs matching
+//! its own buffers to the modules through [`ShaderLibraryPlugin::shader_defs`], so they apply
+//! to every shader importing one without each pipeline setting them.
+//!
+//! The `PipelineCache` finds modules by their `#define_import_path`, whichever way they got
+//! loaded. Debug builds read them from disk, and with the `hot_reload` feature editing one
+//! reloads every shader importing it.
+
+#[cfg(debug_assertions)]
+use bevy::asset::io::AssetSource;
+#[cfg(not(debug_assertions))]
+use bevy::asset::load_internal_asset;
+use bevy::{
+    prelude::*,
+    render::render_resource::{Shader, ShaderDefVal},
+};
+
+#[cfg(debug_assertions)]
+const MODULES: [&str; 4] = [
+    "camera.wgsl",
+    "instancing.wgsl",
+    "lighting.wgsl",
+    "fullscreen.wgsl",
+];
+
+/// Nothing refers to the modules by handle, so this keeps them loaded.
+#[cfg(debug_assertions)]
+#[derive(Resource)]
+struct ShaderLibrary {
+    modules: Vec<Handle<Shader>>,
+    shader_defs: Vec<ShaderDefVal>,
+}
+
+#[cfg(not(debug_assertions))]
+const CAMERA_SHADER_HANDLE: Handle<Shader> =
+    Handle::weak_from_u128(63585968666316973094428713049749663275);
+#[cfg(not(debug_assertions))]
+const INSTANCING_SHADER_HANDLE: Handle<Shader> =
+    Handle::weak_from_u128(127012168984011511414484017575778660123);
+#[cfg(not(debug_assertions))]
+const LIGHTING_SHADER_HANDLE: Handle<Shader> =
+    Handle::weak_from_u128(81350721819333812004246020628861217830);
+#[cfg(not(debug_assertions))]
+const FULLSCREEN_SHADER_HANDLE: Handle<Shader> =
+    Handle::weak_from_u128(96456912929855539312369479472366727121);
+
+/// Debug builds load the modules from a `shader_library` asset source. Asset sources have to
+/// exist before the `AssetPlugin` is built, so this goes in front of `DefaultPlugins`.
+#[derive(Default)]
+pub struct ShaderLibraryPlugin {
+    /// Bound to every module, `LIGHTING` for the lighting tutorial and none for the others.
+    pub shader_defs: Vec<ShaderDefVal>,
+}
+
+impl Plugin for ShaderLibraryPlugin {
+    #[cfg(debug_assertions)]
+    fn build(&self, app: &mut App) {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/shaders");
+        let source = AssetSource::build().with_reader(AssetSource::get_default_reader(dir.into()));
+        #[cfg(feature = "hot_reload")]
+        let source = source.with_watcher(AssetSource::get_default_watcher(
+            dir.into(),
+            std::time::Duration::from_millis(300),
+        ));
+        app.register_asset_source("shader_library", source);
+    }
+
+    #[cfg(not(debug_assertions))]
+    fn build(&self, _app: &mut App) {}
+
+    fn finish(&self, app: &mut App) {
+        #[cfg(debug_assertions)]
+        {
+            let asset_server = app.world().resource::<AssetServer>();
+            let modules = MODULES
+                .iter()
+                .map(|path| asset_server.load(format!("shader_library://{path}")))
+                .collect();
+            app.insert_resource(ShaderLibrary {
+                modules,
+                shader_defs: self.shader_defs.clone(),
+            })
+            .add_systems(PostUpdate, bind_shader_defs);
+        }
+        #[cfg(not(debug_assertions))]
+        {
+            load_internal_asset!(
+                app,
+                CAMERA_SHADER_HANDLE,
+                "../shaders/camera.wgsl",
+                Shader::from_wgsl_with_defs,
+                self.shader_defs.clone()
+            );
+            load_internal_asset!(
+                app,
+                INSTANCING_SHADER_HANDLE,
+                "../shaders/instancing.wgsl",
+                Shader::from_wgsl_with_defs,
+                self.shader_defs.clone()
+            );
+            load_internal_asset!(
+                app,
+                LIGHTING_SHADER_HANDLE,
+                "../shaders/lighting.wgsl",
+                Shader::from_wgsl_with_defs,
+                self.shader_defs.clone()
+            );
+            load_internal_asset!(
+                app,
+                FULLSCREEN_SHADER_HANDLE,
+                "../shaders/fullscreen.wgsl",
+                Shader::from_wgsl_with_defs,
+                self.shader_defs.clone()
+            );
+        }
+    }
+}
+
+/// The asset loader has no way of taking the defs, so modules read from disk come without
+/// them, again after every reload. This runs before the render world extracts the modules, so
+/// the pipeline cache never composes one without its defs.
+#[cfg(debug_assertions)]
+fn bind_shader_defs(library: Res<ShaderLibrary>, mut shaders: ResMut<Assets<Shader>>) {
+    for module in &library.modules {
+        // `get_mut` counts as a change, so only bind the defs when they're missing.
+        let unbound = shaders
+            .get(module)
+            .is_some_and(|shader| shader.shader_defs != library.shader_defs);
+        if unbound {
+            if let Some(shader) = shaders.get_mut(module) {
+                shader.shader_defs = library.shader_defs.clone();
+            }
+        }
+    }
+}
@@ -0,0 +1,109 @@
+// A material defined in assets/materials/tinted.material.ron. It gets the same vertex buffers
+// and camera bind group as shaders.wgsl, but only lights itself with a fixed sun.
+#import applier::camera::{CameraUniform, remove_jitter}
+#import applier::instancing::{
+    TRANSPARENT_ALPHA_CUTOFF,
+    InstanceInput,
+    VertexInput,
+    model_matrix,
+    normal_matrix,
+}
+#ifdef MOTION_VECTORS
+#import applier::instancing::previous_model_matrix
+#endif
+
+@group(1) @binding(0)
+var<uniform> camera: CameraUniform;
+#ifdef MOTION_VECTORS
+@group(1) @binding(1)
+var<uniform> previous_camera: CameraUniform;
+#endif
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+    @location(1) world_normal: vec3<f32>,
+#ifdef MOTION_VECTORS
+    @location(2) current_clip_position: vec4<f32>,
+    @location(3) previous_clip_position: vec4<f32>,
+#endif
+    @location(4) @interpolate(flat) instance_index: u32,
+}
+
+@vertex
+fn vs_main(model: VertexInput, instance: InstanceInput) -> VertexOutput {
+    var out: VertexOutput;
+    out.tex_coords = instance.texture.xy + model.tex_coords * instance.texture.zw;
+    out.world_normal = normal_matrix(instance) * model.normal;
+    out.instance_index = instance.index;
+    let world_position = model_matrix(instance) * vec4<f32>(model.position, 1.0);
+    out.clip_position = camera.view_proj * world_position;
+#ifdef MOTION_VECTORS
+    let previous_world_position = previous_model_matrix(instance) * vec4<f32>(model.position, 1.0);
+    out.current_clip_position = remove_jitter(out.clip_position, camera.jitter);
+    out.previous_clip_position = remove_jitter(
+        previous_camera.view_proj * previous_world_position,
+        previous_camera.jitter,
+    );
+#endif
+    return out;
+}
+
+// Matches the `fields` of the definition's uniform, in the same order.
+struct Material {
+    tint: vec4<f32>,
+    alpha_cutoff: f32,
+};
+
+@group(0) @binding(0)
+var t_diffuse: texture_2d<f32>;
+@group(0) @binding(1)
+var s_diffuse: sampler;
+@group(0) @binding(2)
+var<uniform> material: Material;
+
+const SUN_DIRECTION: vec3<f32> = vec3<f32>(0.3, 0.9, 0.3);
+
+struct FragmentOutput {
+    @location(0) color: vec4<f32>,
+#ifdef MOTION_VECTORS
+    @location(1) motion_vector: vec2<f32>,
+#endif
+    @location(2) instance_index: u32,
+};
+
+@fragment
+fn fs_main(in: VertexOutput) -> FragmentOutput {
+    var color = textureSample(t_diffuse, s_diffuse, in.tex_coords);
+#ifdef ALPHA_MASK
+    if color.a < material.alpha_cutoff {
+        discard;
+    }
+    color.a = 1.0;
+#endif
+#ifdef ALPHA_BLEND
+    if color.a < TRANSPARENT_ALPHA_CUTOFF {
+        discard;
+    }
+#endif
+#ifdef GRAYSCALE
+    color = vec4<f32>(vec3<f32>(dot(color.rgb, vec3<f32>(0.2126, 0.7152, 0.0722))), color.a);
+#endif
+    // Both sides of the quad get lit.
+    let diffuse = abs(dot(normalize(in.world_normal), normalize(SUN_DIRECTION)));
+    let lighting = 0.3 + 0.7 * diffuse;
+
+    var out: FragmentOutput;
+    out.color = vec4<f32>(color.rgb * material.tint.rgb * lighting, color.a * material.tint.a);
+#ifdef PREMULTIPLY_ALPHA
+    out.color = vec4<f32>(out.color.rgb * out.color.a, out.color.a);
+#endif
+#ifdef MOTION_VECTORS
+    let current_ndc = in.current_clip_position.xy / in.current_clip_position.w;
+    let previous_ndc = in.previous_clip_position.xy / in.previous_clip_position.w;
+    out.motion_vector = (current_ndc - previous_ndc) * vec2<f32>(0.5, -0.5);
+#endif
+    // Only reached by texels that passed the cutoffs above, like in shaders.wgsl.
+    out.instance_index = in.instance_index;
+    return out;
+}
@@ -0,0 +1,23 @@
+#import applier::fullscreen::FullscreenVertexOutput
+
+// Fragment shader, the vertex stage comes from fullscreen.wgsl.
+//
+// Draws the pipeline error text over the top left corner of the window, every texel of it
+// scaled up to a square of SCALE pixels. The rest of the window is left alone.
+
+const SCALE: f32 = 2.0;
+const BACKGROUND: vec3<f32> = vec3<f32>(0.3, 0.0, 0.0);
+const TEXT: vec3<f32> = vec3<f32>(1.0, 1.0, 1.0);
+
+@group(0) @binding(0)
+var t_text: texture_2d<f32>;
+
+@fragment
+fn fs_main(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
+    let texel = vec2<i32>(floor(in.clip_position.xy / SCALE));
+    if any(texel >= vec2<i32>(textureDimensions(t_text))) {
+        discard;
+    }
+    let coverage = textureLoad(t_text, texel, 0).r;
+    return vec4<f32>(mix(BACKGROUND, TEXT, coverage), 1.0);
+}
@@ -0,0 +1,14 @@
+#import applier::fullscreen::FullscreenVertexOutput
+
+// Vertex shader shared by every fullscreen pass. Pair it with a fragment shader that takes a
+// `FullscreenVertexOutput`.
+//
+// One triangle that covers the whole screen, no vertex buffer needed.
+@vertex
+fn vs_main(@builtin(vertex_index) in_vertex_index: u32) -> FullscreenVertexOutput {
+    var out: FullscreenVertexOutput;
+    let uv = vec2<f32>(f32((in_vertex_index << 1u) & 2u), f32(in_vertex_index & 2u));
+    out.clip_position = vec4<f32>(uv * vec2<f32>(2.0, -2.0) + vec2<f32>(-1.0, 1.0), 0.0, 1.0);
+    out.uv = uv;
+    return out;
+}
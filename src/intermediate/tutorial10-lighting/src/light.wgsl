@@ -0,0 +1,63 @@
+#import applier::camera::CameraUniform
+#import applier::instancing::{NO_INSTANCE, VertexInput}
+#import applier::lighting::{LIGHT_KIND_DIRECTIONAL, Lights}
+
+// Vertex shader
+@group(0) @binding(0)
+var<uniform> camera: CameraUniform;
+
+#ifdef LIGHTS_STORAGE_BUFFERS
+@group(1) @binding(0)
+var<storage> lights: Lights;
+#else
+@group(1) @binding(0)
+var<uniform> lights: Lights;
+#endif
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) color: vec3<f32>,
+};
+
+const LIGHT_SCALE: f32 = 0.25;
+
+@vertex
+fn vs_main(
+    model: VertexInput,
+    @builtin(instance_index) instance_index: u32,
+) -> VertexOutput {
+    let light = lights.data[instance_index];
+    var out: VertexOutput;
+    out.color = light.color;
+    if light.kind == LIGHT_KIND_DIRECTIONAL {
+        // Directional lights have no position, collapse the gizmo so nothing is drawn.
+        out.clip_position = vec4<f32>(0.0);
+        return out;
+    }
+    let world_position = vec4<f32>(model.position * LIGHT_SCALE + light.position, 1.0);
+    out.clip_position = camera.view_proj * world_position;
+    return out;
+}
+
+// Fragment shader
+
+struct FragmentOutput {
+    @location(0) color: vec4<f32>,
+#ifdef MOTION_VECTORS
+    @location(1) motion_vector: vec2<f32>,
+#endif
+    @location(2) instance_index: u32,
+};
+
+@fragment
+fn fs_main(in: VertexOutput) -> FragmentOutput {
+    var out: FragmentOutput;
+    out.color = vec4<f32>(in.color, 1.0);
+#ifdef MOTION_VECTORS
+    // We don't keep the lights' previous positions, so the gizmos count as static.
+    out.motion_vector = vec2<f32>(0.0);
+#endif
+    // Nothing to pick, the gizmos only show where the lights are.
+    out.instance_index = NO_INSTANCE;
+    return out;
+}
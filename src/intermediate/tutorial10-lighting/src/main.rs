@@ -0,0 +1,23 @@
+mod plugin;
+
+use bevy::prelude::*;
+use common::ShaderLibraryPlugin;
+use plugin::{
+    hot_reload::ShaderSourcePlugin, parallel_encoding::EncodingBenchmarkPlugin, ApplierPlugin,
+};
+
+fn main() {
+    let mut app = App::new();
+    app.add_plugins((
+        ShaderSourcePlugin,
+        ShaderLibraryPlugin {
+            shader_defs: vec!["LIGHTING".into()],
+        },
+        DefaultPlugins.set(ImagePlugin::default_nearest()),
+        ApplierPlugin,
+    ));
+    if std::env::args().any(|arg| arg == "--bench-encoding") {
+        app.add_plugins(EncodingBenchmarkPlugin);
+    }
+    common::run(&mut app);
+}
@@ -0,0 +1,14 @@
+#import applier::fullscreen::FullscreenVertexOutput
+
+// Fragment shader, the vertex stage comes from fullscreen.wgsl. The source is a view of just
+// the level above the one being drawn.
+
+@group(0) @binding(0)
+var t_source: texture_2d<f32>;
+@group(0) @binding(1)
+var s_source: sampler;
+
+@fragment
+fn fs_main(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
+    return textureSampleLevel(t_source, s_source, in.uv, 0.0);
+}
@@ -0,0 +1,109 @@
+#import applier::camera::CameraUniform
+#import applier::fullscreen::FullscreenVertexOutput
+#import applier::instancing::{InstanceInput, VertexInput, model_matrix}
+
+struct Outline {
+    color: vec4<f32>,
+    world_width: f32,
+    // In pixels.
+    screen_width: f32,
+};
+
+#ifdef SCREEN_SPACE
+
+// Fragment shader, the vertex stage comes from fullscreen.wgsl.
+
+@group(0) @binding(0)
+var t_stencil: texture_2d<u32>;
+@group(0) @binding(1)
+var<uniform> outline: Outline;
+
+@fragment
+fn fs_main(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
+    let size = vec2<i32>(textureDimensions(t_stencil));
+    let center = vec2<i32>(in.uv * vec2<f32>(size));
+    if textureLoad(t_stencil, center, 0).r != 0u {
+        discard;
+    }
+    // Colors the pixel when a marked one lies within the outline's width.
+    let radius = i32(ceil(outline.screen_width));
+    let radius_squared = outline.screen_width * outline.screen_width;
+    for (var y = -radius; y <= radius; y += 1) {
+        for (var x = -radius; x <= radius; x += 1) {
+            if f32(x * x + y * y) > radius_squared {
+                continue;
+            }
+            let texel = clamp(center + vec2<i32>(x, y), vec2<i32>(0), size - 1);
+            if textureLoad(t_stencil, texel, 0).r != 0u {
+                return outline.color;
+            }
+        }
+    }
+    discard;
+}
+
+#else
+
+// Vertex shader
+@group(0) @binding(0)
+var<uniform> camera: CameraUniform;
+@group(1) @binding(0)
+var<uniform> outline: Outline;
+
+// Matches `OutlineCoverageUniform`.
+struct Coverage {
+    alpha_cutoff: f32,
+    texture_array: u32,
+};
+
+@group(2) @binding(0)
+var t_coverage: texture_2d_array<f32>;
+@group(2) @binding(1)
+var s_coverage: sampler;
+@group(2) @binding(2)
+var<uniform> coverage: Coverage;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+    @location(1) @interpolate(flat) layer: u32,
+}
+
+@vertex
+fn vs_main(
+    model: VertexInput,
+    instance: InstanceInput,
+) -> VertexOutput {
+    // The mesh is centered on the origin, so growing it outward from there leaves an even rim.
+    let from_center = length(model.position);
+    var position = model.position;
+    if from_center > 0.0 {
+        position += model.position / from_center * outline.world_width;
+    }
+    var out: VertexOutput;
+    // The same texel as the instance itself, like in shaders.wgsl.
+    if coverage.texture_array != 0u {
+        out.tex_coords = model.tex_coords;
+        out.layer = u32(instance.texture.x);
+    } else {
+        out.tex_coords = instance.texture.xy + model.tex_coords * instance.texture.zw;
+        out.layer = 0u;
+    }
+    out.clip_position = camera.view_proj * model_matrix(instance) * vec4<f32>(position, 1.0);
+    return out;
+}
+
+// Fragment shader
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    // The material discarded these texels, so the stencil isn't marked there and the hull
+    // would fill them in.
+    let alpha = textureSample(t_coverage, s_coverage, in.tex_coords, in.layer).a;
+    if alpha < coverage.alpha_cutoff {
+        discard;
+    }
+    return outline.color;
+}
+
+#endif
@@ -0,0 +1,3111 @@
+use bevy::{
+    asset::load_internal_asset,
+    ecs::entity::EntityHashMap,
+    math::FloatOrd,
+    prelude::*,
+    render::{
+        graph::CameraDriverLabel,
+        mesh::VertexBufferLayout,
+        render_asset::{prepare_assets, RenderAssets},
+        render_graph::{RenderGraph, RenderGraphApp},
+        render_resource::{
+            binding_types::uniform_buffer, BindGroup, BindGroupLayout, BindGroupLayoutEntries,
+            DynamicUniformBuffer, PipelineCache, RawBufferVec, ShaderStages, ShaderType,
+            SpecializedRenderPipelines, Texture, TextureView, TextureViewId, UniformBuffer,
+        },
+        renderer::{RenderDevice, RenderQueue},
+        texture::{CachedTexture, GpuImage, TextureCache},
+        view::{ExtractedWindows, ViewDepthTexture},
+        Extract, Render, RenderApp, RenderSet,
+    },
+    window::PrimaryWindow,
+};
+use bevy_internal::image::{ImageFilterMode, ImageLoaderSettings, ImageSamplerDescriptor};
+use camera::CameraUniform;
+use cgmath::{EuclideanSpace, InnerSpace, Quaternion, Rotation3, Vector3, Zero};
+use wgpu::{
+    BufferAddress, BufferUsages, CompareFunction, Extent3d, TextureDescriptor, VertexStepMode,
+};
+
+#[cfg(not(debug_assertions))]
+use crate::plugin::pipeline::APPLIER_SHADER_HANDLE;
+use crate::plugin::pipeline::{
+    ApplierPipeline, ApplierPipelineKey, LightPipeline, LightPipelineKey, LIGHT_SHADER_HANDLE,
+};
+
+use self::{
+    atlas::{CombinedImages, TextureAtlas, ATLAS_MAX_LOD},
+    bind_group_tracker::{BindGroupTracker, TrackedEntries},
+    cluster::{LightBuffer, PreparedLight},
+    draw_commands::ApplierDrawFunctionIds,
+    material::{ApplierMaterial, BlendMode, PreparedApplierMaterial},
+    material_definition::{MaterialDefinition, PreparedMaterialDefinition},
+    node::{
+        ErrorOverlayNode, OutlineNode, PickingNode, PostProcessNode, ResolveTimestampsNode,
+        ShadowPassNode, SurfaceNode, TaaNode, TonemappingNode,
+    },
+    outline::{OutlineCoverage, OutlineSettings, StencilMode},
+    phase::{
+        ApplierDrawFunctions, ApplierPhaseItem, MeshId, OpaquePhase, SortKey, TransparentPhase,
+    },
+    picking::PickingTexture,
+    post_process::PostProcessSettings,
+    taa::TaaSettings,
+    tonemapping::HdrSettings,
+};
+
+pub struct ApplierPlugin;
+
+mod atlas;
+mod bind_group_tracker;
+mod bitmap_font;
+mod cluster;
+mod draw_commands;
+mod fullscreen;
+pub mod hot_reload;
+mod material_definition;
+mod mipmaps;
+mod outline;
+pub mod parallel_encoding;
+mod phase;
+mod picking;
+pub mod pipeline_errors;
+mod post_process;
+mod profiler;
+pub mod render_bundle;
+pub mod render_diagnostics;
+mod shadow;
+mod taa;
+mod tonemapping;
+
+mod camera {
+    use bevy::{prelude::*, render::render_resource::ShaderType};
+    use bitmask_enum::bitmask;
+    use cgmath::{perspective, Deg, InnerSpace, Matrix4, Point3, Vector2, Vector3, Vector4, Zero};
+
+    #[rustfmt::skip]
+    pub const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
+        1.0, 0.0, 0.0, 0.0,
+        0.0, 1.0, 0.0, 0.0,
+        0.0, 0.0, 0.5, 0.5,
+        0.0, 0.0, 0.0, 1.0,
+    );
+
+    #[derive(Resource, Clone, Debug)]
+    pub struct Camera {
+        pub eye: Point3<f32>,
+        pub target: Point3<f32>,
+        pub up: Vector3<f32>,
+        pub aspect: f32,
+        pub fovy: f32,
+        pub znear: f32,
+        pub zfar: f32,
+        /// Sub-pixel offset applied after projection, in NDC units. Used by TAA.
+        pub jitter: Vector2<f32>,
+    }
+
+    pub struct Projection(Matrix4<f32>);
+
+    fn vector_to_vec(from: Vector4<f32>) -> Vec4 {
+        Vec4::new(from.x, from.y, from.z, from.w)
+    }
+
+    impl From<Projection> for Mat4 {
+        fn from(projection: Projection) -> Self {
+            let inner = projection.0;
+            Mat4::from_cols(
+                vector_to_vec(inner.x),
+                vector_to_vec(inner.y),
+                vector_to_vec(inner.z),
+                vector_to_vec(inner.w),
+            )
+        }
+    }
+    impl Camera {
+        pub fn build_view_projection_matrix(&self) -> Projection {
+            let view = Matrix4::look_at_rh(self.eye, self.target, self.up);
+            let proj = perspective(Deg(self.fovy), self.aspect, self.znear, self.zfar);
+
+            if self.jitter.is_zero() {
+                return Projection(OPENGL_TO_WGPU_MATRIX * proj * view);
+            }
+            // Shifting clip space x and y by `jitter * w` moves the image by `jitter` in NDC.
+            let jitter = Matrix4::from_translation(self.jitter.extend(0.0));
+            Projection(jitter * OPENGL_TO_WGPU_MATRIX * proj * view)
+        }
+    }
+
+    #[repr(C)]
+    #[derive(Debug, Clone, Default, ShaderType)]
+    pub struct CameraUniform {
+        pub view_position: Vec4,
+        pub view_proj: Mat4,
+        pub jitter: Vec2,
+    }
+
+    #[bitmask(u8)]
+    pub enum CameraDirection {
+        Forward = 0b00000001,
+        Backward = 0b00000010,
+        Left = 0b00000100,
+        Right = 0b00001000,
+        Up = 0b00010000,
+        Down = 0b00100000,
+    }
+
+    pub struct CameraPlugin;
+
+    impl Plugin for CameraPlugin {
+        fn build(&self, app: &mut App) {
+            app.add_event::<CameraEvent>()
+                .add_systems(Update, (handle_camera_input, process_camera_events));
+        }
+    }
+
+    #[derive(Event)]
+    pub enum CameraEvent {
+        // The move camera should have a bit mask that lets us define forwaard, backward, left, right, up, down
+        MoveCamera(CameraDirection),
+    }
+
+    const CAMERA_SPEED: f32 = 0.2;
+
+    fn process_camera_events(mut events: EventReader<CameraEvent>, mut camera: ResMut<Camera>) {
+        for event in events.read() {
+            match event {
+                CameraEvent::MoveCamera(direction) => {
+                    let forward = camera.target - camera.eye;
+                    let forward_norm = forward.normalize();
+
+                    if direction.contains(CameraDirection::Forward) {
+                        camera.eye += forward_norm * CAMERA_SPEED;
+                    }
+                    if direction.contains(CameraDirection::Backward) {
+                        camera.eye -= forward_norm * CAMERA_SPEED;
+                    }
+
+                    let right = forward_norm.cross(camera.up);
+
+                    let forward = camera.target - camera.eye;
+                    let forward_mag = forward.magnitude();
+
+                    if direction.contains(CameraDirection::Right) {
+                        camera.eye = camera.target
+                            - (forward + right * CAMERA_SPEED).normalize() * forward_mag;
+                    }
+
+                    if direction.contains(CameraDirection::Left) {
+                        camera.eye = camera.target
+                            - (forward - right * CAMERA_SPEED).normalize() * forward_mag;
+                    }
+                }
+            }
+        }
+    }
+
+    fn handle_camera_input(
+        keyboard_input: Res<ButtonInput<KeyCode>>,
+        mut camera_events: EventWriter<CameraEvent>,
+    ) {
+        let mut direction = CameraDirection::none();
+
+        if keyboard_input.pressed(KeyCode::KeyW) {
+            direction |= CameraDirection::Forward;
+        }
+        if keyboard_input.pressed(KeyCode::KeyS) {
+            direction |= CameraDirection::Backward;
+        }
+        if keyboard_input.pressed(KeyCode::KeyA) {
+            direction |= CameraDirection::Left;
+        }
+        if keyboard_input.pressed(KeyCode::KeyD) {
+            direction |= CameraDirection::Right;
+        }
+        if direction != CameraDirection::none() {
+            camera_events.send(CameraEvent::MoveCamera(direction));
+        }
+    }
+}
+
+mod light {
+    use bevy::prelude::*;
+    use cgmath::{Deg, Quaternion, Rotation, Rotation3, Vector3};
+
+    #[derive(Clone, Copy, Debug)]
+    pub enum LightKind {
+        Point,
+        /// Lights the whole scene from `direction`. Position and range are ignored.
+        Directional {
+            direction: Vector3<f32>,
+        },
+        Spot {
+            direction: Vector3<f32>,
+            inner_angle: Deg<f32>,
+            outer_angle: Deg<f32>,
+        },
+    }
+
+    #[derive(Component, Clone, Debug)]
+    pub struct Light {
+        pub kind: LightKind,
+        pub position: Vector3<f32>,
+        pub color: Vector3<f32>,
+        pub range: f32,
+        pub ambient: f32,
+        pub diffuse: f32,
+        pub specular: f32,
+        /// Only directional and spot lights can cast shadows.
+        pub shadows: bool,
+    }
+
+    #[derive(Resource, Clone, Debug)]
+    pub struct ShadowSettings {
+        /// Width and height of every layer in the shadow map array.
+        pub map_size: u32,
+        /// Number of cascades each directional light gets, between 1 and 4.
+        pub cascade_count: u32,
+        /// Distance from the camera at which directional shadows end.
+        pub max_distance: f32,
+        /// Blends the cascade splits between uniform (0.0) and logarithmic (1.0).
+        pub cascade_split_lambda: f32,
+        pub depth_bias: f32,
+        pub normal_bias: f32,
+    }
+
+    impl Default for ShadowSettings {
+        fn default() -> Self {
+            Self {
+                map_size: 1024,
+                cascade_count: 3,
+                max_distance: 40.0,
+                cascade_split_lambda: 0.6,
+                depth_bias: 0.002,
+                normal_bias: 0.02,
+            }
+        }
+    }
+
+    pub struct LightPlugin;
+
+    impl Plugin for LightPlugin {
+        fn build(&self, app: &mut App) {
+            app.init_resource::<ShadowSettings>()
+                .add_systems(Startup, spawn_lights)
+                .add_systems(Update, orbit_lights);
+        }
+    }
+
+    const LIGHT_DEGREES_PER_SECOND: f32 = 60.0;
+    const LIGHTS_PER_ROW: u32 = 5;
+
+    fn spawn_lights(mut commands: Commands) {
+        commands.spawn(Light {
+            kind: LightKind::Directional {
+                direction: (-0.5, -1.0, -0.3).into(),
+            },
+            position: (0.0, 0.0, 0.0).into(),
+            color: (1.0, 0.95, 0.85).into(),
+            range: 0.0,
+            ambient: 0.05,
+            diffuse: 0.6,
+            specular: 0.3,
+            shadows: true,
+        });
+
+        commands.spawn(Light {
+            kind: LightKind::Point,
+            position: (2.0, 2.0, 2.0).into(),
+            color: (1.0, 1.0, 1.0).into(),
+            range: 8.0,
+            ambient: 0.1,
+            diffuse: 1.0,
+            specular: 0.5,
+            shadows: false,
+        });
+
+        // A grid of small coloured lights hovering over the instances.
+        for z in 0..LIGHTS_PER_ROW {
+            for x in 0..LIGHTS_PER_ROW {
+                let hue =
+                    (z * LIGHTS_PER_ROW + x) as f32 / (LIGHTS_PER_ROW * LIGHTS_PER_ROW) as f32;
+                let color = Color::hsl(hue * 360.0, 1.0, 0.5).to_linear();
+                commands.spawn(Light {
+                    kind: LightKind::Point,
+                    position: (x as f32 * 2.0 - 4.0, 1.0, z as f32 * 2.0 - 4.0).into(),
+                    color: (color.red, color.green, color.blue).into(),
+                    range: 2.5,
+                    ambient: 0.0,
+                    diffuse: 1.0,
+                    specular: 0.5,
+                    shadows: false,
+                });
+            }
+        }
+
+        commands.spawn(Light {
+            kind: LightKind::Spot {
+                direction: (0.0, -1.0, 0.0).into(),
+                inner_angle: Deg(15.0),
+                outer_angle: Deg(25.0),
+            },
+            position: (0.0, 4.0, 0.0).into(),
+            color: (1.0, 0.9, 0.7).into(),
+            range: 10.0,
+            ambient: 0.0,
+            diffuse: 1.5,
+            specular: 1.0,
+            shadows: true,
+        });
+    }
+
+    // Spin the lights around the origin so the shading visibly changes, same as learn-wgpu does.
+    fn orbit_lights(time: Res<Time>, mut lights: Query<&mut Light>) {
+        let rotation = Quaternion::from_axis_angle(
+            Vector3::unit_y(),
+            Deg(LIGHT_DEGREES_PER_SECOND * time.delta_secs()),
+        );
+        for mut light in &mut lights {
+            light.position = rotation.rotate_vector(light.position);
+            match &mut light.kind {
+                LightKind::Point => {}
+                LightKind::Directional { direction } | LightKind::Spot { direction, .. } => {
+                    *direction = rotation.rotate_vector(*direction);
+                }
+            }
+        }
+    }
+}
+
+mod graph {
+    use bevy::render::render_graph::{RenderLabel, RenderSubGraph};
+
+    #[derive(Debug, Hash, PartialEq, Eq, Clone, RenderSubGraph)]
+    pub struct ApplierSubgraph;
+
+    /// Each variant is named after the node it labels.
+    #[allow(clippy::enum_variant_names)]
+    #[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+    pub enum ApplierNode {
+        ExecuteNode,
+        ShadowPassNode,
+        SurfaceNode,
+        PickingNode,
+        TaaNode,
+        OutlineNode,
+        PostProcessNode,
+        TonemappingNode,
+        ErrorOverlayNode,
+        ResolveTimestampsNode,
+    }
+}
+
+mod mesh {
+    use std::mem;
+
+    use bevy::render::render_resource::{ShaderType, VertexBufferLayout};
+
+    use wgpu::{BufferAddress, VertexStepMode};
+
+    #[repr(C)]
+    #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable, ShaderType)]
+    pub struct Vertex {
+        position: [f32; 3],
+        tex_coords: [f32; 2],
+        normal: [f32; 3],
+        // xyz is the tangent, w the bitangent sign. A w of 0.0 means the model didn't provide one.
+        tangent: [f32; 4],
+    }
+
+    pub const NO_TANGENT: [f32; 4] = [0.0; 4];
+
+    pub const VERTICES: &[Vertex] = &[
+        Vertex {
+            position: [-0.0868241, 0.49240386, 0.0],
+            tex_coords: [0.4131759, 0.00759614],
+            normal: [0.0, 0.0, 1.0],
+            tangent: NO_TANGENT,
+        },
+        Vertex {
+            position: [-0.49513406, 0.06958647, 0.0],
+            tex_coords: [0.0048659444, 0.43041354],
+            normal: [0.0, 0.0, 1.0],
+            tangent: NO_TANGENT,
+        },
+        Vertex {
+            position: [-0.21918549, -0.44939706, 0.0],
+            tex_coords: [0.28081453, 0.949397],
+            normal: [0.0, 0.0, 1.0],
+            tangent: NO_TANGENT,
+        },
+        Vertex {
+            position: [0.35966998, -0.3473291, 0.0],
+            tex_coords: [0.85967, 0.84732914],
+            normal: [0.0, 0.0, 1.0],
+            tangent: NO_TANGENT,
+        },
+        Vertex {
+            position: [0.44147372, 0.2347359, 0.0],
+            tex_coords: [0.9414737, 0.2652641],
+            normal: [0.0, 0.0, 1.0],
+            tangent: NO_TANGENT,
+        },
+    ];
+
+    pub const INDICES: &[u32] = &[0, 1, 4, 1, 2, 4, 2, 3, 4, 0];
+
+    impl Vertex {
+        pub fn desc() -> VertexBufferLayout {
+            VertexBufferLayout {
+                array_stride: std::mem::size_of::<Vertex>() as BufferAddress,
+                step_mode: VertexStepMode::Vertex,
+                attributes: vec![
+                    wgpu::VertexAttribute {
+                        offset: 0,
+                        shader_location: 0,
+                        format: wgpu::VertexFormat::Float32x3,
+                    },
+                    wgpu::VertexAttribute {
+                        offset: mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
+                        shader_location: 1,
+                        format: wgpu::VertexFormat::Float32x2,
+                    },
+                    wgpu::VertexAttribute {
+                        offset: mem::size_of::<[f32; 5]>() as wgpu::BufferAddress,
+                        shader_location: 2,
+                        format: wgpu::VertexFormat::Float32x3,
+                    },
+                    wgpu::VertexAttribute {
+                        offset: mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
+                        shader_location: 3,
+                        format: wgpu::VertexFormat::Float32x4,
+                    },
+                ],
+            }
+        }
+    }
+
+    /// Feeds an indexed triangle list to mikktspace, the same tangent generator Blender and
+    /// most normal map bakers use, so baked maps line up with our shading.
+    struct TangentGeometry<'a> {
+        vertices: &'a mut [Vertex],
+        indices: &'a [u32],
+    }
+
+    impl TangentGeometry<'_> {
+        fn vertex(&self, face: usize, vert: usize) -> &Vertex {
+            &self.vertices[self.indices[face * 3 + vert] as usize]
+        }
+    }
+
+    impl bevy_mikktspace::Geometry for TangentGeometry<'_> {
+        fn num_faces(&self) -> usize {
+            self.indices.len() / 3
+        }
+
+        fn num_vertices_of_face(&self, _face: usize) -> usize {
+            3
+        }
+
+        fn position(&self, face: usize, vert: usize) -> [f32; 3] {
+            self.vertex(face, vert).position
+        }
+
+        fn normal(&self, face: usize, vert: usize) -> [f32; 3] {
+            self.vertex(face, vert).normal
+        }
+
+        fn tex_coord(&self, face: usize, vert: usize) -> [f32; 2] {
+            self.vertex(face, vert).tex_coords
+        }
+
+        fn set_tangent_encoded(&mut self, tangent: [f32; 4], face: usize, vert: usize) {
+            let index = self.indices[face * 3 + vert] as usize;
+            self.vertices[index].tangent = tangent;
+        }
+    }
+
+    /// Fills in tangents for models that don't ship with them.
+    pub fn generate_tangents(vertices: &mut [Vertex], indices: &[u32]) {
+        if vertices.iter().all(|vertex| vertex.tangent[3] != 0.0) {
+            return;
+        }
+        let mut geometry = TangentGeometry { vertices, indices };
+        if !bevy_mikktspace::generate_tangents(&mut geometry) {
+            bevy::log::warn!("failed to generate tangents, normal maps will be ignored");
+        }
+    }
+}
+
+mod node {
+    use std::time::Instant;
+
+    use bevy::{
+        ecs::world::{FromWorld, World},
+        render::{
+            render_asset::RenderAssets,
+            render_graph::Node,
+            render_phase::TrackedRenderPass,
+            render_resource::{
+                LoadOp, Operations, PipelineCache, RenderPassColorAttachment,
+                RenderPassDepthStencilAttachment, StoreOp,
+            },
+            renderer::RenderDevice,
+            view::ExtractedWindows,
+        },
+    };
+    use wgpu::{Color, CommandEncoderDescriptor, RenderPassDescriptor, RenderPassTimestampWrites};
+
+    use super::{
+        draw_commands::mesh_buffers,
+        fullscreen,
+        graph::ApplierSubgraph,
+        material::PreparedApplierMaterial,
+        material_coverage,
+        material_definition::PreparedMaterialDefinition,
+        outline::{OutlineBuffer, OutlinePipelineId, OutlineStyle, PreparedOutlines},
+        parallel_encoding::ParallelEncoding,
+        phase::{ApplierDrawFunctions, DrawTarget, OpaquePhase, TransparentPhase},
+        picking::{self, PickingReadback, PickingTexture},
+        pipeline_errors::{ErrorOverlayPipeline, PreparedErrorOverlay, WarmupState},
+        post_process::{PostProcessPipelineIds, PreparedPostProcess},
+        profiler::GpuTimestamps,
+        render_bundle::{BundleKey, BundleTargets, RenderBundleSettings, SurfaceBundle},
+        render_diagnostics::{BundleRecordings, EncodeTime, SkipReason, SkippedDraws},
+        shadow::{PreparedShadowViews, ShadowBatches, ShadowMap, ShadowPipelineIds, ShadowViews},
+        taa::{PreparedTaa, TaaPipelineId, TaaTextures},
+        tonemapping::{PreparedTonemapping, TonemappingPipelineIds},
+        ApplierDraws, InstanceBuffer, MainTextures, MousePosition, PreparedCamera, WindowTargets,
+    };
+
+    /// The background follows the mouse. The components are treated as linear values: the HDR
+    /// target stores them as they are, and the sRGB swapchain encodes them on write, so the
+    /// background only differs between the two paths by what the tonemapping operator does to it.
+    fn clear_color(mouse_position: &MousePosition, width: u32, height: u32) -> Color {
+        Color {
+            r: (mouse_position.0 as f64 / width as f64),
+            g: (mouse_position.1 as f64 / height as f64),
+            b: ((width as f64 - mouse_position.0 as f64) / width as f64),
+            a: 1.0,
+        }
+    }
+
+    fn draw_phases<'w>(
+        world: &'w World,
+        render_pass: &mut dyn DrawTarget<'w>,
+        skipped: &SkippedDraws,
+    ) {
+        let (Some(draw_functions), Some(opaque_phase), Some(transparent_phase)) = (
+            world.get_resource::<ApplierDrawFunctions>(),
+            world.get_resource::<OpaquePhase>(),
+            world.get_resource::<TransparentPhase>(),
+        ) else {
+            skipped.count(SkipReason::MissingResource);
+            return;
+        };
+        opaque_phase.render(world, render_pass, draw_functions, skipped);
+        transparent_phase.render(world, render_pass, draw_functions, skipped);
+    }
+
+    /// Timestamps for the pass labeled `label`, while profiling.
+    fn pass_timestamps<'w>(
+        world: &'w World,
+        label: &'static str,
+    ) -> Option<RenderPassTimestampWrites<'w>> {
+        world
+            .get_resource::<GpuTimestamps>()
+            .and_then(|timestamps| timestamps.pass_writes(label))
+    }
+
+    fn surface_pass_descriptor<'a>(
+        color_attachments: &'a [Option<RenderPassColorAttachment<'a>>],
+        depth_texture: &'a super::DepthTexture,
+        timestamp_writes: Option<RenderPassTimestampWrites<'a>>,
+    ) -> RenderPassDescriptor<'a> {
+        let mut depth_stencil_attachment = depth_texture
+            .view_depth_texture
+            .get_attachment(StoreOp::Store);
+        // Left out, the stencil would be read-only.
+        if depth_texture.stencil_view.is_some() {
+            depth_stencil_attachment.stencil_ops = Some(Operations {
+                load: LoadOp::Clear(0),
+                store: StoreOp::Store,
+            });
+        }
+        RenderPassDescriptor {
+            label: Some("applied_pass"),
+            color_attachments,
+            depth_stencil_attachment: Some(depth_stencil_attachment),
+            timestamp_writes,
+            occlusion_query_set: None,
+        }
+    }
+
+    /// Everything the applier pass draws, straight into the pass or through the render bundle.
+    fn draw_surface<'w>(
+        world: &'w World,
+        render_pass: &mut TrackedRenderPass<'w>,
+        render_device: &RenderDevice,
+        targets: BundleTargets,
+        skipped: &SkippedDraws,
+    ) {
+        let start = Instant::now();
+        let bundle = world
+            .get_resource::<RenderBundleSettings>()
+            .filter(|settings| settings.enabled)
+            .and_then(|_| world.get_resource::<SurfaceBundle>())
+            .and_then(|bundle| {
+                let recordings = world.get_resource::<BundleRecordings>()?;
+                Some((bundle, recordings, BundleKey::new(world, targets)?))
+            });
+        match bundle {
+            Some((bundle, recordings, key)) => bundle.draw(
+                render_device,
+                render_pass,
+                key,
+                skipped,
+                recordings,
+                |render_pass, skipped| draw_phases(world, render_pass, skipped),
+            ),
+            None => draw_phases(world, render_pass, skipped),
+        }
+        if let Some(encode_time) = world.get_resource::<EncodeTime>() {
+            encode_time.add(start.elapsed());
+        }
+    }
+
+    pub struct SurfaceNode;
+
+    impl Node for SurfaceNode {
+        fn run<'w>(
+            &self,
+            _graph: &mut bevy::render::render_graph::RenderGraphContext,
+            render_context: &mut bevy::render::renderer::RenderContext<'w>,
+            world: &'w bevy::prelude::World,
+        ) -> Result<(), bevy::render::render_graph::NodeRunError> {
+            let Some(skipped) = world.get_resource::<SkippedDraws>() else {
+                return Ok(());
+            };
+            let taa_textures = world.get_resource::<TaaTextures>();
+            let (
+                Some(windows),
+                Some(mouse_position),
+                Some(depth_textures),
+                Some(main_textures),
+                Some(picking_textures),
+                Some(parallel_encoding),
+                Some(render_device),
+            ) = (
+                world.get_resource::<ExtractedWindows>(),
+                world.get_resource::<MousePosition>(),
+                world.get_resource::<WindowTargets<super::DepthTexture>>(),
+                world.get_resource::<WindowTargets<MainTextures>>(),
+                world.get_resource::<WindowTargets<PickingTexture>>(),
+                world.get_resource::<ParallelEncoding>(),
+                world.get_resource::<RenderDevice>(),
+            )
+            else {
+                skipped.count(SkipReason::MissingResource);
+                return Ok(());
+            };
+
+            // Command buffers get submitted in the order they're added, so going by entity
+            // keeps the windows in the same order every frame.
+            let mut windows: Vec<_> = windows.iter().collect();
+            windows.sort_unstable_by_key(|(entity, _)| **entity);
+            for (&entity, window) in windows {
+                let (Some(swap_chain_view), Some(depth_texture), Some(picking_texture)) = (
+                    window.swap_chain_texture_view.as_ref(),
+                    depth_textures.get(entity),
+                    picking_textures.get(entity),
+                ) else {
+                    continue;
+                };
+                // When HDR or post-processing is on we draw into an intermediate target and
+                // let the tonemapping node write the swapchain.
+                let (view, format) = match main_textures.get(entity) {
+                    Some(main_textures) => (
+                        main_textures.main(),
+                        Some(main_textures.main_texture().format()),
+                    ),
+                    None => (swap_chain_view, window.swap_chain_texture_format),
+                };
+                let color_attachment = Some(RenderPassColorAttachment {
+                    view,
+                    resolve_target: None,
+                    ops: Operations {
+                        load: LoadOp::Clear(clear_color(
+                            mouse_position,
+                            window.physical_width,
+                            window.physical_height,
+                        )),
+                        store: StoreOp::Store,
+                    },
+                });
+                // Pipelines are specialized on the attachments, so only add the motion
+                // vectors when TAA wants them.
+                let mut color_attachments = vec![color_attachment, None];
+                let mut color_formats = vec![format, None];
+                if let Some(taa_textures) = taa_textures {
+                    color_attachments[1] = Some(RenderPassColorAttachment {
+                        view: &taa_textures.motion_vectors.default_view,
+                        resolve_target: None,
+                        ops: Operations {
+                            load: LoadOp::Clear(Color::TRANSPARENT),
+                            store: StoreOp::Store,
+                        },
+                    });
+                    color_formats[1] = Some(taa_textures.motion_vectors.texture.format());
+                }
+                color_attachments.push(Some(picking_texture.color_attachment()));
+                color_formats.push(Some(picking::PICKING_FORMAT));
+                let targets = BundleTargets {
+                    color_formats,
+                    depth_format: depth_texture.view_depth_texture.texture.format(),
+                };
+
+                let timestamp_writes = pass_timestamps(world, "applied_pass");
+                if !parallel_encoding.enabled {
+                    let mut render_pass =
+                        render_context.begin_tracked_render_pass(surface_pass_descriptor(
+                            &color_attachments,
+                            depth_texture,
+                            timestamp_writes,
+                        ));
+                    draw_surface(world, &mut render_pass, render_device, targets, skipped);
+                    continue;
+                }
+                render_context.add_command_buffer_generation_task(move |render_device| {
+                    let mut command_encoder =
+                        render_device.create_command_encoder(&CommandEncoderDescriptor {
+                            label: Some("applied_pass_command_encoder"),
+                        });
+                    {
+                        let render_pass =
+                            command_encoder.begin_render_pass(&surface_pass_descriptor(
+                                &color_attachments,
+                                depth_texture,
+                                timestamp_writes,
+                            ));
+                        let mut render_pass = TrackedRenderPass::new(&render_device, render_pass);
+                        draw_surface(world, &mut render_pass, &render_device, targets, skipped);
+                    }
+                    command_encoder.finish()
+                });
+            }
+            Ok(())
+        }
+    }
+
+    impl FromWorld for SurfaceNode {
+        fn from_world(_world: &mut bevy::prelude::World) -> Self {
+            SurfaceNode
+        }
+    }
+
+    /// Copies the pixel under the cursor out of the picking target, for the readback.
+    pub struct PickingNode;
+
+    impl Node for PickingNode {
+        fn run<'w>(
+            &self,
+            _graph: &mut bevy::render::render_graph::RenderGraphContext,
+            render_context: &mut bevy::render::renderer::RenderContext<'w>,
+            world: &'w bevy::prelude::World,
+        ) -> Result<(), bevy::render::render_graph::NodeRunError> {
+            let (
+                Some(picking_texture),
+                Some(readback),
+                Some(mouse_position),
+                Some(window),
+                Some(draws),
+            ) = (
+                world
+                    .get_resource::<WindowTargets<PickingTexture>>()
+                    .and_then(WindowTargets::primary),
+                world.get_resource::<PickingReadback>(),
+                world.get_resource::<MousePosition>(),
+                world.get_resource::<super::ExtractedWindow>(),
+                world.get_resource::<ApplierDraws>(),
+            )
+            else {
+                return Ok(());
+            };
+            let Some(pixel) = picking::cursor_pixel(mouse_position, window) else {
+                return Ok(());
+            };
+            readback.copy(
+                render_context.command_encoder(),
+                picking_texture,
+                pixel,
+                draws,
+            );
+            Ok(())
+        }
+    }
+
+    impl FromWorld for PickingNode {
+        fn from_world(_world: &mut bevy::prelude::World) -> Self {
+            PickingNode
+        }
+    }
+
+    /// Blends the applier pass output into the TAA history, then copies the result back so
+    /// post-processing picks it up.
+    pub struct TaaNode;
+
+    impl Node for TaaNode {
+        fn run<'w>(
+            &self,
+            _graph: &mut bevy::render::render_graph::RenderGraphContext,
+            render_context: &mut bevy::render::renderer::RenderContext<'w>,
+            world: &'w bevy::prelude::World,
+        ) -> Result<(), bevy::render::render_graph::NodeRunError> {
+            let (
+                Some(main_textures),
+                Some(taa_textures),
+                Some(taa),
+                Some(pipeline_cache),
+                Some(taa_pipeline),
+            ) = (
+                world
+                    .get_resource::<WindowTargets<MainTextures>>()
+                    .and_then(WindowTargets::primary),
+                world.get_resource::<TaaTextures>(),
+                world.get_resource::<PreparedTaa>(),
+                world.get_resource::<PipelineCache>(),
+                world.get_resource::<TaaPipelineId>(),
+            )
+            else {
+                return Ok(());
+            };
+
+            let Some(pipeline) = pipeline_cache.get_render_pipeline(taa_pipeline.0) else {
+                return Ok(());
+            };
+
+            fullscreen::draw_fullscreen(
+                render_context,
+                "taa_pass",
+                pass_timestamps(world, "taa_pass"),
+                &taa_textures.history_write.default_view,
+                pipeline,
+                &taa.bind_group,
+            );
+            render_context.command_encoder().copy_texture_to_texture(
+                taa_textures.history_write.texture.as_image_copy(),
+                main_textures.main_texture().as_image_copy(),
+                taa_textures.history_write.texture.size(),
+            );
+            Ok(())
+        }
+    }
+
+    impl FromWorld for TaaNode {
+        fn from_world(_world: &mut bevy::prelude::World) -> Self {
+            TaaNode
+        }
+    }
+
+    /// Draws the outlines around selected instances over the applier pass output, before
+    /// post-processing so they get tonemapped along with everything else.
+    pub struct OutlineNode;
+
+    impl Node for OutlineNode {
+        fn run<'w>(
+            &self,
+            _graph: &mut bevy::render::render_graph::RenderGraphContext,
+            render_context: &mut bevy::render::renderer::RenderContext<'w>,
+            world: &'w bevy::prelude::World,
+        ) -> Result<(), bevy::render::render_graph::NodeRunError> {
+            let (
+                Some(outlines),
+                Some(pipeline_id),
+                Some(depth_textures),
+                Some(main_textures),
+                Some(pipeline_cache),
+                Some(windows),
+            ) = (
+                world.get_resource::<PreparedOutlines>(),
+                world.get_resource::<OutlinePipelineId>(),
+                world.get_resource::<WindowTargets<super::DepthTexture>>(),
+                world.get_resource::<WindowTargets<MainTextures>>(),
+                world.get_resource::<PipelineCache>(),
+                world.get_resource::<ExtractedWindows>(),
+            )
+            else {
+                return Ok(());
+            };
+            let Some(pipeline) = pipeline_cache.get_render_pipeline(pipeline_id.0) else {
+                return Ok(());
+            };
+            // The outlined instances are picked in the primary window, and the screen space
+            // outline samples its stencil.
+            let (Some(swap_chain_view), Some(depth_texture)) = (
+                windows
+                    .primary
+                    .and_then(|primary| windows.get(&primary))
+                    .and_then(|window| window.swap_chain_texture_view.as_ref()),
+                depth_textures.primary(),
+            ) else {
+                return Ok(());
+            };
+            let view = main_textures
+                .primary()
+                .map_or(swap_chain_view, MainTextures::main);
+            let color_attachments = [Some(RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: Operations {
+                    load: LoadOp::Load,
+                    store: StoreOp::Store,
+                },
+            })];
+            // The scaled outline only tests the stencil, the screen space one samples it
+            // instead and can't have it attached at the same time.
+            let depth_stencil_attachment = (outlines.style == OutlineStyle::Scaled).then(|| {
+                RenderPassDepthStencilAttachment {
+                    view: depth_texture.view_depth_texture.view(),
+                    depth_ops: None,
+                    stencil_ops: None,
+                }
+            });
+            let mut render_pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
+                label: Some("outline_pass"),
+                color_attachments: &color_attachments,
+                depth_stencil_attachment,
+                timestamp_writes: pass_timestamps(world, "outline_pass"),
+                occlusion_query_set: None,
+            });
+            render_pass.set_render_pipeline(pipeline);
+            match outlines.style {
+                OutlineStyle::Scaled => {
+                    let (
+                        Some(camera),
+                        Ok((vertices, indices, index_count)),
+                        Some(outline_buffer),
+                        Some(materials),
+                        Some(definitions),
+                    ) = (
+                        world.get_resource::<PreparedCamera>(),
+                        mesh_buffers(world),
+                        world.get_resource::<OutlineBuffer>(),
+                        world.get_resource::<RenderAssets<PreparedApplierMaterial>>(),
+                        world.get_resource::<RenderAssets<PreparedMaterialDefinition>>(),
+                    )
+                    else {
+                        return Ok(());
+                    };
+                    let Some(instances) = outline_buffer.instances.buffer() else {
+                        return Ok(());
+                    };
+                    render_pass.set_bind_group(0, &camera.bind_group, &[]);
+                    render_pass.set_bind_group(1, &outlines.bind_group, &[]);
+                    render_pass.set_vertex_buffer(0, vertices.slice(..));
+                    render_pass.set_vertex_buffer(1, instances.slice(..));
+                    render_pass.set_index_buffer(indices.slice(..), 0, wgpu::IndexFormat::Uint32);
+                    for (material, range) in &outline_buffer.batches {
+                        let Some(coverage) = material_coverage(materials, definitions, *material)
+                        else {
+                            continue;
+                        };
+                        render_pass.set_bind_group(2, &coverage.bind_group, &[]);
+                        render_pass.draw_indexed(0..index_count, 0, range.clone());
+                    }
+                }
+                OutlineStyle::ScreenSpace => {
+                    render_pass.set_bind_group(0, &outlines.bind_group, &[]);
+                    render_pass.draw(0..3, 0..1);
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl FromWorld for OutlineNode {
+        fn from_world(_world: &mut bevy::prelude::World) -> Self {
+            OutlineNode
+        }
+    }
+
+    /// Runs the enabled post-processing effects in order, ping-ponging between the
+    /// [`MainTextures`].
+    pub struct PostProcessNode;
+
+    impl Node for PostProcessNode {
+        fn run<'w>(
+            &self,
+            _graph: &mut bevy::render::render_graph::RenderGraphContext,
+            render_context: &mut bevy::render::renderer::RenderContext<'w>,
+            world: &'w bevy::prelude::World,
+        ) -> Result<(), bevy::render::render_graph::NodeRunError> {
+            let (Some(main_textures), Some(post_process), Some(pipeline_cache), Some(pipeline_ids)) = (
+                world
+                    .get_resource::<WindowTargets<MainTextures>>()
+                    .and_then(WindowTargets::primary),
+                world.get_resource::<PreparedPostProcess>(),
+                world.get_resource::<PipelineCache>(),
+                world.get_resource::<PostProcessPipelineIds>(),
+            ) else {
+                return Ok(());
+            };
+
+            for (pass, (pipeline_id, bind_group)) in pipeline_ids
+                .0
+                .iter()
+                .zip(&post_process.bind_groups)
+                .enumerate()
+            {
+                // Skipping a pass would leave the next one reading a stale texture, so wait
+                // until every effect has compiled.
+                let Some(pipeline) = pipeline_cache.get_render_pipeline(*pipeline_id) else {
+                    return Ok(());
+                };
+                fullscreen::draw_fullscreen(
+                    render_context,
+                    "post_process_pass",
+                    pass_timestamps(world, "post_process_pass"),
+                    main_textures.destination(pass),
+                    pipeline,
+                    bind_group,
+                );
+            }
+            Ok(())
+        }
+    }
+
+    impl FromWorld for PostProcessNode {
+        fn from_world(_world: &mut bevy::prelude::World) -> Self {
+            PostProcessNode
+        }
+    }
+
+    /// Maps the intermediate target onto the swapchain. Does nothing when the applier pass
+    /// drew to the swapchain directly.
+    pub struct TonemappingNode;
+
+    impl Node for TonemappingNode {
+        fn run<'w>(
+            &self,
+            _graph: &mut bevy::render::render_graph::RenderGraphContext,
+            render_context: &mut bevy::render::renderer::RenderContext<'w>,
+            world: &'w bevy::prelude::World,
+        ) -> Result<(), bevy::render::render_graph::NodeRunError> {
+            let (
+                Some(tonemapping),
+                Some(windows),
+                Some(pipeline_cache),
+                Some(tonemapping_pipelines),
+            ) = (
+                world.get_resource::<PreparedTonemapping>(),
+                world.get_resource::<ExtractedWindows>(),
+                world.get_resource::<PipelineCache>(),
+                world.get_resource::<TonemappingPipelineIds>(),
+            )
+            else {
+                return Ok(());
+            };
+
+            for (entity, window) in windows.iter() {
+                let pipeline = tonemapping_pipelines
+                    .0
+                    .get(entity)
+                    .and_then(|id| pipeline_cache.get_render_pipeline(*id));
+                if let (Some(view), Some(pipeline)) =
+                    (window.swap_chain_texture_view.as_ref(), pipeline)
+                {
+                    fullscreen::draw_fullscreen(
+                        render_context,
+                        "tonemapping_pass",
+                        pass_timestamps(world, "tonemapping_pass"),
+                        view,
+                        pipeline,
+                        &tonemapping.bind_group,
+                    );
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl FromWorld for TonemappingNode {
+        fn from_world(_world: &mut bevy::prelude::World) -> Self {
+            TonemappingNode
+        }
+    }
+
+    /// Renders the instances into every layer of the shadow map.
+    pub struct ShadowPassNode;
+
+    impl Node for ShadowPassNode {
+        fn run<'w>(
+            &self,
+            _graph: &mut bevy::render::render_graph::RenderGraphContext,
+            render_context: &mut bevy::render::renderer::RenderContext<'w>,
+            world: &'w bevy::prelude::World,
+        ) -> Result<(), bevy::render::render_graph::NodeRunError> {
+            let Some(skipped) = world.get_resource::<SkippedDraws>() else {
+                return Ok(());
+            };
+            let (
+                Some(pipeline_cache),
+                Some(shadow_pipelines),
+                Some(shadow_views),
+                Some(shadow_view_bind_group),
+                Some(shadow_map),
+                Some(instance_buffer),
+                Some(batches),
+                Some(materials),
+                Some(definitions),
+            ) = (
+                world.get_resource::<PipelineCache>(),
+                world.get_resource::<ShadowPipelineIds>(),
+                world.get_resource::<ShadowViews>(),
+                world.get_resource::<PreparedShadowViews>(),
+                world.get_resource::<ShadowMap>(),
+                world.get_resource::<InstanceBuffer>(),
+                world.get_resource::<ShadowBatches>(),
+                world.get_resource::<RenderAssets<PreparedApplierMaterial>>(),
+                world.get_resource::<RenderAssets<PreparedMaterialDefinition>>(),
+            )
+            else {
+                skipped.count(SkipReason::MissingResource);
+                return Ok(());
+            };
+
+            let (Some(opaque_pipeline), Some(alpha_tested_pipeline)) = (
+                pipeline_cache.get_render_pipeline(shadow_pipelines.opaque),
+                pipeline_cache.get_render_pipeline(shadow_pipelines.alpha_tested),
+            ) else {
+                skipped.count(SkipReason::PipelineNotReady);
+                return Ok(());
+            };
+            let Some(instances) = instance_buffer.buf.buffer() else {
+                skipped.count(SkipReason::EmptyBuffer);
+                return Ok(());
+            };
+            let (vertices, indices, index_count) = match mesh_buffers(world) {
+                Ok(buffers) => buffers,
+                Err(reason) => {
+                    skipped.count(reason);
+                    return Ok(());
+                }
+            };
+
+            for (offset, layer_view) in shadow_views.offsets.iter().zip(&shadow_map.layer_views) {
+                let mut render_pass =
+                    render_context.begin_tracked_render_pass(RenderPassDescriptor {
+                        label: Some("shadow_pass"),
+                        color_attachments: &[],
+                        depth_stencil_attachment: Some(RenderPassDepthStencilAttachment {
+                            view: layer_view,
+                            depth_ops: Some(Operations {
+                                load: LoadOp::Clear(1.0),
+                                store: StoreOp::Store,
+                            }),
+                            stencil_ops: None,
+                        }),
+                        timestamp_writes: pass_timestamps(world, "shadow_pass"),
+                        occlusion_query_set: None,
+                    });
+                render_pass.set_bind_group(0, &shadow_view_bind_group.bind_group, &[*offset]);
+                render_pass.set_vertex_buffer(0, vertices.slice(..));
+                render_pass.set_vertex_buffer(1, instances.slice(..));
+                render_pass.set_index_buffer(indices.slice(..), 0, wgpu::IndexFormat::Uint32);
+                for (material, range) in &batches.0 {
+                    let Some(coverage) = material_coverage(materials, definitions, *material)
+                    else {
+                        skipped.count(SkipReason::MaterialNotReady);
+                        continue;
+                    };
+                    if coverage.alpha_tested {
+                        render_pass.set_render_pipeline(alpha_tested_pipeline);
+                        render_pass.set_bind_group(1, &coverage.bind_group, &[]);
+                    } else {
+                        render_pass.set_render_pipeline(opaque_pipeline);
+                    }
+                    render_pass.draw_indexed(0..index_count, 0, range.clone());
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl FromWorld for ShadowPassNode {
+        fn from_world(_world: &mut bevy::prelude::World) -> Self {
+            ShadowPassNode
+        }
+    }
+
+    /// Draws the pipeline errors over whatever ended up on the swapchain, while there are any.
+    pub struct ErrorOverlayNode;
+
+    impl Node for ErrorOverlayNode {
+        fn run<'w>(
+            &self,
+            _graph: &mut bevy::render::render_graph::RenderGraphContext,
+            render_context: &mut bevy::render::renderer::RenderContext<'w>,
+            world: &'w bevy::prelude::World,
+        ) -> Result<(), bevy::render::render_graph::NodeRunError> {
+            let (Some(overlay), Some(windows), Some(pipeline_cache), Some(overlay_pipeline)) = (
+                world.get_resource::<PreparedErrorOverlay>(),
+                world.get_resource::<ExtractedWindows>(),
+                world.get_resource::<PipelineCache>(),
+                world.get_resource::<ErrorOverlayPipeline>(),
+            ) else {
+                return Ok(());
+            };
+
+            let Some(pipeline) = pipeline_cache.get_render_pipeline(overlay_pipeline.id) else {
+                return Ok(());
+            };
+
+            for window in windows.values() {
+                let Some(view) = window.swap_chain_texture_view.as_ref() else {
+                    continue;
+                };
+                let mut render_pass =
+                    render_context.begin_tracked_render_pass(RenderPassDescriptor {
+                        label: Some("error_overlay_pass"),
+                        color_attachments: &[Some(RenderPassColorAttachment {
+                            view,
+                            resolve_target: None,
+                            ops: Operations {
+                                load: LoadOp::Load,
+                                store: StoreOp::Store,
+                            },
+                        })],
+                        depth_stencil_attachment: None,
+                        timestamp_writes: pass_timestamps(world, "error_overlay_pass"),
+                        occlusion_query_set: None,
+                    });
+                render_pass.set_render_pipeline(pipeline);
+                render_pass.set_bind_group(0, &overlay.bind_group, &[]);
+                render_pass.draw(0..3, 0..1);
+            }
+            Ok(())
+        }
+    }
+
+    impl FromWorld for ErrorOverlayNode {
+        fn from_world(_world: &mut bevy::prelude::World) -> Self {
+            ErrorOverlayNode
+        }
+    }
+
+    /// Copies the timestamps the passes before it wrote somewhere they can be read back from.
+    pub struct ResolveTimestampsNode;
+
+    impl Node for ResolveTimestampsNode {
+        fn run<'w>(
+            &self,
+            _graph: &mut bevy::render::render_graph::RenderGraphContext,
+            render_context: &mut bevy::render::renderer::RenderContext<'w>,
+            world: &'w bevy::prelude::World,
+        ) -> Result<(), bevy::render::render_graph::NodeRunError> {
+            if let Some(timestamps) = world.get_resource::<GpuTimestamps>() {
+                timestamps.resolve(render_context.command_encoder());
+            }
+            Ok(())
+        }
+    }
+
+    impl FromWorld for ResolveTimestampsNode {
+        fn from_world(_world: &mut bevy::prelude::World) -> Self {
+            ResolveTimestampsNode
+        }
+    }
+
+    pub struct ExecuteNode;
+
+    impl Node for ExecuteNode {
+        fn run<'w>(
+            &self,
+            graph: &mut bevy::render::render_graph::RenderGraphContext,
+            render_context: &mut bevy::render::renderer::RenderContext<'w>,
+            world: &'w bevy::prelude::World,
+        ) -> Result<(), bevy::render::render_graph::NodeRunError> {
+            // Nothing but a cleared window until the pipelines are ready.
+            let warming_up = world
+                .get_resource::<WarmupState>()
+                .is_some_and(|warmup| warmup.warming_up);
+            if warming_up {
+                let windows = world.get_resource::<ExtractedWindows>();
+                for window in windows.into_iter().flat_map(|windows| windows.values()) {
+                    let Some(view) = window.swap_chain_texture_view.as_ref() else {
+                        continue;
+                    };
+                    render_context.begin_tracked_render_pass(RenderPassDescriptor {
+                        label: Some("warmup_pass"),
+                        color_attachments: &[Some(RenderPassColorAttachment {
+                            view,
+                            resolve_target: None,
+                            ops: Operations {
+                                load: LoadOp::Clear(Color::BLACK),
+                                store: StoreOp::Store,
+                            },
+                        })],
+                        depth_stencil_attachment: None,
+                        timestamp_writes: None,
+                        occlusion_query_set: None,
+                    });
+                }
+                return Ok(());
+            }
+            graph.run_sub_graph(ApplierSubgraph, vec![], None)?;
+            Ok(())
+        }
+    }
+}
+
+mod material {
+    use bevy::{
+        asset::{Asset, AssetId, Handle, RenderAssetUsages},
+        ecs::system::{lifetimeless::SRes, SystemParamItem},
+        prelude::*,
+        render::{
+            render_asset::{PrepareAssetError, RenderAsset, RenderAssetPlugin, RenderAssets},
+            render_resource::{
+                AsBindGroup, AsBindGroupError, AsBindGroupShaderType, BindGroup, BindGroupEntry,
+                BindGroupLayout, OwnedBindingResource, ShaderType, UnpreparedBindGroup,
+            },
+            renderer::RenderDevice,
+            texture::GpuImage,
+        },
+    };
+    use bevy_internal::image::ImageSamplerDescriptor;
+    use serde::Deserialize;
+    use wgpu::{
+        BlendComponent, BlendFactor, BlendOperation, BlendState, CompareFunction, Extent3d, Face,
+        TextureDimension, TextureFormat,
+    };
+
+    use super::{outline::OutlineCoverage, pipeline::ApplierPipeline};
+
+    /// How a material's color is combined with what's already been drawn.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize)]
+    pub enum BlendMode {
+        #[default]
+        Opaque,
+        /// Opaque, but pixels with an alpha below `alpha_cutoff` are discarded.
+        Mask,
+        /// Regular alpha blending of an unpremultiplied color.
+        AlphaBlend,
+        /// The shader multiplies the color by alpha before blending.
+        Premultiplied,
+        /// Adds the color weighted by alpha on top, for glows and fire.
+        Additive,
+    }
+
+    impl BlendMode {
+        /// Transparent materials are drawn after everything else, sorted back to front.
+        pub fn is_transparent(&self) -> bool {
+            matches!(
+                self,
+                BlendMode::AlphaBlend | BlendMode::Premultiplied | BlendMode::Additive
+            )
+        }
+
+        pub fn blend_state(&self) -> BlendState {
+            match self {
+                BlendMode::Opaque | BlendMode::Mask => BlendState::REPLACE,
+                BlendMode::AlphaBlend => BlendState::ALPHA_BLENDING,
+                BlendMode::Premultiplied => BlendState::PREMULTIPLIED_ALPHA_BLENDING,
+                BlendMode::Additive => BlendState {
+                    color: BlendComponent {
+                        src_factor: BlendFactor::One,
+                        dst_factor: BlendFactor::One,
+                        operation: BlendOperation::Add,
+                    },
+                    alpha: BlendComponent {
+                        src_factor: BlendFactor::Zero,
+                        dst_factor: BlendFactor::One,
+                        operation: BlendOperation::Add,
+                    },
+                },
+            }
+        }
+    }
+
+    /// The fixed function state a material needs its pipeline to have.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+    pub struct RenderState {
+        pub blend_mode: BlendMode,
+        pub cull_mode: Option<Face>,
+        pub depth_write: bool,
+        pub depth_compare: CompareFunction,
+    }
+
+    /// Instances refer to a material by handle, so any number of them can share one.
+    #[derive(Asset, TypePath, AsBindGroup, Clone)]
+    #[bind_group_data(ApplierMaterialKey)]
+    #[uniform(4, ApplierMaterialUniform)]
+    pub struct ApplierMaterial {
+        /// Without an image the material is plain white.
+        #[texture(0)]
+        #[sampler(1)]
+        pub image: Option<Handle<Image>>,
+        /// Tangent space normals. Has to be loaded as linear data, not sRGB.
+        #[texture(2)]
+        #[sampler(3)]
+        pub normal_map: Option<Handle<Image>>,
+        /// Used instead of `image` when set, every instance picks its own layer.
+        #[texture(5, dimension = "2d_array")]
+        #[sampler(6)]
+        pub image_array: Option<Handle<Image>>,
+        /// Only used by [`BlendMode::Mask`].
+        pub alpha_cutoff: f32,
+        /// Used for both textures instead of the samplers their images come with. Anisotropic
+        /// filtering needs every filter mode set to linear.
+        pub sampler: ImageSamplerDescriptor,
+        /// Shifts the mip level the textures are sampled at. Samplers can't do this in wgpu, so
+        /// the shader adds it.
+        pub lod_bias: f32,
+        pub blend_mode: BlendMode,
+        pub cull_mode: Option<Face>,
+        pub depth_write: bool,
+        pub depth_compare: CompareFunction,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    pub struct ApplierMaterialKey {
+        pub normal_map: bool,
+        pub texture_array: bool,
+        pub render_state: RenderState,
+    }
+
+    #[derive(Clone, Default, ShaderType)]
+    pub struct ApplierMaterialUniform {
+        pub alpha_cutoff: f32,
+        pub lod_bias: f32,
+    }
+
+    impl AsBindGroupShaderType<ApplierMaterialUniform> for ApplierMaterial {
+        fn as_bind_group_shader_type(
+            &self,
+            _images: &RenderAssets<GpuImage>,
+        ) -> ApplierMaterialUniform {
+            ApplierMaterialUniform {
+                alpha_cutoff: self.alpha_cutoff,
+                lod_bias: self.lod_bias,
+            }
+        }
+    }
+
+    impl ApplierMaterial {
+        pub fn images(&self) -> impl Iterator<Item = AssetId<Image>> + '_ {
+            [&self.image, &self.normal_map, &self.image_array]
+                .into_iter()
+                .flatten()
+                .map(Handle::id)
+        }
+
+        fn uses_image(&self, id: AssetId<Image>) -> bool {
+            self.images().any(|image| image == id)
+        }
+    }
+
+    impl From<&ApplierMaterial> for ApplierMaterialKey {
+        fn from(material: &ApplierMaterial) -> Self {
+            Self {
+                normal_map: material.normal_map.is_some(),
+                texture_array: material.image_array.is_some(),
+                render_state: RenderState {
+                    blend_mode: material.blend_mode,
+                    cull_mode: material.cull_mode,
+                    depth_write: material.depth_write,
+                    depth_compare: material.depth_compare,
+                },
+            }
+        }
+    }
+
+    /// The render world's copy of an [`ApplierMaterial`], kept in `RenderAssets` by asset id.
+    pub struct PreparedApplierMaterial {
+        pub _bindings: Vec<(u32, OwnedBindingResource)>,
+        pub bind_group: BindGroup,
+        /// What the pipeline has to be specialized on to draw this material.
+        pub key: ApplierMaterialKey,
+        pub coverage: OutlineCoverage,
+    }
+
+    impl RenderAsset for PreparedApplierMaterial {
+        type SourceAsset = ApplierMaterial;
+        type Param = (
+            SRes<RenderDevice>,
+            SRes<ApplierPipeline>,
+            <ApplierMaterial as AsBindGroup>::Param,
+        );
+
+        fn prepare_asset(
+            material: Self::SourceAsset,
+            (render_device, pipeline, param): &mut SystemParamItem<Self::Param>,
+        ) -> Result<Self, PrepareAssetError<Self::SourceAsset>> {
+            let layout = &pipeline.material_layout;
+            match Self::new(&material, layout, render_device, param) {
+                Ok(prepared) => Ok(prepared),
+                // The textures are still loading. Show the placeholder in the meantime,
+                // `refresh_materials` gets the material prepared again once they're in.
+                Err(AsBindGroupError::RetryNextUpdate) => {
+                    let placeholder = ApplierMaterial {
+                        image: Some(PLACEHOLDER_IMAGE_HANDLE),
+                        normal_map: None,
+                        image_array: None,
+                        ..material.clone()
+                    };
+                    Self::new(&placeholder, layout, render_device, param)
+                        .map_err(|_| PrepareAssetError::RetryNextUpdate(material))
+                }
+                Err(error) => Err(PrepareAssetError::AsBindGroupError(error)),
+            }
+        }
+    }
+
+    impl PreparedApplierMaterial {
+        fn new(
+            material: &ApplierMaterial,
+            layout: &BindGroupLayout,
+            render_device: &RenderDevice,
+            param: &mut SystemParamItem<<ApplierMaterial as AsBindGroup>::Param>,
+        ) -> Result<Self, AsBindGroupError> {
+            let UnpreparedBindGroup { mut bindings, data } =
+                material.unprepared_bind_group(layout, render_device, param)?;
+            let sampler = render_device.create_sampler(&material.sampler.as_wgpu());
+            for (_, binding) in &mut bindings {
+                if let OwnedBindingResource::Sampler(image_sampler) = binding {
+                    *image_sampler = sampler.clone();
+                }
+            }
+            let entries: Vec<_> = bindings
+                .iter()
+                .map(|(index, binding)| BindGroupEntry {
+                    binding: *index,
+                    resource: binding.get_binding(),
+                })
+                .collect();
+            let bind_group =
+                render_device.create_bind_group(ApplierMaterial::label(), layout, &entries);
+
+            // Whatever the bind group got, `unprepared_bind_group` already made sure it's loaded.
+            let (images, fallback_image, _) = param;
+            let coverage_image = match (&material.image_array, &material.image) {
+                (Some(image), _) | (None, Some(image)) => images.get(image),
+                (None, None) => Some(&fallback_image.d2),
+            }
+            .ok_or(AsBindGroupError::RetryNextUpdate)?;
+            let coverage = OutlineCoverage::new(
+                render_device,
+                &coverage_image.texture,
+                &sampler,
+                material.blend_mode,
+                material.alpha_cutoff,
+                material.image_array.is_some(),
+            );
+
+            Ok(Self {
+                _bindings: bindings,
+                bind_group,
+                key: data,
+                coverage,
+            })
+        }
+    }
+
+    pub const PLACEHOLDER_IMAGE_HANDLE: Handle<Image> =
+        Handle::weak_from_u128(286873264896224331691531053091736656541);
+
+    const PLACEHOLDER_SIZE: u32 = 8;
+
+    /// A magenta and black checkerboard, hard to mistake for a finished texture.
+    fn placeholder_image() -> Image {
+        let data = (0..PLACEHOLDER_SIZE)
+            .flat_map(|y| {
+                (0..PLACEHOLDER_SIZE).flat_map(move |x| {
+                    if (x + y) % 2 == 0 {
+                        [255, 0, 255, 255]
+                    } else {
+                        [0, 0, 0, 255]
+                    }
+                })
+            })
+            .collect();
+        Image::new(
+            Extent3d {
+                width: PLACEHOLDER_SIZE,
+                height: PLACEHOLDER_SIZE,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            data,
+            TextureFormat::Rgba8UnormSrgb,
+            RenderAssetUsages::RENDER_WORLD,
+        )
+    }
+
+    pub struct MaterialPlugin;
+
+    impl Plugin for MaterialPlugin {
+        fn build(&self, app: &mut App) {
+            app.init_asset::<ApplierMaterial>()
+                .add_plugins(RenderAssetPlugin::<PreparedApplierMaterial, GpuImage>::default())
+                .add_systems(Update, refresh_materials);
+            app.world_mut()
+                .resource_mut::<Assets<Image>>()
+                .insert(&PLACEHOLDER_IMAGE_HANDLE, placeholder_image());
+        }
+    }
+
+    /// A material only gets prepared again when it changes itself, so this marks the ones
+    /// using an image that was just added, by loading or otherwise, or that changed.
+    fn refresh_materials(
+        mut events: EventReader<AssetEvent<Image>>,
+        mut materials: ResMut<Assets<ApplierMaterial>>,
+    ) {
+        for event in events.read() {
+            let (AssetEvent::Added { id } | AssetEvent::Modified { id }) = event else {
+                continue;
+            };
+            let changed: Vec<_> = materials
+                .iter()
+                .filter(|(_, material)| material.uses_image(*id))
+                .map(|(material_id, _)| material_id)
+                .collect();
+            for material_id in changed {
+                // Getting the material mutably is enough to send `AssetEvent::Modified`.
+                materials.get_mut(material_id);
+            }
+        }
+    }
+}
+
+mod pipeline {
+    use bevy::{
+        asset::Handle,
+        ecs::{system::Resource, world::FromWorld},
+        render::{
+            render_resource::{
+                AsBindGroup, BindGroupLayout, FragmentState, RenderPipelineDescriptor, Shader,
+                ShaderDefVal, SpecializedRenderPipeline, VertexState,
+            },
+            renderer::RenderDevice,
+        },
+    };
+    use wgpu::{
+        BlendState, ColorTargetState, ColorWrites, Face, FrontFace, MultisampleState, PolygonMode,
+        PrimitiveState, PrimitiveTopology,
+    };
+
+    use super::{
+        cluster::{self, LightBuffer},
+        hot_reload::ApplierShader,
+        material::{ApplierMaterial, ApplierMaterialKey, BlendMode, RenderState},
+        mesh::Vertex,
+        outline::StencilMode,
+        picking, taa, tonemapping, CameraBuffer, InstanceRaw,
+    };
+
+    /// Only release builds embed `shaders.wgsl`, see `hot_reload`.
+    #[cfg(not(debug_assertions))]
+    pub const APPLIER_SHADER_HANDLE: Handle<Shader> =
+        Handle::weak_from_u128(154484490495509739857733487233335592041);
+
+    pub const LIGHT_SHADER_HANDLE: Handle<Shader> =
+        Handle::weak_from_u128(261304829750151827468953470923157816370);
+
+    /// Every pipeline drawing in the applier pass has to match its color attachments.
+    fn main_pass_targets(
+        hdr: bool,
+        motion_vectors: bool,
+        blend: BlendState,
+    ) -> Vec<Option<ColorTargetState>> {
+        let mut targets = vec![Some(ColorTargetState {
+            format: tonemapping::main_pass_format(hdr),
+            blend: Some(blend),
+            write_mask: ColorWrites::ALL,
+        })];
+        // The instance indices stay at the same location whether TAA is on or not.
+        targets.push(motion_vectors.then_some(ColorTargetState {
+            format: taa::MOTION_VECTOR_FORMAT,
+            blend: None,
+            write_mask: ColorWrites::ALL,
+        }));
+        targets.push(Some(ColorTargetState {
+            format: picking::PICKING_FORMAT,
+            blend: None,
+            write_mask: ColorWrites::ALL,
+        }));
+        targets
+    }
+
+    #[derive(Resource)]
+    pub struct ApplierPipeline {
+        pub shader: Handle<Shader>,
+        pub material_layout: BindGroupLayout,
+        pub camera_layout: BindGroupLayout,
+        pub light_layout: BindGroupLayout,
+        pub shader_defs: Vec<ShaderDefVal>,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    pub struct ApplierPipelineKey {
+        pub material: ApplierMaterialKey,
+        /// Render into the `Rgba16Float` HDR target instead of the swapchain.
+        pub hdr: bool,
+        /// Also write motion vectors into a second target, for TAA.
+        pub motion_vectors: bool,
+        pub stencil: StencilMode,
+    }
+
+    impl FromWorld for ApplierPipeline {
+        fn from_world(world: &mut bevy::prelude::World) -> Self {
+            let render_device = world.resource::<RenderDevice>();
+
+            Self {
+                shader: world.resource::<ApplierShader>().0.clone(),
+                material_layout: ApplierMaterial::bind_group_layout(render_device),
+                camera_layout: CameraBuffer::bind_group_layout(render_device),
+                light_layout: LightBuffer::bind_group_layout(render_device),
+                shader_defs: cluster::shader_defs(render_device),
+            }
+        }
+    }
+
+    impl ApplierPipeline {
+        /// A pipeline for the applier pass with `material_layout` in group 0. Materials that
+        /// bring their own shader and layout go through here too.
+        pub fn descriptor(
+            &self,
+            shader: Handle<Shader>,
+            material_layout: BindGroupLayout,
+            mut shader_defs: Vec<ShaderDefVal>,
+            render_state: RenderState,
+            hdr: bool,
+            motion_vectors: bool,
+            stencil: StencilMode,
+        ) -> RenderPipelineDescriptor {
+            shader_defs.extend(self.shader_defs.iter().cloned());
+            if motion_vectors {
+                shader_defs.push("MOTION_VECTORS".into());
+            }
+            match render_state.blend_mode {
+                BlendMode::Mask => shader_defs.push("ALPHA_MASK".into()),
+                BlendMode::Premultiplied | BlendMode::Additive => {
+                    shader_defs.push("PREMULTIPLY_ALPHA".into())
+                }
+                BlendMode::Opaque | BlendMode::AlphaBlend => {}
+            }
+            if render_state.blend_mode.is_transparent() {
+                shader_defs.push("ALPHA_BLEND".into());
+            }
+
+            RenderPipelineDescriptor {
+                vertex: VertexState {
+                    shader: shader.clone(),
+                    entry_point: "vs_main".into(),
+                    shader_defs: shader_defs.clone(),
+                    buffers: vec![Vertex::desc(), InstanceRaw::desc()],
+                },
+                fragment: Some(FragmentState {
+                    shader,
+                    shader_defs,
+                    entry_point: "fs_main".into(),
+                    targets: main_pass_targets(
+                        hdr,
+                        motion_vectors,
+                        render_state.blend_mode.blend_state(),
+                    ),
+                }),
+                layout: vec![
+                    material_layout,
+                    self.camera_layout.clone(),
+                    self.light_layout.clone(),
+                ],
+                push_constant_ranges: Vec::new(),
+                primitive: PrimitiveState {
+                    front_face: FrontFace::Ccw,
+                    cull_mode: render_state.cull_mode,
+                    unclipped_depth: false,
+                    polygon_mode: PolygonMode::Fill,
+                    conservative: false,
+                    topology: PrimitiveTopology::TriangleList,
+                    strip_index_format: None,
+                },
+                depth_stencil: Some(wgpu::DepthStencilState {
+                    format: stencil.depth_format(),
+                    depth_write_enabled: render_state.depth_write,
+                    depth_compare: render_state.depth_compare,
+                    stencil: stencil.stencil_state(),
+                    bias: wgpu::DepthBiasState::default(),
+                }),
+                multisample: MultisampleState {
+                    count: 1,
+                    mask: !0,
+                    alpha_to_coverage_enabled: false,
+                },
+                label: Some("applier_pipeline".into()),
+                zero_initialize_workgroup_memory: true,
+            }
+        }
+    }
+
+    impl SpecializedRenderPipeline for ApplierPipeline {
+        type Key = ApplierPipelineKey;
+
+        fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
+            let mut shader_defs = Vec::new();
+            if key.material.normal_map {
+                shader_defs.push("NORMAL_MAP".into());
+            }
+            if key.material.texture_array {
+                shader_defs.push("TEXTURE_ARRAY".into());
+            }
+            self.descriptor(
+                self.shader.clone(),
+                self.material_layout.clone(),
+                shader_defs,
+                key.material.render_state,
+                key.hdr,
+                key.motion_vectors,
+                key.stencil,
+            )
+        }
+    }
+
+    /// Draws a small copy of the mesh at every light's position so we can see where they are.
+    #[derive(Resource)]
+    pub struct LightPipeline {
+        pub camera_layout: BindGroupLayout,
+        pub light_layout: BindGroupLayout,
+        pub shader_defs: Vec<ShaderDefVal>,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    pub struct LightPipelineKey {
+        pub hdr: bool,
+        pub motion_vectors: bool,
+        pub stencil: StencilMode,
+    }
+
+    impl FromWorld for LightPipeline {
+        fn from_world(world: &mut bevy::prelude::World) -> Self {
+            let render_device = world.resource::<RenderDevice>();
+
+            Self {
+                camera_layout: CameraBuffer::bind_group_layout(render_device),
+                light_layout: LightBuffer::bind_group_layout(render_device),
+                shader_defs: cluster::shader_defs(render_device),
+            }
+        }
+    }
+
+    impl SpecializedRenderPipeline for LightPipeline {
+        type Key = LightPipelineKey;
+
+        fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
+            let mut shader_defs = self.shader_defs.clone();
+            if key.motion_vectors {
+                shader_defs.push("MOTION_VECTORS".into());
+            }
+
+            RenderPipelineDescriptor {
+                vertex: VertexState {
+                    shader: LIGHT_SHADER_HANDLE,
+                    entry_point: "vs_main".into(),
+                    shader_defs: shader_defs.clone(),
+                    buffers: vec![Vertex::desc()],
+                },
+                fragment: Some(FragmentState {
+                    shader: LIGHT_SHADER_HANDLE,
+                    shader_defs,
+                    entry_point: "fs_main".into(),
+                    targets: main_pass_targets(key.hdr, key.motion_vectors, BlendState::REPLACE),
+                }),
+                layout: vec![self.camera_layout.clone(), self.light_layout.clone()],
+                push_constant_ranges: Vec::new(),
+                primitive: PrimitiveState {
+                    front_face: FrontFace::Ccw,
+                    cull_mode: Some(Face::Back),
+                    unclipped_depth: false,
+                    polygon_mode: PolygonMode::Fill,
+                    conservative: false,
+                    topology: PrimitiveTopology::TriangleList,
+                    strip_index_format: None,
+                },
+                depth_stencil: Some(wgpu::DepthStencilState {
+                    format: key.stencil.depth_format(),
+                    depth_write_enabled: true,
+                    depth_compare: wgpu::CompareFunction::Less,
+                    stencil: key.stencil.stencil_state(),
+                    bias: wgpu::DepthBiasState::default(),
+                }),
+                multisample: MultisampleState {
+                    count: 1,
+                    mask: !0,
+                    alpha_to_coverage_enabled: false,
+                },
+                label: Some("light_pipeline".into()),
+                zero_initialize_workgroup_memory: true,
+            }
+        }
+    }
+}
+
+impl Plugin for ApplierPlugin {
+    fn build(&self, app: &mut App) {
+        // Debug builds read the shader from disk, so edits show up without a rebuild.
+        #[cfg(debug_assertions)]
+        let applier_shader = app
+            .world()
+            .resource::<AssetServer>()
+            .load(hot_reload::APPLIER_SHADER_PATH);
+        #[cfg(not(debug_assertions))]
+        let applier_shader = {
+            load_internal_asset!(
+                app,
+                APPLIER_SHADER_HANDLE,
+                "shaders.wgsl",
+                Shader::from_wgsl
+            );
+            APPLIER_SHADER_HANDLE
+        };
+        app.insert_resource(hot_reload::ApplierShader(applier_shader));
+        load_internal_asset!(app, LIGHT_SHADER_HANDLE, "light.wgsl", Shader::from_wgsl);
+        load_internal_asset!(
+            app,
+            shadow::SHADOW_SHADER_HANDLE,
+            "shadow.wgsl",
+            Shader::from_wgsl
+        );
+        load_internal_asset!(
+            app,
+            fullscreen::FULLSCREEN_SHADER_HANDLE,
+            "fullscreen.wgsl",
+            Shader::from_wgsl
+        );
+        load_internal_asset!(
+            app,
+            mipmaps::MIPMAP_SHADER_HANDLE,
+            "mipmap.wgsl",
+            Shader::from_wgsl
+        );
+        load_internal_asset!(
+            app,
+            post_process::POST_PROCESS_SHADER_HANDLE,
+            "post_process.wgsl",
+            Shader::from_wgsl
+        );
+        load_internal_asset!(app, taa::TAA_SHADER_HANDLE, "taa.wgsl", Shader::from_wgsl);
+        load_internal_asset!(
+            app,
+            outline::OUTLINE_SHADER_HANDLE,
+            "outline.wgsl",
+            Shader::from_wgsl
+        );
+        load_internal_asset!(
+            app,
+            pipeline_errors::ERROR_OVERLAY_SHADER_HANDLE,
+            "error_overlay.wgsl",
+            Shader::from_wgsl
+        );
+        load_internal_asset!(
+            app,
+            tonemapping::TONEMAPPING_SHADER_HANDLE,
+            "tonemapping.wgsl",
+            Shader::from_wgsl
+        );
+        app.add_plugins((
+            camera::CameraPlugin,
+            light::LightPlugin,
+            tonemapping::TonemappingPlugin,
+            post_process::PostProcessPlugin,
+            taa::TaaPlugin,
+            render_bundle::RenderBundlePlugin,
+            material::MaterialPlugin,
+            material_definition::MaterialDefinitionPlugin,
+            atlas::AtlasPlugin,
+            render_diagnostics::RenderDiagnosticsPlugin,
+            profiler::ProfilerPlugin,
+            outline::OutlinePlugin,
+            picking::PickingPlugin,
+        ))
+        .insert_resource(MousePosition(0.0, 0.0))
+        .init_resource::<pipeline_errors::PipelineWarmup>()
+        .init_resource::<parallel_encoding::ParallelEncoding>()
+        .init_resource::<Instances>()
+        .insert_resource(camera::Camera {
+            eye: (0.0, 5.0, 10.0).into(),
+            target: (0.0, 0.0, 0.0).into(),
+            up: cgmath::Vector3::unit_y(),
+            aspect: 1.0,
+            fovy: 45.0,
+            znear: 0.1,
+            zfar: 100.0,
+            jitter: cgmath::Vector2::zero(),
+        })
+        .add_systems(Update, (cursor_events,));
+
+        let profiler = app.world().resource::<profiler::Profiler>().clone();
+        if let Some(render_app) = app.get_sub_app_mut(RenderApp) {
+            render_app
+                .insert_resource(MousePosition(0.0, 0.0))
+                .init_resource::<VertexBuffer>()
+                .init_resource::<IndexBuffer>()
+                .init_resource::<CameraBuffer>()
+                .init_resource::<cluster::ExtractedLights>()
+                .init_resource::<SpecializedRenderPipelines<ApplierPipeline>>()
+                .init_resource::<SpecializedRenderPipelines<LightPipeline>>()
+                .init_resource::<SpecializedRenderPipelines<tonemapping::TonemappingPipeline>>()
+                .init_resource::<tonemapping::TonemappingPipelineIds>()
+                .init_resource::<SpecializedRenderPipelines<post_process::PostProcessPipeline>>()
+                .init_resource::<SpecializedRenderPipelines<taa::TaaPipeline>>()
+                .init_resource::<SpecializedRenderPipelines<mipmaps::MipmapPipeline>>()
+                .init_resource::<SpecializedRenderPipelines<outline::OutlinePipeline>>()
+                .init_resource::<SpecializedRenderPipelines<shadow::ShadowPipeline>>()
+                .init_resource::<shadow::ShadowBatches>()
+                .init_resource::<outline::OutlineBuffer>()
+                .init_resource::<mipmaps::GeneratedMipmaps>()
+                .init_resource::<mipmaps::MaterialImages>()
+                .init_resource::<InstanceBuffer>()
+                .init_resource::<ApplierDraws>()
+                .init_resource::<OpaquePhase>()
+                .init_resource::<TransparentPhase>()
+                .init_resource::<ApplierDrawFunctions>()
+                .init_resource::<ApplierDrawFunctionIds>()
+                .init_resource::<render_bundle::SurfaceBundle>()
+                .init_resource::<hot_reload::LastGoodPipelines>()
+                .init_resource::<BindGroupTracker>()
+                .init_resource::<BindGroupTracker<TextureViewId>>()
+                .init_resource::<pipeline_errors::ShaderSources>()
+                .init_resource::<pipeline_errors::PipelineErrors>()
+                .init_resource::<pipeline_errors::WarmupState>()
+                .init_resource::<ExtractedWindow>()
+                .init_resource::<WindowTargets<DepthTexture>>()
+                .init_resource::<WindowTargets<MainTextures>>()
+                .init_resource::<WindowTargets<PickingTexture>>()
+                .add_systems(
+                    ExtractSchedule,
+                    (
+                        profiler.timed(extract_mouse_position),
+                        profiler.timed(extract_instances),
+                        profiler.timed(extract_camera),
+                        profiler.timed(cluster::extract_lights),
+                        profiler.timed(shadow::extract_shadow_settings),
+                        profiler.timed(tonemapping::extract_hdr_settings),
+                        profiler.timed(post_process::extract_post_process_settings),
+                        profiler.timed(taa::extract_taa_settings),
+                        profiler.timed(pipeline_errors::extract_shader_sources),
+                        profiler.timed(pipeline_errors::extract_pipeline_warmup),
+                        profiler.timed(render_bundle::extract_render_bundle_settings),
+                        profiler.timed(parallel_encoding::extract_parallel_encoding),
+                        profiler.timed(outline::extract_outline_settings),
+                        profiler.timed(extract_window),
+                        profiler.timed(mipmaps::extract_material_images),
+                    ),
+                )
+                .add_systems(
+                    Render,
+                    (
+                        mipmaps::generate_mipmaps
+                            .in_set(RenderSet::PrepareAssets)
+                            .after(prepare_assets::<GpuImage>)
+                            .before(prepare_assets::<PreparedApplierMaterial>)
+                            .before(prepare_assets::<PreparedMaterialDefinition>),
+                        queue_applier_draws.in_set(RenderSet::Queue),
+                        queue_light_draw.in_set(RenderSet::Queue),
+                        phase::sort_phases.in_set(RenderSet::PhaseSort),
+                        hot_reload::track_last_good_pipelines.in_set(RenderSet::PrepareResources),
+                        tonemapping::queue_tonemapping_pipelines.in_set(RenderSet::Queue),
+                        post_process::queue_post_process_pipelines.in_set(RenderSet::Queue),
+                        taa::queue_taa_pipeline.in_set(RenderSet::Queue),
+                        profiler
+                            .timed(prepare_depth_texture)
+                            .in_set(RenderSet::PrepareResources),
+                        profiler
+                            .timed(prepare_main_textures)
+                            .in_set(RenderSet::PrepareResources),
+                        profiler
+                            .timed(taa::prepare_taa_textures)
+                            .in_set(RenderSet::PrepareResources),
+                        profiler
+                            .timed(shadow::prepare_shadows)
+                            .in_set(RenderSet::PrepareResources),
+                        cluster::assign_lights_to_clusters
+                            .in_set(RenderSet::PrepareResources)
+                            .after(shadow::prepare_shadows)
+                            .before(prepare_buffers),
+                        profiler
+                            .timed(prepare_buffers)
+                            .in_set(RenderSet::PrepareResources),
+                        profiler
+                            .timed(prepare_bind_groups)
+                            .in_set(RenderSet::PrepareBindGroups),
+                        profiler
+                            .timed(shadow::prepare_shadow_bind_groups)
+                            .in_set(RenderSet::PrepareBindGroups),
+                        profiler
+                            .timed(taa::prepare_taa_bind_group)
+                            .in_set(RenderSet::PrepareBindGroups),
+                        profiler
+                            .timed(post_process::prepare_post_process_bind_groups)
+                            .in_set(RenderSet::PrepareBindGroups),
+                        profiler
+                            .timed(tonemapping::prepare_tonemapping_bind_group)
+                            .in_set(RenderSet::PrepareBindGroups),
+                    ),
+                )
+                .add_systems(
+                    Render,
+                    (
+                        pipeline_errors::collect_pipeline_errors
+                            .in_set(RenderSet::PrepareResources),
+                        pipeline_errors::update_pipeline_warmup.in_set(RenderSet::PrepareResources),
+                        profiler
+                            .timed(pipeline_errors::prepare_error_overlay)
+                            .in_set(RenderSet::PrepareBindGroups),
+                        outline::queue_outline_pipeline.in_set(RenderSet::Queue),
+                        shadow::queue_shadow_pipelines.in_set(RenderSet::Queue),
+                        profiler
+                            .timed(shadow::prepare_shadow_batches)
+                            .in_set(RenderSet::PrepareResources),
+                        profiler
+                            .timed(outline::prepare_outlines)
+                            .in_set(RenderSet::PrepareBindGroups),
+                        profiler
+                            .timed(picking::prepare_picking_texture)
+                            .in_set(RenderSet::PrepareResources),
+                        picking::read_picked_pixel.in_set(RenderSet::PrepareResources),
+                        picking::map_picked_pixel.in_set(RenderSet::Cleanup),
+                        bind_group_tracker::evict_unused_bind_groups::<&'static str>
+                            .in_set(RenderSet::Cleanup),
+                        bind_group_tracker::evict_unused_bind_groups::<TextureViewId>
+                            .in_set(RenderSet::Cleanup),
+                        phase::clear_phases.in_set(RenderSet::Cleanup),
+                    ),
+                );
+
+            let mut render_graph = render_app.world_mut().resource_mut::<RenderGraph>();
+            render_graph.add_node(graph::ApplierNode::ExecuteNode, node::ExecuteNode);
+
+            render_graph
+                .remove_node(CameraDriverLabel)
+                .expect("failed to remove camera driver");
+
+            render_app
+                .add_render_sub_graph(graph::ApplierSubgraph)
+                .add_render_graph_node::<ShadowPassNode>(
+                    graph::ApplierSubgraph,
+                    graph::ApplierNode::ShadowPassNode,
+                )
+                .add_render_graph_node::<SurfaceNode>(
+                    graph::ApplierSubgraph,
+                    graph::ApplierNode::SurfaceNode,
+                )
+                .add_render_graph_node::<PickingNode>(
+                    graph::ApplierSubgraph,
+                    graph::ApplierNode::PickingNode,
+                )
+                .add_render_graph_node::<TaaNode>(
+                    graph::ApplierSubgraph,
+                    graph::ApplierNode::TaaNode,
+                )
+                .add_render_graph_node::<OutlineNode>(
+                    graph::ApplierSubgraph,
+                    graph::ApplierNode::OutlineNode,
+                )
+                .add_render_graph_node::<PostProcessNode>(
+                    graph::ApplierSubgraph,
+                    graph::ApplierNode::PostProcessNode,
+                )
+                .add_render_graph_node::<TonemappingNode>(
+                    graph::ApplierSubgraph,
+                    graph::ApplierNode::TonemappingNode,
+                )
+                .add_render_graph_node::<ErrorOverlayNode>(
+                    graph::ApplierSubgraph,
+                    graph::ApplierNode::ErrorOverlayNode,
+                )
+                .add_render_graph_node::<ResolveTimestampsNode>(
+                    graph::ApplierSubgraph,
+                    graph::ApplierNode::ResolveTimestampsNode,
+                )
+                .add_render_graph_edges(
+                    graph::ApplierSubgraph,
+                    (
+                        graph::ApplierNode::ShadowPassNode,
+                        graph::ApplierNode::SurfaceNode,
+                        graph::ApplierNode::PickingNode,
+                        graph::ApplierNode::TaaNode,
+                        graph::ApplierNode::OutlineNode,
+                        graph::ApplierNode::PostProcessNode,
+                        graph::ApplierNode::TonemappingNode,
+                        graph::ApplierNode::ErrorOverlayNode,
+                        graph::ApplierNode::ResolveTimestampsNode,
+                    ),
+                );
+        }
+    }
+
+    fn finish(&self, app: &mut App) {
+        let applier_shader = app.world().resource::<hot_reload::ApplierShader>().clone();
+        if let Some(render_app) = app.get_sub_app_mut(RenderApp) {
+            render_app
+                .insert_resource(applier_shader)
+                .init_resource::<LightBuffer>()
+                .init_resource::<shadow::ShadowViews>()
+                .init_resource::<shadow::ShadowPipeline>()
+                .init_resource::<ApplierPipeline>()
+                .init_resource::<LightPipeline>()
+                .init_resource::<tonemapping::TonemappingBuffer>()
+                .init_resource::<tonemapping::TonemappingPipeline>()
+                .init_resource::<post_process::PostProcessBuffer>()
+                .init_resource::<post_process::PostProcessPipeline>()
+                .init_resource::<taa::TaaBuffer>()
+                .init_resource::<taa::TaaPipeline>()
+                .init_resource::<mipmaps::MipmapPipeline>()
+                .init_resource::<outline::OutlinePipeline>()
+                .init_resource::<picking::PickingReadback>()
+                .init_resource::<pipeline_errors::ErrorOverlayPipeline>();
+        }
+    }
+}
+
+#[derive(Resource)]
+pub struct CameraBuffer {
+    buf: DynamicUniformBuffer<CameraUniform>,
+    /// Last frame's camera, for motion vectors.
+    previous: UniformBuffer<CameraUniform>,
+    current: Option<CameraUniform>,
+}
+
+#[derive(Resource)]
+pub struct PreparedCamera {
+    bind_group: BindGroup,
+}
+
+impl FromWorld for CameraBuffer {
+    fn from_world(_world: &mut World) -> Self {
+        let buf = DynamicUniformBuffer::default();
+
+        Self {
+            buf,
+            previous: UniformBuffer::default(),
+            current: None,
+        }
+    }
+}
+
+impl CameraBuffer {
+    /// Both buffers get reallocated when what's written to them outgrows them, so this gets
+    /// rebuilt whenever that happens. `None` until both have been written.
+    pub fn bind_group(
+        &self,
+        render_device: &RenderDevice,
+        bind_groups: &mut BindGroupTracker,
+    ) -> Option<BindGroup> {
+        let entries = TrackedEntries::sequential((self.buf.buffer()?, &self.previous))?;
+        Some(
+            bind_groups.get_or_create("Camera bind group", entries, |entries| {
+                let layout = Self::bind_group_layout(render_device);
+                render_device.create_bind_group("Camera bind group", &layout, entries)
+            }),
+        )
+    }
+
+    pub fn bind_group_layout(render_device: &RenderDevice) -> BindGroupLayout {
+        render_device.create_bind_group_layout(
+            "Camera bind group layout",
+            &BindGroupLayoutEntries::sequential(
+                ShaderStages::VERTEX,
+                (
+                    uniform_buffer::<CameraUniform>(false)
+                        .visibility(ShaderStages::VERTEX_FRAGMENT),
+                    uniform_buffer::<CameraUniform>(false),
+                ),
+            ),
+        )
+    }
+}
+
+#[derive(Resource)]
+pub struct VertexBuffer(RawBufferVec<mesh::Vertex>);
+
+impl FromWorld for VertexBuffer {
+    fn from_world(_world: &mut World) -> Self {
+        let mut vertices = mesh::VERTICES.to_vec();
+        mesh::generate_tangents(&mut vertices, mesh::INDICES);
+        let mut buff = RawBufferVec::new(BufferUsages::VERTEX);
+        buff.extend(vertices);
+        Self(buff)
+    }
+}
+
+/// Which part of its material's image an instance shows.
+#[derive(Clone)]
+pub enum InstanceTexture {
+    Whole,
+    /// In uv units.
+    Rect(Rect),
+    /// One of the images packed into an atlas. Stays [`InstanceTexture::Whole`] until the
+    /// atlas is built.
+    Atlas {
+        atlas: Handle<TextureAtlas>,
+        index: usize,
+    },
+    /// For materials with an `image_array`.
+    Layer(u32),
+}
+
+/// What an instance is drawn with.
+#[derive(Clone)]
+pub enum InstanceMaterial {
+    Applier(Handle<ApplierMaterial>),
+    /// Loaded from a `.material.ron` file.
+    Defined(Handle<MaterialDefinition>),
+}
+
+/// Opaque instances are batched by this, so it tells the two kinds of material apart.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MaterialId {
+    Applier(AssetId<ApplierMaterial>),
+    Defined(AssetId<MaterialDefinition>),
+}
+
+impl InstanceMaterial {
+    pub fn id(&self) -> MaterialId {
+        match self {
+            InstanceMaterial::Applier(handle) => MaterialId::Applier(handle.id()),
+            InstanceMaterial::Defined(handle) => MaterialId::Defined(handle.id()),
+        }
+    }
+}
+
+/// The bind group of whichever material `id` refers to, once it's prepared.
+pub fn material_bind_group<'a>(
+    materials: &'a RenderAssets<PreparedApplierMaterial>,
+    definitions: &'a RenderAssets<PreparedMaterialDefinition>,
+    id: MaterialId,
+) -> Option<&'a BindGroup> {
+    match id {
+        MaterialId::Applier(id) => materials.get(id).map(|material| &material.bind_group),
+        MaterialId::Defined(id) => definitions.get(id).map(|definition| &definition.bind_group),
+    }
+}
+
+/// What the scaled outline hull samples for whichever material `id` refers to.
+pub fn material_coverage<'a>(
+    materials: &'a RenderAssets<PreparedApplierMaterial>,
+    definitions: &'a RenderAssets<PreparedMaterialDefinition>,
+    id: MaterialId,
+) -> Option<&'a OutlineCoverage> {
+    match id {
+        MaterialId::Applier(id) => materials.get(id).map(|material| &material.coverage),
+        MaterialId::Defined(id) => definitions.get(id).map(|definition| &definition.coverage),
+    }
+}
+
+#[derive(Clone)]
+struct Instance {
+    position: Vector3<f32>,
+    rotation: Quaternion<f32>,
+    material: InstanceMaterial,
+    texture: InstanceTexture,
+    /// Drawn with an outline while outlines are enabled.
+    selected: bool,
+}
+impl Instance {
+    pub fn to_raw(&self) -> InstanceRaw {
+        let model = (cgmath::Matrix4::from_translation(self.position)
+            * cgmath::Matrix4::from(self.rotation))
+        .into();
+        InstanceRaw {
+            model,
+            // Rotation only, so the rotation matrix is its own inverse transpose.
+            normal: cgmath::Matrix3::from(self.rotation).into(),
+            previous_model: model,
+            texture: match &self.texture {
+                InstanceTexture::Whole | InstanceTexture::Atlas { .. } => [0.0, 0.0, 1.0, 1.0],
+                InstanceTexture::Rect(rect) => {
+                    [rect.min.x, rect.min.y, rect.width(), rect.height()]
+                }
+                InstanceTexture::Layer(layer) => [*layer as f32, 0.0, 0.0, 0.0],
+            },
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, ShaderType, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct InstanceRaw {
+    model: [[f32; 4]; 4],
+    normal: [[f32; 3]; 3],
+    /// Last frame's model matrix, for motion vectors.
+    previous_model: [[f32; 4]; 4],
+    /// Offset and size of the instance's rect in uv units, or its layer in `x` for texture
+    /// arrays. Both share the one attribute left under the limit of 16.
+    texture: [f32; 4],
+}
+
+impl InstanceRaw {
+    pub fn desc() -> VertexBufferLayout {
+        VertexBufferLayout {
+            array_stride: std::mem::size_of::<InstanceRaw>() as BufferAddress,
+            step_mode: VertexStepMode::Instance,
+            attributes: vec![
+                wgpu::VertexAttribute {
+                    offset: 0,
+                    shader_location: 5,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+                wgpu::VertexAttribute {
+                    offset: 16,
+                    shader_location: 6,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+                wgpu::VertexAttribute {
+                    offset: 32,
+                    shader_location: 7,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+                wgpu::VertexAttribute {
+                    offset: 48,
+                    shader_location: 8,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+                wgpu::VertexAttribute {
+                    offset: 64,
+                    shader_location: 9,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+                wgpu::VertexAttribute {
+                    offset: 76,
+                    shader_location: 10,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+                wgpu::VertexAttribute {
+                    offset: 88,
+                    shader_location: 11,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+                wgpu::VertexAttribute {
+                    offset: 100,
+                    shader_location: 12,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+                wgpu::VertexAttribute {
+                    offset: 116,
+                    shader_location: 13,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+                wgpu::VertexAttribute {
+                    offset: 132,
+                    shader_location: 14,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+                wgpu::VertexAttribute {
+                    offset: 148,
+                    shader_location: 15,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+                wgpu::VertexAttribute {
+                    offset: 164,
+                    shader_location: 4,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+            ],
+        }
+    }
+}
+
+#[derive(Resource)]
+pub struct IndexBuffer(RawBufferVec<u32>);
+
+impl FromWorld for IndexBuffer {
+    fn from_world(_world: &mut World) -> Self {
+        let mut buff = RawBufferVec::new(BufferUsages::INDEX);
+        buff.extend(mesh::INDICES.to_vec());
+        Self(buff)
+    }
+}
+
+/// A `T` for every window, keyed by its entity, so windows that get encoded in parallel never
+/// write into the same target. TAA, outlines, post-processing and picking only run for the
+/// primary window and only read its targets.
+#[derive(Resource)]
+pub struct WindowTargets<T> {
+    primary: Option<Entity>,
+    targets: EntityHashMap<T>,
+}
+
+impl<T> Default for WindowTargets<T> {
+    fn default() -> Self {
+        Self {
+            primary: None,
+            targets: EntityHashMap::default(),
+        }
+    }
+}
+
+impl<T> WindowTargets<T> {
+    pub fn get(&self, window: Entity) -> Option<&T> {
+        self.targets.get(&window)
+    }
+
+    pub fn primary(&self) -> Option<&T> {
+        self.get(self.primary?)
+    }
+
+    /// Empties the targets for this frame's windows and hands back last frame's, so the ones
+    /// that stayed the same can be kept.
+    fn start_frame(&mut self, windows: &ExtractedWindows) -> EntityHashMap<T> {
+        self.primary = windows.primary;
+        std::mem::take(&mut self.targets)
+    }
+
+    fn insert(&mut self, window: Entity, target: T) {
+        self.targets.insert(window, target);
+    }
+}
+
+pub struct DepthTexture {
+    view_depth_texture: ViewDepthTexture,
+    /// The depth aspect alone, for sampling. A view of both aspects can't be bound.
+    depth_view: TextureView,
+    /// The stencil aspect, when the texture has one.
+    stencil_view: Option<TextureView>,
+}
+
+/// Color targets for when something has to run between the applier pass and the swapchain.
+/// The applier pass renders into the first one and post-processing ping-pongs between both.
+pub struct MainTextures {
+    textures: [CachedTexture; 2],
+}
+
+impl MainTextures {
+    /// The texture the applier pass renders into.
+    pub fn main(&self) -> &TextureView {
+        self.source(0)
+    }
+
+    pub fn main_texture(&self) -> &Texture {
+        &self.textures[0].texture
+    }
+
+    /// What the `pass`-th post-processing pass reads. With `pass` set to the number of
+    /// passes, this is the final image.
+    pub fn source(&self, pass: usize) -> &TextureView {
+        &self.textures[pass % 2].default_view
+    }
+
+    /// What the `pass`-th post-processing pass writes.
+    pub fn destination(&self, pass: usize) -> &TextureView {
+        self.source(pass + 1)
+    }
+}
+
+#[derive(Resource)]
+pub struct InstanceBuffer {
+    /// In the order of [`ApplierDraws::order`].
+    buf: RawBufferVec<InstanceRaw>,
+    /// Last frame's model matrices by index into [`Instances`], since the position of an
+    /// instance in the buffer changes as materials finish loading.
+    previous_models: Vec<[[f32; 4]; 4]>,
+}
+
+impl FromWorld for InstanceBuffer {
+    fn from_world(_world: &mut World) -> Self {
+        Self {
+            buf: RawBufferVec::new(BufferUsages::VERTEX),
+            previous_models: Vec::new(),
+        }
+    }
+}
+
+#[derive(Resource, Clone)]
+pub struct Instances(Vec<Instance>);
+
+/// Where every instance drawn this frame goes in the instance buffer. Instances whose
+/// material isn't prepared yet are left out.
+#[derive(Resource, Default)]
+pub struct ApplierDraws {
+    /// Indices into [`Instances`] in the order they're written to the instance buffer, which
+    /// is the order of the phases after sorting.
+    pub order: Vec<u32>,
+}
+
+const NUM_INSTANCES_PER_ROW: u32 = 10;
+const INSTANCE_DISPLACEMENT: cgmath::Vector3<f32> = cgmath::Vector3::new(
+    NUM_INSTANCES_PER_ROW as f32 * 0.5,
+    0.0,
+    NUM_INSTANCES_PER_ROW as f32 * 0.5,
+);
+
+impl FromWorld for Instances {
+    fn from_world(world: &mut World) -> Self {
+        let asset_server = world.resource::<AssetServer>().clone();
+        let image = asset_server.load("tree.png");
+        let normal_map = asset_server.load_with_settings(
+            "tree_normal.png",
+            |settings: &mut ImageLoaderSettings| {
+                settings.is_srgb = false;
+            },
+        );
+        let tinted: Handle<MaterialDefinition> = asset_server.load("materials/tinted.material.ron");
+        let tree_images = vec![
+            image.clone(),
+            asset_server.load("tree_autumn.png"),
+            asset_server.load("tree_blossom.png"),
+        ];
+        let (atlas_image, atlas, tree_array) =
+            world.resource_scope(|world, mut combined_images: Mut<CombinedImages>| {
+                let images = world.resource::<Assets<Image>>();
+                let (atlas_image, atlas) = combined_images.atlas(
+                    images,
+                    world.resource::<Assets<TextureAtlas>>(),
+                    tree_images.clone(),
+                );
+                let tree_array = combined_images.array(images, tree_images);
+                (atlas_image, atlas, tree_array)
+            });
+
+        let mut materials = world.resource_mut::<Assets<ApplierMaterial>>();
+        // The tree has a transparent background and should be visible from both sides.
+        let blended = materials.add(ApplierMaterial {
+            image: Some(image),
+            normal_map: Some(normal_map),
+            image_array: None,
+            alpha_cutoff: 0.5,
+            sampler: ImageSamplerDescriptor {
+                anisotropy_clamp: 16,
+                ..ImageSamplerDescriptor::linear()
+            },
+            lod_bias: 0.0,
+            blend_mode: BlendMode::AlphaBlend,
+            cull_mode: None,
+            depth_write: false,
+            depth_compare: CompareFunction::Less,
+        });
+        // The trees cut out instead of blended, without the normal map. They keep the pixelated
+        // look up close, and the bias stops the cutout from thinning out with distance.
+        let cutout = |image, image_array| ApplierMaterial {
+            image,
+            normal_map: None,
+            image_array,
+            alpha_cutoff: 0.5,
+            sampler: ImageSamplerDescriptor {
+                min_filter: ImageFilterMode::Linear,
+                mipmap_filter: ImageFilterMode::Linear,
+                ..ImageSamplerDescriptor::nearest()
+            },
+            lod_bias: -0.5,
+            blend_mode: BlendMode::Mask,
+            cull_mode: None,
+            depth_write: true,
+            depth_compare: CompareFunction::Less,
+        };
+        let mut atlas_cutout = cutout(Some(atlas_image), None);
+        atlas_cutout.sampler.lod_max_clamp = ATLAS_MAX_LOD;
+        let atlas_cutout = materials.add(atlas_cutout);
+        let array_cutout = materials.add(cutout(None, Some(tree_array)));
+
+        let instances = (0..NUM_INSTANCES_PER_ROW)
+            .flat_map(|z| {
+                let (blended, atlas_cutout, array_cutout, tinted, atlas) = (
+                    blended.clone(),
+                    atlas_cutout.clone(),
+                    array_cutout.clone(),
+                    tinted.clone(),
+                    atlas.clone(),
+                );
+                (0..NUM_INSTANCES_PER_ROW).map(move |x| {
+                    let position =
+                        cgmath::Vector3::new(x as f32, 0.0, z as f32) - INSTANCE_DISPLACEMENT;
+                    let rotation = if position.is_zero() {
+                        cgmath::Quaternion::from_axis_angle(
+                            cgmath::Vector3::unit_y(),
+                            cgmath::Deg(0.0),
+                        )
+                    } else {
+                        cgmath::Quaternion::from_axis_angle(
+                            position.normalize(),
+                            cgmath::Deg(45.0),
+                        )
+                    };
+                    // Alternate blended and cutout trees like a checkerboard. The cutouts come
+                    // in three colors, taken from the atlas in the front half and from the
+                    // texture array in the back, so each half is still a single draw. The
+                    // diagonal uses the material from the RON file instead.
+                    let variant = x % 3;
+                    let (material, texture) = if x == z {
+                        let material = InstanceMaterial::Defined(tinted.clone());
+                        (material, InstanceTexture::Whole)
+                    } else if (x + z) % 2 == 0 {
+                        let material = InstanceMaterial::Applier(blended.clone());
+                        (material, InstanceTexture::Whole)
+                    } else if z < NUM_INSTANCES_PER_ROW / 2 {
+                        let texture = InstanceTexture::Atlas {
+                            atlas: atlas.clone(),
+                            index: variant as usize,
+                        };
+                        (InstanceMaterial::Applier(atlas_cutout.clone()), texture)
+                    } else {
+                        let material = InstanceMaterial::Applier(array_cutout.clone());
+                        (material, InstanceTexture::Layer(variant))
+                    };
+                    Instance {
+                        position,
+                        rotation,
+                        material,
+                        texture,
+                        selected: false,
+                    }
+                })
+            })
+            .collect();
+        Self(instances)
+    }
+}
+
+fn extract_mouse_position(
+    mut mouse_position: ResMut<MousePosition>,
+    main_mouse_position: Extract<Res<MousePosition>>,
+) {
+    mouse_position.0 = main_mouse_position.0;
+    mouse_position.1 = main_mouse_position.1;
+}
+
+fn extract_instances(
+    mut commands: Commands,
+    main_instances: Extract<Res<Instances>>,
+    atlases: Extract<Res<Assets<TextureAtlas>>>,
+) {
+    let mut instances = main_instances.clone();
+    // Atlases only exist in the main world, so look up the rects while we can.
+    for instance in &mut instances.0 {
+        if let InstanceTexture::Atlas { atlas, index } = &instance.texture {
+            if let Some(rect) = atlases.get(atlas).and_then(|atlas| atlas.rects.get(*index)) {
+                instance.texture = InstanceTexture::Rect(*rect);
+            }
+        }
+    }
+    commands.insert_resource(instances);
+}
+
+pub fn extract_camera(
+    mut commands: Commands,
+    mut camera_buffer: ResMut<CameraBuffer>,
+    main_camera: Extract<Res<camera::Camera>>,
+) {
+    let view_proj = main_camera.build_view_projection_matrix();
+    let uniform = CameraUniform {
+        view_position: Vec4::new(main_camera.eye.x, main_camera.eye.y, main_camera.eye.z, 1.0),
+        view_proj: view_proj.into(),
+        jitter: Vec2::new(main_camera.jitter.x, main_camera.jitter.y),
+    };
+    camera_buffer.buf.clear();
+    camera_buffer.buf.push(&uniform);
+    // On the first frame there's no previous camera, so nothing moves.
+    let previous = camera_buffer
+        .current
+        .replace(uniform.clone())
+        .unwrap_or(uniform);
+    camera_buffer.previous.set(previous);
+    // Light clustering needs the frustum itself, not just the matrix.
+    commands.insert_resource(main_camera.clone());
+}
+
+#[derive(Resource, Debug, Default, PartialEq, Eq, Clone)]
+pub struct ExtractedWindow {
+    pub physical_width: u32,
+    pub physical_height: u32,
+}
+
+pub fn extract_window(
+    window: Extract<Single<&Window, With<PrimaryWindow>>>,
+    mut extracted_window: ResMut<ExtractedWindow>,
+) {
+    extracted_window.physical_width = window.physical_width();
+    extracted_window.physical_height = window.physical_height();
+}
+
+/// The cursor in physical pixels, like the window sizes the render world works with.
+#[derive(Resource, Debug)]
+pub struct MousePosition(f32, f32);
+
+fn cursor_events(
+    mut events: EventReader<CursorMoved>,
+    windows: Query<&Window>,
+    mut current_position: ResMut<MousePosition>,
+) {
+    for event in events.read() {
+        let scale_factor = windows
+            .get(event.window)
+            .map_or(1.0, |window| window.scale_factor());
+        current_position.0 = event.position.x * scale_factor;
+        current_position.1 = event.position.y * scale_factor;
+    }
+}
+
+fn prepare_buffers(
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    mut vertex_buffer: ResMut<VertexBuffer>,
+    mut index_buffer: ResMut<IndexBuffer>,
+    mut uniform_buffer: ResMut<CameraBuffer>,
+    mut light_buffer: ResMut<LightBuffer>,
+    mut instance_buffer: ResMut<InstanceBuffer>,
+    instances: Res<Instances>,
+    draws: Res<ApplierDraws>,
+) {
+    vertex_buffer.0.write_buffer(&render_device, &render_queue);
+    index_buffer.0.write_buffer(&render_device, &render_queue);
+    uniform_buffer
+        .buf
+        .write_buffer(&render_device, &render_queue);
+    uniform_buffer
+        .previous
+        .write_buffer(&render_device, &render_queue);
+    light_buffer.write_buffer(&render_device, &render_queue);
+    let InstanceBuffer {
+        buf,
+        previous_models,
+    } = instance_buffer.as_mut();
+    buf.clear();
+    buf.extend(draws.order.iter().map(|&index| {
+        let mut raw = instances.0[index as usize].to_raw();
+        if let Some(previous_model) = previous_models.get(index as usize) {
+            raw.previous_model = *previous_model;
+        }
+        raw
+    }));
+    *previous_models = instances.0.iter().map(|i| i.to_raw().model).collect();
+    buf.write_buffer(&render_device, &render_queue);
+}
+
+/// Specializes a pipeline for every material in use and queues an item per instance, into
+/// the [`TransparentPhase`] when it has to be blended.
+fn queue_applier_draws(
+    pipeline_cache: Res<PipelineCache>,
+    pipeline: Res<ApplierPipeline>,
+    mut pipelines: ResMut<SpecializedRenderPipelines<ApplierPipeline>>,
+    materials: Res<RenderAssets<PreparedApplierMaterial>>,
+    mut definitions: ResMut<RenderAssets<PreparedMaterialDefinition>>,
+    hdr: Res<HdrSettings>,
+    taa: Res<TaaSettings>,
+    outlines: Res<OutlineSettings>,
+    camera: Res<camera::Camera>,
+    instances: Res<Instances>,
+    draw_functions: Res<ApplierDrawFunctionIds>,
+    mut opaque_phase: ResMut<OpaquePhase>,
+    mut transparent_phase: ResMut<TransparentPhase>,
+) {
+    let eye = camera.eye.to_vec();
+    for (index, instance) in instances.0.iter().enumerate() {
+        let stencil = StencilMode::new(&outlines, instance.selected);
+        let (id, render_state) = match &instance.material {
+            InstanceMaterial::Applier(handle) => {
+                let Some(material) = materials.get(handle) else {
+                    continue;
+                };
+                let key = ApplierPipelineKey {
+                    material: material.key,
+                    hdr: hdr.enabled,
+                    motion_vectors: taa.enabled,
+                    stencil,
+                };
+                let id = pipelines.specialize(&pipeline_cache, &pipeline, key);
+                (id, material.key.render_state)
+            }
+            InstanceMaterial::Defined(handle) => {
+                let Some(definition) = definitions.get_mut(handle) else {
+                    continue;
+                };
+                let id = definition.pipeline(
+                    &pipeline_cache,
+                    &pipeline,
+                    hdr.enabled,
+                    taa.enabled,
+                    stencil,
+                );
+                (id, definition.render_state)
+            }
+        };
+        let item = ApplierPhaseItem {
+            sort_key: SortKey {
+                pipeline: id,
+                material: Some(instance.material.id()),
+                mesh: MeshId::SHARED,
+                depth: FloatOrd((instance.position - eye).magnitude()),
+            },
+            draw_function: draw_functions.instance,
+            instance: Some(index as u32),
+            instances: 0..0,
+        };
+        if render_state.blend_mode.is_transparent() {
+            transparent_phase.add(item);
+        } else {
+            opaque_phase.add(item);
+        }
+    }
+}
+
+/// The light gizmos, one item for all of them.
+fn queue_light_draw(
+    pipeline_cache: Res<PipelineCache>,
+    pipeline: Res<LightPipeline>,
+    mut pipelines: ResMut<SpecializedRenderPipelines<LightPipeline>>,
+    hdr: Res<HdrSettings>,
+    taa: Res<TaaSettings>,
+    outlines: Res<OutlineSettings>,
+    draw_functions: Res<ApplierDrawFunctionIds>,
+    mut opaque_phase: ResMut<OpaquePhase>,
+) {
+    let key = LightPipelineKey {
+        hdr: hdr.enabled,
+        motion_vectors: taa.enabled,
+        stencil: StencilMode::new(&outlines, false),
+    };
+    let id = pipelines.specialize(&pipeline_cache, &pipeline, key);
+    opaque_phase.add(ApplierPhaseItem {
+        sort_key: SortKey {
+            pipeline: id,
+            material: None,
+            mesh: MeshId::SHARED,
+            depth: FloatOrd(0.0),
+        },
+        draw_function: draw_functions.lights,
+        instance: None,
+        instances: 0..0,
+    });
+}
+
+fn prepare_bind_groups(
+    mut commands: Commands,
+    render_device: Res<RenderDevice>,
+    camera: Res<CameraBuffer>,
+    light: Res<LightBuffer>,
+    shadow_views: Res<shadow::ShadowViews>,
+    shadow_map: Res<shadow::ShadowMap>,
+    mut bind_groups: ResMut<BindGroupTracker>,
+) {
+    match camera.bind_group(&render_device, &mut bind_groups) {
+        Some(bind_group) => commands.insert_resource(PreparedCamera { bind_group }),
+        None => commands.remove_resource::<PreparedCamera>(),
+    }
+    match light.bind_group(&render_device, &mut bind_groups, &shadow_views, &shadow_map) {
+        Some(bind_group) => commands.insert_resource(PreparedLight { bind_group }),
+        None => commands.remove_resource::<PreparedLight>(),
+    }
+}
+
+fn prepare_depth_texture(
+    windows: Res<ExtractedWindows>,
+    outlines: Res<OutlineSettings>,
+    render_device: Res<RenderDevice>,
+    mut depth_textures: ResMut<WindowTargets<DepthTexture>>,
+    mut texture_cache: ResMut<TextureCache>,
+) {
+    let mut previous = depth_textures.start_frame(&windows);
+    for (&entity, window) in windows.iter() {
+        let size = Extent3d {
+            width: window.physical_width,
+            height: window.physical_height,
+            depth_or_array_layers: 1,
+        };
+
+        let descriptor = TextureDescriptor {
+            label: Some("depth_texture"),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: outline::depth_format(outlines.enabled),
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        };
+
+        let view_depth_texture = texture_cache.get(&render_device, descriptor);
+
+        // Keeps the views while the texture stays the same, so bind groups of them don't get
+        // created again every frame.
+        let (depth_view, stencil_view) = match previous.remove(&entity) {
+            Some(previous)
+                if previous.view_depth_texture.texture.id() == view_depth_texture.texture.id() =>
+            {
+                (previous.depth_view, previous.stencil_view)
+            }
+            _ => {
+                let aspect_view = |aspect| {
+                    view_depth_texture
+                        .texture
+                        .create_view(&wgpu::TextureViewDescriptor {
+                            aspect,
+                            ..Default::default()
+                        })
+                };
+                let stencil_view = view_depth_texture
+                    .texture
+                    .format()
+                    .has_stencil_aspect()
+                    .then(|| aspect_view(wgpu::TextureAspect::StencilOnly));
+                (aspect_view(wgpu::TextureAspect::DepthOnly), stencil_view)
+            }
+        };
+
+        depth_textures.insert(
+            entity,
+            DepthTexture {
+                view_depth_texture: ViewDepthTexture::new(view_depth_texture, Some(1.0)),
+                depth_view,
+                stencil_view,
+            },
+        );
+    }
+}
+
+fn prepare_main_textures(
+    windows: Res<ExtractedWindows>,
+    hdr: Res<HdrSettings>,
+    post_process: Res<PostProcessSettings>,
+    taa: Res<TaaSettings>,
+    render_device: Res<RenderDevice>,
+    mut main_textures: ResMut<WindowTargets<MainTextures>>,
+    mut texture_cache: ResMut<TextureCache>,
+) {
+    main_textures.start_frame(&windows);
+    if !hdr.enabled && !taa.enabled && post_process.enabled_effects().next().is_none() {
+        return;
+    }
+
+    for (&entity, window) in windows.iter() {
+        let size = Extent3d {
+            width: window.physical_width,
+            height: window.physical_height,
+            depth_or_array_layers: 1,
+        };
+
+        let mut texture = |label| {
+            texture_cache.get(
+                &render_device,
+                TextureDescriptor {
+                    label: Some(label),
+                    size,
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: wgpu::TextureDimension::D2,
+                    format: tonemapping::main_pass_format(hdr.enabled),
+                    // TAA copies its resolved image back in.
+                    usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+                        | wgpu::TextureUsages::TEXTURE_BINDING
+                        | wgpu::TextureUsages::COPY_DST,
+                    view_formats: &[],
+                },
+            )
+        };
+
+        main_textures.insert(
+            entity,
+            MainTextures {
+                textures: [texture("main_texture_a"), texture("main_texture_b")],
+            },
+        );
+    }
+}
@@ -0,0 +1,349 @@
+//! Images built out of several others, so instances with different textures can share one
+//! material and be drawn together.
+//!
+//! An atlas packs its sources side by side and records where each one ended up in a
+//! [`TextureAtlas`], instances pick one with `InstanceTexture::Atlas`. A texture array stacks
+//! sources of the same size as layers, picked with `InstanceTexture::Layer`. Handles to both are
+//! handed out right away, the images themselves get built once every source has loaded, and
+//! again whenever one of them changes on disk.
+
+use bevy::{
+    asset::{Asset, AssetId, Handle, RenderAssetUsages},
+    prelude::*,
+};
+use bevy_internal::image::TextureFormatPixelInfo;
+use wgpu::{Extent3d, TextureDimension, TextureViewDescriptor, TextureViewDimension};
+
+/// Space left around every image in an atlas, filled with copies of its edge texels so
+/// filtering doesn't pick up the neighbours. Every mip level halves it though, see
+/// [`ATLAS_MAX_LOD`].
+const ATLAS_PADDING: u32 = 4;
+
+/// The smallest mip level the padding still covers: a bilinear tap reaches a texel past the
+/// one it's in, and a texel of level 1 spans two of the original, so that's the last level
+/// where it stays inside the padding. Samplers of materials using an atlas should set it as
+/// their `lod_max_clamp`, smaller levels mix neighbouring images.
+pub const ATLAS_MAX_LOD: f32 = (ATLAS_PADDING.ilog2() - 1) as f32;
+
+/// Where the images of an atlas ended up.
+#[derive(Asset, TypePath, Clone, Debug, Default)]
+pub struct TextureAtlas {
+    /// In uv units of the atlas image, in the order the sources were given.
+    pub rects: Vec<Rect>,
+}
+
+enum CombinedImageKind {
+    Atlas(Handle<TextureAtlas>),
+    Array,
+}
+
+struct CombinedImage {
+    kind: CombinedImageKind,
+    sources: Vec<Handle<Image>>,
+    image: Handle<Image>,
+    built: bool,
+}
+
+impl CombinedImage {
+    fn uses_image(&self, id: AssetId<Image>) -> bool {
+        self.sources.iter().any(|source| source.id() == id)
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct CombinedImages(Vec<CombinedImage>);
+
+impl CombinedImages {
+    /// An image with `sources` packed into it, and where each of them went.
+    pub fn atlas(
+        &mut self,
+        images: &Assets<Image>,
+        atlases: &Assets<TextureAtlas>,
+        sources: Vec<Handle<Image>>,
+    ) -> (Handle<Image>, Handle<TextureAtlas>) {
+        let image = images.reserve_handle();
+        let atlas = atlases.reserve_handle();
+        self.0.push(CombinedImage {
+            kind: CombinedImageKind::Atlas(atlas.clone()),
+            sources,
+            image: image.clone(),
+            built: false,
+        });
+        (image, atlas)
+    }
+
+    /// A texture array with one layer per source. There have to be at least two, all of the
+    /// same size and format.
+    pub fn array(&mut self, images: &Assets<Image>, sources: Vec<Handle<Image>>) -> Handle<Image> {
+        let image = images.reserve_handle();
+        self.0.push(CombinedImage {
+            kind: CombinedImageKind::Array,
+            sources,
+            image: image.clone(),
+            built: false,
+        });
+        image
+    }
+}
+
+pub struct AtlasPlugin;
+
+impl Plugin for AtlasPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_asset::<TextureAtlas>()
+            .init_resource::<CombinedImages>()
+            .add_systems(Update, combine_images);
+    }
+}
+
+fn combine_images(
+    mut events: EventReader<AssetEvent<Image>>,
+    mut combined_images: ResMut<CombinedImages>,
+    mut images: ResMut<Assets<Image>>,
+    mut atlases: ResMut<Assets<TextureAtlas>>,
+) {
+    for event in events.read() {
+        if let AssetEvent::Modified { id } = event {
+            for combined in combined_images
+                .0
+                .iter_mut()
+                .filter(|combined| combined.uses_image(*id))
+            {
+                combined.built = false;
+            }
+        }
+    }
+
+    for combined in combined_images.0.iter_mut().filter(|c| !c.built) {
+        let Some(sources) = combined
+            .sources
+            .iter()
+            .map(|source| images.get(source))
+            .collect::<Option<Vec<_>>>()
+        else {
+            continue;
+        };
+        // Don't try again every frame, only once a source changes.
+        combined.built = true;
+        match &combined.kind {
+            CombinedImageKind::Atlas(atlas) => {
+                let Some((image, rects)) = pack_atlas(&sources) else {
+                    warn!("atlas sources have to be uncompressed and share a format");
+                    continue;
+                };
+                images.insert(&combined.image, image);
+                atlases.insert(atlas, TextureAtlas { rects });
+            }
+            CombinedImageKind::Array => {
+                let Some(image) = stack_texture_array(&sources) else {
+                    warn!("texture array sources have to share a size and format");
+                    continue;
+                };
+                images.insert(&combined.image, image);
+            }
+        }
+    }
+}
+
+fn shared_format(sources: &[&Image]) -> Option<wgpu::TextureFormat> {
+    let format = sources.first()?.texture_descriptor.format;
+    let shared = sources
+        .iter()
+        .all(|source| source.texture_descriptor.format == format);
+    (shared && !format.is_compressed()).then_some(format)
+}
+
+/// Shelf packing: the images go in rows from the tallest to the shortest, each row as high as
+/// its first image.
+fn pack_atlas(sources: &[&Image]) -> Option<(Image, Vec<Rect>)> {
+    let format = shared_format(sources)?;
+    let pixel_size = format.pixel_size();
+
+    let padded = |size: u32| size + ATLAS_PADDING * 2;
+    let area: u32 = sources
+        .iter()
+        .map(|source| padded(source.width()) * padded(source.height()))
+        .sum();
+    let widest = sources.iter().map(|source| padded(source.width())).max()?;
+    let width = ((area as f32).sqrt().ceil() as u32)
+        .max(widest)
+        .next_power_of_two();
+
+    let mut order: Vec<_> = (0..sources.len()).collect();
+    order.sort_by_key(|&index| std::cmp::Reverse(sources[index].height()));
+    let mut positions = vec![UVec2::ZERO; sources.len()];
+    let (mut x, mut y, mut row_height) = (0, 0, 0);
+    for index in order {
+        let source = sources[index];
+        if x + padded(source.width()) > width {
+            x = 0;
+            y += row_height;
+            row_height = 0;
+        }
+        positions[index] = UVec2::new(x + ATLAS_PADDING, y + ATLAS_PADDING);
+        x += padded(source.width());
+        row_height = row_height.max(padded(source.height()));
+    }
+    let height = y + row_height;
+
+    let mut data = vec![0; (width * height) as usize * pixel_size];
+    let padding = ATLAS_PADDING as usize;
+    for (source, position) in sources.iter().zip(&positions) {
+        let row_len = source.width() as usize * pixel_size;
+        let rows: Vec<_> = source.data.chunks_exact(row_len).collect();
+        let last_row = rows.len() - 1;
+        // The rows above and below repeat the first and last one, and every row repeats its
+        // first and last texel to the sides.
+        for y in 0..rows.len() + padding * 2 {
+            let pixels = rows[y.saturating_sub(padding).min(last_row)];
+            let row_start = ((position.y as usize + y - padding) * width as usize
+                + position.x as usize)
+                * pixel_size;
+            data[row_start..row_start + row_len].copy_from_slice(pixels);
+            let first = &pixels[..pixel_size];
+            let last = &pixels[row_len - pixel_size..];
+            for x in 1..=padding {
+                let left = row_start - x * pixel_size;
+                data[left..left + pixel_size].copy_from_slice(first);
+                let right = row_start + row_len + (x - 1) * pixel_size;
+                data[right..right + pixel_size].copy_from_slice(last);
+            }
+        }
+    }
+
+    let size = Vec2::new(width as f32, height as f32);
+    let rects = sources
+        .iter()
+        .zip(&positions)
+        .map(|(source, position)| {
+            let min = position.as_vec2();
+            Rect::from_corners(min / size, (min + source.size().as_vec2()) / size)
+        })
+        .collect();
+    let image = Image::new(
+        Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        data,
+        format,
+        RenderAssetUsages::RENDER_WORLD,
+    );
+    Some((image, rects))
+}
+
+fn stack_texture_array(sources: &[&Image]) -> Option<Image> {
+    let format = shared_format(sources)?;
+    let size = sources.first()?.size();
+    if sources.len() < 2 || sources.iter().any(|source| source.size() != size) {
+        return None;
+    }
+
+    let data = sources
+        .iter()
+        .flat_map(|source| source.data.iter().copied())
+        .collect();
+    let mut image = Image::new(
+        Extent3d {
+            width: size.x,
+            height: size.y,
+            depth_or_array_layers: sources.len() as u32,
+        },
+        TextureDimension::D2,
+        data,
+        format,
+        RenderAssetUsages::RENDER_WORLD,
+    );
+    image.texture_view_descriptor = Some(TextureViewDescriptor {
+        dimension: Some(TextureViewDimension::D2Array),
+        ..Default::default()
+    });
+    Some(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use wgpu::TextureFormat;
+
+    use super::*;
+
+    /// Every texel holds its own coordinates and `tag`.
+    fn image(width: u32, height: u32, tag: u8) -> Image {
+        let data = (0..height)
+            .flat_map(|y| (0..width).flat_map(move |x| [x as u8, y as u8, tag, 255]))
+            .collect();
+        Image::new(
+            Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            data,
+            TextureFormat::Rgba8UnormSrgb,
+            RenderAssetUsages::RENDER_WORLD,
+        )
+    }
+
+    fn texel(atlas: &Image, x: u32, y: u32) -> [u8; 4] {
+        let start = ((y * atlas.width() + x) * 4) as usize;
+        atlas.data[start..start + 4].try_into().unwrap()
+    }
+
+    /// The top left texel of `rect` in atlas texels.
+    fn origin(atlas: &Image, rect: Rect) -> UVec2 {
+        (rect.min * atlas.size().as_vec2()).round().as_uvec2()
+    }
+
+    #[test]
+    fn rects_cover_their_source() {
+        let sources = [image(8, 4, 0), image(3, 5, 1), image(6, 6, 2)];
+        let (atlas, rects) = pack_atlas(&sources.iter().collect::<Vec<_>>()).unwrap();
+
+        assert!(atlas.width().is_power_of_two());
+        for (tag, (source, rect)) in sources.iter().zip(&rects).enumerate() {
+            let size = (rect.size() * atlas.size().as_vec2()).round().as_uvec2();
+            assert_eq!(size, source.size());
+            let origin = origin(&atlas, *rect);
+            for y in 0..source.height() {
+                for x in 0..source.width() {
+                    assert_eq!(
+                        texel(&atlas, origin.x + x, origin.y + y),
+                        [x as u8, y as u8, tag as u8, 255]
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn padding_repeats_the_edge_texels() {
+        let sources = [image(4, 4, 0), image(2, 3, 1)];
+        let (atlas, rects) = pack_atlas(&sources.iter().collect::<Vec<_>>()).unwrap();
+
+        for (tag, (source, rect)) in sources.iter().zip(&rects).enumerate() {
+            let origin = origin(&atlas, *rect).as_ivec2();
+            let padding = ATLAS_PADDING as i32;
+            let size = source.size().as_ivec2();
+            for y in -padding..size.y + padding {
+                for x in -padding..size.x + padding {
+                    let nearest = IVec2::new(x, y).clamp(IVec2::ZERO, size - 1);
+                    let position = (origin + IVec2::new(x, y)).as_uvec2();
+                    assert_eq!(
+                        texel(&atlas, position.x, position.y),
+                        [nearest.x as u8, nearest.y as u8, tag as u8, 255]
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mixed_formats_are_not_packed() {
+        let mut other = image(4, 4, 1);
+        other.texture_descriptor.format = TextureFormat::Rgba8Unorm;
+        assert!(pack_atlas(&[&image(4, 4, 0), &other]).is_none());
+    }
+}
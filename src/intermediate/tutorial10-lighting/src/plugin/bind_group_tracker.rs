@@ -0,0 +1,339 @@
+//! Bind groups that get rebuilt when what they bind gets replaced.
+//!
+//! A bind group points at the exact buffers and textures it was created with. Buffers like
+//! `DynamicUniformBuffer` reallocate when their contents outgrow them, textures get recreated
+//! when the window is resized, and a bind group made before that keeps pointing at the old
+//! one. Bind groups going through a [`BindGroupTracker`] get their entries from
+//! [`TrackedEntries`], which remembers the id of everything it binds, and only get created
+//! again when one of those ids changed. The tracker is keyed by whatever tells its bind
+//! groups apart, a label for ones there's only one of, or something like a pass or material
+//! for ones made per item.
+
+use std::{fmt::Debug, hash::Hash};
+
+use bevy::{
+    prelude::*,
+    render::render_resource::{
+        encase::internal::WriteInto, BindGroup, BindGroupEntry, BindingResource, Buffer, BufferId,
+        DynamicUniformBuffer, Sampler, SamplerId, ShaderType, StorageBuffer, TextureView,
+        TextureViewId, UniformBuffer,
+    },
+    utils::HashMap,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ResourceId {
+    Buffer(BufferId),
+    TextureView(TextureViewId),
+    Sampler(SamplerId),
+}
+
+/// A bind group entry's resource, along with the id of what it binds.
+pub struct TrackedBinding<'a> {
+    id: ResourceId,
+    resource: BindingResource<'a>,
+}
+
+/// Things that can be bound and told apart from whatever replaces them.
+pub trait IntoTrackedBinding<'a> {
+    /// `None` for buffers that haven't been written yet.
+    fn into_tracked_binding(self) -> Option<TrackedBinding<'a>>;
+}
+
+impl<'a> IntoTrackedBinding<'a> for TrackedBinding<'a> {
+    fn into_tracked_binding(self) -> Option<TrackedBinding<'a>> {
+        Some(self)
+    }
+}
+
+impl<'a> IntoTrackedBinding<'a> for &'a TextureView {
+    fn into_tracked_binding(self) -> Option<TrackedBinding<'a>> {
+        Some(TrackedBinding {
+            id: ResourceId::TextureView(self.id()),
+            resource: BindingResource::TextureView(self),
+        })
+    }
+}
+
+impl<'a> IntoTrackedBinding<'a> for &'a Sampler {
+    fn into_tracked_binding(self) -> Option<TrackedBinding<'a>> {
+        Some(TrackedBinding {
+            id: ResourceId::Sampler(self.id()),
+            resource: BindingResource::Sampler(self),
+        })
+    }
+}
+
+/// The whole buffer.
+impl<'a> IntoTrackedBinding<'a> for &'a Buffer {
+    fn into_tracked_binding(self) -> Option<TrackedBinding<'a>> {
+        Some(TrackedBinding {
+            id: ResourceId::Buffer(self.id()),
+            resource: self.as_entire_binding(),
+        })
+    }
+}
+
+macro_rules! impl_buffer_wrapper {
+    ($($wrapper:ident),*) => {
+        $(
+            /// What the wrapper's own `binding()` binds.
+            impl<'a, T: ShaderType + WriteInto> IntoTrackedBinding<'a> for &'a $wrapper<T> {
+                fn into_tracked_binding(self) -> Option<TrackedBinding<'a>> {
+                    Some(TrackedBinding {
+                        id: ResourceId::Buffer(self.buffer()?.id()),
+                        resource: self.binding()?,
+                    })
+                }
+            }
+        )*
+    };
+}
+
+impl_buffer_wrapper!(UniformBuffer, DynamicUniformBuffer, StorageBuffer);
+
+/// The ids of everything a bind group binds, in binding order.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct BindGroupDependencies(Vec<ResourceId>);
+
+/// The entries of a bind group, with the dependencies the tracker compares taken from the
+/// same resources.
+pub struct TrackedEntries<'a> {
+    entries: Vec<BindGroupEntry<'a>>,
+    dependencies: BindGroupDependencies,
+}
+
+impl<'a> TrackedEntries<'a> {
+    /// Bound at 0, 1, 2 and so on, like `BindGroupEntries::sequential`. `None` when one of
+    /// the buffers hasn't been written yet.
+    pub fn sequential(bindings: impl IntoTrackedBindings<'a>) -> Option<Self> {
+        let mut entries = Vec::new();
+        let mut dependencies = BindGroupDependencies::default();
+        for (binding, tracked) in bindings.into_tracked_bindings()?.into_iter().enumerate() {
+            entries.push(BindGroupEntry {
+                binding: binding as u32,
+                resource: tracked.resource,
+            });
+            dependencies.0.push(tracked.id);
+        }
+        Some(Self {
+            entries,
+            dependencies,
+        })
+    }
+
+    pub fn single(binding: impl IntoTrackedBinding<'a>) -> Option<Self> {
+        Self::sequential((binding,))
+    }
+}
+
+/// Tuples of [`IntoTrackedBinding`], for [`TrackedEntries::sequential`].
+pub trait IntoTrackedBindings<'a> {
+    fn into_tracked_bindings(self) -> Option<Vec<TrackedBinding<'a>>>;
+}
+
+macro_rules! impl_tracked_bindings_tuple {
+    ($($binding:ident),*) => {
+        impl<'a, $($binding: IntoTrackedBinding<'a>),*> IntoTrackedBindings<'a>
+            for ($($binding,)*)
+        {
+            #[allow(non_snake_case)]
+            fn into_tracked_bindings(self) -> Option<Vec<TrackedBinding<'a>>> {
+                let ($($binding,)*) = self;
+                Some(vec![$($binding.into_tracked_binding()?),*])
+            }
+        }
+    };
+}
+
+impl_tracked_bindings_tuple!(B0);
+impl_tracked_bindings_tuple!(B0, B1);
+impl_tracked_bindings_tuple!(B0, B1, B2);
+impl_tracked_bindings_tuple!(B0, B1, B2, B3);
+impl_tracked_bindings_tuple!(B0, B1, B2, B3, B4);
+impl_tracked_bindings_tuple!(B0, B1, B2, B3, B4, B5);
+impl_tracked_bindings_tuple!(B0, B1, B2, B3, B4, B5, B6);
+impl_tracked_bindings_tuple!(B0, B1, B2, B3, B4, B5, B6, B7);
+
+struct Tracked<V> {
+    dependencies: BindGroupDependencies,
+    value: V,
+    used: bool,
+}
+
+/// What's behind [`BindGroupTracker`], apart from the bind groups themselves.
+struct DependencyCache<K, V> {
+    values: HashMap<K, Tracked<V>>,
+}
+
+impl<K, V> Default for DependencyCache<K, V> {
+    fn default() -> Self {
+        Self {
+            values: HashMap::default(),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Debug, V: Clone> DependencyCache<K, V> {
+    fn get_or_create(
+        &mut self,
+        key: K,
+        dependencies: BindGroupDependencies,
+        create: impl FnOnce() -> V,
+    ) -> V {
+        if let Some(tracked) = self.values.get_mut(&key) {
+            tracked.used = true;
+            if tracked.dependencies == dependencies {
+                return tracked.value.clone();
+            }
+            debug!("rebuilding bind group {key:?}, something it binds got reallocated");
+        }
+        let value = create();
+        self.values.insert(
+            key,
+            Tracked {
+                dependencies,
+                value: value.clone(),
+                used: true,
+            },
+        );
+        value
+    }
+
+    fn evict_unused(&mut self) {
+        self.values.retain(|_, tracked| tracked.used);
+        for tracked in self.values.values_mut() {
+            tracked.used = false;
+        }
+    }
+}
+
+/// Bind groups by `K`. Bind groups there's only one of go by their label.
+#[derive(Resource)]
+pub struct BindGroupTracker<K = &'static str>(DependencyCache<K, BindGroup>);
+
+impl<K> Default for BindGroupTracker<K> {
+    fn default() -> Self {
+        Self(DependencyCache::default())
+    }
+}
+
+impl<K: Eq + Hash + Debug> BindGroupTracker<K> {
+    /// The bind group made for `key` before, or a new one from `create` when there wasn't any
+    /// or one of the resources in `entries` got replaced since.
+    pub fn get_or_create<'a>(
+        &mut self,
+        key: K,
+        entries: TrackedEntries<'a>,
+        create: impl FnOnce(&[BindGroupEntry<'a>]) -> BindGroup,
+    ) -> BindGroup {
+        let TrackedEntries {
+            entries,
+            dependencies,
+        } = entries;
+        self.0.get_or_create(key, dependencies, || create(&entries))
+    }
+}
+
+/// Drops the bind groups nobody asked for this frame, like the tonemapping one while HDR is
+/// off, so they don't keep old textures alive.
+pub fn evict_unused_bind_groups<K: Eq + Hash + Debug + Send + Sync + 'static>(
+    mut tracker: ResMut<BindGroupTracker<K>>,
+) {
+    tracker.0.evict_unused();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dependencies(ids: &[ResourceId]) -> BindGroupDependencies {
+        BindGroupDependencies(ids.to_vec())
+    }
+
+    #[test]
+    fn same_dependencies_reuse_the_value() {
+        let buffer = ResourceId::Buffer(BufferId::new());
+        let view = ResourceId::TextureView(TextureViewId::new());
+        let mut cache = DependencyCache::default();
+
+        assert_eq!(
+            cache.get_or_create("a", dependencies(&[buffer, view]), || 1),
+            1
+        );
+        assert_eq!(
+            cache.get_or_create("a", dependencies(&[buffer, view]), || 2),
+            1
+        );
+    }
+
+    #[test]
+    fn replaced_dependency_rebuilds_the_value() {
+        let sampler = ResourceId::Sampler(SamplerId::new());
+        let old_buffer = ResourceId::Buffer(BufferId::new());
+        let new_buffer = ResourceId::Buffer(BufferId::new());
+        let mut cache = DependencyCache::default();
+
+        assert_eq!(
+            cache.get_or_create("a", dependencies(&[old_buffer, sampler]), || 1),
+            1
+        );
+        assert_eq!(
+            cache.get_or_create("a", dependencies(&[new_buffer, sampler]), || 2),
+            2
+        );
+        // The new dependencies are what gets compared from now on.
+        assert_eq!(
+            cache.get_or_create("a", dependencies(&[new_buffer, sampler]), || 3),
+            2
+        );
+    }
+
+    #[test]
+    fn reordered_dependencies_rebuild_the_value() {
+        let buffer = ResourceId::Buffer(BufferId::new());
+        let view = ResourceId::TextureView(TextureViewId::new());
+        let mut cache = DependencyCache::default();
+
+        assert_eq!(
+            cache.get_or_create("a", dependencies(&[buffer, view]), || 1),
+            1
+        );
+        assert_eq!(
+            cache.get_or_create("a", dependencies(&[view, buffer]), || 2),
+            2
+        );
+    }
+
+    #[test]
+    fn keys_are_tracked_separately() {
+        let buffer = ResourceId::Buffer(BufferId::new());
+        let mut cache = DependencyCache::default();
+
+        assert_eq!(cache.get_or_create("a", dependencies(&[buffer]), || 1), 1);
+        assert_eq!(cache.get_or_create("b", dependencies(&[buffer]), || 2), 2);
+        assert_eq!(cache.get_or_create("a", dependencies(&[buffer]), || 3), 1);
+    }
+
+    #[test]
+    fn evict_unused_drops_only_values_not_asked_for() {
+        let buffer = ResourceId::Buffer(BufferId::new());
+        let mut cache = DependencyCache::default();
+        cache.get_or_create("used", dependencies(&[buffer]), || 1);
+        cache.get_or_create("unused", dependencies(&[buffer]), || 2);
+        cache.evict_unused();
+
+        // Only "used" gets asked for during the next frame.
+        cache.get_or_create("used", dependencies(&[buffer]), || 3);
+        cache.evict_unused();
+
+        assert_eq!(
+            cache.get_or_create("used", dependencies(&[buffer]), || 4),
+            1
+        );
+        assert_eq!(
+            cache.get_or_create("unused", dependencies(&[buffer]), || 5),
+            5
+        );
+    }
+}
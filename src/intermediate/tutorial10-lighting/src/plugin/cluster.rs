@@ -0,0 +1,753 @@
+//! Clustered forward light assignment.
+//!
+//! The view frustum is cut into a grid of clusters (screen-space tiles times exponential depth
+//! slices). Every frame the extracted lights are tested against each cluster on the CPU and the
+//! result is uploaded as a flat list of light indices plus an `(offset, count)` pair per cluster.
+//! The fragment shader works out which cluster it is in and only loops over those lights.
+//! Directional lights reach every cluster, so they aren't clustered at all: they come first in
+//! the light buffer and every fragment loops over the first `directional_light_count` lights
+//! before its cluster's.
+//!
+//! Adapters that can't bind storage buffers in the fragment stage get fixed size uniform arrays
+//! instead, with the cluster data packed into `vec4<u32>`s.
+
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::{
+            binding_types::{sampler, storage_buffer_read_only, texture_2d_array, uniform_buffer},
+            BindGroup, BindGroupLayout, BindGroupLayoutEntries, BufferBindingType,
+            SamplerBindingType, ShaderDefVal, ShaderStages, ShaderType, StorageBuffer,
+            TextureSampleType, UniformBuffer,
+        },
+        renderer::{RenderDevice, RenderQueue},
+        Extract,
+    },
+};
+use cgmath::{Deg, EuclideanSpace, InnerSpace, Matrix4, Point3, Rad, Transform};
+
+use super::{
+    bind_group_tracker::{BindGroupTracker, IntoTrackedBinding, TrackedEntries},
+    camera,
+    light::{Light, LightKind},
+    shadow::{GpuShadows, ShadowMap, ShadowViews},
+    ExtractedWindow,
+};
+
+/// Number of clusters along x, y (screen tiles) and z (depth slices).
+pub const CLUSTER_DIMENSIONS: UVec3 = UVec3::new(16, 9, 24);
+
+/// Uniform buffers are limited to 16KiB on the adapters that need the fallback.
+pub const MAX_UNIFORM_LIGHTS: usize = 128;
+pub const MAX_UNIFORM_ITEMS: usize = 1024;
+
+/// Set when the light data is bound as storage buffers instead of the uniform fallback.
+pub const LIGHTS_STORAGE_BUFFERS_DEF: &str = "LIGHTS_STORAGE_BUFFERS";
+
+const LIGHT_KIND_POINT: u32 = 0;
+const LIGHT_KIND_SPOT: u32 = 1;
+const LIGHT_KIND_DIRECTIONAL: u32 = 2;
+
+const NO_SHADOW: i32 = -1;
+
+#[derive(Debug, Clone, Copy, Default, ShaderType)]
+pub struct GpuLight {
+    position: Vec3,
+    range: f32,
+    color: Vec3,
+    kind: u32,
+    direction: Vec3,
+    spot_inner_cos: f32,
+    spot_outer_cos: f32,
+    ambient: f32,
+    diffuse: f32,
+    specular: f32,
+    // First layer in the shadow map, or -1 if the light doesn't cast shadows.
+    shadow_layer: i32,
+}
+
+impl GpuLight {
+    fn new(light: &Light, shadow_layer: Option<u32>) -> Self {
+        let (kind, direction, spot_inner_cos, spot_outer_cos) = match light.kind {
+            LightKind::Point => (LIGHT_KIND_POINT, Vec3::ZERO, 0.0, 0.0),
+            LightKind::Directional { direction } => {
+                let direction = direction.normalize();
+                (
+                    LIGHT_KIND_DIRECTIONAL,
+                    Vec3::new(direction.x, direction.y, direction.z),
+                    0.0,
+                    0.0,
+                )
+            }
+            LightKind::Spot {
+                direction,
+                inner_angle,
+                outer_angle,
+            } => {
+                let direction = direction.normalize();
+                (
+                    LIGHT_KIND_SPOT,
+                    Vec3::new(direction.x, direction.y, direction.z),
+                    Rad::from(inner_angle).0.cos(),
+                    Rad::from(outer_angle).0.cos(),
+                )
+            }
+        };
+        Self {
+            position: Vec3::new(light.position.x, light.position.y, light.position.z),
+            range: light.range,
+            color: Vec3::new(light.color.x, light.color.y, light.color.z),
+            kind,
+            direction,
+            spot_inner_cos,
+            spot_outer_cos,
+            ambient: light.ambient,
+            diffuse: light.diffuse,
+            specular: light.specular,
+            shadow_layer: shadow_layer.map_or(NO_SHADOW, |layer| layer as i32),
+        }
+    }
+}
+
+#[derive(Debug, Clone, ShaderType)]
+pub struct ClusterConfig {
+    dimensions: UVec3,
+    light_count: u32,
+    screen_size: Vec2,
+    z_near: f32,
+    z_far: f32,
+    directional_light_count: u32,
+}
+
+#[derive(Default, ShaderType)]
+struct GpuLightsStorage {
+    #[size(runtime)]
+    data: Vec<GpuLight>,
+}
+
+#[derive(ShaderType)]
+struct GpuLightsUniform {
+    data: Box<[GpuLight; MAX_UNIFORM_LIGHTS]>,
+}
+
+#[derive(Default, ShaderType)]
+struct ClusterLightIndexListsStorage {
+    #[size(runtime)]
+    data: Vec<u32>,
+}
+
+#[derive(ShaderType)]
+struct ClusterLightIndexListsUniform {
+    // Light indices packed as u8, sixteen to a vec4.
+    data: Box<[UVec4; MAX_UNIFORM_ITEMS]>,
+}
+
+#[derive(Default, ShaderType)]
+struct ClusterOffsetsAndCountsStorage {
+    #[size(runtime)]
+    data: Vec<UVec2>,
+}
+
+#[derive(ShaderType)]
+struct ClusterOffsetsAndCountsUniform {
+    // One `offset << 8 | count` per cluster, four to a vec4.
+    data: Box<[UVec4; MAX_UNIFORM_ITEMS]>,
+}
+
+enum GpuClusteredLights {
+    Storage {
+        lights: StorageBuffer<GpuLightsStorage>,
+        index_lists: StorageBuffer<ClusterLightIndexListsStorage>,
+        offsets_and_counts: StorageBuffer<ClusterOffsetsAndCountsStorage>,
+    },
+    Uniform {
+        lights: UniformBuffer<GpuLightsUniform>,
+        index_lists: UniformBuffer<ClusterLightIndexListsUniform>,
+        offsets_and_counts: UniformBuffer<ClusterOffsetsAndCountsUniform>,
+    },
+}
+
+#[derive(Resource)]
+pub struct LightBuffer {
+    buffers: GpuClusteredLights,
+    config: UniformBuffer<ClusterConfig>,
+}
+
+#[derive(Resource)]
+pub struct PreparedLight {
+    pub bind_group: BindGroup,
+}
+
+impl FromWorld for LightBuffer {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let buffers = match binding_type(render_device) {
+            BufferBindingType::Storage { .. } => GpuClusteredLights::Storage {
+                lights: StorageBuffer::default(),
+                index_lists: StorageBuffer::default(),
+                offsets_and_counts: StorageBuffer::default(),
+            },
+            BufferBindingType::Uniform => GpuClusteredLights::Uniform {
+                lights: UniformBuffer::from(GpuLightsUniform {
+                    data: Box::new([GpuLight::default(); MAX_UNIFORM_LIGHTS]),
+                }),
+                index_lists: UniformBuffer::from(ClusterLightIndexListsUniform {
+                    data: Box::new([UVec4::ZERO; MAX_UNIFORM_ITEMS]),
+                }),
+                offsets_and_counts: UniformBuffer::from(ClusterOffsetsAndCountsUniform {
+                    data: Box::new([UVec4::ZERO; MAX_UNIFORM_ITEMS]),
+                }),
+            },
+        };
+        let config = UniformBuffer::from(ClusterConfig {
+            dimensions: CLUSTER_DIMENSIONS,
+            light_count: 0,
+            screen_size: Vec2::ONE,
+            z_near: 0.1,
+            z_far: 100.0,
+            directional_light_count: 0,
+        });
+
+        Self { buffers, config }
+    }
+}
+
+fn binding_type(render_device: &RenderDevice) -> BufferBindingType {
+    // Lights, index lists and offsets are the three storage buffers the fragment stage needs.
+    render_device.get_supported_read_only_binding_type(3)
+}
+
+/// Shader defs every pipeline that binds [`LightBuffer`] has to be specialized with.
+pub fn shader_defs(render_device: &RenderDevice) -> Vec<ShaderDefVal> {
+    match binding_type(render_device) {
+        BufferBindingType::Storage { .. } => vec![LIGHTS_STORAGE_BUFFERS_DEF.into()],
+        BufferBindingType::Uniform => vec![],
+    }
+}
+
+impl LightBuffer {
+    pub fn light_count(&self) -> u32 {
+        self.config.get().light_count
+    }
+
+    /// The light buffers grow with the number of lights, so this gets rebuilt whenever they
+    /// do. `None` until all of them have been written.
+    pub fn bind_group(
+        &self,
+        render_device: &RenderDevice,
+        bind_groups: &mut BindGroupTracker,
+        shadow_views: &ShadowViews,
+        shadow_map: &ShadowMap,
+    ) -> Option<BindGroup> {
+        let (lights, index_lists, offsets_and_counts) = match &self.buffers {
+            GpuClusteredLights::Storage {
+                lights,
+                index_lists,
+                offsets_and_counts,
+            } => (
+                lights.into_tracked_binding()?,
+                index_lists.into_tracked_binding()?,
+                offsets_and_counts.into_tracked_binding()?,
+            ),
+            GpuClusteredLights::Uniform {
+                lights,
+                index_lists,
+                offsets_and_counts,
+            } => (
+                lights.into_tracked_binding()?,
+                index_lists.into_tracked_binding()?,
+                offsets_and_counts.into_tracked_binding()?,
+            ),
+        };
+        let entries = TrackedEntries::sequential((
+            lights,
+            index_lists,
+            offsets_and_counts,
+            &self.config,
+            &shadow_map.array_view,
+            shadow_views.sampler(),
+            shadow_views.uniform(),
+        ))?;
+        Some(
+            bind_groups.get_or_create("Light bind group", entries, |entries| {
+                render_device.create_bind_group(
+                    "Light bind group",
+                    &Self::bind_group_layout(render_device),
+                    entries,
+                )
+            }),
+        )
+    }
+
+    pub fn bind_group_layout(render_device: &RenderDevice) -> BindGroupLayout {
+        match binding_type(render_device) {
+            BufferBindingType::Storage { .. } => render_device.create_bind_group_layout(
+                "Light bind group layout",
+                &BindGroupLayoutEntries::sequential(
+                    ShaderStages::VERTEX_FRAGMENT,
+                    (
+                        storage_buffer_read_only::<GpuLightsStorage>(false),
+                        storage_buffer_read_only::<ClusterLightIndexListsStorage>(false),
+                        storage_buffer_read_only::<ClusterOffsetsAndCountsStorage>(false),
+                        uniform_buffer::<ClusterConfig>(false),
+                        texture_2d_array(TextureSampleType::Depth),
+                        sampler(SamplerBindingType::Comparison),
+                        uniform_buffer::<GpuShadows>(false),
+                    ),
+                ),
+            ),
+            BufferBindingType::Uniform => render_device.create_bind_group_layout(
+                "Light bind group layout",
+                &BindGroupLayoutEntries::sequential(
+                    ShaderStages::VERTEX_FRAGMENT,
+                    (
+                        uniform_buffer::<GpuLightsUniform>(false),
+                        uniform_buffer::<ClusterLightIndexListsUniform>(false),
+                        uniform_buffer::<ClusterOffsetsAndCountsUniform>(false),
+                        uniform_buffer::<ClusterConfig>(false),
+                        texture_2d_array(TextureSampleType::Depth),
+                        sampler(SamplerBindingType::Comparison),
+                        uniform_buffer::<GpuShadows>(false),
+                    ),
+                ),
+            ),
+        }
+    }
+
+    pub fn write_buffer(&mut self, render_device: &RenderDevice, render_queue: &RenderQueue) {
+        match &mut self.buffers {
+            GpuClusteredLights::Storage {
+                lights,
+                index_lists,
+                offsets_and_counts,
+            } => {
+                lights.write_buffer(render_device, render_queue);
+                index_lists.write_buffer(render_device, render_queue);
+                offsets_and_counts.write_buffer(render_device, render_queue);
+            }
+            GpuClusteredLights::Uniform {
+                lights,
+                index_lists,
+                offsets_and_counts,
+            } => {
+                lights.write_buffer(render_device, render_queue);
+                index_lists.write_buffer(render_device, render_queue);
+                offsets_and_counts.write_buffer(render_device, render_queue);
+            }
+        }
+        self.config.write_buffer(render_device, render_queue);
+    }
+
+    fn max_lights(&self) -> usize {
+        match self.buffers {
+            GpuClusteredLights::Storage { .. } => usize::MAX,
+            GpuClusteredLights::Uniform { .. } => MAX_UNIFORM_LIGHTS,
+        }
+    }
+
+    fn set(&mut self, lights: &[GpuLight], clusters: &[Vec<u32>]) {
+        match &mut self.buffers {
+            GpuClusteredLights::Storage {
+                lights: light_buffer,
+                index_lists,
+                offsets_and_counts,
+            } => {
+                let mut indices = Vec::new();
+                let mut offsets = Vec::with_capacity(clusters.len());
+                for cluster in clusters {
+                    offsets.push(UVec2::new(indices.len() as u32, cluster.len() as u32));
+                    indices.extend_from_slice(cluster);
+                }
+                // Zero sized bindings aren't allowed, so always upload at least one element.
+                let mut data = lights.to_vec();
+                if data.is_empty() {
+                    data.push(GpuLight::default());
+                }
+                if indices.is_empty() {
+                    indices.push(0);
+                }
+                light_buffer.get_mut().data = data;
+                index_lists.get_mut().data = indices;
+                offsets_and_counts.get_mut().data = offsets;
+            }
+            GpuClusteredLights::Uniform {
+                lights: light_buffer,
+                index_lists,
+                offsets_and_counts,
+            } => {
+                let light_data = &mut light_buffer.get_mut().data;
+                for (slot, light) in light_data.iter_mut().zip(lights) {
+                    *slot = *light;
+                }
+                pack_clusters(
+                    clusters,
+                    &mut index_lists.get_mut().data,
+                    &mut offsets_and_counts.get_mut().data,
+                );
+            }
+        }
+    }
+}
+
+/// The uniform fallback's layout: light indices as `u8`s, sixteen to a `vec4<u32>`, and one
+/// `offset << 8 | count` per cluster, four to a `vec4<u32>`. Clusters with more than 255
+/// lights, or past the capacity of `index_data`, lose the lights that don't fit.
+fn pack_clusters(
+    clusters: &[Vec<u32>],
+    index_data: &mut [UVec4; MAX_UNIFORM_ITEMS],
+    offset_data: &mut [UVec4; MAX_UNIFORM_ITEMS],
+) {
+    index_data.fill(UVec4::ZERO);
+    offset_data.fill(UVec4::ZERO);
+
+    let capacity = MAX_UNIFORM_ITEMS * 16;
+    let mut offset = 0;
+    for (cluster_index, cluster) in clusters.iter().enumerate() {
+        let count = cluster.len().min(u8::MAX as usize).min(capacity - offset);
+        for (i, light_index) in cluster[..count].iter().enumerate() {
+            let index = offset + i;
+            index_data[index / 16][(index / 4) % 4] |= light_index << (8 * (index % 4));
+        }
+        offset_data[cluster_index / 4][cluster_index % 4] = ((offset as u32) << 8) | count as u32;
+        offset += count;
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct ExtractedLights(pub Vec<Light>);
+
+pub fn extract_lights(
+    mut extracted_lights: ResMut<ExtractedLights>,
+    lights: Extract<Query<&Light>>,
+) {
+    extracted_lights.0.clear();
+    extracted_lights.0.extend(lights.iter().cloned());
+}
+
+/// View space bounds of a single cluster.
+struct ClusterAabb {
+    min: Point3<f32>,
+    max: Point3<f32>,
+}
+
+fn cluster_aabb(
+    x: u32,
+    y: u32,
+    near: f32,
+    far: f32,
+    tan_half_fovx: f32,
+    tan_half_fovy: f32,
+) -> ClusterAabb {
+    let tile_x = 2.0 / CLUSTER_DIMENSIONS.x as f32;
+    let tile_y = 2.0 / CLUSTER_DIMENSIONS.y as f32;
+    // Tiles are numbered from the top left of the screen, NDC y points up.
+    let ndc_left = -1.0 + x as f32 * tile_x;
+    let ndc_top = 1.0 - y as f32 * tile_y;
+    let xs = [
+        ndc_left * tan_half_fovx * near,
+        ndc_left * tan_half_fovx * far,
+        (ndc_left + tile_x) * tan_half_fovx * near,
+        (ndc_left + tile_x) * tan_half_fovx * far,
+    ];
+    let ys = [
+        ndc_top * tan_half_fovy * near,
+        ndc_top * tan_half_fovy * far,
+        (ndc_top - tile_y) * tan_half_fovy * near,
+        (ndc_top - tile_y) * tan_half_fovy * far,
+    ];
+    ClusterAabb {
+        min: Point3::new(
+            xs.into_iter().fold(f32::INFINITY, f32::min),
+            ys.into_iter().fold(f32::INFINITY, f32::min),
+            -far,
+        ),
+        max: Point3::new(
+            xs.into_iter().fold(f32::NEG_INFINITY, f32::max),
+            ys.into_iter().fold(f32::NEG_INFINITY, f32::max),
+            -near,
+        ),
+    }
+}
+
+fn sphere_intersects_aabb(center: Point3<f32>, radius: f32, aabb: &ClusterAabb) -> bool {
+    let closest = Point3::new(
+        center.x.clamp(aabb.min.x, aabb.max.x),
+        center.y.clamp(aabb.min.y, aabb.max.y),
+        center.z.clamp(aabb.min.z, aabb.max.z),
+    );
+    (center - closest).magnitude2() <= radius * radius
+}
+
+pub fn assign_lights_to_clusters(
+    lights: Res<ExtractedLights>,
+    camera: Res<camera::Camera>,
+    window: Res<ExtractedWindow>,
+    shadow_views: Res<ShadowViews>,
+    mut light_buffer: ResMut<LightBuffer>,
+) {
+    let (ordered, directional_light_count) = buffer_order(
+        &lights.0,
+        &shadow_views.light_layers,
+        light_buffer.max_lights(),
+    );
+    let gpu_lights: Vec<GpuLight> = ordered
+        .iter()
+        .map(|(light, shadow_layer)| GpuLight::new(light, *shadow_layer))
+        .collect();
+    let ordered: Vec<_> = ordered.into_iter().map(|(light, _)| light).collect();
+    let clusters = cluster_lights(&ordered, &camera);
+
+    light_buffer.set(&gpu_lights, &clusters);
+    light_buffer.config.set(ClusterConfig {
+        dimensions: CLUSTER_DIMENSIONS,
+        light_count: gpu_lights.len() as u32,
+        screen_size: Vec2::new(
+            window.physical_width.max(1) as f32,
+            window.physical_height.max(1) as f32,
+        ),
+        z_near: camera.znear,
+        z_far: camera.zfar,
+        directional_light_count: directional_light_count as u32,
+    });
+}
+
+/// The lights with their first shadow map layer, in the order they go into the light buffer:
+/// directional lights first, the rest in extraction order. Also returns how many are
+/// directional.
+fn buffer_order<'a>(
+    lights: &'a [Light],
+    shadow_layers: &[Option<u32>],
+    max_lights: usize,
+) -> (Vec<(&'a Light, Option<u32>)>, usize) {
+    let mut ordered: Vec<_> = lights.iter().zip(shadow_layers.iter().copied()).collect();
+    // The sort is stable.
+    ordered.sort_by_key(|(light, _)| !is_directional(light));
+    ordered.truncate(max_lights);
+    let directional_light_count = ordered
+        .iter()
+        .take_while(|(light, _)| is_directional(light))
+        .count();
+    (ordered, directional_light_count)
+}
+
+/// The indices into `lights` of the point and spot lights reaching each cluster. Directional
+/// lights aren't in any list.
+fn cluster_lights(lights: &[&Light], camera: &camera::Camera) -> Vec<Vec<u32>> {
+    let view = Matrix4::look_at_rh(camera.eye, camera.target, camera.up);
+    let tan_half_fovy = Rad::from(Deg(camera.fovy * 0.5)).0.tan();
+    let tan_half_fovx = tan_half_fovy * camera.aspect;
+    let depth_ratio = camera.zfar / camera.znear;
+    let slice_depth =
+        |slice: u32| camera.znear * depth_ratio.powf(slice as f32 / CLUSTER_DIMENSIONS.z as f32);
+    let depth_slice = |depth: f32| {
+        let slice = (depth / camera.znear).ln() / depth_ratio.ln() * CLUSTER_DIMENSIONS.z as f32;
+        (slice.max(0.0) as u32).min(CLUSTER_DIMENSIONS.z - 1)
+    };
+
+    let mut clusters = vec![Vec::new(); CLUSTER_DIMENSIONS.element_product() as usize];
+    for (light_index, light) in lights.iter().enumerate() {
+        if is_directional(light) {
+            continue;
+        }
+        // View space looks down -z.
+        let center = view.transform_point(Point3::from_vec(light.position));
+        let depth = -center.z;
+        if depth + light.range < camera.znear || depth - light.range > camera.zfar {
+            continue;
+        }
+        let first_slice = depth_slice((depth - light.range).max(camera.znear));
+        let last_slice = depth_slice((depth + light.range).min(camera.zfar));
+        for z in first_slice..=last_slice {
+            let (near, far) = (slice_depth(z), slice_depth(z + 1));
+            for y in 0..CLUSTER_DIMENSIONS.y {
+                for x in 0..CLUSTER_DIMENSIONS.x {
+                    let aabb = cluster_aabb(x, y, near, far, tan_half_fovx, tan_half_fovy);
+                    if sphere_intersects_aabb(center, light.range, &aabb) {
+                        let cluster_index =
+                            (z * CLUSTER_DIMENSIONS.y + y) * CLUSTER_DIMENSIONS.x + x;
+                        clusters[cluster_index as usize].push(light_index as u32);
+                    }
+                }
+            }
+        }
+    }
+    clusters
+}
+
+fn is_directional(light: &Light) -> bool {
+    matches!(light.kind, LightKind::Directional { .. })
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{Point3, Vector2, Vector3};
+
+    use super::*;
+
+    fn camera() -> camera::Camera {
+        camera::Camera {
+            eye: Point3::new(0.0, 0.0, 0.0),
+            target: Point3::new(0.0, 0.0, -1.0),
+            up: Vector3::unit_y(),
+            aspect: 16.0 / 9.0,
+            fovy: 45.0,
+            znear: 0.1,
+            zfar: 100.0,
+            jitter: Vector2::new(0.0, 0.0),
+        }
+    }
+
+    fn light(kind: LightKind, position: Vector3<f32>, range: f32) -> Light {
+        Light {
+            kind,
+            position,
+            color: Vector3::new(1.0, 1.0, 1.0),
+            range,
+            ambient: 0.1,
+            diffuse: 1.0,
+            specular: 1.0,
+            shadows: false,
+        }
+    }
+
+    fn directional() -> Light {
+        let direction = Vector3::new(0.0, -1.0, 0.0);
+        light(
+            LightKind::Directional { direction },
+            Vector3::new(0.0, 0.0, 0.0),
+            0.0,
+        )
+    }
+
+    /// `cluster_offset_and_count` and `cluster_light_index` from shaders.wgsl.
+    fn unpack(
+        index_data: &[UVec4; MAX_UNIFORM_ITEMS],
+        offset_data: &[UVec4; MAX_UNIFORM_ITEMS],
+        cluster_index: usize,
+    ) -> Vec<u32> {
+        let packed = offset_data[cluster_index >> 2][cluster_index & 3];
+        let (offset, count) = (packed >> 8, packed & 0xff);
+        (offset..offset + count)
+            .map(|index| {
+                let index = index as usize;
+                let packed = index_data[index >> 4][(index >> 2) & 3];
+                (packed >> (8 * (index & 3))) & 0xff
+            })
+            .collect()
+    }
+
+    fn packed(
+        clusters: &[Vec<u32>],
+    ) -> (
+        Box<[UVec4; MAX_UNIFORM_ITEMS]>,
+        Box<[UVec4; MAX_UNIFORM_ITEMS]>,
+    ) {
+        let mut index_data = Box::new([UVec4::ZERO; MAX_UNIFORM_ITEMS]);
+        let mut offset_data = Box::new([UVec4::ZERO; MAX_UNIFORM_ITEMS]);
+        pack_clusters(clusters, &mut index_data, &mut offset_data);
+        (index_data, offset_data)
+    }
+
+    #[test]
+    fn packed_clusters_unpack_like_the_shader() {
+        let clusters = vec![vec![1, 2, 3], vec![], vec![127, 0, 5, 9, 17], vec![4]];
+        let (index_data, offset_data) = packed(&clusters);
+        for (cluster_index, cluster) in clusters.iter().enumerate() {
+            assert_eq!(&unpack(&index_data, &offset_data, cluster_index), cluster);
+        }
+    }
+
+    #[test]
+    fn packing_drops_what_doesnt_fit_in_a_u8_count() {
+        let crowded: Vec<u32> = (0..300)
+            .map(|index| index % MAX_UNIFORM_LIGHTS as u32)
+            .collect();
+        let clusters = vec![crowded.clone(), vec![7]];
+        let (index_data, offset_data) = packed(&clusters);
+        assert_eq!(unpack(&index_data, &offset_data, 0), crowded[..255]);
+        assert_eq!(unpack(&index_data, &offset_data, 1), [7]);
+    }
+
+    #[test]
+    fn packing_stops_at_the_index_capacity() {
+        let full: Vec<u32> = vec![1; 255];
+        let clusters = vec![full; MAX_UNIFORM_ITEMS * 16 / 255 + 2];
+        let (index_data, offset_data) = packed(&clusters);
+        let total: usize = (0..clusters.len())
+            .map(|cluster_index| unpack(&index_data, &offset_data, cluster_index).len())
+            .sum();
+        assert_eq!(total, MAX_UNIFORM_ITEMS * 16);
+        assert!(unpack(&index_data, &offset_data, clusters.len() - 1).is_empty());
+    }
+
+    #[test]
+    fn directional_lights_go_first_and_keep_their_shadow_layers() {
+        let lights = [
+            light(LightKind::Point, Vector3::new(0.0, 0.0, -5.0), 1.0),
+            directional(),
+            light(LightKind::Point, Vector3::new(1.0, 0.0, -5.0), 1.0),
+            directional(),
+        ];
+        let layers = [None, Some(0), None, Some(3)];
+        let (ordered, directional_count) = buffer_order(&lights, &layers, usize::MAX);
+        assert_eq!(directional_count, 2);
+        let order: Vec<_> = ordered
+            .iter()
+            .map(|(light, layer)| (light.position.x, *layer, is_directional(light)))
+            .collect();
+        assert_eq!(
+            order,
+            [
+                (0.0, Some(0), true),
+                (0.0, Some(3), true),
+                (0.0, None, false),
+                (1.0, None, false),
+            ]
+        );
+
+        let (ordered, directional_count) = buffer_order(&lights, &layers, 1);
+        assert_eq!(ordered.len(), 1);
+        assert_eq!(directional_count, 1);
+    }
+
+    #[test]
+    fn only_point_and_spot_lights_get_clustered() {
+        let in_front = light(LightKind::Point, Vector3::new(0.0, 0.0, -10.0), 1.0);
+        let behind = light(LightKind::Point, Vector3::new(0.0, 0.0, 10.0), 1.0);
+        let lights = [&directional(), &in_front, &behind];
+        let clusters = cluster_lights(&lights, &camera());
+
+        let reached: Vec<_> = clusters
+            .iter()
+            .enumerate()
+            .filter(|(_, cluster)| !cluster.is_empty())
+            .collect();
+        assert!(!reached.is_empty());
+        for (cluster_index, cluster) in reached {
+            assert_eq!(cluster, &[1]);
+            // The light is in the middle of the screen, 10 units away.
+            let cluster_index = cluster_index as u32;
+            let x = cluster_index % CLUSTER_DIMENSIONS.x;
+            let y = cluster_index / CLUSTER_DIMENSIONS.x % CLUSTER_DIMENSIONS.y;
+            assert!((6..=9).contains(&x), "tile x {x}");
+            assert!((3..=5).contains(&y), "tile y {y}");
+        }
+    }
+
+    #[test]
+    fn a_light_lands_in_the_slices_around_its_depth() {
+        let camera = camera();
+        let lights = [&light(LightKind::Point, Vector3::new(0.0, 0.0, -10.0), 1.0)];
+        let clusters = cluster_lights(&lights, &camera);
+        let tiles = (CLUSTER_DIMENSIONS.x * CLUSTER_DIMENSIONS.y) as usize;
+        let depth_ratio = camera.zfar / camera.znear;
+        for (slice, slice_clusters) in clusters.chunks(tiles).enumerate() {
+            let near = camera.znear * depth_ratio.powf(slice as f32 / CLUSTER_DIMENSIONS.z as f32);
+            let far =
+                camera.znear * depth_ratio.powf((slice + 1) as f32 / CLUSTER_DIMENSIONS.z as f32);
+            let overlaps = near <= 11.0 && far >= 9.0;
+            let lit = slice_clusters.iter().any(|cluster| !cluster.is_empty());
+            assert_eq!(lit, overlaps, "slice {slice} from {near} to {far}");
+        }
+    }
+}
@@ -0,0 +1,203 @@
+//! The draw commands of the applier pass, and the draw functions built from them.
+
+use bevy::{
+    prelude::*,
+    render::{
+        render_asset::RenderAssets,
+        render_resource::{Buffer, PipelineCache},
+    },
+};
+
+use super::{
+    cluster::{LightBuffer, PreparedLight},
+    hot_reload::LastGoodPipelines,
+    material::PreparedApplierMaterial,
+    material_bind_group,
+    material_definition::PreparedMaterialDefinition,
+    phase::{
+        ApplierDrawCommand, ApplierDrawFunctionId, ApplierDrawFunctions, ApplierPhaseItem,
+        DrawTarget,
+    },
+    render_diagnostics::SkipReason,
+    IndexBuffer, InstanceBuffer, PreparedCamera, VertexBuffer,
+};
+
+fn get<T: Resource>(world: &World) -> Result<&T, SkipReason> {
+    world.get_resource::<T>().ok_or(SkipReason::MissingResource)
+}
+
+/// The shared mesh's vertex and index buffers, and how many indices there are.
+pub fn mesh_buffers(world: &World) -> Result<(&Buffer, &Buffer, u32), SkipReason> {
+    let vertex_buffer = get::<VertexBuffer>(world)?;
+    let index_buffer = get::<IndexBuffer>(world)?;
+    Ok((
+        vertex_buffer.0.buffer().ok_or(SkipReason::EmptyBuffer)?,
+        index_buffer.0.buffer().ok_or(SkipReason::EmptyBuffer)?,
+        index_buffer.0.len() as u32,
+    ))
+}
+
+/// The item's pipeline, or the last one that compiled while it's being rebuilt.
+pub struct ApplierSetItemPipeline;
+
+impl ApplierDrawCommand for ApplierSetItemPipeline {
+    fn render<'w>(
+        item: &ApplierPhaseItem,
+        world: &'w World,
+        render_pass: &mut dyn DrawTarget<'w>,
+    ) -> Result<(), SkipReason> {
+        let pipeline = get::<LastGoodPipelines>(world)?
+            .get(get::<PipelineCache>(world)?, item.sort_key.pipeline)
+            .ok_or(SkipReason::PipelineNotReady)?;
+        render_pass.set_render_pipeline(pipeline);
+        Ok(())
+    }
+}
+
+pub struct ApplierSetMaterialBindGroup<const I: usize>;
+
+impl<const I: usize> ApplierDrawCommand for ApplierSetMaterialBindGroup<I> {
+    fn render<'w>(
+        item: &ApplierPhaseItem,
+        world: &'w World,
+        render_pass: &mut dyn DrawTarget<'w>,
+    ) -> Result<(), SkipReason> {
+        let material = item.sort_key.material.ok_or(SkipReason::MaterialNotReady)?;
+        let bind_group = material_bind_group(
+            get::<RenderAssets<PreparedApplierMaterial>>(world)?,
+            get::<RenderAssets<PreparedMaterialDefinition>>(world)?,
+            material,
+        )
+        .ok_or(SkipReason::MaterialNotReady)?;
+        render_pass.set_bind_group(I, bind_group, &[]);
+        Ok(())
+    }
+}
+
+pub struct ApplierSetCameraBindGroup<const I: usize>;
+
+impl<const I: usize> ApplierDrawCommand for ApplierSetCameraBindGroup<I> {
+    fn render<'w>(
+        _item: &ApplierPhaseItem,
+        world: &'w World,
+        render_pass: &mut dyn DrawTarget<'w>,
+    ) -> Result<(), SkipReason> {
+        render_pass.set_bind_group(I, &get::<PreparedCamera>(world)?.bind_group, &[]);
+        Ok(())
+    }
+}
+
+pub struct ApplierSetLightBindGroup<const I: usize>;
+
+impl<const I: usize> ApplierDrawCommand for ApplierSetLightBindGroup<I> {
+    fn render<'w>(
+        _item: &ApplierPhaseItem,
+        world: &'w World,
+        render_pass: &mut dyn DrawTarget<'w>,
+    ) -> Result<(), SkipReason> {
+        render_pass.set_bind_group(I, &get::<PreparedLight>(world)?.bind_group, &[]);
+        Ok(())
+    }
+}
+
+/// Vertices in slot 0, and the indices.
+pub struct ApplierSetMeshBuffers;
+
+impl ApplierDrawCommand for ApplierSetMeshBuffers {
+    fn render<'w>(
+        _item: &ApplierPhaseItem,
+        world: &'w World,
+        render_pass: &mut dyn DrawTarget<'w>,
+    ) -> Result<(), SkipReason> {
+        let (vertices, indices, _) = mesh_buffers(world)?;
+        render_pass.set_vertex_buffer(0, vertices.slice(..));
+        render_pass.set_index_buffer(indices.slice(..), 0, wgpu::IndexFormat::Uint32);
+        Ok(())
+    }
+}
+
+/// Only items that made it into the instance buffer get queued, so a missing buffer means
+/// the upload got skipped.
+pub struct ApplierSetInstanceBuffer<const SLOT: usize>;
+
+impl<const SLOT: usize> ApplierDrawCommand for ApplierSetInstanceBuffer<SLOT> {
+    fn render<'w>(
+        _item: &ApplierPhaseItem,
+        world: &'w World,
+        render_pass: &mut dyn DrawTarget<'w>,
+    ) -> Result<(), SkipReason> {
+        let instances = get::<InstanceBuffer>(world)?
+            .buf
+            .buffer()
+            .ok_or(SkipReason::EmptyBuffer)?;
+        render_pass.set_vertex_buffer(SLOT, instances.slice(..));
+        Ok(())
+    }
+}
+
+/// The shared mesh, once for every instance the item got batched with.
+pub struct ApplierDrawMesh;
+
+impl ApplierDrawCommand for ApplierDrawMesh {
+    fn render<'w>(
+        item: &ApplierPhaseItem,
+        world: &'w World,
+        render_pass: &mut dyn DrawTarget<'w>,
+    ) -> Result<(), SkipReason> {
+        let (_, _, index_count) = mesh_buffers(world)?;
+        render_pass.draw_indexed(0..index_count, 0, item.instances.clone());
+        Ok(())
+    }
+}
+
+/// A small copy of the shared applier mesh at every light, with the light's index as the
+/// instance index.
+pub struct ApplierDrawLightGizmos;
+
+impl ApplierDrawCommand for ApplierDrawLightGizmos {
+    fn render<'w>(
+        _item: &ApplierPhaseItem,
+        world: &'w World,
+        render_pass: &mut dyn DrawTarget<'w>,
+    ) -> Result<(), SkipReason> {
+        let (_, _, index_count) = mesh_buffers(world)?;
+        let light_count = get::<LightBuffer>(world)?.light_count();
+        render_pass.draw_indexed(0..index_count, 0, 0..light_count);
+        Ok(())
+    }
+}
+
+pub type ApplierDrawInstance = (
+    ApplierSetItemPipeline,
+    ApplierSetMaterialBindGroup<0>,
+    ApplierSetCameraBindGroup<1>,
+    ApplierSetLightBindGroup<2>,
+    ApplierSetMeshBuffers,
+    ApplierSetInstanceBuffer<1>,
+    ApplierDrawMesh,
+);
+
+pub type ApplierDrawLights = (
+    ApplierSetItemPipeline,
+    ApplierSetCameraBindGroup<0>,
+    ApplierSetLightBindGroup<1>,
+    ApplierSetMeshBuffers,
+    ApplierDrawLightGizmos,
+);
+
+/// The draw functions the applier queues its items with.
+#[derive(Resource)]
+pub struct ApplierDrawFunctionIds {
+    pub instance: ApplierDrawFunctionId,
+    pub lights: ApplierDrawFunctionId,
+}
+
+impl FromWorld for ApplierDrawFunctionIds {
+    fn from_world(world: &mut World) -> Self {
+        let mut draw_functions = world.resource_mut::<ApplierDrawFunctions>();
+        Self {
+            instance: draw_functions.add::<ApplierDrawInstance>(),
+            lights: draw_functions.add::<ApplierDrawLights>(),
+        }
+    }
+}
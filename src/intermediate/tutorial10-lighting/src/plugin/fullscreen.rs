@@ -0,0 +1,99 @@
+//! Building blocks for passes that shade every pixel of a texture, like tonemapping and
+//! post-processing. They draw a single triangle generated from `vertex_index` (the trick from
+//! tutorial3's shader) so no vertex or index buffer is needed.
+
+use bevy::{
+    asset::Handle,
+    render::{
+        render_resource::{
+            BindGroup, BindGroupLayout, FragmentState, LoadOp, Operations,
+            RenderPassColorAttachment, RenderPipeline, RenderPipelineDescriptor, Shader,
+            ShaderDefVal, StoreOp, TextureView, VertexState,
+        },
+        renderer::RenderContext,
+    },
+};
+use wgpu::{
+    BlendState, ColorTargetState, ColorWrites, FrontFace, MultisampleState, PolygonMode,
+    PrimitiveState, PrimitiveTopology, RenderPassDescriptor, RenderPassTimestampWrites,
+    TextureFormat,
+};
+
+pub const FULLSCREEN_SHADER_HANDLE: Handle<Shader> =
+    Handle::weak_from_u128(127361450986623474283650195573982101458);
+
+/// Describes a pipeline that runs `shader`'s `fs_main` over the whole `format` target.
+pub fn fullscreen_pipeline_descriptor(
+    label: &'static str,
+    shader: Handle<Shader>,
+    shader_defs: Vec<ShaderDefVal>,
+    layout: Vec<BindGroupLayout>,
+    format: TextureFormat,
+) -> RenderPipelineDescriptor {
+    RenderPipelineDescriptor {
+        vertex: VertexState {
+            shader: FULLSCREEN_SHADER_HANDLE,
+            entry_point: "vs_main".into(),
+            shader_defs: vec![],
+            buffers: vec![],
+        },
+        fragment: Some(FragmentState {
+            shader,
+            shader_defs,
+            entry_point: "fs_main".into(),
+            targets: vec![Some(ColorTargetState {
+                format,
+                blend: Some(BlendState::REPLACE),
+                write_mask: ColorWrites::ALL,
+            })],
+        }),
+        layout,
+        push_constant_ranges: Vec::new(),
+        primitive: PrimitiveState {
+            front_face: FrontFace::Ccw,
+            cull_mode: None,
+            unclipped_depth: false,
+            polygon_mode: PolygonMode::Fill,
+            conservative: false,
+            topology: PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+        },
+        depth_stencil: None,
+        multisample: MultisampleState {
+            count: 1,
+            mask: !0,
+            alpha_to_coverage_enabled: false,
+        },
+        label: Some(label.into()),
+        zero_initialize_workgroup_memory: true,
+    }
+}
+
+/// Records one fullscreen draw into `destination`. Every pixel gets written, so there's
+/// nothing to clear or load.
+pub fn draw_fullscreen(
+    render_context: &mut RenderContext,
+    label: &'static str,
+    timestamp_writes: Option<RenderPassTimestampWrites>,
+    destination: &TextureView,
+    pipeline: &RenderPipeline,
+    bind_group: &BindGroup,
+) {
+    let mut render_pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
+        label: Some(label),
+        color_attachments: &[Some(RenderPassColorAttachment {
+            view: destination,
+            resolve_target: None,
+            ops: Operations {
+                load: LoadOp::Clear(Default::default()),
+                store: StoreOp::Store,
+            },
+        })],
+        depth_stencil_attachment: None,
+        timestamp_writes,
+        occlusion_query_set: None,
+    });
+    render_pass.set_render_pipeline(pipeline);
+    render_pass.set_bind_group(0, bind_group, &[]);
+    render_pass.draw(0..3, 0..1);
+}
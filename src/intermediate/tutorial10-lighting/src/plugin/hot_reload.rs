@@ -0,0 +1,91 @@
+//! Editing `shaders.wgsl` without rebuilding.
+//!
+//! Release builds embed the shader like every other one. Debug builds load it through the
+//! asset server from a `shaders` asset source pointing at `src/`, which gets watched for
+//! changes with the `hot_reload` feature, `cargo run --features hot_reload`. When the file
+//! changes the `PipelineCache` compiles every pipeline using it again, and until one of those
+//! is ready, or when the edit doesn't compile, the applier pass keeps drawing with the
+//! pipeline it had before.
+
+#[cfg(debug_assertions)]
+use bevy::asset::io::AssetSource;
+use bevy::{
+    asset::Handle,
+    prelude::*,
+    render::render_resource::{CachedRenderPipelineId, PipelineCache, RenderPipeline, Shader},
+    utils::HashMap,
+};
+
+use super::phase::{OpaquePhase, TransparentPhase};
+
+/// Where debug builds load `shaders.wgsl` from.
+#[cfg(debug_assertions)]
+pub const APPLIER_SHADER_PATH: &str = "shaders://shaders.wgsl";
+
+/// Registers the `shaders` asset source. Asset sources have to exist before the
+/// `AssetPlugin` is built, so this goes in front of `DefaultPlugins`.
+pub struct ShaderSourcePlugin;
+
+impl Plugin for ShaderSourcePlugin {
+    #[cfg(debug_assertions)]
+    fn build(&self, app: &mut App) {
+        let source =
+            AssetSource::build().with_reader(AssetSource::get_default_reader("src".into()));
+        #[cfg(feature = "hot_reload")]
+        let source = source.with_watcher(AssetSource::get_default_watcher(
+            "src".into(),
+            std::time::Duration::from_millis(300),
+        ));
+        app.register_asset_source("shaders", source);
+    }
+
+    #[cfg(not(debug_assertions))]
+    fn build(&self, _app: &mut App) {}
+}
+
+/// The shader `ApplierPipeline` specializes, in both worlds. Holding on to it keeps the
+/// loaded shader alive.
+#[derive(Resource, Clone)]
+pub struct ApplierShader(pub Handle<Shader>);
+
+/// The last pipeline that compiled for each pipeline drawn this frame.
+#[derive(Resource, Default)]
+pub struct LastGoodPipelines(HashMap<CachedRenderPipelineId, RenderPipeline>);
+
+impl LastGoodPipelines {
+    /// The pipeline for `id`, or the one it replaced while it's still compiling or broken.
+    pub fn get<'a>(
+        &'a self,
+        pipeline_cache: &'a PipelineCache,
+        id: CachedRenderPipelineId,
+    ) -> Option<&'a RenderPipeline> {
+        pipeline_cache
+            .get_render_pipeline(id)
+            .or_else(|| self.0.get(&id))
+    }
+}
+
+/// Runs before the pipeline cache processes its queue, so a pipeline being compiled again
+/// after a shader change doesn't show up here yet and the previous one is kept.
+pub fn track_last_good_pipelines(
+    pipeline_cache: Res<PipelineCache>,
+    opaque_phase: Res<OpaquePhase>,
+    transparent_phase: Res<TransparentPhase>,
+    mut last_good: ResMut<LastGoodPipelines>,
+) {
+    let mut previous = std::mem::take(&mut last_good.0);
+    let ids = opaque_phase
+        .items
+        .iter()
+        .chain(&transparent_phase.items)
+        .map(|item| item.sort_key.pipeline);
+    for id in ids {
+        let pipeline = pipeline_cache
+            .get_render_pipeline(id)
+            .cloned()
+            .or_else(|| previous.remove(&id));
+        if let Some(pipeline) = pipeline {
+            last_good.0.insert(id, pipeline);
+        }
+    }
+}
@@ -0,0 +1,693 @@
+//! Materials described in `.material.ron` files instead of Rust.
+//!
+//! A definition names its own WGSL shader and lists the textures and uniform values the
+//! shader's group 0 expects, along with the render state. The bind group layout is built from
+//! that list, and the pipelines come from the same descriptor `ApplierPipeline` uses, so the
+//! shader gets the regular vertex buffers and the camera and light bind groups, and can
+//! `#import` the same modules the built-in shaders do. Its fragment shader has to write
+//! `InstanceInput::index` to `@location(2)` for picking, and discard texels below the cutoff
+//! under `ALPHA_MASK` and `ALPHA_BLEND`, like the built-in ones. Outlines assume the first
+//! texture is the one its alpha comes from, and take the cutoff from a uniform field named
+//! `alpha_cutoff`. A definition looks like this:
+//!
+//! ```ron
+//! (
+//!     shader: "shaders/tinted.wgsl",
+//!     shader_defs: ["GRAYSCALE"],
+//!     textures: [
+//!         (binding: 0, sampler_binding: 1, path: "tree.png"),
+//!     ],
+//!     uniform: Some((
+//!         binding: 2,
+//!         fields: [
+//!             ("tint", Vec4((1.0, 0.5, 0.2, 1.0))),
+//!             ("alpha_cutoff", Float(0.5)),
+//!         ],
+//!     )),
+//!     render_state: (
+//!         blend_mode: Mask,
+//!         cull_mode: None,
+//!         depth_write: true,
+//!         depth_compare: less,
+//!     ),
+//! )
+//! ```
+//!
+//! `cull_mode` and `depth_compare` go by wgpu's own names, which are lowercase, like
+//! `Some(back)` or `less-equal`.
+
+use std::{error::Error, fmt, num::NonZeroU64};
+
+use bevy::{
+    asset::{io::Reader, Asset, AssetId, AssetLoader, Handle, LoadContext},
+    ecs::system::{lifetimeless::SRes, SystemParamItem},
+    prelude::*,
+    render::{
+        render_asset::{PrepareAssetError, RenderAsset, RenderAssetPlugin, RenderAssets},
+        render_resource::{
+            binding_types::{sampler, texture_2d, uniform_buffer_sized},
+            BindGroup, BindGroupEntry, BindGroupLayout, BindingResource, Buffer,
+            BufferInitDescriptor, CachedRenderPipelineId, PipelineCache, SamplerBindingType,
+            Shader, ShaderDefVal, ShaderStages, TextureSampleType,
+        },
+        renderer::RenderDevice,
+        texture::{FallbackImage, GpuImage},
+    },
+    utils::{HashMap, HashSet},
+};
+use bevy_internal::image::{ImageFilterMode, ImageLoaderSettings, ImageSamplerDescriptor};
+use serde::Deserialize;
+use wgpu::BufferUsages;
+
+use super::{
+    material::RenderState,
+    outline::{OutlineCoverage, StencilMode},
+    pipeline::ApplierPipeline,
+};
+
+/// A value in the uniform buffer of a definition. They're laid out in the order they're listed,
+/// following WGSL's alignment rules for uniforms.
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub enum UniformValue {
+    Float(f32),
+    Vec2([f32; 2]),
+    Vec3([f32; 3]),
+    Vec4([f32; 4]),
+}
+
+impl UniformValue {
+    fn components(&self) -> &[f32] {
+        match self {
+            UniformValue::Float(value) => std::slice::from_ref(value),
+            UniformValue::Vec2(value) => value,
+            UniformValue::Vec3(value) => value,
+            UniformValue::Vec4(value) => value,
+        }
+    }
+
+    /// In floats. A `vec3` takes up 12 bytes but starts on a 16 byte boundary.
+    fn alignment(&self) -> usize {
+        match self {
+            UniformValue::Float(_) => 1,
+            UniformValue::Vec2(_) => 2,
+            UniformValue::Vec3(_) | UniformValue::Vec4(_) => 4,
+        }
+    }
+}
+
+fn default_srgb() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+struct TextureFile {
+    binding: u32,
+    sampler_binding: u32,
+    path: String,
+    /// Off for data like normal maps.
+    #[serde(default = "default_srgb")]
+    srgb: bool,
+    #[serde(default)]
+    sampler: ImageSamplerDescriptor,
+}
+
+#[derive(Deserialize)]
+struct UniformFile {
+    binding: u32,
+    /// The names are only there to match the fields up with the shader's struct.
+    fields: Vec<(String, UniformValue)>,
+}
+
+/// The contents of a `.material.ron` file.
+#[derive(Deserialize)]
+struct MaterialDefinitionFile {
+    shader: String,
+    #[serde(default)]
+    shader_defs: Vec<String>,
+    #[serde(default)]
+    textures: Vec<TextureFile>,
+    #[serde(default)]
+    uniform: Option<UniformFile>,
+    render_state: RenderState,
+}
+
+#[derive(Clone)]
+pub struct DefinedTexture {
+    pub binding: u32,
+    pub sampler_binding: u32,
+    pub image: Handle<Image>,
+    pub sampler: ImageSamplerDescriptor,
+}
+
+#[derive(Clone)]
+pub struct DefinedUniform {
+    pub binding: u32,
+    /// Already laid out for the GPU.
+    pub data: Vec<u8>,
+}
+
+/// A material loaded from a `.material.ron` file. Instances refer to it by handle, just like
+/// an `ApplierMaterial`.
+#[derive(Asset, TypePath, Clone)]
+pub struct MaterialDefinition {
+    pub shader: Handle<Shader>,
+    pub shader_defs: Vec<ShaderDefVal>,
+    pub textures: Vec<DefinedTexture>,
+    pub uniform: Option<DefinedUniform>,
+    pub render_state: RenderState,
+    /// The uniform's `alpha_cutoff` field, for outlines of `Mask` definitions.
+    pub alpha_cutoff: f32,
+}
+
+impl MaterialDefinition {
+    pub fn images(&self) -> impl Iterator<Item = AssetId<Image>> + '_ {
+        self.textures.iter().map(|texture| texture.image.id())
+    }
+
+    fn uses_image(&self, id: AssetId<Image>) -> bool {
+        self.images().any(|image| image == id)
+    }
+}
+
+/// Packs the values like a WGSL struct in the uniform address space, which rounds its size up
+/// to a multiple of 16 bytes.
+fn pack_uniform(values: impl IntoIterator<Item = UniformValue>) -> Vec<u8> {
+    let mut floats: Vec<f32> = Vec::new();
+    for value in values {
+        floats.resize(floats.len().next_multiple_of(value.alignment()), 0.0);
+        floats.extend_from_slice(value.components());
+    }
+    floats.resize(floats.len().next_multiple_of(4).max(4), 0.0);
+    bytemuck::cast_slice(&floats).to_vec()
+}
+
+/// What a group 0 binding of a definition holds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BindingKind {
+    /// A `texture_2d<f32>`.
+    Texture,
+    /// A filtering `sampler`.
+    Sampler,
+    Uniform,
+}
+
+impl BindingKind {
+    /// From a declaration with all whitespace removed, like `vart_diffuse:texture_2d<f32>`.
+    fn of_declaration(declaration: &str) -> Option<Self> {
+        if declaration.starts_with("var<uniform>") {
+            return Some(BindingKind::Uniform);
+        }
+        match declaration.rsplit(':').next()? {
+            "texture_2d<f32>" => Some(BindingKind::Texture),
+            "sampler" => Some(BindingKind::Sampler),
+            _ => None,
+        }
+    }
+}
+
+/// Why a definition got rejected. Its bind group layout or pipeline would fail wgpu's
+/// validation otherwise, which panics.
+#[derive(Debug)]
+pub enum MaterialDefinitionError {
+    DuplicateBinding(u32),
+    /// Layouts only have room for filtering samplers.
+    ComparisonSampler(u32),
+    /// wgpu only allows anisotropic filtering with every filter mode set to linear.
+    AnisotropyWithoutLinearFilter(u32),
+    /// The shader declares nothing at the binding, or something other than the definition
+    /// puts there.
+    ShaderMismatch {
+        binding: u32,
+        kind: BindingKind,
+    },
+    /// The shader declares a group 0 binding the definition doesn't fill.
+    Unbound(u32),
+}
+
+impl fmt::Display for MaterialDefinitionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MaterialDefinitionError::DuplicateBinding(binding) => {
+                write!(f, "binding {binding} is used more than once")
+            }
+            MaterialDefinitionError::ComparisonSampler(binding) => {
+                write!(
+                    f,
+                    "the sampler at binding {binding} can't have a compare function"
+                )
+            }
+            MaterialDefinitionError::AnisotropyWithoutLinearFilter(binding) => write!(
+                f,
+                "the sampler at binding {binding} needs linear filtering for its anisotropy clamp"
+            ),
+            MaterialDefinitionError::ShaderMismatch { binding, kind } => write!(
+                f,
+                "the shader doesn't declare a {kind:?} at @group(0) @binding({binding})"
+            ),
+            MaterialDefinitionError::Unbound(binding) => write!(
+                f,
+                "the shader's @group(0) @binding({binding}) isn't filled by the definition"
+            ),
+        }
+    }
+}
+
+impl Error for MaterialDefinitionError {}
+
+/// The group 0 bindings `source` declares. Conditional declarations count whether their
+/// shader def is set or not, and a binding declared with a type the definitions can't fill
+/// comes back without a kind.
+fn declared_bindings(source: &str) -> Vec<(u32, Option<BindingKind>)> {
+    let without_comments: String = source
+        .lines()
+        .map(|line| line.split("//").next().unwrap_or_default())
+        .collect();
+    let attribute = |statement: &str, name: &str| -> Option<u32> {
+        let start = statement.find(name)? + name.len();
+        let end = start + statement[start..].find(')')?;
+        statement[start..end].parse().ok()
+    };
+    without_comments
+        .split(';')
+        .map(|statement| statement.split_whitespace().collect::<String>())
+        .filter(|statement| attribute(statement.as_str(), "@group(") == Some(0))
+        .filter_map(|statement| {
+            let binding = attribute(statement.as_str(), "@binding(")?;
+            // The attributes come first, in either order.
+            let attributes_end = statement.find("@group(")?.max(statement.find("@binding(")?);
+            let declaration = &statement[attributes_end..];
+            let declaration = &declaration[declaration.find("var")?..];
+            Some((binding, BindingKind::of_declaration(declaration)))
+        })
+        .collect()
+}
+
+/// Checks the bindings of `file` against each other and against what its shader declares.
+fn validate_bindings(
+    file: &MaterialDefinitionFile,
+    shader_source: &str,
+) -> Result<(), MaterialDefinitionError> {
+    let mut bindings = Vec::new();
+    for texture in &file.textures {
+        bindings.push((texture.binding, BindingKind::Texture));
+        bindings.push((texture.sampler_binding, BindingKind::Sampler));
+
+        let sampler = &texture.sampler;
+        if sampler.compare.is_some() {
+            return Err(MaterialDefinitionError::ComparisonSampler(
+                texture.sampler_binding,
+            ));
+        }
+        let linear = [
+            sampler.mag_filter,
+            sampler.min_filter,
+            sampler.mipmap_filter,
+        ]
+        .iter()
+        .all(|filter| matches!(filter, ImageFilterMode::Linear));
+        if sampler.anisotropy_clamp > 1 && !linear {
+            return Err(MaterialDefinitionError::AnisotropyWithoutLinearFilter(
+                texture.sampler_binding,
+            ));
+        }
+    }
+    if let Some(uniform) = &file.uniform {
+        bindings.push((uniform.binding, BindingKind::Uniform));
+    }
+
+    let mut seen = HashSet::default();
+    if let Some((binding, _)) = bindings.iter().find(|(binding, _)| !seen.insert(*binding)) {
+        return Err(MaterialDefinitionError::DuplicateBinding(*binding));
+    }
+
+    let declared = declared_bindings(shader_source);
+    for &(binding, kind) in &bindings {
+        if !declared.contains(&(binding, Some(kind))) {
+            return Err(MaterialDefinitionError::ShaderMismatch { binding, kind });
+        }
+    }
+    if let Some((binding, _)) = declared.iter().find(|(binding, _)| !seen.contains(binding)) {
+        return Err(MaterialDefinitionError::Unbound(*binding));
+    }
+    Ok(())
+}
+
+/// Used by outlines when the uniform has no `alpha_cutoff`.
+const DEFAULT_ALPHA_CUTOFF: f32 = 0.5;
+
+#[derive(Default)]
+pub struct MaterialDefinitionLoader;
+
+impl AssetLoader for MaterialDefinitionLoader {
+    type Asset = MaterialDefinition;
+    type Settings = ();
+    type Error = Box<dyn Error + Send + Sync>;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &Self::Settings,
+        load_context: &mut LoadContext<'_>,
+    ) -> Result<Self::Asset, Self::Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        let file: MaterialDefinitionFile = ron::de::from_bytes(&bytes)?;
+        // Also makes the definition load again when its shader changes.
+        let shader_source = load_context.read_asset_bytes(file.shader.as_str()).await?;
+        validate_bindings(&file, &String::from_utf8(shader_source)?)?;
+
+        let textures = file
+            .textures
+            .into_iter()
+            .map(|texture| {
+                let srgb = texture.srgb;
+                let image = load_context
+                    .loader()
+                    .with_settings(move |settings: &mut ImageLoaderSettings| {
+                        settings.is_srgb = srgb;
+                    })
+                    .load(texture.path);
+                DefinedTexture {
+                    binding: texture.binding,
+                    sampler_binding: texture.sampler_binding,
+                    image,
+                    sampler: texture.sampler,
+                }
+            })
+            .collect();
+        let alpha_cutoff = file
+            .uniform
+            .iter()
+            .flat_map(|uniform| &uniform.fields)
+            .find_map(|(name, value)| match (name.as_str(), value) {
+                ("alpha_cutoff", UniformValue::Float(cutoff)) => Some(*cutoff),
+                _ => None,
+            })
+            .unwrap_or(DEFAULT_ALPHA_CUTOFF);
+
+        Ok(MaterialDefinition {
+            shader: load_context.load(file.shader),
+            shader_defs: file.shader_defs.into_iter().map(Into::into).collect(),
+            textures,
+            uniform: file.uniform.map(|uniform| DefinedUniform {
+                binding: uniform.binding,
+                data: pack_uniform(uniform.fields.into_iter().map(|(_, value)| value)),
+            }),
+            render_state: file.render_state,
+            alpha_cutoff,
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["material.ron"]
+    }
+}
+
+/// The render world's copy of a [`MaterialDefinition`].
+pub struct PreparedMaterialDefinition {
+    pub bind_group: BindGroup,
+    pub render_state: RenderState,
+    pub coverage: OutlineCoverage,
+    layout: BindGroupLayout,
+    shader: Handle<Shader>,
+    shader_defs: Vec<ShaderDefVal>,
+    _uniform: Option<Buffer>,
+    /// Queued the first time they're needed, by `(hdr, motion_vectors, stencil)`. Preparing
+    /// the definition again starts over, so edits to the file reach the pipelines.
+    pipelines: HashMap<(bool, bool, StencilMode), CachedRenderPipelineId>,
+}
+
+impl RenderAsset for PreparedMaterialDefinition {
+    type SourceAsset = MaterialDefinition;
+    type Param = (
+        SRes<RenderDevice>,
+        SRes<RenderAssets<GpuImage>>,
+        SRes<FallbackImage>,
+    );
+
+    fn prepare_asset(
+        definition: Self::SourceAsset,
+        (render_device, images, fallback_image): &mut SystemParamItem<Self::Param>,
+    ) -> Result<Self, PrepareAssetError<Self::SourceAsset>> {
+        let Some(gpu_images) = definition
+            .textures
+            .iter()
+            .map(|texture| images.get(&texture.image))
+            .collect::<Option<Vec<_>>>()
+        else {
+            return Err(PrepareAssetError::RetryNextUpdate(definition));
+        };
+
+        let visibility = ShaderStages::VERTEX_FRAGMENT;
+        let mut layout_entries = Vec::new();
+        for texture in &definition.textures {
+            layout_entries.push(
+                texture_2d(TextureSampleType::Float { filterable: true })
+                    .build(texture.binding, visibility),
+            );
+            layout_entries.push(
+                sampler(SamplerBindingType::Filtering).build(texture.sampler_binding, visibility),
+            );
+        }
+        if let Some(uniform) = &definition.uniform {
+            layout_entries.push(
+                uniform_buffer_sized(false, NonZeroU64::new(uniform.data.len() as u64))
+                    .build(uniform.binding, visibility),
+            );
+        }
+        let layout =
+            render_device.create_bind_group_layout("material_definition_layout", &layout_entries);
+
+        let samplers: Vec<_> = definition
+            .textures
+            .iter()
+            .map(|texture| render_device.create_sampler(&texture.sampler.as_wgpu()))
+            .collect();
+        let uniform = definition.uniform.as_ref().map(|uniform| {
+            let buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
+                label: Some("material_definition_uniform"),
+                contents: &uniform.data,
+                usage: BufferUsages::UNIFORM,
+            });
+            (uniform.binding, buffer)
+        });
+
+        let mut entries = Vec::new();
+        for ((texture, gpu_image), sampler) in
+            definition.textures.iter().zip(&gpu_images).zip(&samplers)
+        {
+            entries.push(BindGroupEntry {
+                binding: texture.binding,
+                resource: BindingResource::TextureView(&gpu_image.texture_view),
+            });
+            entries.push(BindGroupEntry {
+                binding: texture.sampler_binding,
+                resource: BindingResource::Sampler(sampler),
+            });
+        }
+        if let Some((binding, buffer)) = &uniform {
+            entries.push(BindGroupEntry {
+                binding: *binding,
+                resource: buffer.as_entire_binding(),
+            });
+        }
+        let bind_group =
+            render_device.create_bind_group("material_definition_bind_group", &layout, &entries);
+
+        let coverage = match (gpu_images.first(), samplers.first()) {
+            (Some(gpu_image), Some(sampler)) => OutlineCoverage::new(
+                render_device,
+                &gpu_image.texture,
+                sampler,
+                definition.render_state.blend_mode,
+                definition.alpha_cutoff,
+                false,
+            ),
+            _ => OutlineCoverage::new(
+                render_device,
+                &fallback_image.d2.texture,
+                &fallback_image.d2.sampler,
+                definition.render_state.blend_mode,
+                definition.alpha_cutoff,
+                false,
+            ),
+        };
+
+        Ok(Self {
+            bind_group,
+            render_state: definition.render_state,
+            coverage,
+            layout,
+            shader: definition.shader,
+            shader_defs: definition.shader_defs,
+            _uniform: uniform.map(|(_, buffer)| buffer),
+            pipelines: HashMap::default(),
+        })
+    }
+}
+
+impl PreparedMaterialDefinition {
+    pub fn pipeline(
+        &mut self,
+        pipeline_cache: &PipelineCache,
+        applier_pipeline: &ApplierPipeline,
+        hdr: bool,
+        motion_vectors: bool,
+        stencil: StencilMode,
+    ) -> CachedRenderPipelineId {
+        *self
+            .pipelines
+            .entry((hdr, motion_vectors, stencil))
+            .or_insert_with(|| {
+                pipeline_cache.queue_render_pipeline(applier_pipeline.descriptor(
+                    self.shader.clone(),
+                    self.layout.clone(),
+                    self.shader_defs.clone(),
+                    self.render_state,
+                    hdr,
+                    motion_vectors,
+                    stencil,
+                ))
+            })
+    }
+}
+
+pub struct MaterialDefinitionPlugin;
+
+impl Plugin for MaterialDefinitionPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_asset::<MaterialDefinition>()
+            .init_asset_loader::<MaterialDefinitionLoader>()
+            .add_plugins(RenderAssetPlugin::<PreparedMaterialDefinition, GpuImage>::default())
+            .add_systems(Update, refresh_definitions);
+    }
+}
+
+/// Like `refresh_materials`, gets definitions prepared again when one of their images changes.
+fn refresh_definitions(
+    mut events: EventReader<AssetEvent<Image>>,
+    mut definitions: ResMut<Assets<MaterialDefinition>>,
+) {
+    for event in events.read() {
+        let AssetEvent::Modified { id } = event else {
+            continue;
+        };
+        let changed: Vec<_> = definitions
+            .iter()
+            .filter(|(_, definition)| definition.uses_image(*id))
+            .map(|(definition_id, _)| definition_id)
+            .collect();
+        for definition_id in changed {
+            definitions.get_mut(definition_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TINTED: &str = include_str!("../../assets/materials/tinted.material.ron");
+    const TINTED_SHADER: &str = include_str!("../../assets/shaders/tinted.wgsl");
+
+    fn tinted() -> MaterialDefinitionFile {
+        ron::de::from_str(TINTED).expect("the shipped definition parses")
+    }
+
+    #[test]
+    fn the_shipped_definition_matches_its_shader() {
+        validate_bindings(&tinted(), TINTED_SHADER).unwrap();
+    }
+
+    #[test]
+    fn bindings_used_twice_are_rejected() {
+        let mut file = tinted();
+        file.uniform.as_mut().unwrap().binding = 1;
+        assert!(matches!(
+            validate_bindings(&file, TINTED_SHADER),
+            Err(MaterialDefinitionError::DuplicateBinding(1))
+        ));
+    }
+
+    #[test]
+    fn samplers_wgpu_would_reject_are_rejected() {
+        let mut file = tinted();
+        file.textures[0].sampler.compare = Some(bevy_internal::image::ImageCompareFunction::Less);
+        assert!(matches!(
+            validate_bindings(&file, TINTED_SHADER),
+            Err(MaterialDefinitionError::ComparisonSampler(1))
+        ));
+
+        let mut file = tinted();
+        file.textures[0].sampler.anisotropy_clamp = 16;
+        file.textures[0].sampler.mipmap_filter = ImageFilterMode::Nearest;
+        assert!(matches!(
+            validate_bindings(&file, TINTED_SHADER),
+            Err(MaterialDefinitionError::AnisotropyWithoutLinearFilter(1))
+        ));
+    }
+
+    #[test]
+    fn bindings_the_shader_declares_differently_are_rejected() {
+        let mut file = tinted();
+        let texture = &mut file.textures[0];
+        (texture.binding, texture.sampler_binding) = (texture.sampler_binding, texture.binding);
+        assert!(matches!(
+            validate_bindings(&file, TINTED_SHADER),
+            Err(MaterialDefinitionError::ShaderMismatch {
+                binding: 1,
+                kind: BindingKind::Texture,
+            })
+        ));
+    }
+
+    #[test]
+    fn declarations_the_definition_leaves_empty_are_rejected() {
+        let mut file = tinted();
+        file.uniform = None;
+        assert!(matches!(
+            validate_bindings(&file, TINTED_SHADER),
+            Err(MaterialDefinitionError::Unbound(2))
+        ));
+    }
+
+    #[test]
+    fn declarations_are_found_in_any_layout() {
+        let source = "
+            // @group(0) @binding(7) var commented_out: sampler;
+            @binding(1)
+            @group(0) var s_diffuse: sampler;
+            @group(0) @binding(0) var t_diffuse : texture_2d<f32>;
+            @group(0) @binding(2) var<uniform> tint: Tint;
+            @group(0) @binding(3) var t_depth: texture_depth_2d;
+            @group(1) @binding(0) var<uniform> camera: CameraUniform;
+        ";
+        assert_eq!(
+            declared_bindings(source),
+            [
+                (1, Some(BindingKind::Sampler)),
+                (0, Some(BindingKind::Texture)),
+                (2, Some(BindingKind::Uniform)),
+                (3, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn uniform_values_follow_wgsl_alignment() {
+        // A float fits into the end of a vec3, a vec2 starts on an 8 byte boundary.
+        let packed = pack_uniform([
+            UniformValue::Float(1.0),
+            UniformValue::Vec3([2.0, 3.0, 4.0]),
+            UniformValue::Float(5.0),
+            UniformValue::Float(6.0),
+            UniformValue::Vec2([7.0, 8.0]),
+        ]);
+        let floats: &[f32] = bytemuck::cast_slice(&packed);
+        assert_eq!(
+            floats,
+            [1.0, 0.0, 0.0, 0.0, 2.0, 3.0, 4.0, 5.0, 6.0, 0.0, 7.0, 8.0]
+        );
+        assert_eq!(pack_uniform([]).len(), 16);
+    }
+}
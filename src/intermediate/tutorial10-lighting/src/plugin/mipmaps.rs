@@ -0,0 +1,246 @@
+//! Mip chains for material textures.
+//!
+//! PNGs come with a single level, so distant instances sample the full resolution texture and
+//! shimmer. Right after an image some material samples gets uploaded, `generate_mipmaps` swaps
+//! its texture for one with a full mip chain, filled by rendering each level from the one above
+//! it. This runs before materials are prepared so their bind groups pick up the new texture.
+//! Other images, like fonts, UI and render targets, keep the texture they came with.
+
+use bevy::{
+    asset::{AssetEvent, AssetId, Handle},
+    prelude::*,
+    render::{
+        render_asset::RenderAssets,
+        render_resource::{
+            binding_types::{sampler, texture_2d},
+            BindGroupEntries, BindGroupLayout, BindGroupLayoutEntries, LoadOp, Operations,
+            PipelineCache, RenderPassColorAttachment, RenderPipelineDescriptor, Sampler,
+            SamplerBindingType, Shader, ShaderStages, SpecializedRenderPipeline,
+            SpecializedRenderPipelines, StoreOp, Texture, TextureId, TextureSampleType,
+        },
+        renderer::{RenderDevice, RenderQueue},
+        texture::GpuImage,
+        Extract,
+    },
+    utils::{HashMap, HashSet},
+};
+use wgpu::{
+    CommandEncoderDescriptor, FilterMode, RenderPassDescriptor, SamplerDescriptor,
+    TextureDescriptor, TextureDimension, TextureFormat, TextureUsages, TextureViewDescriptor,
+    TextureViewDimension,
+};
+
+use super::{fullscreen, material::ApplierMaterial, material_definition::MaterialDefinition};
+
+pub const MIPMAP_SHADER_HANDLE: Handle<Shader> =
+    Handle::weak_from_u128(291151446781169974876642118094268981853);
+
+#[derive(Resource)]
+pub struct MipmapPipeline {
+    layout: BindGroupLayout,
+    sampler: Sampler,
+}
+
+impl FromWorld for MipmapPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let layout = render_device.create_bind_group_layout(
+            "Mipmap bind group layout",
+            &BindGroupLayoutEntries::sequential(
+                ShaderStages::FRAGMENT,
+                (
+                    texture_2d(TextureSampleType::Float { filterable: true }),
+                    sampler(SamplerBindingType::Filtering),
+                ),
+            ),
+        );
+        // A bilinear tap between four texels of the level above averages them.
+        let sampler = render_device.create_sampler(&SamplerDescriptor {
+            label: Some("mipmap_sampler"),
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            ..Default::default()
+        });
+
+        Self { layout, sampler }
+    }
+}
+
+impl SpecializedRenderPipeline for MipmapPipeline {
+    /// Format of the texture getting mipmapped.
+    type Key = TextureFormat;
+
+    fn specialize(&self, format: Self::Key) -> RenderPipelineDescriptor {
+        fullscreen::fullscreen_pipeline_descriptor(
+            "mipmap_pipeline",
+            MIPMAP_SHADER_HANDLE,
+            vec![],
+            vec![self.layout.clone()],
+            format,
+        )
+    }
+}
+
+/// The textures `generate_mipmaps` already handled, by image. A re-upload of the image gets
+/// a new texture, which is how changed images are told apart.
+#[derive(Resource, Default)]
+pub struct GeneratedMipmaps(HashMap<AssetId<Image>, TextureId>);
+
+/// Every image an `ApplierMaterial` or a `MaterialDefinition` samples, the only ones that get
+/// a mip chain.
+#[derive(Resource, Default)]
+pub struct MaterialImages(HashSet<AssetId<Image>>);
+
+pub fn extract_material_images(
+    mut material_images: ResMut<MaterialImages>,
+    mut generated: ResMut<GeneratedMipmaps>,
+    materials: Extract<Res<Assets<ApplierMaterial>>>,
+    definitions: Extract<Res<Assets<MaterialDefinition>>>,
+    mut events: Extract<EventReader<AssetEvent<Image>>>,
+) {
+    material_images.0.clear();
+    material_images.0.extend(
+        materials
+            .iter()
+            .flat_map(|(_, material)| material.images())
+            .chain(
+                definitions
+                    .iter()
+                    .flat_map(|(_, definition)| definition.images()),
+            ),
+    );
+    for event in events.read() {
+        if let AssetEvent::Removed { id } | AssetEvent::Unused { id } = event {
+            generated.0.remove(id);
+        }
+    }
+}
+
+fn mip_level_count(image: &GpuImage) -> u32 {
+    32 - image.size.x.max(image.size.y).leading_zeros()
+}
+
+/// Only 2D textures and arrays of them that can be rendered into and filtered get a mip chain.
+fn can_generate_mipmaps(image: &GpuImage, render_device: &RenderDevice) -> bool {
+    let features = image
+        .texture_format
+        .guaranteed_format_features(render_device.features());
+    image.mip_level_count == 1
+        && image.texture.dimension() == TextureDimension::D2
+        && features
+            .allowed_usages
+            .contains(TextureUsages::RENDER_ATTACHMENT)
+        && features
+            .flags
+            .contains(wgpu::TextureFormatFeatureFlags::FILTERABLE)
+}
+
+pub fn generate_mipmaps(
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    mut pipeline_cache: ResMut<PipelineCache>,
+    pipeline: Res<MipmapPipeline>,
+    mut pipelines: ResMut<SpecializedRenderPipelines<MipmapPipeline>>,
+    mut images: ResMut<RenderAssets<GpuImage>>,
+    mut generated: ResMut<GeneratedMipmaps>,
+    material_images: Res<MaterialImages>,
+) {
+    let mut command_encoder = render_device.create_command_encoder(&CommandEncoderDescriptor {
+        label: Some("mipmap_command_encoder"),
+    });
+    let mut any_generated = false;
+
+    for (id, image) in images.iter_mut() {
+        if !material_images.0.contains(&id) || generated.0.get(&id) == Some(&image.texture.id()) {
+            continue;
+        }
+        if !can_generate_mipmaps(image, &render_device) {
+            generated.0.insert(id, image.texture.id());
+            continue;
+        }
+        // Materials can't wait for the pipeline, they'd be prepared without mips. The shader
+        // is embedded, so compiling it right away is fine.
+        let pipeline_id = pipelines.specialize(&pipeline_cache, &pipeline, image.texture_format);
+        pipeline_cache.block_on_render_pipeline(pipeline_id);
+        let Some(render_pipeline) = pipeline_cache.get_render_pipeline(pipeline_id) else {
+            continue;
+        };
+
+        let level_count = mip_level_count(image);
+        let texture = render_device.create_texture(&TextureDescriptor {
+            label: Some("mipmapped_image"),
+            size: image.texture.size(),
+            mip_level_count: level_count,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: image.texture_format,
+            // Whatever the image was made for, and rendering its levels.
+            usage: image.texture.usage() | TextureUsages::RENDER_ATTACHMENT,
+            view_formats: &[],
+        });
+        let layer_count = image.texture.depth_or_array_layers();
+        let view = |texture: &Texture, level, layer| {
+            texture.create_view(&TextureViewDescriptor {
+                dimension: Some(TextureViewDimension::D2),
+                base_mip_level: level,
+                mip_level_count: Some(1),
+                base_array_layer: layer,
+                array_layer_count: Some(1),
+                ..Default::default()
+            })
+        };
+
+        for layer in 0..layer_count {
+            let level_views: Vec<_> = (0..level_count)
+                .map(|level| view(&texture, level, layer))
+                .collect();
+            // The first level is a straight copy of the original texture, every other level is
+            // drawn from the one before it.
+            let original = view(&image.texture, 0, layer);
+            let sources = std::iter::once(&original).chain(&level_views);
+            for (source, destination) in sources.zip(&level_views) {
+                let bind_group = render_device.create_bind_group(
+                    "Mipmap bind group",
+                    &pipeline.layout,
+                    &BindGroupEntries::sequential((source, &pipeline.sampler)),
+                );
+                let mut render_pass = command_encoder.begin_render_pass(&RenderPassDescriptor {
+                    label: Some("mipmap_pass"),
+                    color_attachments: &[Some(RenderPassColorAttachment {
+                        view: destination,
+                        resolve_target: None,
+                        ops: Operations {
+                            load: LoadOp::Clear(Default::default()),
+                            store: StoreOp::Store,
+                        },
+                    })],
+                    depth_stencil_attachment: None,
+                    timestamp_writes: None,
+                    occlusion_query_set: None,
+                });
+                render_pass.set_pipeline(render_pipeline);
+                render_pass.set_bind_group(0, &*bind_group, &[]);
+                render_pass.draw(0..3, 0..1);
+            }
+        }
+
+        // Texture arrays only come with more than one layer, see `atlas::stack_texture_array`.
+        let dimension = if layer_count > 1 {
+            TextureViewDimension::D2Array
+        } else {
+            TextureViewDimension::D2
+        };
+        image.texture_view = texture.create_view(&TextureViewDescriptor {
+            dimension: Some(dimension),
+            ..Default::default()
+        });
+        image.texture = texture;
+        image.mip_level_count = level_count;
+        generated.0.insert(id, image.texture.id());
+        any_generated = true;
+    }
+
+    if any_generated {
+        render_queue.submit([command_encoder.finish()]);
+    }
+}
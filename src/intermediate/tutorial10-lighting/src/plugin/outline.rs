@@ -0,0 +1,536 @@
+//! Outlines around selected instances.
+//!
+//! While outlines are enabled the depth texture is `Depth24PlusStencil8`, and the pipelines of
+//! selected instances increment the stencil wherever they draw. `OutlineNode` then draws the
+//! outline where the stencil is still zero, in one of two ways:
+//!
+//! - [`OutlineStyle::Scaled`] draws the selected instances again, grown by `world_width`, in
+//!   the outline color. The stencil test cuts the instance itself out, leaving a rim. The
+//!   stencil is only marked where the material drew, so the hull samples each material's
+//!   [`OutlineCoverage`] and discards the same texels, or it would fill in cutouts.
+//! - [`OutlineStyle::ScreenSpace`] runs a fullscreen pass over the stencil instead, coloring
+//!   every pixel within `screen_width` pixels of a marked one. The rim stays the same width
+//!   at any distance.
+//!
+//! Setting `selected` on an instance is all it takes to outline it. `O` cycles through the
+//! styles and turning outlines off, `N` moves the selection to the next instance.
+
+use std::ops::Range;
+
+use bevy::{
+    asset::Handle,
+    prelude::*,
+    render::{
+        render_resource::{
+            binding_types::{sampler, texture_2d, texture_2d_array, uniform_buffer},
+            encase, BindGroup, BindGroupEntries, BindGroupLayout, BindGroupLayoutEntries, Buffer,
+            BufferInitDescriptor, CachedRenderPipelineId, ColorTargetState, ColorWrites,
+            FragmentState, MultisampleState, PipelineCache, PrimitiveState, RawBufferVec,
+            RenderPipelineDescriptor, Sampler, SamplerBindingType, Shader, ShaderStages,
+            ShaderType, SpecializedRenderPipeline, SpecializedRenderPipelines, Texture,
+            TextureSampleType, TextureView, UniformBuffer, VertexState,
+        },
+        renderer::{RenderDevice, RenderQueue},
+        Extract,
+    },
+};
+use wgpu::{
+    BlendState, BufferUsages, CompareFunction, DepthBiasState, DepthStencilState, StencilFaceState,
+    StencilOperation, StencilState, TextureFormat, TextureViewDescriptor, TextureViewDimension,
+};
+
+use super::{
+    bind_group_tracker::{BindGroupTracker, TrackedEntries},
+    fullscreen,
+    material::BlendMode,
+    mesh::Vertex,
+    tonemapping::{self, HdrSettings},
+    CameraBuffer, DepthTexture, InstanceRaw, Instances, MaterialId, WindowTargets,
+};
+
+pub const OUTLINE_SHADER_HANDLE: Handle<Shader> =
+    Handle::weak_from_u128(185629870129463102850166720433189256214);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum OutlineStyle {
+    #[default]
+    Scaled,
+    ScreenSpace,
+}
+
+#[derive(Resource, Clone, Debug)]
+pub struct OutlineSettings {
+    /// Also switches the depth texture to `Depth24PlusStencil8`.
+    pub enabled: bool,
+    pub style: OutlineStyle,
+    pub color: LinearRgba,
+    /// How far the selected instances get grown for [`OutlineStyle::Scaled`].
+    pub world_width: f32,
+    /// In pixels, for [`OutlineStyle::ScreenSpace`].
+    pub screen_width: f32,
+}
+
+impl Default for OutlineSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            style: OutlineStyle::default(),
+            color: LinearRgba::new(1.0, 0.45, 0.05, 1.0),
+            world_width: 0.06,
+            screen_width: 3.0,
+        }
+    }
+}
+
+pub struct OutlinePlugin;
+
+impl Plugin for OutlinePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<OutlineSettings>()
+            .add_systems(Update, handle_outline_input);
+    }
+}
+
+fn handle_outline_input(
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    mut settings: ResMut<OutlineSettings>,
+    mut instances: ResMut<Instances>,
+) {
+    if keyboard_input.just_pressed(KeyCode::KeyO) {
+        match (settings.enabled, settings.style) {
+            (false, _) => {
+                settings.enabled = true;
+                settings.style = OutlineStyle::Scaled;
+            }
+            (true, OutlineStyle::Scaled) => settings.style = OutlineStyle::ScreenSpace,
+            (true, OutlineStyle::ScreenSpace) => settings.enabled = false,
+        }
+    }
+    if keyboard_input.just_pressed(KeyCode::KeyN) && !instances.0.is_empty() {
+        let next = instances
+            .0
+            .iter()
+            .position(|instance| instance.selected)
+            .map_or(0, |selected| (selected + 1) % instances.0.len());
+        for (index, instance) in instances.0.iter_mut().enumerate() {
+            instance.selected = index == next;
+        }
+    }
+}
+
+pub fn extract_outline_settings(
+    mut commands: Commands,
+    main_settings: Extract<Res<OutlineSettings>>,
+) {
+    commands.insert_resource(main_settings.clone());
+}
+
+/// What a pipeline drawing in the applier pass does with the stencil.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum StencilMode {
+    /// The depth texture has no stencil.
+    None,
+    Keep,
+    /// Marks what gets drawn as selected.
+    Mark,
+}
+
+impl StencilMode {
+    pub fn new(settings: &OutlineSettings, selected: bool) -> Self {
+        match (settings.enabled, selected) {
+            (false, _) => StencilMode::None,
+            (true, false) => StencilMode::Keep,
+            (true, true) => StencilMode::Mark,
+        }
+    }
+
+    pub fn depth_format(self) -> TextureFormat {
+        depth_format(self != StencilMode::None)
+    }
+
+    /// Marking increments rather than writing a reference value, so it works without
+    /// `set_stencil_reference` and in render bundles too.
+    pub fn stencil_state(self) -> StencilState {
+        match self {
+            StencilMode::None | StencilMode::Keep => StencilState::default(),
+            StencilMode::Mark => {
+                let face = StencilFaceState {
+                    compare: CompareFunction::Always,
+                    fail_op: StencilOperation::Keep,
+                    depth_fail_op: StencilOperation::Keep,
+                    pass_op: StencilOperation::IncrementClamp,
+                };
+                StencilState {
+                    front: face,
+                    back: face,
+                    read_mask: !0,
+                    write_mask: !0,
+                }
+            }
+        }
+    }
+}
+
+/// Format of the depth texture the applier pass draws with.
+pub fn depth_format(stencil: bool) -> TextureFormat {
+    if stencil {
+        TextureFormat::Depth24PlusStencil8
+    } else {
+        TextureFormat::Depth32Float
+    }
+}
+
+/// Transparent materials discard texels with a lower alpha, so the nearly invisible parts of an
+/// instance neither mark the stencil nor get picked. Matches `TRANSPARENT_ALPHA_CUTOFF` in
+/// `instancing.wgsl`.
+pub const TRANSPARENT_ALPHA_CUTOFF: f32 = 0.02;
+
+#[derive(Debug, Clone, Default, ShaderType)]
+struct OutlineCoverageUniform {
+    alpha_cutoff: f32,
+    /// Non-zero when instances pick a layer instead of a rect of the image.
+    texture_array: u32,
+}
+
+/// What the scaled hull of an instance samples to leave out the texels its material discards.
+/// Every material keeps one next to its own bind group. The shadow pass samples it too.
+pub struct OutlineCoverage {
+    pub bind_group: BindGroup,
+    /// Whether the material discards anything at all. Opaque ones don't.
+    pub alpha_tested: bool,
+    _uniform: Buffer,
+}
+
+impl OutlineCoverage {
+    /// `texture` is read through a 2D array view either way, so plain images and texture
+    /// arrays share the layout. `alpha_cutoff` only matters for [`BlendMode::Mask`].
+    pub fn new(
+        render_device: &RenderDevice,
+        texture: &Texture,
+        sampler: &Sampler,
+        blend_mode: BlendMode,
+        alpha_cutoff: f32,
+        texture_array: bool,
+    ) -> Self {
+        let alpha_cutoff = match blend_mode {
+            BlendMode::Opaque => 0.0,
+            BlendMode::Mask => alpha_cutoff,
+            BlendMode::AlphaBlend | BlendMode::Premultiplied | BlendMode::Additive => {
+                TRANSPARENT_ALPHA_CUTOFF
+            }
+        };
+        let mut contents = encase::UniformBuffer::new(Vec::new());
+        contents
+            .write(&OutlineCoverageUniform {
+                alpha_cutoff,
+                texture_array: texture_array as u32,
+            })
+            .expect("a fixed size uniform always fits into a Vec");
+        let uniform = render_device.create_buffer_with_data(&BufferInitDescriptor {
+            label: Some("outline_coverage_uniform"),
+            contents: &contents.into_inner(),
+            usage: BufferUsages::UNIFORM,
+        });
+        let view = texture.create_view(&TextureViewDescriptor {
+            dimension: Some(TextureViewDimension::D2Array),
+            ..default()
+        });
+        let bind_group = render_device.create_bind_group(
+            "outline_coverage_bind_group",
+            &Self::bind_group_layout(render_device),
+            &BindGroupEntries::sequential((&view, sampler, uniform.as_entire_binding())),
+        );
+        Self {
+            bind_group,
+            alpha_tested: blend_mode != BlendMode::Opaque,
+            _uniform: uniform,
+        }
+    }
+
+    pub fn bind_group_layout(render_device: &RenderDevice) -> BindGroupLayout {
+        render_device.create_bind_group_layout(
+            "outline_coverage_layout",
+            &BindGroupLayoutEntries::sequential(
+                ShaderStages::VERTEX_FRAGMENT,
+                (
+                    texture_2d_array(TextureSampleType::Float { filterable: true }),
+                    sampler(SamplerBindingType::Filtering),
+                    uniform_buffer::<OutlineCoverageUniform>(false),
+                ),
+            ),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Default, ShaderType)]
+pub struct OutlineUniform {
+    color: Vec4,
+    world_width: f32,
+    screen_width: f32,
+}
+
+#[derive(Resource)]
+pub struct OutlineBuffer {
+    buf: UniformBuffer<OutlineUniform>,
+    /// The selected instances, for [`OutlineStyle::Scaled`].
+    pub instances: RawBufferVec<InstanceRaw>,
+    /// Ranges of `instances` sharing a material, and so an [`OutlineCoverage`].
+    pub batches: Vec<(MaterialId, Range<u32>)>,
+}
+
+impl Default for OutlineBuffer {
+    fn default() -> Self {
+        Self {
+            buf: UniformBuffer::default(),
+            instances: RawBufferVec::new(BufferUsages::VERTEX),
+            batches: Vec::new(),
+        }
+    }
+}
+
+impl OutlineBuffer {
+    /// `None` until the uniform has been written.
+    fn bind_group(
+        &self,
+        render_device: &RenderDevice,
+        bind_groups: &mut BindGroupTracker,
+        style: OutlineStyle,
+        stencil_view: &TextureView,
+    ) -> Option<BindGroup> {
+        Some(match style {
+            OutlineStyle::Scaled => {
+                let entries = TrackedEntries::single(&self.buf)?;
+                bind_groups.get_or_create("Outline bind group", entries, |entries| {
+                    render_device.create_bind_group(
+                        "Outline bind group",
+                        &Self::bind_group_layout(render_device, style),
+                        entries,
+                    )
+                })
+            }
+            OutlineStyle::ScreenSpace => {
+                let entries = TrackedEntries::sequential((stencil_view, &self.buf))?;
+                bind_groups.get_or_create("Screen space outline bind group", entries, |entries| {
+                    render_device.create_bind_group(
+                        "Screen space outline bind group",
+                        &Self::bind_group_layout(render_device, style),
+                        entries,
+                    )
+                })
+            }
+        })
+    }
+
+    pub fn bind_group_layout(render_device: &RenderDevice, style: OutlineStyle) -> BindGroupLayout {
+        match style {
+            OutlineStyle::Scaled => render_device.create_bind_group_layout(
+                "Outline bind group layout",
+                &BindGroupLayoutEntries::single(
+                    ShaderStages::VERTEX_FRAGMENT,
+                    uniform_buffer::<OutlineUniform>(false),
+                ),
+            ),
+            OutlineStyle::ScreenSpace => render_device.create_bind_group_layout(
+                "Screen space outline bind group layout",
+                &BindGroupLayoutEntries::sequential(
+                    ShaderStages::FRAGMENT,
+                    (
+                        texture_2d(TextureSampleType::Uint),
+                        uniform_buffer::<OutlineUniform>(false),
+                    ),
+                ),
+            ),
+        }
+    }
+}
+
+#[derive(Resource)]
+pub struct PreparedOutlines {
+    pub style: OutlineStyle,
+    pub bind_group: BindGroup,
+}
+
+/// Only prepares anything while outlines are enabled and something is selected.
+pub fn prepare_outlines(
+    mut commands: Commands,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    settings: Res<OutlineSettings>,
+    instances: Res<Instances>,
+    depth_textures: Res<WindowTargets<DepthTexture>>,
+    mut outline: ResMut<OutlineBuffer>,
+    mut bind_groups: ResMut<BindGroupTracker>,
+) {
+    let stencil_view = depth_textures
+        .primary()
+        .and_then(|depth_texture| depth_texture.stencil_view.as_ref());
+    let (Some(stencil_view), true) = (stencil_view, settings.enabled) else {
+        commands.remove_resource::<PreparedOutlines>();
+        return;
+    };
+    let mut selected: Vec<_> = instances
+        .0
+        .iter()
+        .filter(|instance| instance.selected)
+        .collect();
+    if selected.is_empty() {
+        commands.remove_resource::<PreparedOutlines>();
+        return;
+    }
+    selected.sort_by_key(|instance| instance.material.id());
+    let OutlineBuffer {
+        instances: raw_instances,
+        batches,
+        ..
+    } = outline.as_mut();
+    raw_instances.clear();
+    batches.clear();
+    for instance in selected {
+        let index = raw_instances.len() as u32;
+        let material = instance.material.id();
+        match batches.last_mut() {
+            Some((batch_material, range)) if *batch_material == material => range.end += 1,
+            _ => batches.push((material, index..index + 1)),
+        }
+        raw_instances.push(instance.to_raw());
+    }
+    raw_instances.write_buffer(&render_device, &render_queue);
+
+    outline.buf.set(OutlineUniform {
+        color: settings.color.to_vec4(),
+        world_width: settings.world_width,
+        screen_width: settings.screen_width,
+    });
+    outline.buf.write_buffer(&render_device, &render_queue);
+    match outline.bind_group(
+        &render_device,
+        &mut bind_groups,
+        settings.style,
+        stencil_view,
+    ) {
+        Some(bind_group) => commands.insert_resource(PreparedOutlines {
+            style: settings.style,
+            bind_group,
+        }),
+        None => commands.remove_resource::<PreparedOutlines>(),
+    }
+}
+
+#[derive(Resource)]
+pub struct OutlinePipeline {
+    camera_layout: BindGroupLayout,
+    scaled_layout: BindGroupLayout,
+    coverage_layout: BindGroupLayout,
+    screen_space_layout: BindGroupLayout,
+}
+
+impl FromWorld for OutlinePipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+
+        Self {
+            camera_layout: CameraBuffer::bind_group_layout(render_device),
+            scaled_layout: OutlineBuffer::bind_group_layout(render_device, OutlineStyle::Scaled),
+            coverage_layout: OutlineCoverage::bind_group_layout(render_device),
+            screen_space_layout: OutlineBuffer::bind_group_layout(
+                render_device,
+                OutlineStyle::ScreenSpace,
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct OutlinePipelineKey {
+    pub style: OutlineStyle,
+    pub hdr: bool,
+}
+
+impl SpecializedRenderPipeline for OutlinePipeline {
+    type Key = OutlinePipelineKey;
+
+    fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
+        let format = tonemapping::main_pass_format(key.hdr);
+        if key.style == OutlineStyle::ScreenSpace {
+            return fullscreen::fullscreen_pipeline_descriptor(
+                "screen_space_outline_pipeline",
+                OUTLINE_SHADER_HANDLE,
+                vec!["SCREEN_SPACE".into()],
+                vec![self.screen_space_layout.clone()],
+                format,
+            );
+        }
+
+        // Draws over everything, but only where the stencil isn't marked.
+        let outside_selection = StencilFaceState {
+            compare: CompareFunction::Equal,
+            fail_op: StencilOperation::Keep,
+            depth_fail_op: StencilOperation::Keep,
+            pass_op: StencilOperation::Keep,
+        };
+        RenderPipelineDescriptor {
+            vertex: VertexState {
+                shader: OUTLINE_SHADER_HANDLE,
+                entry_point: "vs_main".into(),
+                shader_defs: vec![],
+                buffers: vec![Vertex::desc(), InstanceRaw::desc()],
+            },
+            fragment: Some(FragmentState {
+                shader: OUTLINE_SHADER_HANDLE,
+                shader_defs: vec![],
+                entry_point: "fs_main".into(),
+                targets: vec![Some(ColorTargetState {
+                    format,
+                    blend: Some(BlendState::REPLACE),
+                    write_mask: ColorWrites::ALL,
+                })],
+            }),
+            layout: vec![
+                self.camera_layout.clone(),
+                self.scaled_layout.clone(),
+                self.coverage_layout.clone(),
+            ],
+            push_constant_ranges: Vec::new(),
+            primitive: PrimitiveState::default(),
+            depth_stencil: Some(DepthStencilState {
+                format: depth_format(true),
+                depth_write_enabled: false,
+                depth_compare: CompareFunction::Always,
+                stencil: StencilState {
+                    front: outside_selection,
+                    back: outside_selection,
+                    read_mask: !0,
+                    write_mask: 0,
+                },
+                bias: DepthBiasState::default(),
+            }),
+            multisample: MultisampleState::default(),
+            label: Some("outline_pipeline".into()),
+            zero_initialize_workgroup_memory: true,
+        }
+    }
+}
+
+/// The specialization of [`OutlinePipeline`] picked for this frame, while outlines are on.
+#[derive(Resource)]
+pub struct OutlinePipelineId(pub CachedRenderPipelineId);
+
+pub fn queue_outline_pipeline(
+    mut commands: Commands,
+    pipeline_cache: Res<PipelineCache>,
+    pipeline: Res<OutlinePipeline>,
+    mut pipelines: ResMut<SpecializedRenderPipelines<OutlinePipeline>>,
+    settings: Res<OutlineSettings>,
+    hdr: Res<HdrSettings>,
+) {
+    if !settings.enabled {
+        commands.remove_resource::<OutlinePipelineId>();
+        return;
+    }
+    let key = OutlinePipelineKey {
+        style: settings.style,
+        hdr: hdr.enabled,
+    };
+    let id = pipelines.specialize(&pipeline_cache, &pipeline, key);
+    commands.insert_resource(OutlinePipelineId(id));
+}
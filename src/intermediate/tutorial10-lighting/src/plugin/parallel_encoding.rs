@@ -0,0 +1,217 @@
+//! Recording the applier pass of every window on its own thread.
+//!
+//! With [`ParallelEncoding`] enabled `SurfaceNode` hands the pass of each window to
+//! `RenderContext::add_command_buffer_generation_task`, and the render context records them
+//! in parallel once the graph has run. Command buffers get submitted in the order they were
+//! added, whichever task finishes first. Every window draws into its own
+//! [`WindowTargets`](super::WindowTargets), so the tasks never write the same texture. It's
+//! off by default, since everything after the applier pass still only runs for the primary
+//! window.
+//!
+//! `--bench-encoding` runs [`EncodingBenchmarkPlugin`]: 100k instances in four windows,
+//! rendered with serial and then with parallel encoding. It logs the average time the render
+//! graph took with each and exits.
+
+use std::{
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use bevy::{
+    prelude::*,
+    render::{renderer::render_system, Extract, Render, RenderApp, RenderSet},
+    window::{PresentMode, PrimaryWindow},
+};
+use cgmath::{Quaternion, Rotation3};
+
+use super::{
+    material::ApplierMaterial, post_process::PostProcessSettings, tonemapping::HdrSettings,
+    Instance, InstanceMaterial, InstanceTexture, Instances,
+};
+
+#[derive(Resource, Clone, Debug, Default)]
+pub struct ParallelEncoding {
+    pub enabled: bool,
+}
+
+pub fn extract_parallel_encoding(
+    mut commands: Commands,
+    main_settings: Extract<Res<ParallelEncoding>>,
+) {
+    commands.insert_resource(main_settings.clone());
+}
+
+const BENCHMARK_VIEWS: usize = 4;
+/// Just over 100k instances.
+const BENCHMARK_INSTANCES_PER_ROW: u32 = 317;
+/// Lets the pipelines compile and the materials load before anything gets measured.
+const WARMUP_FRAMES: u32 = 120;
+const MEASURED_FRAMES: u32 = 300;
+
+pub struct EncodingBenchmarkPlugin;
+
+impl Plugin for EncodingBenchmarkPlugin {
+    fn build(&self, app: &mut App) {
+        let timings = RenderTimings::default();
+        app.insert_resource(timings.clone())
+            .add_systems(Startup, (spawn_benchmark_views, fill_benchmark_scene))
+            .add_systems(Update, run_benchmark);
+
+        if let Some(render_app) = app.get_sub_app_mut(RenderApp) {
+            render_app
+                .insert_resource(timings)
+                .init_resource::<RenderStart>()
+                .add_systems(
+                    Render,
+                    (
+                        start_render_timer
+                            .in_set(RenderSet::Render)
+                            .before(render_system),
+                        stop_render_timer
+                            .in_set(RenderSet::Render)
+                            .after(render_system),
+                    ),
+                );
+        }
+    }
+}
+
+/// Nanoseconds spent running the render graph and the frames they add up over, indexed by
+/// whether encoding was parallel. The render world lags a frame behind the main world, so it
+/// goes by the setting it actually rendered with.
+#[derive(Resource, Clone, Default)]
+struct RenderTimings(Arc<[(AtomicU64, AtomicU32); 2]>);
+
+impl RenderTimings {
+    fn add(&self, parallel: bool, elapsed: Duration) {
+        let (nanos, frames) = &self.0[parallel as usize];
+        nanos.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+        frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn reset(&self) {
+        for (nanos, frames) in self.0.iter() {
+            nanos.store(0, Ordering::Relaxed);
+            frames.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// In milliseconds.
+    fn average(&self, parallel: bool) -> Option<f64> {
+        let (nanos, frames) = &self.0[parallel as usize];
+        let frames = frames.load(Ordering::Relaxed);
+        (frames > 0).then(|| nanos.load(Ordering::Relaxed) as f64 / frames as f64 / 1_000_000.0)
+    }
+}
+
+/// The extra windows are copies of the primary one, so every pass has the same amount of
+/// pixels to fill. Vsync would hide the difference.
+fn spawn_benchmark_views(
+    mut commands: Commands,
+    mut primary_window: Single<&mut Window, With<PrimaryWindow>>,
+) {
+    primary_window.resizable = false;
+    primary_window.present_mode = PresentMode::AutoNoVsync;
+    for view in 1..BENCHMARK_VIEWS {
+        commands.spawn(Window {
+            title: format!("view {view}"),
+            resolution: primary_window.resolution.clone(),
+            resizable: false,
+            present_mode: PresentMode::AutoNoVsync,
+            ..default()
+        });
+    }
+}
+
+/// A grid of blended instances alternating between two copies of the same material, so the
+/// back to front order keeps switching materials and nearly every instance is a draw of its
+/// own. HDR and post-processing are off, so every window gets drawn straight to its
+/// swapchain instead of all of them into the one main texture.
+fn fill_benchmark_scene(
+    mut instances: ResMut<Instances>,
+    mut materials: ResMut<Assets<ApplierMaterial>>,
+    mut hdr: ResMut<HdrSettings>,
+    mut post_process: ResMut<PostProcessSettings>,
+) {
+    hdr.enabled = false;
+    for pass in &mut post_process.stack {
+        pass.enabled = false;
+    }
+
+    let blended = instances
+        .0
+        .iter()
+        .find_map(|instance| match &instance.material {
+            InstanceMaterial::Applier(handle) => materials
+                .get(handle)
+                .filter(|material| material.blend_mode.is_transparent())
+                .map(|material| (handle.clone(), material.clone())),
+            InstanceMaterial::Defined(_) => None,
+        });
+    let Some((blended, copy)) = blended else {
+        warn!("the scene has no blended material to benchmark with");
+        return;
+    };
+    let materials = [blended, materials.add(copy)];
+
+    let half = BENCHMARK_INSTANCES_PER_ROW as f32 * 0.5;
+    instances.0 = (0..BENCHMARK_INSTANCES_PER_ROW)
+        .flat_map(|z| (0..BENCHMARK_INSTANCES_PER_ROW).map(move |x| (x, z)))
+        .map(|(x, z)| Instance {
+            position: cgmath::Vector3::new(x as f32 - half, 0.0, z as f32 - half),
+            rotation: Quaternion::from_axis_angle(cgmath::Vector3::unit_y(), cgmath::Deg(0.0)),
+            material: InstanceMaterial::Applier(materials[((x + z) % 2) as usize].clone()),
+            texture: InstanceTexture::Whole,
+            selected: false,
+        })
+        .collect();
+}
+
+/// Warms up, measures serial encoding, then parallel encoding, and reports.
+fn run_benchmark(
+    timings: Res<RenderTimings>,
+    instances: Res<Instances>,
+    mut parallel_encoding: ResMut<ParallelEncoding>,
+    mut exit: EventWriter<AppExit>,
+    mut frame: Local<u32>,
+) {
+    *frame += 1;
+    if *frame == 1 {
+        parallel_encoding.enabled = false;
+    } else if *frame == WARMUP_FRAMES {
+        timings.reset();
+    } else if *frame == WARMUP_FRAMES + MEASURED_FRAMES {
+        parallel_encoding.enabled = true;
+    } else if *frame == WARMUP_FRAMES + 2 * MEASURED_FRAMES {
+        match (timings.average(false), timings.average(true)) {
+            (Some(serial), Some(parallel)) => info!(
+                "render graph with {BENCHMARK_VIEWS} views of {} instances: {serial:.3} ms \
+                 serial, {parallel:.3} ms parallel, {:.2}x",
+                instances.0.len(),
+                serial / parallel,
+            ),
+            _ => warn!("the benchmark didn't render a frame in both modes"),
+        }
+        exit.send(AppExit::Success);
+    }
+}
+
+#[derive(Resource, Default)]
+struct RenderStart(Option<Instant>);
+
+fn start_render_timer(mut start: ResMut<RenderStart>) {
+    start.0 = Some(Instant::now());
+}
+
+fn stop_render_timer(
+    mut start: ResMut<RenderStart>,
+    parallel_encoding: Res<ParallelEncoding>,
+    timings: Res<RenderTimings>,
+) {
+    if let Some(start) = start.0.take() {
+        timings.add(parallel_encoding.enabled, start.elapsed());
+    }
+}
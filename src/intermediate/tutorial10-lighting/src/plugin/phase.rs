@@ -0,0 +1,285 @@
+//! Render phases: what a pass draws, sorted and batched before the pass runs.
+//!
+//! Queue systems add an [`ApplierPhaseItem`] for everything they want drawn, with the
+//! [`ApplierDrawFunctionId`] of the [`ApplierDrawCommand`] that draws it. `sort_phases` puts
+//! each phase in its order, then hands out instance buffer slots in that order and merges
+//! neighbouring items that only differ in their instance into a single instanced draw. The pass
+//! just runs the draw function of every item, so drawing something new means adding a draw
+//! command and queueing items for it, without touching the node. Draw commands go through a
+//! [`DrawTarget`], usually a `TrackedRenderPass`, which skips setting a pipeline, bind group or
+//! buffer that's already set, so consecutive items sharing state don't bind it again.
+//!
+//! The types are prefixed with `Applier` so they don't get mixed up with Bevy's own
+//! `render_phase` types of the same shape.
+use std::{cmp::Reverse, marker::PhantomData, ops::Range};
+
+use bevy::{
+    math::FloatOrd,
+    prelude::*,
+    render::{
+        render_phase::TrackedRenderPass,
+        render_resource::{BindGroup, BufferSlice, CachedRenderPipelineId, RenderPipeline},
+    },
+};
+use wgpu::IndexFormat;
+
+use super::{
+    render_diagnostics::{SkipReason, SkippedDraws},
+    ApplierDraws, MaterialId,
+};
+
+/// Which mesh an item draws. Everything shares the one in `VertexBuffer` for now, so there's
+/// only [`MeshId::SHARED`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MeshId(pub u32);
+
+impl MeshId {
+    pub const SHARED: MeshId = MeshId(0);
+}
+
+/// The fields are in the order the opaque phase sorts by, so items that share a pipeline,
+/// then a material, end up next to each other.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SortKey {
+    pub pipeline: CachedRenderPipelineId,
+    pub material: Option<MaterialId>,
+    pub mesh: MeshId,
+    /// Distance from the camera.
+    pub depth: FloatOrd,
+}
+
+#[derive(Clone, Debug)]
+pub struct ApplierPhaseItem {
+    pub sort_key: SortKey,
+    pub draw_function: ApplierDrawFunctionId,
+    /// Index into `Instances` of the instance to draw, for items that read the instance
+    /// buffer. After batching it's the first instance of the batch.
+    pub instance: Option<u32>,
+    /// Into the instance buffer, filled in by batching.
+    pub instances: Range<u32>,
+}
+
+impl ApplierPhaseItem {
+    fn batches_with(&self, other: &ApplierPhaseItem) -> bool {
+        self.instance.is_some()
+            && other.instance.is_some()
+            && self.draw_function == other.draw_function
+            && self.sort_key.pipeline == other.sort_key.pipeline
+            && self.sort_key.material == other.sort_key.material
+            && self.sort_key.mesh == other.sort_key.mesh
+    }
+}
+
+/// How the items of a phase get ordered.
+pub trait PhaseOrder: Send + Sync + 'static {
+    fn sort(items: &mut [ApplierPhaseItem]);
+}
+
+/// By pipeline, then material, then mesh, and front to back within those.
+pub struct Opaque;
+
+impl PhaseOrder for Opaque {
+    fn sort(items: &mut [ApplierPhaseItem]) {
+        items.sort_by_key(|item| item.sort_key);
+    }
+}
+
+/// Back to front, so blending sees everything behind. Items sharing state only get batched
+/// when they happen to end up next to each other.
+pub struct Transparent;
+
+impl PhaseOrder for Transparent {
+    fn sort(items: &mut [ApplierPhaseItem]) {
+        items.sort_by_key(|item| Reverse(item.sort_key.depth));
+    }
+}
+
+/// Gets filled by the queue systems every frame and emptied once the frame is rendered.
+#[derive(Resource)]
+pub struct ApplierRenderPhase<P: PhaseOrder> {
+    pub items: Vec<ApplierPhaseItem>,
+    _order: PhantomData<P>,
+}
+
+pub type OpaquePhase = ApplierRenderPhase<Opaque>;
+pub type TransparentPhase = ApplierRenderPhase<Transparent>;
+
+impl<P: PhaseOrder> Default for ApplierRenderPhase<P> {
+    fn default() -> Self {
+        Self {
+            items: Vec::new(),
+            _order: PhantomData,
+        }
+    }
+}
+
+impl<P: PhaseOrder> ApplierRenderPhase<P> {
+    pub fn add(&mut self, item: ApplierPhaseItem) {
+        self.items.push(item);
+    }
+
+    /// Gives every item that draws an instance the next slots of `order`, then merges it into
+    /// the item before when they can be drawn together.
+    fn batch(&mut self, order: &mut Vec<u32>) {
+        let mut batched: Vec<ApplierPhaseItem> = Vec::with_capacity(self.items.len());
+        for mut item in self.items.drain(..) {
+            let Some(instance) = item.instance else {
+                batched.push(item);
+                continue;
+            };
+            let position = order.len() as u32;
+            order.push(instance);
+            item.instances = position..position + 1;
+            match batched.last_mut() {
+                Some(last) if last.batches_with(&item) => last.instances.end = position + 1,
+                _ => batched.push(item),
+            }
+        }
+        self.items = batched;
+    }
+
+    pub fn render<'w>(
+        &self,
+        world: &'w World,
+        render_pass: &mut dyn DrawTarget<'w>,
+        draw_functions: &ApplierDrawFunctions,
+        skipped: &SkippedDraws,
+    ) {
+        for item in &self.items {
+            let Some(draw) = draw_functions.get(item.draw_function) else {
+                skipped.count(SkipReason::MissingResource);
+                continue;
+            };
+            if let Err(reason) = draw(item, world, render_pass) {
+                skipped.count(reason);
+            }
+        }
+    }
+}
+
+/// What draw commands record into: the render pass, or a render bundle being recorded.
+pub trait DrawTarget<'w> {
+    fn set_render_pipeline(&mut self, pipeline: &'w RenderPipeline);
+    fn set_bind_group(
+        &mut self,
+        index: usize,
+        bind_group: &'w BindGroup,
+        dynamic_uniform_indices: &[u32],
+    );
+    fn set_vertex_buffer(&mut self, slot_index: usize, buffer_slice: BufferSlice<'w>);
+    fn set_index_buffer(
+        &mut self,
+        buffer_slice: BufferSlice<'w>,
+        offset: u64,
+        index_format: IndexFormat,
+    );
+    fn draw_indexed(&mut self, indices: Range<u32>, base_vertex: i32, instances: Range<u32>);
+}
+
+impl<'w> DrawTarget<'w> for TrackedRenderPass<'w> {
+    fn set_render_pipeline(&mut self, pipeline: &'w RenderPipeline) {
+        TrackedRenderPass::set_render_pipeline(self, pipeline);
+    }
+
+    fn set_bind_group(
+        &mut self,
+        index: usize,
+        bind_group: &'w BindGroup,
+        dynamic_uniform_indices: &[u32],
+    ) {
+        TrackedRenderPass::set_bind_group(self, index, bind_group, dynamic_uniform_indices);
+    }
+
+    fn set_vertex_buffer(&mut self, slot_index: usize, buffer_slice: BufferSlice<'w>) {
+        TrackedRenderPass::set_vertex_buffer(self, slot_index, buffer_slice);
+    }
+
+    fn set_index_buffer(
+        &mut self,
+        buffer_slice: BufferSlice<'w>,
+        offset: u64,
+        index_format: IndexFormat,
+    ) {
+        TrackedRenderPass::set_index_buffer(self, buffer_slice, offset, index_format);
+    }
+
+    fn draw_indexed(&mut self, indices: Range<u32>, base_vertex: i32, instances: Range<u32>) {
+        TrackedRenderPass::draw_indexed(self, indices, base_vertex, instances);
+    }
+}
+
+/// One step of drawing an item, like setting a bind group. Tuples of them run one after the
+/// other and stop at the first one that can't go ahead.
+pub trait ApplierDrawCommand {
+    fn render<'w>(
+        item: &ApplierPhaseItem,
+        world: &'w World,
+        render_pass: &mut dyn DrawTarget<'w>,
+    ) -> Result<(), SkipReason>;
+}
+
+macro_rules! impl_draw_command_tuple {
+    ($($command:ident),*) => {
+        impl<$($command: ApplierDrawCommand),*> ApplierDrawCommand for ($($command,)*) {
+            fn render<'w>(
+                item: &ApplierPhaseItem,
+                world: &'w World,
+                render_pass: &mut dyn DrawTarget<'w>,
+            ) -> Result<(), SkipReason> {
+                $($command::render(item, world, render_pass)?;)*
+                Ok(())
+            }
+        }
+    };
+}
+
+impl_draw_command_tuple!(C0, C1);
+impl_draw_command_tuple!(C0, C1, C2);
+impl_draw_command_tuple!(C0, C1, C2, C3);
+impl_draw_command_tuple!(C0, C1, C2, C3, C4);
+impl_draw_command_tuple!(C0, C1, C2, C3, C4, C5);
+impl_draw_command_tuple!(C0, C1, C2, C3, C4, C5, C6);
+impl_draw_command_tuple!(C0, C1, C2, C3, C4, C5, C6, C7);
+
+pub type ApplierDrawFunction =
+    for<'w> fn(&ApplierPhaseItem, &'w World, &mut dyn DrawTarget<'w>) -> Result<(), SkipReason>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ApplierDrawFunctionId(usize);
+
+/// Every draw command items can refer to.
+#[derive(Resource, Default)]
+pub struct ApplierDrawFunctions(Vec<ApplierDrawFunction>);
+
+impl ApplierDrawFunctions {
+    pub fn add<C: ApplierDrawCommand>(&mut self) -> ApplierDrawFunctionId {
+        self.0.push(C::render);
+        ApplierDrawFunctionId(self.0.len() - 1)
+    }
+
+    fn get(&self, id: ApplierDrawFunctionId) -> Option<ApplierDrawFunction> {
+        self.0.get(id.0).copied()
+    }
+}
+
+/// Sorts both phases, then batches them with the opaque instances first in the instance
+/// buffer.
+pub fn sort_phases(
+    mut opaque_phase: ResMut<OpaquePhase>,
+    mut transparent_phase: ResMut<TransparentPhase>,
+    mut draws: ResMut<ApplierDraws>,
+) {
+    Opaque::sort(&mut opaque_phase.items);
+    Transparent::sort(&mut transparent_phase.items);
+    draws.order.clear();
+    opaque_phase.batch(&mut draws.order);
+    transparent_phase.batch(&mut draws.order);
+}
+
+pub fn clear_phases(
+    mut opaque_phase: ResMut<OpaquePhase>,
+    mut transparent_phase: ResMut<TransparentPhase>,
+) {
+    opaque_phase.items.clear();
+    transparent_phase.items.clear();
+}
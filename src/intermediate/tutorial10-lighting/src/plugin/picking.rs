@@ -0,0 +1,307 @@
+//! Which instance is under the cursor.
+//!
+//! Besides the color, the applier pass writes every pixel's position in the instance buffer
+//! into an `R32Uint` target, or [`NO_INSTANCE`] where no instance got drawn. The target can't
+//! be blended, so only texels that get drawn at all may write it: masked materials discard
+//! below their cutoff and transparent ones below
+//! [`TRANSPARENT_ALPHA_CUTOFF`](super::outline::TRANSPARENT_ALPHA_CUTOFF), and what's behind
+//! them stays pickable there. After the pass
+//! `PickingNode` copies the pixel under the cursor into a small buffer, and once that maps,
+//! [`ApplierDraws::order`] of the same frame turns it back into an index into [`Instances`],
+//! which ends up in the main world's [`HoveredInstance`]. Clicking selects the hovered instance
+//! for outlining.
+//!
+//! Like the GPU timestamps, the pixel gets read back a frame or two after it was drawn, and
+//! no pixel gets copied while that readback is in flight.
+
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc, Mutex, PoisonError,
+};
+
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::{Buffer, RenderPassColorAttachment, Texture},
+        renderer::RenderDevice,
+        texture::{CachedTexture, TextureCache},
+        view::ExtractedWindows,
+        RenderApp,
+    },
+};
+use wgpu::{
+    BufferAsyncError, BufferDescriptor, BufferUsages, Color, CommandEncoder, Extent3d,
+    ImageCopyBuffer, ImageDataLayout, LoadOp, Maintain, MapMode, Operations, Origin3d, StoreOp,
+    TextureDescriptor, TextureDimension, TextureFormat, TextureUsages,
+};
+
+use super::{ApplierDraws, ExtractedWindow, Instances, MousePosition, WindowTargets};
+
+pub const PICKING_FORMAT: TextureFormat = TextureFormat::R32Uint;
+
+/// Written where no instance got drawn. Matches `NO_INSTANCE` in instancing.wgsl.
+pub const NO_INSTANCE: u32 = u32::MAX;
+
+const PIXEL_SIZE: u64 = 4;
+
+/// The instance under the cursor, as an index into [`Instances`]. Only changes when another
+/// instance gets hovered, so change detection tells when that happens.
+#[derive(Resource, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HoveredInstance(pub Option<usize>);
+
+/// The same value in both worlds. The render world stores what it read back, the main world
+/// picks it up once a frame.
+#[derive(Resource, Clone)]
+pub struct PickedInstance(Arc<AtomicU32>);
+
+impl Default for PickedInstance {
+    fn default() -> Self {
+        Self(Arc::new(AtomicU32::new(NO_INSTANCE)))
+    }
+}
+
+pub struct PickingPlugin;
+
+impl Plugin for PickingPlugin {
+    fn build(&self, app: &mut App) {
+        let picked = PickedInstance::default();
+        app.init_resource::<HoveredInstance>()
+            .insert_resource(picked.clone())
+            .add_systems(
+                Update,
+                (update_hovered_instance, select_hovered_instance).chain(),
+            );
+
+        if let Some(render_app) = app.get_sub_app_mut(RenderApp) {
+            render_app.insert_resource(picked);
+        }
+    }
+}
+
+fn update_hovered_instance(picked: Res<PickedInstance>, mut hovered: ResMut<HoveredInstance>) {
+    let index = picked.0.load(Ordering::Relaxed);
+    hovered.set_if_neq(HoveredInstance(
+        (index != NO_INSTANCE).then_some(index as usize),
+    ));
+}
+
+/// Clicking empty space clears the selection.
+fn select_hovered_instance(
+    mouse_input: Res<ButtonInput<MouseButton>>,
+    hovered: Res<HoveredInstance>,
+    mut instances: ResMut<Instances>,
+) {
+    if !mouse_input.just_pressed(MouseButton::Left) {
+        return;
+    }
+    for (index, instance) in instances.0.iter_mut().enumerate() {
+        instance.selected = hovered.0 == Some(index);
+    }
+}
+
+/// The target the applier pass writes the instances into.
+pub struct PickingTexture(CachedTexture);
+
+impl PickingTexture {
+    /// Cleared to [`NO_INSTANCE`].
+    pub fn color_attachment(&self) -> RenderPassColorAttachment<'_> {
+        RenderPassColorAttachment {
+            view: &self.0.default_view,
+            resolve_target: None,
+            ops: Operations {
+                load: LoadOp::Clear(Color {
+                    r: NO_INSTANCE as f64,
+                    g: 0.0,
+                    b: 0.0,
+                    a: 0.0,
+                }),
+                store: StoreOp::Store,
+            },
+        }
+    }
+
+    pub fn texture(&self) -> &Texture {
+        &self.0.texture
+    }
+}
+
+pub fn prepare_picking_texture(
+    windows: Res<ExtractedWindows>,
+    render_device: Res<RenderDevice>,
+    mut picking_textures: ResMut<WindowTargets<PickingTexture>>,
+    mut texture_cache: ResMut<TextureCache>,
+) {
+    picking_textures.start_frame(&windows);
+    for (&entity, window) in windows.iter() {
+        let texture = texture_cache.get(
+            &render_device,
+            TextureDescriptor {
+                label: Some("picking_texture"),
+                size: Extent3d {
+                    width: window.physical_width,
+                    height: window.physical_height,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: TextureDimension::D2,
+                format: PICKING_FORMAT,
+                usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::COPY_SRC,
+                view_formats: &[],
+            },
+        );
+        picking_textures.insert(entity, PickingTexture(texture));
+    }
+}
+
+/// The pixel under the cursor, `None` when it's outside the window.
+pub fn cursor_pixel(mouse_position: &MousePosition, window: &ExtractedWindow) -> Option<UVec2> {
+    let inside = |position: f32, size: u32| (0.0..size as f32).contains(&position);
+    (inside(mouse_position.0, window.physical_width)
+        && inside(mouse_position.1, window.physical_height))
+    .then(|| UVec2::new(mouse_position.0 as u32, mouse_position.1 as u32))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ReadbackState {
+    /// Ready for the next pixel.
+    Idle,
+    /// A pixel got copied into the readback buffer.
+    Copied,
+    /// Waiting for the readback buffer to map.
+    Mapping,
+}
+
+struct PickingFrame {
+    state: ReadbackState,
+    /// [`ApplierDraws::order`] of the frame the pixel was copied in.
+    order: Vec<u32>,
+}
+
+#[derive(Resource)]
+pub struct PickingReadback {
+    buffer: Buffer,
+    frame: Mutex<PickingFrame>,
+    mapped: Arc<Mutex<Option<Result<(), BufferAsyncError>>>>,
+}
+
+impl FromWorld for PickingReadback {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        Self {
+            buffer: render_device.create_buffer(&BufferDescriptor {
+                label: Some("picking_readback_buffer"),
+                size: PIXEL_SIZE,
+                usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+                mapped_at_creation: false,
+            }),
+            frame: Mutex::new(PickingFrame {
+                state: ReadbackState::Idle,
+                order: Vec::new(),
+            }),
+            mapped: Arc::default(),
+        }
+    }
+}
+
+impl PickingReadback {
+    /// Copies `pixel` of the picking texture into the readback buffer, unless the last one is
+    /// still being read. Has to come after the applier pass.
+    pub fn copy(
+        &self,
+        command_encoder: &mut CommandEncoder,
+        texture: &PickingTexture,
+        pixel: UVec2,
+        draws: &ApplierDraws,
+    ) {
+        let mut frame = self.frame.lock().unwrap_or_else(PoisonError::into_inner);
+        if frame.state != ReadbackState::Idle {
+            return;
+        }
+        let mut source = texture.texture().as_image_copy();
+        source.origin = Origin3d {
+            x: pixel.x,
+            y: pixel.y,
+            z: 0,
+        };
+        command_encoder.copy_texture_to_buffer(
+            source,
+            ImageCopyBuffer {
+                buffer: &self.buffer,
+                // A single row doesn't need its size or alignment spelled out.
+                layout: ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: None,
+                    rows_per_image: None,
+                },
+            },
+            Extent3d {
+                width: 1,
+                height: 1,
+                depth_or_array_layers: 1,
+            },
+        );
+        frame.state = ReadbackState::Copied;
+        frame.order.clone_from(&draws.order);
+    }
+}
+
+/// Runs once the frame got submitted.
+pub fn map_picked_pixel(readback: Res<PickingReadback>) {
+    let mut frame = readback
+        .frame
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    if frame.state != ReadbackState::Copied {
+        return;
+    }
+    frame.state = ReadbackState::Mapping;
+    let mapped = readback.mapped.clone();
+    readback
+        .buffer
+        .slice(..)
+        .map_async(MapMode::Read, move |result| {
+            *mapped.lock().unwrap_or_else(PoisonError::into_inner) = Some(result);
+        });
+}
+
+pub fn read_picked_pixel(
+    readback: Res<PickingReadback>,
+    picked: Res<PickedInstance>,
+    render_device: Res<RenderDevice>,
+) {
+    let mut frame = readback
+        .frame
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    if frame.state != ReadbackState::Mapping {
+        return;
+    }
+    render_device.poll(Maintain::Poll);
+    let Some(result) = readback
+        .mapped
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .take()
+    else {
+        return;
+    };
+    frame.state = ReadbackState::Idle;
+    if let Err(error) = result {
+        warn!("couldn't read the picked pixel back: {error}");
+        return;
+    }
+
+    let position = {
+        let data = readback.buffer.slice(..).get_mapped_range();
+        bytemuck::pod_read_unaligned::<u32>(&data[..PIXEL_SIZE as usize])
+    };
+    readback.buffer.unmap();
+    // Lights and empty space both come back as `NO_INSTANCE`, which isn't in the order.
+    let instance = frame
+        .order
+        .get(position as usize)
+        .copied()
+        .unwrap_or(NO_INSTANCE);
+    picked.0.store(instance, Ordering::Relaxed);
+}
@@ -0,0 +1,358 @@
+//! Pipelines that fail to compile, shown instead of just leaving things undrawn.
+//!
+//! `collect_pipeline_errors` goes over every pipeline in the `PipelineCache` and logs each one
+//! that failed, once per failure, with the shader path, line and column the error points at.
+//! As long as any are broken, `ErrorOverlayNode` prints the same messages in the top left of
+//! the window with [`bitmap_font`](super::bitmap_font), so a typo in a shader doesn't just
+//! show up as a blank screen.
+//!
+//! With [`PipelineWarmup`] enabled the applier graph doesn't run until the pipelines queued so
+//! far are done compiling, so the first frames don't pop in material by material.
+
+use std::fmt;
+
+use bevy::{
+    asset::Handle,
+    prelude::*,
+    render::{
+        render_resource::{
+            binding_types::texture_2d, BindGroup, BindGroupEntries, BindGroupLayout,
+            BindGroupLayoutEntries, CachedPipelineState, CachedRenderPipelineId, PipelineCache,
+            PipelineCacheError, PipelineDescriptor, Shader, ShaderStages, Source,
+            TextureSampleType,
+        },
+        renderer::{RenderDevice, RenderQueue},
+        Extract,
+    },
+    utils::{HashMap, HashSet},
+};
+use naga_oil::compose::{ComposerError, ComposerErrorInner, ErrSource};
+use wgpu::{
+    util::TextureDataOrder, Extent3d, TextureDescriptor, TextureDimension, TextureFormat,
+    TextureUsages, TextureViewDescriptor,
+};
+
+use super::{bitmap_font, fullscreen, tonemapping::SURFACE_TEXTURE_FORMAT};
+
+pub const ERROR_OVERLAY_SHADER_HANDLE: Handle<Shader> =
+    Handle::weak_from_u128(262114609541797324402185349771519064082);
+
+/// naga_oil keeps the index of the module a span is in above this many bits.
+const SPAN_SHIFT: usize = 21;
+
+/// Longer lines get wrapped.
+const MAX_COLUMNS: usize = 120;
+/// Anything past this is cut off, the log has all of it.
+const MAX_LINES: usize = 40;
+/// Around the text, in pixels of the font.
+const OVERLAY_PADDING: usize = 4;
+
+/// Where in a shader a compile error points.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceLocation {
+    pub path: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PipelineError {
+    /// The label of the pipeline that failed.
+    pub pipeline: String,
+    /// Missing when the error doesn't point anywhere in particular, like when wgpu rejects the
+    /// shader module after naga_oil is done with it.
+    pub location: Option<SourceLocation>,
+    pub message: String,
+}
+
+impl fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: ", self.pipeline)?;
+        if let Some(location) = &self.location {
+            write!(
+                f,
+                "{}:{}:{}: ",
+                location.path, location.line, location.column
+            )?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Every loaded shader by the name naga_oil knows it under, so errors inside an imported
+/// module can be traced back to a file and line.
+#[derive(Resource, Default)]
+pub struct ShaderSources(HashMap<String, (String, String)>);
+
+pub fn extract_shader_sources(
+    mut events: Extract<EventReader<AssetEvent<Shader>>>,
+    shaders: Extract<Res<Assets<Shader>>>,
+    mut sources: ResMut<ShaderSources>,
+) {
+    for event in events.read() {
+        let (AssetEvent::Added { id } | AssetEvent::Modified { id }) = event else {
+            continue;
+        };
+        let Some(shader) = shaders.get(*id) else {
+            continue;
+        };
+        let Source::Wgsl(source) = &shader.source else {
+            continue;
+        };
+        sources.0.insert(
+            shader.import_path().module_name().into_owned(),
+            (shader.path.clone(), source.to_string()),
+        );
+    }
+}
+
+/// Turns the byte offset naga_oil reports into a line and column, the way naga_oil's own
+/// error printing does.
+fn error_location(error: &ComposerError, sources: &ShaderSources) -> Option<SourceLocation> {
+    let (path, source) = match &error.source {
+        ErrSource::Constructing { path, source, .. } => (path, source),
+        ErrSource::Module { name, .. } => {
+            let (path, source) = sources.0.get(name)?;
+            (path, source)
+        }
+    };
+    let offset = error.source.offset();
+    let map_span = |start: usize| (start & ((1 << SPAN_SHIFT) - 1)).saturating_sub(offset);
+
+    let position = match &error.inner {
+        ComposerErrorInner::WgslParseError(error) => {
+            map_span(error.labels().next()?.0.to_range()?.start)
+        }
+        ComposerErrorInner::ShaderValidationError(error)
+        | ComposerErrorInner::HeaderValidationError(error) => {
+            map_span(error.spans().next()?.0.to_range()?.start)
+        }
+        ComposerErrorInner::ImportNotFound(_, pos)
+        | ComposerErrorInner::ImportParseError(_, pos)
+        | ComposerErrorInner::NotEnoughEndIfs(pos)
+        | ComposerErrorInner::TooManyEndIfs(pos)
+        | ComposerErrorInner::ElseWithoutCondition(pos)
+        | ComposerErrorInner::UnknownShaderDef { pos, .. }
+        | ComposerErrorInner::UnknownShaderDefOperator { pos, .. }
+        | ComposerErrorInner::InvalidShaderDefComparisonValue { pos, .. }
+        | ComposerErrorInner::DefineInModule(pos)
+        | ComposerErrorInner::InvalidShaderDefDefinitionValue { pos, .. } => *pos,
+        _ => return None,
+    };
+
+    let before = source.get(..position)?;
+    let line_start = before.rfind('\n').map_or(0, |index| index + 1);
+    Some(SourceLocation {
+        path: path.clone(),
+        line: before.matches('\n').count() + 1,
+        column: before[line_start..].chars().count() + 1,
+    })
+}
+
+/// The pipelines that failed to compile as of last frame, without repeats. Specializations of
+/// the same pipeline tend to fail the same way.
+#[derive(Resource, Default)]
+pub struct PipelineErrors {
+    pub errors: Vec<PipelineError>,
+    /// Indices in the cache of the pipelines failing right now, so each failure gets logged
+    /// once. A pipeline that compiles again drops out and gets logged the next time it breaks.
+    failing: HashSet<usize>,
+}
+
+/// Runs before the cache processes its queue, so errors show up a frame after they happen.
+pub fn collect_pipeline_errors(
+    pipeline_cache: Res<PipelineCache>,
+    sources: Res<ShaderSources>,
+    mut pipeline_errors: ResMut<PipelineErrors>,
+) {
+    let PipelineErrors { errors, failing } = pipeline_errors.as_mut();
+    errors.clear();
+    for (index, pipeline) in pipeline_cache.pipelines().enumerate() {
+        let CachedPipelineState::Err(error) = &pipeline.state else {
+            failing.remove(&index);
+            continue;
+        };
+        let label = match &pipeline.descriptor {
+            PipelineDescriptor::RenderPipelineDescriptor(descriptor) => descriptor.label.clone(),
+            PipelineDescriptor::ComputePipelineDescriptor(descriptor) => descriptor.label.clone(),
+        };
+        let (location, message) = match error {
+            PipelineCacheError::ProcessShaderError(error) => {
+                (error_location(error, &sources), error.inner.to_string())
+            }
+            error => (None, error.to_string()),
+        };
+        let error = PipelineError {
+            pipeline: label.map_or_else(|| "unlabeled pipeline".into(), Into::into),
+            location,
+            message,
+        };
+        if failing.insert(index) {
+            error!("{error}");
+        }
+        if !errors.contains(&error) {
+            errors.push(error);
+        }
+    }
+}
+
+/// Holds the applier graph back until the pipelines it needs are compiled.
+#[derive(Resource, Clone, Debug, Default)]
+pub struct PipelineWarmup {
+    pub enabled: bool,
+}
+
+pub fn extract_pipeline_warmup(
+    mut commands: Commands,
+    main_settings: Extract<Res<PipelineWarmup>>,
+) {
+    commands.insert_resource(main_settings.clone());
+}
+
+/// Whether `ExecuteNode` should skip the applier graph this frame. Once the warmup is over it
+/// doesn't start again, even when pipelines are compiled later on.
+#[derive(Resource)]
+pub struct WarmupState {
+    pub warming_up: bool,
+}
+
+impl Default for WarmupState {
+    fn default() -> Self {
+        Self { warming_up: true }
+    }
+}
+
+/// Pipelines queued this frame don't show up in the cache before it processes its queue, so
+/// an empty cache means nothing got queued yet rather than that everything is ready.
+pub fn update_pipeline_warmup(
+    settings: Res<PipelineWarmup>,
+    pipeline_cache: Res<PipelineCache>,
+    mut state: ResMut<WarmupState>,
+) {
+    if !state.warming_up {
+        return;
+    }
+    let mut pipelines = pipeline_cache.pipelines().peekable();
+    let nothing_queued = pipelines.peek().is_none();
+    let compiling = pipelines.any(|pipeline| {
+        matches!(
+            pipeline.state,
+            CachedPipelineState::Queued | CachedPipelineState::Creating(_)
+        )
+    });
+    state.warming_up = settings.enabled && (nothing_queued || compiling);
+}
+
+#[derive(Resource)]
+pub struct ErrorOverlayPipeline {
+    layout: BindGroupLayout,
+    pub id: CachedRenderPipelineId,
+}
+
+impl FromWorld for ErrorOverlayPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let layout = render_device.create_bind_group_layout(
+            "error_overlay_layout",
+            &BindGroupLayoutEntries::single(
+                ShaderStages::FRAGMENT,
+                texture_2d(TextureSampleType::Float { filterable: false }),
+            ),
+        );
+        let id = world.resource::<PipelineCache>().queue_render_pipeline(
+            fullscreen::fullscreen_pipeline_descriptor(
+                "error_overlay_pipeline",
+                ERROR_OVERLAY_SHADER_HANDLE,
+                vec![],
+                vec![layout.clone()],
+                SURFACE_TEXTURE_FORMAT,
+            ),
+        );
+
+        Self { layout, id }
+    }
+}
+
+/// The error messages drawn into a texture. Only there while something is failing.
+#[derive(Resource)]
+pub struct PreparedErrorOverlay {
+    pub bind_group: BindGroup,
+    /// What's in the texture, so it only gets drawn again when the errors change.
+    lines: Vec<String>,
+}
+
+fn overlay_lines(errors: &[PipelineError]) -> Vec<String> {
+    let mut lines = vec![format!(
+        "{} pipeline error{}",
+        errors.len(),
+        if errors.len() == 1 { "" } else { "s" }
+    )];
+    for error in errors {
+        lines.push(String::new());
+        let location = error.location.as_ref().map_or(String::new(), |location| {
+            format!(
+                " at {}:{}:{}",
+                location.path, location.line, location.column
+            )
+        });
+        lines.push(format!("{}{location}", error.pipeline));
+        for line in error.message.lines() {
+            let chars: Vec<char> = line.chars().collect();
+            lines.extend(
+                chars
+                    .chunks(MAX_COLUMNS)
+                    .map(|chunk| format!("  {}", chunk.iter().collect::<String>())),
+            );
+        }
+    }
+    lines.truncate(MAX_LINES);
+    lines
+}
+
+pub fn prepare_error_overlay(
+    mut commands: Commands,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    pipeline_errors: Res<PipelineErrors>,
+    pipeline: Res<ErrorOverlayPipeline>,
+    overlay: Option<Res<PreparedErrorOverlay>>,
+) {
+    if pipeline_errors.errors.is_empty() {
+        commands.remove_resource::<PreparedErrorOverlay>();
+        return;
+    }
+    let lines = overlay_lines(&pipeline_errors.errors);
+    if overlay.is_some_and(|overlay| overlay.lines == lines) {
+        return;
+    }
+
+    let (width, height, pixels) = bitmap_font::rasterize(&lines, OVERLAY_PADDING);
+    let texture = render_device.create_texture_with_data(
+        &render_queue,
+        &TextureDescriptor {
+            label: Some("error_overlay_texture"),
+            size: Extent3d {
+                width: width as u32,
+                height: height as u32,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::R8Unorm,
+            usage: TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        },
+        TextureDataOrder::LayerMajor,
+        &pixels,
+    );
+    let view = texture.create_view(&TextureViewDescriptor::default());
+    commands.insert_resource(PreparedErrorOverlay {
+        bind_group: render_device.create_bind_group(
+            "error_overlay_bind_group",
+            &pipeline.layout,
+            &BindGroupEntries::single(&view),
+        ),
+        lines,
+    });
+}
@@ -0,0 +1,364 @@
+//! Fullscreen effects that run between the applier pass and tonemapping.
+//!
+//! The effects form a stack described by [`PostProcessSettings`]. Each enabled effect reads the
+//! image the previous one wrote and writes into the other [`MainTextures`] texture, so the
+//! stack ping-pongs between the two. Reordering or toggling effects at runtime only changes
+//! which pipelines and bind groups get prepared for the frame.
+
+use bevy::{
+    asset::Handle,
+    prelude::*,
+    render::{
+        render_resource::{
+            binding_types::{sampler, texture_2d, texture_depth_2d, uniform_buffer},
+            BindGroup, BindGroupLayout, BindGroupLayoutEntries, CachedRenderPipelineId,
+            PipelineCache, RenderPipelineDescriptor, Sampler, SamplerBindingType, Shader,
+            ShaderStages, ShaderType, SpecializedRenderPipeline, SpecializedRenderPipelines,
+            TextureSampleType, TextureView, TextureViewId, UniformBuffer,
+        },
+        renderer::{RenderDevice, RenderQueue},
+        Extract,
+    },
+};
+use wgpu::{AddressMode, FilterMode, SamplerDescriptor};
+
+use super::{
+    bind_group_tracker::{BindGroupTracker, TrackedEntries},
+    camera, fullscreen, tonemapping,
+    tonemapping::HdrSettings,
+    DepthTexture, ExtractedWindow, MainTextures, WindowTargets,
+};
+
+pub const POST_PROCESS_SHADER_HANDLE: Handle<Shader> =
+    Handle::weak_from_u128(209437117842311539866294069415123765842);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PostProcessEffect {
+    Fxaa,
+    Bloom,
+    Vignette,
+    ChromaticAberration,
+    DepthOfField,
+}
+
+impl PostProcessEffect {
+    fn shader_def(&self) -> &'static str {
+        match self {
+            PostProcessEffect::Fxaa => "FXAA",
+            PostProcessEffect::Bloom => "BLOOM",
+            PostProcessEffect::Vignette => "VIGNETTE",
+            PostProcessEffect::ChromaticAberration => "CHROMATIC_ABERRATION",
+            PostProcessEffect::DepthOfField => "DEPTH_OF_FIELD",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PostProcessPass {
+    pub effect: PostProcessEffect,
+    pub enabled: bool,
+}
+
+#[derive(Resource, Clone, Debug)]
+pub struct PostProcessSettings {
+    /// Runs front to back.
+    pub stack: Vec<PostProcessPass>,
+    /// Brightness above which pixels start to glow.
+    pub bloom_threshold: f32,
+    pub bloom_intensity: f32,
+    /// Blur radius of the glow in pixels.
+    pub bloom_radius: f32,
+    pub vignette_intensity: f32,
+    /// Distance from the center, in uv units, where the darkening starts.
+    pub vignette_radius: f32,
+    pub vignette_smoothness: f32,
+    /// How far apart the red and blue channels get at the corners, in uv units.
+    pub chromatic_aberration: f32,
+    /// View space distance that stays sharp.
+    pub focus_distance: f32,
+    /// Distance from the focus at which the blur reaches `max_blur`.
+    pub focal_range: f32,
+    /// Blur radius in pixels.
+    pub max_blur: f32,
+}
+
+impl PostProcessSettings {
+    pub fn enabled_effects(&self) -> impl Iterator<Item = PostProcessEffect> + '_ {
+        self.stack
+            .iter()
+            .filter(|pass| pass.enabled)
+            .map(|pass| pass.effect)
+    }
+
+    fn toggle(&mut self, effect: PostProcessEffect) {
+        for pass in self.stack.iter_mut().filter(|pass| pass.effect == effect) {
+            pass.enabled = !pass.enabled;
+        }
+    }
+}
+
+impl Default for PostProcessSettings {
+    fn default() -> Self {
+        let pass = |effect, enabled| PostProcessPass { effect, enabled };
+        Self {
+            stack: vec![
+                pass(PostProcessEffect::DepthOfField, false),
+                pass(PostProcessEffect::Bloom, true),
+                pass(PostProcessEffect::ChromaticAberration, false),
+                pass(PostProcessEffect::Vignette, true),
+                pass(PostProcessEffect::Fxaa, true),
+            ],
+            bloom_threshold: 1.0,
+            bloom_intensity: 0.3,
+            bloom_radius: 16.0,
+            vignette_intensity: 0.6,
+            vignette_radius: 0.75,
+            vignette_smoothness: 0.45,
+            chromatic_aberration: 0.004,
+            focus_distance: 10.0,
+            focal_range: 8.0,
+            max_blur: 8.0,
+        }
+    }
+}
+
+pub struct PostProcessPlugin;
+
+impl Plugin for PostProcessPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PostProcessSettings>()
+            .add_systems(Update, handle_post_process_input);
+    }
+}
+
+fn handle_post_process_input(
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    mut settings: ResMut<PostProcessSettings>,
+) {
+    let toggles = [
+        (KeyCode::F1, PostProcessEffect::Fxaa),
+        (KeyCode::F2, PostProcessEffect::Bloom),
+        (KeyCode::F3, PostProcessEffect::Vignette),
+        (KeyCode::F4, PostProcessEffect::ChromaticAberration),
+        (KeyCode::F5, PostProcessEffect::DepthOfField),
+    ];
+    for (key, effect) in toggles {
+        if keyboard_input.just_pressed(key) {
+            settings.toggle(effect);
+        }
+    }
+    // Moves the first effect to the back so the ordering can be played with.
+    if keyboard_input.just_pressed(KeyCode::F6) {
+        settings.stack.rotate_left(1);
+    }
+}
+
+pub fn extract_post_process_settings(
+    mut commands: Commands,
+    main_settings: Extract<Res<PostProcessSettings>>,
+) {
+    commands.insert_resource(main_settings.clone());
+}
+
+#[derive(Debug, Clone, Default, ShaderType)]
+pub struct PostProcessUniform {
+    texel_size: Vec2,
+    znear: f32,
+    zfar: f32,
+    bloom_threshold: f32,
+    bloom_intensity: f32,
+    bloom_radius: f32,
+    vignette_intensity: f32,
+    vignette_radius: f32,
+    vignette_smoothness: f32,
+    chromatic_aberration: f32,
+    focus_distance: f32,
+    focal_range: f32,
+    max_blur: f32,
+}
+
+#[derive(Resource)]
+pub struct PostProcessBuffer {
+    buf: UniformBuffer<PostProcessUniform>,
+    sampler: Sampler,
+}
+
+impl FromWorld for PostProcessBuffer {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        // Blur taps land outside the image near the edges.
+        let sampler = render_device.create_sampler(&SamplerDescriptor {
+            label: Some("post_process_sampler"),
+            address_mode_u: AddressMode::ClampToEdge,
+            address_mode_v: AddressMode::ClampToEdge,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            ..Default::default()
+        });
+
+        Self {
+            buf: UniformBuffer::default(),
+            sampler,
+        }
+    }
+}
+
+impl PostProcessBuffer {
+    /// Passes only ever read one of the two main textures, so there's a bind group for each,
+    /// kept until the texture, the depth texture or the uniform buffer gets replaced.
+    pub fn bind_group(
+        &self,
+        render_device: &RenderDevice,
+        layout: &BindGroupLayout,
+        bind_groups: &mut BindGroupTracker<TextureViewId>,
+        source: &TextureView,
+        depth_texture: &DepthTexture,
+    ) -> Option<BindGroup> {
+        let entries = TrackedEntries::sequential((
+            source,
+            &self.sampler,
+            &self.buf,
+            &depth_texture.depth_view,
+        ))?;
+        Some(bind_groups.get_or_create(source.id(), entries, |entries| {
+            render_device.create_bind_group("Post process bind group", layout, entries)
+        }))
+    }
+
+    pub fn bind_group_layout(render_device: &RenderDevice) -> BindGroupLayout {
+        render_device.create_bind_group_layout(
+            "Post process bind group layout",
+            &BindGroupLayoutEntries::sequential(
+                ShaderStages::FRAGMENT,
+                (
+                    texture_2d(TextureSampleType::Float { filterable: true }),
+                    sampler(SamplerBindingType::Filtering),
+                    uniform_buffer::<PostProcessUniform>(false),
+                    texture_depth_2d(),
+                ),
+            ),
+        )
+    }
+}
+
+/// One bind group per enabled effect, in stack order.
+#[derive(Resource)]
+pub struct PreparedPostProcess {
+    pub bind_groups: Vec<BindGroup>,
+}
+
+pub fn prepare_post_process_bind_groups(
+    mut commands: Commands,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    settings: Res<PostProcessSettings>,
+    window: Res<ExtractedWindow>,
+    camera: Res<camera::Camera>,
+    main_textures: Res<WindowTargets<MainTextures>>,
+    depth_textures: Res<WindowTargets<DepthTexture>>,
+    pipeline: Res<PostProcessPipeline>,
+    mut post_process: ResMut<PostProcessBuffer>,
+    mut bind_groups: ResMut<BindGroupTracker<TextureViewId>>,
+) {
+    let (Some(main_textures), Some(depth_texture)) =
+        (main_textures.primary(), depth_textures.primary())
+    else {
+        commands.remove_resource::<PreparedPostProcess>();
+        return;
+    };
+    post_process.buf.set(PostProcessUniform {
+        texel_size: Vec2::new(
+            1.0 / window.physical_width as f32,
+            1.0 / window.physical_height as f32,
+        ),
+        znear: camera.znear,
+        zfar: camera.zfar,
+        bloom_threshold: settings.bloom_threshold,
+        bloom_intensity: settings.bloom_intensity,
+        bloom_radius: settings.bloom_radius,
+        vignette_intensity: settings.vignette_intensity,
+        vignette_radius: settings.vignette_radius,
+        vignette_smoothness: settings.vignette_smoothness,
+        chromatic_aberration: settings.chromatic_aberration,
+        focus_distance: settings.focus_distance,
+        focal_range: settings.focal_range,
+        max_blur: settings.max_blur,
+    });
+    post_process.buf.write_buffer(&render_device, &render_queue);
+
+    let bind_groups = (0..settings.enabled_effects().count())
+        .map(|pass| {
+            post_process.bind_group(
+                &render_device,
+                &pipeline.layout,
+                &mut bind_groups,
+                main_textures.source(pass),
+                depth_texture,
+            )
+        })
+        .collect::<Option<Vec<_>>>();
+    match bind_groups {
+        Some(bind_groups) => commands.insert_resource(PreparedPostProcess { bind_groups }),
+        None => commands.remove_resource::<PreparedPostProcess>(),
+    }
+}
+
+#[derive(Resource)]
+pub struct PostProcessPipeline {
+    layout: BindGroupLayout,
+}
+
+impl FromWorld for PostProcessPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+
+        Self {
+            layout: PostProcessBuffer::bind_group_layout(render_device),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PostProcessPipelineKey {
+    pub effect: PostProcessEffect,
+    pub hdr: bool,
+}
+
+impl SpecializedRenderPipeline for PostProcessPipeline {
+    type Key = PostProcessPipelineKey;
+
+    fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
+        fullscreen::fullscreen_pipeline_descriptor(
+            "post_process_pipeline",
+            POST_PROCESS_SHADER_HANDLE,
+            vec![key.effect.shader_def().into()],
+            vec![self.layout.clone()],
+            tonemapping::main_pass_format(key.hdr),
+        )
+    }
+}
+
+/// The pipelines for this frame's enabled effects, in stack order.
+#[derive(Resource)]
+pub struct PostProcessPipelineIds(pub Vec<CachedRenderPipelineId>);
+
+pub fn queue_post_process_pipelines(
+    mut commands: Commands,
+    pipeline_cache: Res<PipelineCache>,
+    pipeline: Res<PostProcessPipeline>,
+    mut pipelines: ResMut<SpecializedRenderPipelines<PostProcessPipeline>>,
+    settings: Res<PostProcessSettings>,
+    hdr: Res<HdrSettings>,
+) {
+    let ids = settings
+        .enabled_effects()
+        .map(|effect| {
+            let key = PostProcessPipelineKey {
+                effect,
+                hdr: hdr.enabled,
+            };
+            pipelines.specialize(&pipeline_cache, &pipeline, key)
+        })
+        .collect();
+    commands.insert_resource(PostProcessPipelineIds(ids));
+}
@@ -0,0 +1,506 @@
+//! Where the frame time goes.
+//!
+//! `P` toggles profiling, and `--profile [TRACE]` starts with it on. While it's on, every pass
+//! of `ApplierSubgraph` writes GPU timestamps around itself, when the device supports
+//! `TIMESTAMP_QUERY`, and every `extract_*` and `prepare_*` system gets timed on the CPU. Both
+//! end up in the `DiagnosticsStore` as `applier/gpu/<pass>` and `applier/cpu/<system>`, in
+//! milliseconds, and their averages get logged when profiling is turned off. Given a `TRACE`
+//! file, every span also gets written there as a Chrome trace when the app exits, to open in
+//! `chrome://tracing` or Perfetto.
+//!
+//! A frame's timestamps get read back a frame or two after it was submitted, and no passes
+//! get timed while that readback is in flight, so the GPU times skip a few frames.
+
+use std::{
+    borrow::Cow,
+    fs,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+
+use bevy::{
+    diagnostic::{Diagnostic, DiagnosticMeasurement, DiagnosticPath, DiagnosticsStore},
+    ecs::system::{Adapt, AdapterSystem, SystemIn},
+    prelude::*,
+    render::{
+        render_resource::Buffer,
+        renderer::{RenderDevice, RenderQueue},
+        Render, RenderApp, RenderSet,
+    },
+    utils::HashMap,
+};
+use wgpu::{
+    BufferAsyncError, BufferDescriptor, BufferUsages, CommandEncoder, Features, Maintain, MapMode,
+    QuerySet, QuerySetDescriptor, QueryType, RenderPassTimestampWrites,
+};
+
+#[derive(Resource, Clone, Debug, Default)]
+pub struct ProfilerSettings {
+    pub enabled: bool,
+    /// Where the Chrome trace gets written on exit.
+    pub trace: Option<PathBuf>,
+}
+
+impl ProfilerSettings {
+    fn from_args() -> Self {
+        let mut args = std::env::args().skip_while(|arg| arg != "--profile");
+        let enabled = args.next().is_some();
+        let trace = args
+            .next()
+            .filter(|arg| !arg.starts_with("--"))
+            .map(PathBuf::from);
+        Self { enabled, trace }
+    }
+}
+
+pub struct ProfilerPlugin;
+
+impl Plugin for ProfilerPlugin {
+    fn build(&self, app: &mut App) {
+        let profiler = Profiler::default();
+        app.insert_resource(ProfilerSettings::from_args())
+            .insert_resource(profiler.clone())
+            .init_resource::<ChromeTrace>()
+            .add_systems(Update, (handle_profiler_input, collect_spans).chain())
+            .add_systems(Last, write_chrome_trace);
+
+        if let Some(render_app) = app.get_sub_app_mut(RenderApp) {
+            render_app.insert_resource(profiler).add_systems(
+                Render,
+                (
+                    read_gpu_timestamps.in_set(RenderSet::PrepareResources),
+                    map_gpu_timestamps.in_set(RenderSet::Cleanup),
+                ),
+            );
+        }
+    }
+
+    fn finish(&self, app: &mut App) {
+        if let Some(render_app) = app.get_sub_app_mut(RenderApp) {
+            render_app.init_resource::<GpuTimestamps>();
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Track {
+    Cpu,
+    Gpu,
+}
+
+impl Track {
+    fn name(self) -> &'static str {
+        match self {
+            Track::Cpu => "cpu",
+            Track::Gpu => "gpu",
+        }
+    }
+}
+
+struct Span {
+    track: Track,
+    name: Cow<'static, str>,
+    /// The thread on the CPU track, always 0 on the GPU one.
+    thread: u64,
+    /// Since the profiler was created.
+    start: Duration,
+    duration: Duration,
+}
+
+struct ProfilerState {
+    enabled: AtomicBool,
+    epoch: Instant,
+    spans: Mutex<Vec<Span>>,
+}
+
+/// The same profiler in both worlds. The render world records spans into it, the main world
+/// collects them once a frame.
+#[derive(Resource, Clone)]
+pub struct Profiler(Arc<ProfilerState>);
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self(Arc::new(ProfilerState {
+            enabled: AtomicBool::new(false),
+            epoch: Instant::now(),
+            spans: Mutex::default(),
+        }))
+    }
+}
+
+impl Profiler {
+    pub fn enabled(&self) -> bool {
+        self.0.enabled.load(Ordering::Relaxed)
+    }
+
+    fn record(&self, span: Span) {
+        self.0
+            .spans
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(span);
+    }
+
+    /// `system`, timed under its own name while profiling.
+    pub fn timed<M>(&self, system: impl IntoSystem<(), (), M>) -> impl System<In = (), Out = ()> {
+        let system = IntoSystem::into_system(system);
+        let name = system.name();
+        let short_name = name.rsplit("::").next().unwrap_or(&name).to_owned();
+        let timed = Timed {
+            profiler: self.clone(),
+            name: short_name.into(),
+        };
+        AdapterSystem::new(timed, system, name)
+    }
+}
+
+/// Small ids for the threads systems run on, for the trace.
+fn thread_index() -> u64 {
+    static NEXT_INDEX: AtomicU64 = AtomicU64::new(1);
+    thread_local! {
+        static INDEX: u64 = NEXT_INDEX.fetch_add(1, Ordering::Relaxed);
+    }
+    INDEX.with(|index| *index)
+}
+
+struct Timed {
+    profiler: Profiler,
+    name: Cow<'static, str>,
+}
+
+impl<S: System<In = (), Out = ()>> Adapt<S> for Timed {
+    type In = ();
+    type Out = ();
+
+    fn adapt(&mut self, input: (), run_system: impl FnOnce(SystemIn<'_, S>)) {
+        if !self.profiler.enabled() {
+            return run_system(input);
+        }
+        let start = Instant::now();
+        run_system(input);
+        self.profiler.record(Span {
+            track: Track::Cpu,
+            name: self.name.clone(),
+            thread: thread_index(),
+            start: start - self.profiler.0.epoch,
+            duration: start.elapsed(),
+        });
+    }
+}
+
+fn handle_profiler_input(
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    diagnostics: Res<DiagnosticsStore>,
+    profiler: Res<Profiler>,
+    mut settings: ResMut<ProfilerSettings>,
+) {
+    if keyboard_input.just_pressed(KeyCode::KeyP) {
+        settings.enabled = !settings.enabled;
+        if !settings.enabled {
+            log_averages(&diagnostics);
+        }
+    }
+    profiler
+        .0
+        .enabled
+        .store(settings.enabled, Ordering::Relaxed);
+}
+
+/// Slowest first.
+fn log_averages(diagnostics: &DiagnosticsStore) {
+    let mut averages: Vec<_> = diagnostics
+        .iter()
+        .filter(|diagnostic| {
+            let path = diagnostic.path().as_str();
+            path.starts_with("applier/cpu/") || path.starts_with("applier/gpu/")
+        })
+        .filter_map(|diagnostic| Some((diagnostic.path().as_str(), diagnostic.average()?)))
+        .collect();
+    averages.sort_unstable_by(|(_, a), (_, b)| b.total_cmp(a));
+    for (path, average) in averages {
+        info!("{path}: {average:.3} ms");
+    }
+}
+
+/// Adds up the spans recorded since last frame by name, so a pass that ran once per shadow
+/// map layer or per window counts as one measurement.
+fn collect_spans(
+    profiler: Res<Profiler>,
+    settings: Res<ProfilerSettings>,
+    mut diagnostics: ResMut<DiagnosticsStore>,
+    mut trace: ResMut<ChromeTrace>,
+) {
+    let spans = std::mem::take(
+        &mut *profiler
+            .0
+            .spans
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner),
+    );
+    let mut totals: HashMap<(Track, &str), Duration> = HashMap::default();
+    for span in &spans {
+        *totals.entry((span.track, &span.name)).or_default() += span.duration;
+    }
+
+    let time = Instant::now();
+    for ((track, name), total) in totals {
+        let path = DiagnosticPath::new(format!("applier/{}/{name}", track.name()));
+        if diagnostics.get(&path).is_none() {
+            diagnostics.add(Diagnostic::new(path.clone()).with_suffix("ms"));
+        }
+        if let Some(diagnostic) = diagnostics.get_mut(&path) {
+            diagnostic.add_measurement(DiagnosticMeasurement {
+                time,
+                value: total.as_secs_f64() * 1000.0,
+            });
+        }
+    }
+
+    if settings.trace.is_some() {
+        trace.0.extend(spans);
+    }
+}
+
+/// Every span recorded while profiling, when there's a trace to write.
+#[derive(Resource, Default)]
+struct ChromeTrace(Vec<Span>);
+
+fn write_chrome_trace(
+    mut exit: EventReader<AppExit>,
+    settings: Res<ProfilerSettings>,
+    trace: Res<ChromeTrace>,
+) {
+    if exit.read().next().is_none() {
+        return;
+    }
+    let Some(path) = &settings.trace else {
+        return;
+    };
+
+    // Each track is a process of its own, so the GPU passes get a row apart from the threads.
+    let process_names = [Track::Cpu, Track::Gpu].map(|track| {
+        format!(
+            r#"{{"name":"process_name","ph":"M","pid":{},"args":{{"name":"{}"}}}}"#,
+            track as u32,
+            track.name()
+        )
+    });
+    let spans = trace.0.iter().map(|span| {
+        format!(
+            r#"{{"name":{:?},"cat":"{}","ph":"X","pid":{},"tid":{},"ts":{:.3},"dur":{:.3}}}"#,
+            span.name,
+            span.track.name(),
+            span.track as u32,
+            span.thread,
+            span.start.as_secs_f64() * 1_000_000.0,
+            span.duration.as_secs_f64() * 1_000_000.0,
+        )
+    });
+    let events: Vec<String> = process_names.into_iter().chain(spans).collect();
+    let json = format!("{{\"traceEvents\":[\n{}\n]}}\n", events.join(",\n"));
+    match fs::write(path, json) {
+        Ok(()) => info!("wrote {} spans to {}", trace.0.len(), path.display()),
+        Err(error) => error!("couldn't write the trace to {}: {error}", path.display()),
+    }
+}
+
+/// Room for this many timed passes a frame.
+const MAX_TIMED_PASSES: u32 = 32;
+const TIMESTAMP_SIZE: u64 = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ReadbackState {
+    /// Passes are getting timed.
+    Recording,
+    /// The timestamps got copied into the readback buffer.
+    Resolved,
+    /// Waiting for the readback buffer to map.
+    Mapping,
+}
+
+struct TimestampFrame {
+    state: ReadbackState,
+    /// The label of the pass each pair of timestamps belongs to.
+    passes: Vec<&'static str>,
+    /// When the passes were resolved, to line the GPU track up with the CPU one in the trace.
+    resolved_at: Duration,
+}
+
+struct TimestampQueries {
+    query_set: QuerySet,
+    resolve_buffer: Buffer,
+    readback_buffer: Buffer,
+    /// Nanoseconds per tick.
+    period: f32,
+    frame: Mutex<TimestampFrame>,
+    mapped: Arc<Mutex<Option<Result<(), BufferAsyncError>>>>,
+}
+
+/// The timestamp queries of the applier passes, `None` when the device doesn't support them.
+#[derive(Resource)]
+pub struct GpuTimestamps {
+    profiler: Profiler,
+    queries: Option<TimestampQueries>,
+}
+
+impl FromWorld for GpuTimestamps {
+    fn from_world(world: &mut World) -> Self {
+        let profiler = world.resource::<Profiler>().clone();
+        let render_device = world.resource::<RenderDevice>();
+        if !render_device.features().contains(Features::TIMESTAMP_QUERY) {
+            info!("timestamp queries aren't supported, only CPU times get profiled");
+            return Self {
+                profiler,
+                queries: None,
+            };
+        }
+
+        let size = 2 * MAX_TIMED_PASSES as u64 * TIMESTAMP_SIZE;
+        let queries = TimestampQueries {
+            query_set: render_device
+                .wgpu_device()
+                .create_query_set(&QuerySetDescriptor {
+                    label: Some("applier_timestamps"),
+                    ty: QueryType::Timestamp,
+                    count: 2 * MAX_TIMED_PASSES,
+                }),
+            resolve_buffer: render_device.create_buffer(&BufferDescriptor {
+                label: Some("applier_timestamp_resolve_buffer"),
+                size,
+                usage: BufferUsages::QUERY_RESOLVE | BufferUsages::COPY_SRC,
+                mapped_at_creation: false,
+            }),
+            readback_buffer: render_device.create_buffer(&BufferDescriptor {
+                label: Some("applier_timestamp_readback_buffer"),
+                size,
+                usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+                mapped_at_creation: false,
+            }),
+            period: world.resource::<RenderQueue>().get_timestamp_period(),
+            frame: Mutex::new(TimestampFrame {
+                state: ReadbackState::Recording,
+                passes: Vec::new(),
+                resolved_at: Duration::ZERO,
+            }),
+            mapped: Arc::default(),
+        };
+        Self {
+            profiler,
+            queries: Some(queries),
+        }
+    }
+}
+
+impl GpuTimestamps {
+    /// Timestamps for the beginning and the end of a pass, while profiling and when there's
+    /// room for them this frame.
+    pub fn pass_writes(&self, label: &'static str) -> Option<RenderPassTimestampWrites<'_>> {
+        let queries = self.queries.as_ref().filter(|_| self.profiler.enabled())?;
+        let mut frame = queries.frame.lock().unwrap_or_else(PoisonError::into_inner);
+        if frame.state != ReadbackState::Recording || frame.passes.len() as u32 == MAX_TIMED_PASSES
+        {
+            return None;
+        }
+        let index = frame.passes.len() as u32;
+        frame.passes.push(label);
+        Some(RenderPassTimestampWrites {
+            query_set: &queries.query_set,
+            beginning_of_pass_write_index: Some(2 * index),
+            end_of_pass_write_index: Some(2 * index + 1),
+        })
+    }
+
+    /// Copies the timestamps written this frame into the readback buffer. Has to come after
+    /// every timed pass.
+    pub fn resolve(&self, command_encoder: &mut CommandEncoder) {
+        let Some(queries) = &self.queries else {
+            return;
+        };
+        let mut frame = queries.frame.lock().unwrap_or_else(PoisonError::into_inner);
+        if frame.state != ReadbackState::Recording || frame.passes.is_empty() {
+            return;
+        }
+        let count = 2 * frame.passes.len() as u32;
+        command_encoder.resolve_query_set(&queries.query_set, 0..count, &queries.resolve_buffer, 0);
+        command_encoder.copy_buffer_to_buffer(
+            &queries.resolve_buffer,
+            0,
+            &queries.readback_buffer,
+            0,
+            count as u64 * TIMESTAMP_SIZE,
+        );
+        frame.state = ReadbackState::Resolved;
+        frame.resolved_at = self.profiler.0.epoch.elapsed();
+    }
+}
+
+/// Runs once the frame got submitted.
+fn map_gpu_timestamps(timestamps: Res<GpuTimestamps>) {
+    let Some(queries) = &timestamps.queries else {
+        return;
+    };
+    let mut frame = queries.frame.lock().unwrap_or_else(PoisonError::into_inner);
+    if frame.state != ReadbackState::Resolved {
+        return;
+    }
+    frame.state = ReadbackState::Mapping;
+    let mapped = queries.mapped.clone();
+    queries
+        .readback_buffer
+        .slice(..)
+        .map_async(MapMode::Read, move |result| {
+            *mapped.lock().unwrap_or_else(PoisonError::into_inner) = Some(result);
+        });
+}
+
+fn read_gpu_timestamps(
+    timestamps: Res<GpuTimestamps>,
+    profiler: Res<Profiler>,
+    render_device: Res<RenderDevice>,
+) {
+    let Some(queries) = &timestamps.queries else {
+        return;
+    };
+    let mut frame = queries.frame.lock().unwrap_or_else(PoisonError::into_inner);
+    if frame.state != ReadbackState::Mapping {
+        return;
+    }
+    render_device.poll(Maintain::Poll);
+    let Some(result) = queries
+        .mapped
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .take()
+    else {
+        return;
+    };
+    frame.state = ReadbackState::Recording;
+    let passes = std::mem::take(&mut frame.passes);
+    if let Err(error) = result {
+        warn!("couldn't read the GPU timestamps back: {error}");
+        return;
+    }
+
+    {
+        let data = queries.readback_buffer.slice(..).get_mapped_range();
+        let ticks: &[u64] =
+            bytemuck::cast_slice(&data[..passes.len() * 2 * TIMESTAMP_SIZE as usize]);
+        let first = ticks.iter().step_by(2).copied().min().unwrap_or_default();
+        let to_duration =
+            |ticks: u64| Duration::from_nanos((ticks as f64 * queries.period as f64) as u64);
+        for (label, pass) in passes.into_iter().zip(ticks.chunks_exact(2)) {
+            profiler.record(Span {
+                track: Track::Gpu,
+                name: label.into(),
+                thread: 0,
+                start: frame.resolved_at + to_duration(pass[0].saturating_sub(first)),
+                duration: to_duration(pass[1].saturating_sub(pass[0])),
+            });
+        }
+    }
+    queries.readback_buffer.unmap();
+}
@@ -0,0 +1,394 @@
+//! Replaying the applier draws from a `wgpu::RenderBundle`.
+//!
+//! Most frames draw exactly what the frame before did: instances move by writing the instance
+//! buffer, not by drawing differently. With [`RenderBundleSettings`] enabled, a [`BundleKey`]
+//! gets put together from the phase items and the ids of the pipelines, bind groups and
+//! buffers they'd bind. When it matches the cached bundle's, the pass executes that bundle
+//! without running any draw function. When a pipeline, material, mesh or the instance buffer
+//! got replaced, or the items themselves changed, the phases get run against a
+//! [`BundleRecorder`] instead of the pass, which only writes the commands down, and those get
+//! encoded into a new bundle first.
+//!
+//! `B` toggles it, and logs the average `applier/encode_time` of the mode being left, so
+//! switching back and forth gives a before and after. Leaving bundle mode also logs the
+//! average `applier/bundle_recordings`, which stays at zero while nothing in the scene
+//! changes, after the first frame recorded the bundle.
+
+use std::{
+    ops::Range,
+    sync::{Mutex, PoisonError},
+};
+
+use bevy::{
+    diagnostic::DiagnosticsStore,
+    prelude::*,
+    render::{
+        render_asset::RenderAssets,
+        render_phase::TrackedRenderPass,
+        render_resource::{
+            BindGroup, BindGroupId, Buffer, BufferId, BufferSlice, PipelineCache, RenderPipeline,
+            RenderPipelineId,
+        },
+        renderer::RenderDevice,
+        Extract,
+    },
+    utils::HashMap,
+};
+use wgpu::{
+    IndexFormat, RenderBundle, RenderBundleDepthStencil, RenderBundleDescriptor,
+    RenderBundleEncoder, RenderBundleEncoderDescriptor, TextureFormat,
+};
+
+use super::{
+    cluster::{LightBuffer, PreparedLight},
+    hot_reload::LastGoodPipelines,
+    material::PreparedApplierMaterial,
+    material_bind_group,
+    material_definition::PreparedMaterialDefinition,
+    phase::{ApplierDrawFunctionId, DrawTarget, OpaquePhase, TransparentPhase},
+    render_diagnostics::{BundleRecordings, SkippedDraws, BUNDLE_RECORDINGS, ENCODE_TIME},
+    IndexBuffer, InstanceBuffer, PreparedCamera, VertexBuffer,
+};
+
+#[derive(Resource, Clone, Debug, Default)]
+pub struct RenderBundleSettings {
+    pub enabled: bool,
+}
+
+pub struct RenderBundlePlugin;
+
+impl Plugin for RenderBundlePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<RenderBundleSettings>()
+            .add_systems(Update, handle_render_bundle_input);
+    }
+}
+
+fn handle_render_bundle_input(
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    diagnostics: Res<DiagnosticsStore>,
+    mut settings: ResMut<RenderBundleSettings>,
+) {
+    if !keyboard_input.just_pressed(KeyCode::KeyB) {
+        return;
+    }
+    let mode = if settings.enabled {
+        "with render bundles"
+    } else {
+        "without render bundles"
+    };
+    if let Some(average) = diagnostics.get(&ENCODE_TIME).and_then(|d| d.average()) {
+        info!("applier encode time {mode}: {average:.3} ms");
+    }
+    if settings.enabled {
+        if let Some(average) = diagnostics
+            .get(&BUNDLE_RECORDINGS)
+            .and_then(|d| d.average())
+        {
+            info!(
+                "applier render bundle recorded in {:.1}% of frames",
+                average * 100.0
+            );
+        }
+    }
+    settings.enabled = !settings.enabled;
+}
+
+pub fn extract_render_bundle_settings(
+    mut commands: Commands,
+    main_settings: Extract<Res<RenderBundleSettings>>,
+) {
+    commands.insert_resource(main_settings.clone());
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum StateSlot {
+    Pipeline,
+    BindGroup(usize),
+    VertexBuffer(usize),
+    IndexBuffer,
+}
+
+/// A recorded command with ids in place of the resources, to tell whether the state it sets
+/// is already set.
+#[derive(Clone, Debug, PartialEq)]
+enum CommandKey {
+    Pipeline(RenderPipelineId),
+    BindGroup(usize, BindGroupId, Vec<u32>),
+    VertexBuffer(usize, BufferId, u64, u64),
+    IndexBuffer(BufferId, u64, u64, IndexFormat),
+    DrawIndexed(Range<u32>, i32, Range<u32>),
+}
+
+impl CommandKey {
+    fn slot(&self) -> Option<StateSlot> {
+        match self {
+            CommandKey::Pipeline(_) => Some(StateSlot::Pipeline),
+            CommandKey::BindGroup(index, ..) => Some(StateSlot::BindGroup(*index)),
+            CommandKey::VertexBuffer(slot, ..) => Some(StateSlot::VertexBuffer(*slot)),
+            CommandKey::IndexBuffer(..) => Some(StateSlot::IndexBuffer),
+            CommandKey::DrawIndexed(..) => None,
+        }
+    }
+}
+
+enum RecordedCommand<'w> {
+    Pipeline(&'w RenderPipeline),
+    BindGroup(usize, &'w BindGroup, Vec<u32>),
+    VertexBuffer(usize, BufferSlice<'w>),
+    IndexBuffer(BufferSlice<'w>, IndexFormat),
+    DrawIndexed(Range<u32>, i32, Range<u32>),
+}
+
+impl<'w> RecordedCommand<'w> {
+    fn key(&self) -> CommandKey {
+        match self {
+            RecordedCommand::Pipeline(pipeline) => CommandKey::Pipeline(pipeline.id()),
+            RecordedCommand::BindGroup(index, bind_group, offsets) => {
+                CommandKey::BindGroup(*index, bind_group.id(), offsets.clone())
+            }
+            RecordedCommand::VertexBuffer(slot, slice) => {
+                CommandKey::VertexBuffer(*slot, slice.id(), slice.offset(), slice.size())
+            }
+            RecordedCommand::IndexBuffer(slice, format) => {
+                CommandKey::IndexBuffer(slice.id(), slice.offset(), slice.size(), *format)
+            }
+            RecordedCommand::DrawIndexed(indices, base_vertex, instances) => {
+                CommandKey::DrawIndexed(indices.clone(), *base_vertex, instances.clone())
+            }
+        }
+    }
+
+    fn encode<'e>(&'e self, encoder: &mut RenderBundleEncoder<'e>) {
+        match self {
+            RecordedCommand::Pipeline(pipeline) => encoder.set_pipeline(pipeline),
+            RecordedCommand::BindGroup(index, bind_group, offsets) => {
+                encoder.set_bind_group(*index as u32, &***bind_group, offsets)
+            }
+            RecordedCommand::VertexBuffer(slot, slice) => {
+                encoder.set_vertex_buffer(*slot as u32, **slice)
+            }
+            RecordedCommand::IndexBuffer(slice, format) => {
+                encoder.set_index_buffer(**slice, *format)
+            }
+            RecordedCommand::DrawIndexed(indices, base_vertex, instances) => {
+                encoder.draw_indexed(indices.clone(), *base_vertex, instances.clone())
+            }
+        }
+    }
+}
+
+/// Writes down the commands run against it, leaving out the ones that set state that's
+/// already set, like `TrackedRenderPass` does.
+#[derive(Default)]
+pub struct BundleRecorder<'w> {
+    commands: Vec<RecordedCommand<'w>>,
+    bound: HashMap<StateSlot, CommandKey>,
+}
+
+impl<'w> BundleRecorder<'w> {
+    fn record(&mut self, command: RecordedCommand<'w>) {
+        let key = command.key();
+        if let Some(slot) = key.slot() {
+            if self.bound.get(&slot) == Some(&key) {
+                return;
+            }
+            self.bound.insert(slot, key.clone());
+        }
+        self.commands.push(command);
+    }
+}
+
+impl<'w> DrawTarget<'w> for BundleRecorder<'w> {
+    fn set_render_pipeline(&mut self, pipeline: &'w RenderPipeline) {
+        self.record(RecordedCommand::Pipeline(pipeline));
+    }
+
+    fn set_bind_group(
+        &mut self,
+        index: usize,
+        bind_group: &'w BindGroup,
+        dynamic_uniform_indices: &[u32],
+    ) {
+        self.record(RecordedCommand::BindGroup(
+            index,
+            bind_group,
+            dynamic_uniform_indices.to_vec(),
+        ));
+    }
+
+    fn set_vertex_buffer(&mut self, slot_index: usize, buffer_slice: BufferSlice<'w>) {
+        self.record(RecordedCommand::VertexBuffer(slot_index, buffer_slice));
+    }
+
+    /// `offset` is already part of `buffer_slice`, `TrackedRenderPass` only uses it for
+    /// tracking.
+    fn set_index_buffer(
+        &mut self,
+        buffer_slice: BufferSlice<'w>,
+        _offset: u64,
+        index_format: IndexFormat,
+    ) {
+        self.record(RecordedCommand::IndexBuffer(buffer_slice, index_format));
+    }
+
+    fn draw_indexed(&mut self, indices: Range<u32>, base_vertex: i32, instances: Range<u32>) {
+        self.record(RecordedCommand::DrawIndexed(
+            indices,
+            base_vertex,
+            instances,
+        ));
+    }
+}
+
+/// The attachments of the pass, which a bundle has to be encoded for.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BundleTargets {
+    pub color_formats: Vec<Option<TextureFormat>>,
+    pub depth_format: TextureFormat,
+}
+
+/// What a phase item draws with, by id.
+#[derive(Clone, Debug, PartialEq)]
+struct ItemKey {
+    draw_function: ApplierDrawFunctionId,
+    /// `None` while the item gets skipped, the same goes for `material`.
+    pipeline: Option<RenderPipelineId>,
+    material: Option<BindGroupId>,
+    instances: Range<u32>,
+}
+
+/// Everything the draw commands read, so two frames with the same key record the same
+/// commands. Putting it together only looks up every item's pipeline and material.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BundleKey {
+    targets: BundleTargets,
+    items: Vec<ItemKey>,
+    camera: Option<BindGroupId>,
+    light: Option<BindGroupId>,
+    vertex_buffer: Option<BufferId>,
+    index_buffer: Option<BufferId>,
+    instance_buffer: Option<BufferId>,
+    index_count: u32,
+    light_count: u32,
+}
+
+impl BundleKey {
+    /// `None` when a resource the items get looked up in is missing, leaving the phases to be
+    /// drawn directly and count their skips.
+    pub fn new(world: &World, targets: BundleTargets) -> Option<Self> {
+        let opaque_phase = world.get_resource::<OpaquePhase>()?;
+        let transparent_phase = world.get_resource::<TransparentPhase>()?;
+        let pipelines = world.get_resource::<LastGoodPipelines>()?;
+        let pipeline_cache = world.get_resource::<PipelineCache>()?;
+        let materials = world.get_resource::<RenderAssets<PreparedApplierMaterial>>()?;
+        let definitions = world.get_resource::<RenderAssets<PreparedMaterialDefinition>>()?;
+        let items = opaque_phase
+            .items
+            .iter()
+            .chain(&transparent_phase.items)
+            .map(|item| ItemKey {
+                draw_function: item.draw_function,
+                pipeline: pipelines
+                    .get(pipeline_cache, item.sort_key.pipeline)
+                    .map(RenderPipeline::id),
+                material: item
+                    .sort_key
+                    .material
+                    .and_then(|material| material_bind_group(materials, definitions, material))
+                    .map(BindGroup::id),
+                instances: item.instances.clone(),
+            })
+            .collect();
+        let index_buffer = world.get_resource::<IndexBuffer>();
+        Some(Self {
+            targets,
+            items,
+            camera: world
+                .get_resource::<PreparedCamera>()
+                .map(|camera| camera.bind_group.id()),
+            light: world
+                .get_resource::<PreparedLight>()
+                .map(|light| light.bind_group.id()),
+            vertex_buffer: world
+                .get_resource::<VertexBuffer>()
+                .and_then(|vertices| vertices.0.buffer())
+                .map(Buffer::id),
+            index_buffer: index_buffer
+                .and_then(|indices| indices.0.buffer())
+                .map(Buffer::id),
+            instance_buffer: world
+                .get_resource::<InstanceBuffer>()
+                .and_then(|instances| instances.buf.buffer())
+                .map(Buffer::id),
+            index_count: index_buffer.map_or(0, |indices| indices.0.len() as u32),
+            light_count: world
+                .get_resource::<LightBuffer>()
+                .map_or(0, LightBuffer::light_count),
+        })
+    }
+}
+
+struct CachedBundle {
+    key: BundleKey,
+    /// What got skipped while recording, counted again every frame the bundle gets executed.
+    skipped: SkippedDraws,
+    bundle: RenderBundle,
+}
+
+/// The bundle of the applier pass. Nodes only get a `&World`, hence the mutex.
+#[derive(Resource, Default)]
+pub struct SurfaceBundle(Mutex<Option<CachedBundle>>);
+
+impl SurfaceBundle {
+    /// Executes the cached bundle, first recording what `draw` draws into a new one when `key`
+    /// changed. Executing a bundle resets the pass's state, so nothing should be drawn after
+    /// this without setting everything again.
+    pub fn draw<'w>(
+        &self,
+        render_device: &RenderDevice,
+        render_pass: &mut TrackedRenderPass<'w>,
+        key: BundleKey,
+        skipped: &SkippedDraws,
+        recordings: &BundleRecordings,
+        draw: impl FnOnce(&mut dyn DrawTarget<'w>, &SkippedDraws),
+    ) {
+        let mut cached = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        let up_to_date = cached.as_ref().is_some_and(|cached| cached.key == key);
+        if !up_to_date {
+            debug!("encoding the applier render bundle again, its draws changed");
+            recordings.count();
+            let mut recorder = BundleRecorder::default();
+            let recording_skipped = SkippedDraws::default();
+            draw(&mut recorder, &recording_skipped);
+            let mut encoder = render_device.wgpu_device().create_render_bundle_encoder(
+                &RenderBundleEncoderDescriptor {
+                    label: Some("applier_bundle_encoder"),
+                    color_formats: &key.targets.color_formats,
+                    depth_stencil: Some(RenderBundleDepthStencil {
+                        format: key.targets.depth_format,
+                        depth_read_only: false,
+                        stencil_read_only: false,
+                    }),
+                    sample_count: 1,
+                    multiview: None,
+                },
+            );
+            for command in &recorder.commands {
+                command.encode(&mut encoder);
+            }
+            let bundle = encoder.finish(&RenderBundleDescriptor {
+                label: Some("applier_bundle"),
+            });
+            *cached = Some(CachedBundle {
+                key,
+                skipped: recording_skipped,
+                bundle,
+            });
+        }
+        if let Some(cached) = cached.as_ref() {
+            skipped.add(&cached.skipped);
+            render_pass.wgpu_pass().execute_bundles([&cached.bundle]);
+        }
+    }
+}
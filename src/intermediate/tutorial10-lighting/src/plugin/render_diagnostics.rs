@@ -0,0 +1,148 @@
+//! Counts of the draws the render graph left out instead of panicking, like when a pass runs
+//! before the buffers it reads were uploaded.
+//!
+//! Nodes only get a `&World`, so they count into atomics shared by both worlds, and
+//! `publish_skipped_draws` hands last frame's counts to Bevy's diagnostics under
+//! `applier/skipped/...`, where `LogDiagnosticsPlugin` and friends can pick them up. The CPU
+//! time the applier pass spends encoding its draws goes to `applier/encode_time` the same way,
+//! and how often the render bundle got recorded again to `applier/bundle_recordings`.
+
+use std::{
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use bevy::{
+    diagnostic::{Diagnostic, DiagnosticPath, Diagnostics, RegisterDiagnostic},
+    prelude::*,
+    render::RenderApp,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SkipReason {
+    /// A resource the pass reads hasn't been inserted yet.
+    MissingResource,
+    /// A buffer the draw needs was never uploaded, usually because there was nothing to put
+    /// in it.
+    EmptyBuffer,
+    /// The pipeline is still compiling, or failed to.
+    PipelineNotReady,
+    /// The material's bind group isn't prepared yet.
+    MaterialNotReady,
+}
+
+impl SkipReason {
+    const ALL: [SkipReason; 4] = [
+        SkipReason::MissingResource,
+        SkipReason::EmptyBuffer,
+        SkipReason::PipelineNotReady,
+        SkipReason::MaterialNotReady,
+    ];
+
+    pub const fn path(self) -> DiagnosticPath {
+        match self {
+            SkipReason::MissingResource => {
+                DiagnosticPath::const_new("applier/skipped/missing_resource")
+            }
+            SkipReason::EmptyBuffer => DiagnosticPath::const_new("applier/skipped/empty_buffer"),
+            SkipReason::PipelineNotReady => {
+                DiagnosticPath::const_new("applier/skipped/pipeline_not_ready")
+            }
+            SkipReason::MaterialNotReady => {
+                DiagnosticPath::const_new("applier/skipped/material_not_ready")
+            }
+        }
+    }
+}
+
+/// The same counters in both worlds. The render world adds to them, the main world reads and
+/// resets them once a frame.
+#[derive(Resource, Clone, Default)]
+pub struct SkippedDraws(Arc<[AtomicU32; SkipReason::ALL.len()]>);
+
+impl SkippedDraws {
+    pub fn count(&self, reason: SkipReason) {
+        self.0[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Adds what `other` counted, for draws that got skipped once and replayed since.
+    pub fn add(&self, other: &SkippedDraws) {
+        for reason in SkipReason::ALL {
+            let count = other.0[reason as usize].load(Ordering::Relaxed);
+            self.0[reason as usize].fetch_add(count, Ordering::Relaxed);
+        }
+    }
+}
+
+/// In milliseconds per frame.
+pub const ENCODE_TIME: DiagnosticPath = DiagnosticPath::const_new("applier/encode_time");
+
+/// Nanoseconds, shared between the worlds like [`SkippedDraws`].
+#[derive(Resource, Clone, Default)]
+pub struct EncodeTime(Arc<AtomicU64>);
+
+impl EncodeTime {
+    pub fn add(&self, elapsed: Duration) {
+        self.0
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Times per frame the applier render bundle got recorded instead of replayed.
+pub const BUNDLE_RECORDINGS: DiagnosticPath =
+    DiagnosticPath::const_new("applier/bundle_recordings");
+
+/// Shared between the worlds like [`SkippedDraws`].
+#[derive(Resource, Clone, Default)]
+pub struct BundleRecordings(Arc<AtomicU32>);
+
+impl BundleRecordings {
+    pub fn count(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub struct RenderDiagnosticsPlugin;
+
+impl Plugin for RenderDiagnosticsPlugin {
+    fn build(&self, app: &mut App) {
+        for reason in SkipReason::ALL {
+            app.register_diagnostic(Diagnostic::new(reason.path()));
+        }
+        app.register_diagnostic(Diagnostic::new(ENCODE_TIME).with_suffix("ms"))
+            .register_diagnostic(Diagnostic::new(BUNDLE_RECORDINGS));
+        let skipped = SkippedDraws::default();
+        let encode_time = EncodeTime::default();
+        let bundle_recordings = BundleRecordings::default();
+        app.insert_resource(skipped.clone())
+            .insert_resource(encode_time.clone())
+            .insert_resource(bundle_recordings.clone())
+            .add_systems(Update, publish_render_diagnostics);
+
+        if let Some(render_app) = app.get_sub_app_mut(RenderApp) {
+            render_app
+                .insert_resource(skipped)
+                .insert_resource(encode_time)
+                .insert_resource(bundle_recordings);
+        }
+    }
+}
+
+fn publish_render_diagnostics(
+    skipped: Res<SkippedDraws>,
+    encode_time: Res<EncodeTime>,
+    bundle_recordings: Res<BundleRecordings>,
+    mut diagnostics: Diagnostics,
+) {
+    for reason in SkipReason::ALL {
+        let count = skipped.0[reason as usize].swap(0, Ordering::Relaxed);
+        diagnostics.add_measurement(&reason.path(), || count as f64);
+    }
+    let nanos = encode_time.0.swap(0, Ordering::Relaxed);
+    diagnostics.add_measurement(&ENCODE_TIME, || nanos as f64 / 1_000_000.0);
+    let recordings = bundle_recordings.0.swap(0, Ordering::Relaxed);
+    diagnostics.add_measurement(&BUNDLE_RECORDINGS, || recordings as f64);
+}
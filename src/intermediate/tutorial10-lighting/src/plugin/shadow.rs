@@ -0,0 +1,550 @@
+//! Shadow maps for directional and spot lights.
+//!
+//! Every shadow casting light gets one or more layers in a single depth array texture. Spot
+//! lights render one perspective view, directional lights get `cascade_count` orthographic
+//! views that each cover a slice of the camera frustum. `ShadowPassNode` fills the layers before
+//! the main pass, which samples them with a comparison sampler and a small PCF kernel.
+
+use std::ops::Range;
+
+use bevy::{
+    asset::Handle,
+    prelude::*,
+    render::{
+        render_resource::{
+            binding_types::uniform_buffer, BindGroup, BindGroupLayout, BindGroupLayoutEntries,
+            CachedRenderPipelineId, DynamicUniformBuffer, FragmentState, PipelineCache,
+            RenderPipelineDescriptor, Sampler, Shader, ShaderDefVal, ShaderStages, ShaderType,
+            SpecializedRenderPipeline, SpecializedRenderPipelines, TextureId, TextureView,
+            UniformBuffer, VertexState,
+        },
+        renderer::{RenderDevice, RenderQueue},
+        texture::TextureCache,
+        Extract,
+    },
+};
+use cgmath::{
+    ortho, perspective, Deg, EuclideanSpace, InnerSpace, Matrix4, MetricSpace, Point3, Rad,
+    SquareMatrix, Transform, Vector3,
+};
+use wgpu::{
+    AddressMode, CompareFunction, DepthBiasState, DepthStencilState, Extent3d, FilterMode,
+    FrontFace, MultisampleState, PolygonMode, PrimitiveState, PrimitiveTopology, SamplerDescriptor,
+    StencilState, TextureDescriptor, TextureDimension, TextureFormat, TextureUsages,
+    TextureViewDescriptor,
+};
+
+use super::{
+    bind_group_tracker::{BindGroupTracker, TrackedEntries},
+    camera::{self, OPENGL_TO_WGPU_MATRIX},
+    cluster::ExtractedLights,
+    light::{LightKind, ShadowSettings},
+    mesh::Vertex,
+    outline::OutlineCoverage,
+    ApplierDraws, InstanceRaw, Instances, MaterialId,
+};
+
+pub const SHADOW_SHADER_HANDLE: Handle<Shader> =
+    Handle::weak_from_u128(93217430960925367451062398453106712653);
+
+pub const MAX_SHADOW_LAYERS: usize = 8;
+pub const MAX_CASCADES: usize = 4;
+
+/// How far behind a directional cascade we still pick up shadow casters.
+const CASCADE_CASTER_MARGIN: f32 = 20.0;
+const SPOT_SHADOW_NEAR: f32 = 0.05;
+
+#[derive(Debug, Clone, ShaderType)]
+pub struct ShadowViewUniform {
+    view_proj: Mat4,
+}
+
+#[derive(Debug, Clone, ShaderType)]
+pub struct GpuShadows {
+    view_projections: [Mat4; MAX_SHADOW_LAYERS],
+    // View space depth at which each cascade ends.
+    cascade_splits: Vec4,
+    cascade_count: u32,
+    texel_size: f32,
+    depth_bias: f32,
+    normal_bias: f32,
+}
+
+/// The views rendered by the shadow pass this frame, one per shadow map layer.
+#[derive(Resource)]
+pub struct ShadowViews {
+    buf: DynamicUniformBuffer<ShadowViewUniform>,
+    pub offsets: Vec<u32>,
+    /// First shadow map layer of each extracted light, in extraction order.
+    pub light_layers: Vec<Option<u32>>,
+    uniform: UniformBuffer<GpuShadows>,
+    sampler: Sampler,
+}
+
+impl FromWorld for ShadowViews {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let sampler = render_device.create_sampler(&SamplerDescriptor {
+            label: Some("shadow_sampler"),
+            address_mode_u: AddressMode::ClampToEdge,
+            address_mode_v: AddressMode::ClampToEdge,
+            address_mode_w: AddressMode::ClampToEdge,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            mipmap_filter: FilterMode::Nearest,
+            compare: Some(CompareFunction::LessEqual),
+            ..Default::default()
+        });
+
+        Self {
+            buf: DynamicUniformBuffer::default(),
+            offsets: Vec::new(),
+            light_layers: Vec::new(),
+            uniform: UniformBuffer::from(GpuShadows {
+                view_projections: [Mat4::IDENTITY; MAX_SHADOW_LAYERS],
+                cascade_splits: Vec4::ZERO,
+                cascade_count: 0,
+                texel_size: 0.0,
+                depth_bias: 0.0,
+                normal_bias: 0.0,
+            }),
+            sampler,
+        }
+    }
+}
+
+impl ShadowViews {
+    pub fn sampler(&self) -> &Sampler {
+        &self.sampler
+    }
+
+    pub fn uniform(&self) -> &UniformBuffer<GpuShadows> {
+        &self.uniform
+    }
+
+    /// The view buffer grows with the number of shadow casters, so this gets rebuilt whenever
+    /// it does. `None` until the buffer has been written.
+    pub fn bind_group(
+        &self,
+        render_device: &RenderDevice,
+        bind_groups: &mut BindGroupTracker,
+    ) -> Option<BindGroup> {
+        let entries = TrackedEntries::single(&self.buf)?;
+        Some(
+            bind_groups.get_or_create("Shadow view bind group", entries, |entries| {
+                let layout = Self::bind_group_layout(render_device);
+                render_device.create_bind_group("Shadow view bind group", &layout, entries)
+            }),
+        )
+    }
+
+    pub fn bind_group_layout(render_device: &RenderDevice) -> BindGroupLayout {
+        render_device.create_bind_group_layout(
+            "Shadow view bind group layout",
+            &BindGroupLayoutEntries::single(
+                ShaderStages::VERTEX,
+                uniform_buffer::<ShadowViewUniform>(true),
+            ),
+        )
+    }
+}
+
+#[derive(Resource)]
+pub struct PreparedShadowViews {
+    pub bind_group: BindGroup,
+}
+
+/// The depth array every shadow casting light renders into.
+#[derive(Resource)]
+pub struct ShadowMap {
+    /// Of the texture the views were made of.
+    texture: TextureId,
+    /// All layers, for sampling in the main pass.
+    pub array_view: TextureView,
+    /// One view per layer, for rendering in the shadow pass.
+    pub layer_views: Vec<TextureView>,
+}
+
+pub fn extract_shadow_settings(
+    mut commands: Commands,
+    main_settings: Extract<Res<ShadowSettings>>,
+) {
+    commands.insert_resource(main_settings.clone());
+}
+
+fn up_vector(direction: Vector3<f32>) -> Vector3<f32> {
+    if direction.y.abs() > 0.99 {
+        Vector3::unit_x()
+    } else {
+        Vector3::unit_y()
+    }
+}
+
+fn to_mat4(matrix: Matrix4<f32>) -> Mat4 {
+    let columns: [[f32; 4]; 4] = matrix.into();
+    Mat4::from_cols_array_2d(&columns)
+}
+
+/// Splits `[znear, max_distance]` into cascades, returning each cascade's far depth.
+fn cascade_splits(camera: &camera::Camera, settings: &ShadowSettings, count: usize) -> Vec<f32> {
+    let near = camera.znear;
+    let far = settings.max_distance.min(camera.zfar);
+    (1..=count)
+        .map(|i| {
+            let t = i as f32 / count as f32;
+            let logarithmic = near * (far / near).powf(t);
+            let uniform = near + (far - near) * t;
+            settings.cascade_split_lambda * logarithmic
+                + (1.0 - settings.cascade_split_lambda) * uniform
+        })
+        .collect()
+}
+
+fn cascade_view_projection(
+    camera: &camera::Camera,
+    direction: Vector3<f32>,
+    near: f32,
+    far: f32,
+    map_size: u32,
+) -> Matrix4<f32> {
+    let view = Matrix4::look_at_rh(camera.eye, camera.target, camera.up);
+    let inverse_view = view.invert().unwrap_or_else(Matrix4::identity);
+    let tan_half_fovy = Rad::from(Deg(camera.fovy * 0.5)).0.tan();
+    let tan_half_fovx = tan_half_fovy * camera.aspect;
+
+    let mut corners = Vec::with_capacity(8);
+    for depth in [near, far] {
+        for (sx, sy) in [(-1.0, -1.0), (1.0, -1.0), (-1.0, 1.0), (1.0, 1.0)] {
+            corners.push(inverse_view.transform_point(Point3::new(
+                sx * tan_half_fovx * depth,
+                sy * tan_half_fovy * depth,
+                -depth,
+            )));
+        }
+    }
+    let center = Point3::centroid(&corners);
+    // A bounding sphere keeps the projection size constant as the camera turns.
+    let radius = corners
+        .iter()
+        .map(|corner| corner.distance(center))
+        .fold(0.0, f32::max)
+        .ceil();
+
+    // Snap the center to whole shadow map texels so the shadows don't shimmer when moving.
+    let direction = direction.normalize();
+    let up = up_vector(direction);
+    let light_rotation = Matrix4::look_at_rh(Point3::origin(), Point3::from_vec(direction), up);
+    let texel = 2.0 * radius / map_size as f32;
+    let mut light_center = light_rotation.transform_point(center);
+    light_center.x = (light_center.x / texel).floor() * texel;
+    light_center.y = (light_center.y / texel).floor() * texel;
+    let center = light_rotation
+        .invert()
+        .unwrap_or_else(Matrix4::identity)
+        .transform_point(light_center);
+
+    let eye = center - direction * (radius + CASCADE_CASTER_MARGIN);
+    let light_view = Matrix4::look_at_rh(eye, center, up);
+    let projection = ortho(
+        -radius,
+        radius,
+        -radius,
+        radius,
+        0.0,
+        2.0 * radius + CASCADE_CASTER_MARGIN,
+    );
+    OPENGL_TO_WGPU_MATRIX * projection * light_view
+}
+
+fn spot_view_projection(
+    position: Vector3<f32>,
+    direction: Vector3<f32>,
+    outer_angle: Deg<f32>,
+    range: f32,
+) -> Matrix4<f32> {
+    let direction = direction.normalize();
+    let eye = Point3::from_vec(position);
+    let view = Matrix4::look_at_rh(eye, eye + direction, up_vector(direction));
+    let projection = perspective(outer_angle * 2.0, 1.0, SPOT_SHADOW_NEAR, range);
+    OPENGL_TO_WGPU_MATRIX * projection * view
+}
+
+pub fn prepare_shadows(
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    mut commands: Commands,
+    mut texture_cache: ResMut<TextureCache>,
+    mut shadow_views: ResMut<ShadowViews>,
+    lights: Res<ExtractedLights>,
+    camera: Res<camera::Camera>,
+    settings: Res<ShadowSettings>,
+    previous: Option<Res<ShadowMap>>,
+) {
+    let cascade_count = (settings.cascade_count as usize).clamp(1, MAX_CASCADES);
+    let splits = cascade_splits(&camera, &settings, cascade_count);
+
+    let mut view_projections = Vec::new();
+    let mut light_layers = Vec::with_capacity(lights.0.len());
+    for light in lights.0.iter() {
+        let first_layer = view_projections.len();
+        match light.kind {
+            LightKind::Directional { direction }
+                if light.shadows && first_layer + cascade_count <= MAX_SHADOW_LAYERS =>
+            {
+                let mut near = camera.znear;
+                for &far in &splits {
+                    view_projections.push(cascade_view_projection(
+                        &camera,
+                        direction,
+                        near,
+                        far,
+                        settings.map_size,
+                    ));
+                    near = far;
+                }
+            }
+            LightKind::Spot {
+                direction,
+                outer_angle,
+                ..
+            } if light.shadows && first_layer < MAX_SHADOW_LAYERS => {
+                view_projections.push(spot_view_projection(
+                    light.position,
+                    direction,
+                    outer_angle,
+                    light.range,
+                ));
+            }
+            _ => {
+                light_layers.push(None);
+                continue;
+            }
+        }
+        light_layers.push(Some(first_layer as u32));
+    }
+
+    shadow_views.buf.clear();
+    shadow_views.offsets = view_projections
+        .iter()
+        .map(|view_proj| {
+            shadow_views.buf.push(&ShadowViewUniform {
+                view_proj: to_mat4(*view_proj),
+            })
+        })
+        .collect();
+    shadow_views.light_layers = light_layers;
+
+    let mut gpu_view_projections = [Mat4::IDENTITY; MAX_SHADOW_LAYERS];
+    for (slot, view_proj) in gpu_view_projections.iter_mut().zip(&view_projections) {
+        *slot = to_mat4(*view_proj);
+    }
+    let mut cascade_splits = Vec4::ZERO;
+    for (i, split) in splits.iter().enumerate() {
+        cascade_splits[i] = *split;
+    }
+    shadow_views.uniform.set(GpuShadows {
+        view_projections: gpu_view_projections,
+        cascade_splits,
+        cascade_count: cascade_count as u32,
+        texel_size: 1.0 / settings.map_size as f32,
+        depth_bias: settings.depth_bias,
+        normal_bias: settings.normal_bias,
+    });
+
+    shadow_views.buf.write_buffer(&render_device, &render_queue);
+    shadow_views
+        .uniform
+        .write_buffer(&render_device, &render_queue);
+
+    // The main pass always binds the array, so keep at least one layer around.
+    let layer_count = view_projections.len().max(1) as u32;
+    let shadow_texture = texture_cache.get(
+        &render_device,
+        TextureDescriptor {
+            label: Some("shadow_map"),
+            size: Extent3d {
+                width: settings.map_size,
+                height: settings.map_size,
+                depth_or_array_layers: layer_count,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::Depth32Float,
+            usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        },
+    );
+    // Keeps the views while the texture stays the same, so the light bind group sampling them
+    // doesn't get created again every frame.
+    if previous.is_some_and(|previous| previous.texture == shadow_texture.texture.id()) {
+        return;
+    }
+    let array_view = shadow_texture.texture.create_view(&TextureViewDescriptor {
+        label: Some("shadow_map_array_view"),
+        dimension: Some(wgpu::TextureViewDimension::D2Array),
+        ..Default::default()
+    });
+    let layer_views = (0..layer_count)
+        .map(|layer| {
+            shadow_texture.texture.create_view(&TextureViewDescriptor {
+                label: Some("shadow_map_layer_view"),
+                dimension: Some(wgpu::TextureViewDimension::D2),
+                base_array_layer: layer,
+                array_layer_count: Some(1),
+                ..Default::default()
+            })
+        })
+        .collect();
+
+    commands.insert_resource(ShadowMap {
+        texture: shadow_texture.texture.id(),
+        array_view,
+        layer_views,
+    });
+}
+
+pub fn prepare_shadow_bind_groups(
+    mut commands: Commands,
+    render_device: Res<RenderDevice>,
+    shadow_views: Res<ShadowViews>,
+    mut bind_groups: ResMut<BindGroupTracker>,
+) {
+    match shadow_views.bind_group(&render_device, &mut bind_groups) {
+        Some(bind_group) => commands.insert_resource(PreparedShadowViews { bind_group }),
+        None => commands.remove_resource::<PreparedShadowViews>(),
+    }
+}
+
+/// Depth only pipeline used to render the instances from each light's point of view.
+/// Materials that discard texels get a fragment stage that samples their
+/// [`OutlineCoverage`] and discards the same texels, so cutouts let the light through.
+#[derive(Resource)]
+pub struct ShadowPipeline {
+    view_layout: BindGroupLayout,
+    coverage_layout: BindGroupLayout,
+}
+
+impl FromWorld for ShadowPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+
+        Self {
+            view_layout: ShadowViews::bind_group_layout(render_device),
+            coverage_layout: OutlineCoverage::bind_group_layout(render_device),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ShadowPipelineKey {
+    /// For masked and blended materials. Opaque ones stay depth only.
+    pub alpha_tested: bool,
+}
+
+impl SpecializedRenderPipeline for ShadowPipeline {
+    type Key = ShadowPipelineKey;
+
+    fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
+        let shader_defs: Vec<ShaderDefVal> = if key.alpha_tested {
+            vec!["ALPHA_TEST".into()]
+        } else {
+            vec![]
+        };
+        let mut layout = vec![self.view_layout.clone()];
+        if key.alpha_tested {
+            layout.push(self.coverage_layout.clone());
+        }
+        RenderPipelineDescriptor {
+            vertex: VertexState {
+                shader: SHADOW_SHADER_HANDLE,
+                entry_point: "vs_main".into(),
+                shader_defs: shader_defs.clone(),
+                buffers: vec![Vertex::desc(), InstanceRaw::desc()],
+            },
+            fragment: key.alpha_tested.then(|| FragmentState {
+                shader: SHADOW_SHADER_HANDLE,
+                shader_defs,
+                entry_point: "fs_main".into(),
+                targets: vec![],
+            }),
+            layout,
+            push_constant_ranges: Vec::new(),
+            primitive: PrimitiveState {
+                front_face: FrontFace::Ccw,
+                // The instances are single sided, so both faces have to cast.
+                cull_mode: None,
+                unclipped_depth: false,
+                polygon_mode: PolygonMode::Fill,
+                conservative: false,
+                topology: PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+            },
+            depth_stencil: Some(DepthStencilState {
+                format: TextureFormat::Depth32Float,
+                depth_write_enabled: true,
+                depth_compare: CompareFunction::Less,
+                stencil: StencilState::default(),
+                bias: DepthBiasState {
+                    constant: 2,
+                    slope_scale: 2.0,
+                    clamp: 0.0,
+                },
+            }),
+            multisample: MultisampleState {
+                count: 1,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+            label: Some("shadow_pipeline".into()),
+            zero_initialize_workgroup_memory: true,
+        }
+    }
+}
+
+/// Both specializations of [`ShadowPipeline`].
+#[derive(Resource)]
+pub struct ShadowPipelineIds {
+    pub opaque: CachedRenderPipelineId,
+    pub alpha_tested: CachedRenderPipelineId,
+}
+
+pub fn queue_shadow_pipelines(
+    mut commands: Commands,
+    pipeline_cache: Res<PipelineCache>,
+    pipeline: Res<ShadowPipeline>,
+    mut pipelines: ResMut<SpecializedRenderPipelines<ShadowPipeline>>,
+) {
+    let mut specialize = |alpha_tested| {
+        pipelines.specialize(
+            &pipeline_cache,
+            &pipeline,
+            ShadowPipelineKey { alpha_tested },
+        )
+    };
+    commands.insert_resource(ShadowPipelineIds {
+        opaque: specialize(false),
+        alpha_tested: specialize(true),
+    });
+}
+
+/// Runs of the instance buffer sharing a material, so the shadow pass can switch between
+/// the opaque and the alpha tested pipeline and bind each material's coverage.
+#[derive(Resource, Default)]
+pub struct ShadowBatches(pub Vec<(MaterialId, Range<u32>)>);
+
+pub fn prepare_shadow_batches(
+    instances: Res<Instances>,
+    draws: Res<ApplierDraws>,
+    mut batches: ResMut<ShadowBatches>,
+) {
+    batches.0.clear();
+    for (index, &instance) in draws.order.iter().enumerate() {
+        let index = index as u32;
+        let material = instances.0[instance as usize].material.id();
+        match batches.0.last_mut() {
+            Some((batch_material, range)) if *batch_material == material => range.end += 1,
+            _ => batches.0.push((material, index..index + 1)),
+        }
+    }
+}
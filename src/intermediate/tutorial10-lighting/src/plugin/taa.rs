@@ -0,0 +1,368 @@
+//! Temporal anti-aliasing.
+//!
+//! Every frame the projection gets shifted by a different sub-pixel offset, so over a few frames
+//! each pixel sees several positions inside it. The applier pass also writes how far every
+//! surface moved since the last frame, which lets `TaaNode` find where a pixel was in the
+//! accumulated history and blend the new frame into it.
+
+use bevy::{
+    asset::Handle,
+    prelude::*,
+    render::{
+        render_resource::{
+            binding_types::{sampler, texture_2d, uniform_buffer},
+            BindGroup, BindGroupLayout, BindGroupLayoutEntries, CachedRenderPipelineId,
+            PipelineCache, RenderPipelineDescriptor, Sampler, SamplerBindingType, Shader,
+            ShaderStages, ShaderType, SpecializedRenderPipeline, SpecializedRenderPipelines,
+            TextureSampleType, UniformBuffer,
+        },
+        renderer::{RenderDevice, RenderQueue},
+        texture::{CachedTexture, TextureCache},
+        Extract,
+    },
+    window::PrimaryWindow,
+};
+use cgmath::{Vector2, Zero};
+use wgpu::{
+    AddressMode, Extent3d, FilterMode, SamplerDescriptor, TextureDescriptor, TextureDimension,
+    TextureFormat, TextureUsages,
+};
+
+use super::{
+    bind_group_tracker::{BindGroupTracker, TrackedEntries},
+    camera, fullscreen, tonemapping, ExtractedWindow, MainTextures, WindowTargets,
+};
+
+pub const TAA_SHADER_HANDLE: Handle<Shader> =
+    Handle::weak_from_u128(54021925815327781466318346260180941635);
+
+pub const MOTION_VECTOR_FORMAT: TextureFormat = TextureFormat::Rg16Float;
+
+#[derive(Resource, Clone, Debug)]
+pub struct TaaSettings {
+    pub enabled: bool,
+    /// Without jitter TAA still smooths over motion, but can't resolve sub-pixel detail.
+    pub jitter: bool,
+    /// How much of the current frame goes into the result, the rest comes from the history.
+    pub current_weight: f32,
+}
+
+impl Default for TaaSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            jitter: true,
+            current_weight: 0.1,
+        }
+    }
+}
+
+pub struct TaaPlugin;
+
+impl Plugin for TaaPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<TaaSettings>()
+            .add_systems(Update, (handle_taa_input, jitter_camera).chain());
+    }
+}
+
+fn handle_taa_input(keyboard_input: Res<ButtonInput<KeyCode>>, mut settings: ResMut<TaaSettings>) {
+    if keyboard_input.just_pressed(KeyCode::KeyT) {
+        settings.enabled = !settings.enabled;
+    }
+    if keyboard_input.just_pressed(KeyCode::KeyJ) {
+        settings.jitter = !settings.jitter;
+    }
+}
+
+const JITTER_SEQUENCE_LENGTH: u32 = 8;
+
+/// The Halton low discrepancy sequence spreads the samples evenly over the pixel.
+fn halton(mut index: u32, base: u32) -> f32 {
+    let mut fraction = 1.0;
+    let mut result = 0.0;
+    while index > 0 {
+        fraction /= base as f32;
+        result += fraction * (index % base) as f32;
+        index /= base;
+    }
+    result
+}
+
+fn jitter_camera(
+    settings: Res<TaaSettings>,
+    window: Single<&Window, With<PrimaryWindow>>,
+    mut camera: ResMut<camera::Camera>,
+    mut frame: Local<u32>,
+) {
+    if !settings.enabled || !settings.jitter {
+        camera.jitter = Vector2::zero();
+        return;
+    }
+    *frame = frame.wrapping_add(1);
+    let index = *frame % JITTER_SEQUENCE_LENGTH + 1;
+    // From [0, 1) of a pixel to [-1, 1) of a pixel in NDC, which spans two units per axis.
+    camera.jitter = Vector2::new(
+        (halton(index, 2) - 0.5) * 2.0 / window.physical_width() as f32,
+        (halton(index, 3) - 0.5) * 2.0 / window.physical_height() as f32,
+    );
+}
+
+pub fn extract_taa_settings(mut commands: Commands, main_settings: Extract<Res<TaaSettings>>) {
+    commands.insert_resource(main_settings.clone());
+}
+
+#[derive(Resource)]
+pub struct TaaTextures {
+    pub motion_vectors: CachedTexture,
+    pub history_read: CachedTexture,
+    pub history_write: CachedTexture,
+    /// The history doesn't hold a previous frame of the same size and format.
+    reset: bool,
+}
+
+pub fn prepare_taa_textures(
+    window: Res<ExtractedWindow>,
+    settings: Res<TaaSettings>,
+    hdr: Res<tonemapping::HdrSettings>,
+    render_device: Res<RenderDevice>,
+    mut commands: Commands,
+    mut texture_cache: ResMut<TextureCache>,
+    mut swap_history: Local<bool>,
+    mut history_layout: Local<Option<(ExtractedWindow, bool)>>,
+) {
+    if !settings.enabled {
+        *history_layout = None;
+        commands.remove_resource::<TaaTextures>();
+        return;
+    }
+
+    let mut texture = |label, format, usage| {
+        texture_cache.get(
+            &render_device,
+            TextureDescriptor {
+                label: Some(label),
+                size: Extent3d {
+                    width: window.physical_width,
+                    height: window.physical_height,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: TextureDimension::D2,
+                format,
+                usage,
+                view_formats: &[],
+            },
+        )
+    };
+
+    // The two history textures swap roles every frame. Their labels differ, so the texture
+    // cache hands back the same two textures each time.
+    let history_usage =
+        TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_SRC;
+    let history_format = tonemapping::main_pass_format(hdr.enabled);
+    let history_a = texture("taa_history_a", history_format, history_usage);
+    let history_b = texture("taa_history_b", history_format, history_usage);
+    let (history_read, history_write) = if *swap_history {
+        (history_b, history_a)
+    } else {
+        (history_a, history_b)
+    };
+    *swap_history = !*swap_history;
+
+    let motion_vectors = texture(
+        "motion_vectors",
+        MOTION_VECTOR_FORMAT,
+        TextureUsages::RENDER_ATTACHMENT | TextureUsages::TEXTURE_BINDING,
+    );
+
+    let layout = (window.clone(), hdr.enabled);
+    let reset = history_layout.as_ref() != Some(&layout);
+    *history_layout = Some(layout);
+
+    commands.insert_resource(TaaTextures {
+        motion_vectors,
+        history_read,
+        history_write,
+        reset,
+    });
+}
+
+#[derive(Debug, Clone, Default, ShaderType)]
+pub struct TaaUniform {
+    current_weight: f32,
+    reset: u32,
+}
+
+#[derive(Resource)]
+pub struct TaaBuffer {
+    buf: UniformBuffer<TaaUniform>,
+    sampler: Sampler,
+}
+
+impl FromWorld for TaaBuffer {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let sampler = render_device.create_sampler(&SamplerDescriptor {
+            label: Some("taa_sampler"),
+            address_mode_u: AddressMode::ClampToEdge,
+            address_mode_v: AddressMode::ClampToEdge,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            ..Default::default()
+        });
+
+        Self {
+            buf: UniformBuffer::default(),
+            sampler,
+        }
+    }
+}
+
+impl TaaBuffer {
+    pub fn bind_group(
+        &self,
+        render_device: &RenderDevice,
+        bind_groups: &mut BindGroupTracker,
+        main_textures: &MainTextures,
+        taa_textures: &TaaTextures,
+    ) -> Option<BindGroup> {
+        let entries = TrackedEntries::sequential((
+            main_textures.main(),
+            &taa_textures.history_read.default_view,
+            &taa_textures.motion_vectors.default_view,
+            &self.sampler,
+            &self.buf,
+        ))?;
+        Some(
+            bind_groups.get_or_create("TAA bind group", entries, |entries| {
+                let layout = Self::bind_group_layout(render_device);
+                render_device.create_bind_group("TAA bind group", &layout, entries)
+            }),
+        )
+    }
+
+    pub fn bind_group_layout(render_device: &RenderDevice) -> BindGroupLayout {
+        render_device.create_bind_group_layout(
+            "TAA bind group layout",
+            &BindGroupLayoutEntries::sequential(
+                ShaderStages::FRAGMENT,
+                (
+                    texture_2d(TextureSampleType::Float { filterable: true }),
+                    texture_2d(TextureSampleType::Float { filterable: true }),
+                    texture_2d(TextureSampleType::Float { filterable: true }),
+                    sampler(SamplerBindingType::Filtering),
+                    uniform_buffer::<TaaUniform>(false),
+                ),
+            ),
+        )
+    }
+}
+
+#[derive(Resource)]
+pub struct PreparedTaa {
+    pub bind_group: BindGroup,
+}
+
+pub fn prepare_taa_bind_group(
+    mut commands: Commands,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    settings: Res<TaaSettings>,
+    main_textures: Res<WindowTargets<MainTextures>>,
+    taa_textures: Option<Res<TaaTextures>>,
+    mut taa: ResMut<TaaBuffer>,
+    mut bind_groups: ResMut<BindGroupTracker>,
+) {
+    let (Some(main_textures), Some(taa_textures)) = (main_textures.primary(), taa_textures) else {
+        commands.remove_resource::<PreparedTaa>();
+        return;
+    };
+    taa.buf.set(TaaUniform {
+        current_weight: settings.current_weight,
+        reset: taa_textures.reset.into(),
+    });
+    taa.buf.write_buffer(&render_device, &render_queue);
+
+    match taa.bind_group(
+        &render_device,
+        &mut bind_groups,
+        main_textures,
+        &taa_textures,
+    ) {
+        Some(bind_group) => commands.insert_resource(PreparedTaa { bind_group }),
+        None => commands.remove_resource::<PreparedTaa>(),
+    }
+}
+
+#[derive(Resource)]
+pub struct TaaPipeline {
+    layout: BindGroupLayout,
+}
+
+impl FromWorld for TaaPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+
+        Self {
+            layout: TaaBuffer::bind_group_layout(render_device),
+        }
+    }
+}
+
+impl SpecializedRenderPipeline for TaaPipeline {
+    /// Whether the history is HDR.
+    type Key = bool;
+
+    fn specialize(&self, hdr: Self::Key) -> RenderPipelineDescriptor {
+        fullscreen::fullscreen_pipeline_descriptor(
+            "taa_pipeline",
+            TAA_SHADER_HANDLE,
+            vec![],
+            vec![self.layout.clone()],
+            tonemapping::main_pass_format(hdr),
+        )
+    }
+}
+
+/// The specialization of [`TaaPipeline`] picked for this frame.
+#[derive(Resource)]
+pub struct TaaPipelineId(pub CachedRenderPipelineId);
+
+pub fn queue_taa_pipeline(
+    mut commands: Commands,
+    pipeline_cache: Res<PipelineCache>,
+    pipeline: Res<TaaPipeline>,
+    mut pipelines: ResMut<SpecializedRenderPipelines<TaaPipeline>>,
+    hdr: Res<tonemapping::HdrSettings>,
+) {
+    let id = pipelines.specialize(&pipeline_cache, &pipeline, hdr.enabled);
+    commands.insert_resource(TaaPipelineId(id));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn halton_radical_inverse() {
+        let base_2: Vec<_> = (1..=4).map(|index| halton(index, 2)).collect();
+        assert_eq!(base_2, [0.5, 0.25, 0.75, 0.125]);
+        let base_3: Vec<_> = (1..=3).map(|index| halton(index, 3)).collect();
+        for (value, expected) in base_3.into_iter().zip([1.0 / 3.0, 2.0 / 3.0, 1.0 / 9.0]) {
+            assert!((value - expected).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn jitter_samples_are_distinct_and_inside_the_pixel() {
+        let samples: Vec<_> = (1..=JITTER_SEQUENCE_LENGTH)
+            .map(|index| Vector2::new(halton(index, 2), halton(index, 3)))
+            .collect();
+        for (i, sample) in samples.iter().enumerate() {
+            assert!((0.0..1.0).contains(&sample.x) && (0.0..1.0).contains(&sample.y));
+            assert!(samples[i + 1..].iter().all(|other| other != sample));
+        }
+    }
+}
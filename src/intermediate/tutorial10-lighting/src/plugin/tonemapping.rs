@@ -0,0 +1,279 @@
+//! HDR rendering and tonemapping.
+//!
+//! With HDR enabled the applier pass draws into an `Rgba16Float` texture instead of the
+//! swapchain, so lighting can go above 1.0. `TonemappingNode` then runs a fullscreen pass that
+//! applies exposure and maps the result back into display range with the selected operator.
+//! Without HDR the same pass just copies the post-processed image to the swapchain.
+
+use bevy::{
+    asset::Handle,
+    ecs::entity::EntityHashMap,
+    prelude::*,
+    render::{
+        render_resource::{
+            binding_types::{sampler, texture_2d, uniform_buffer},
+            BindGroup, BindGroupLayout, BindGroupLayoutEntries, CachedRenderPipelineId,
+            PipelineCache, RenderPipelineDescriptor, Sampler, SamplerBindingType, Shader,
+            ShaderStages, ShaderType, SpecializedRenderPipeline, SpecializedRenderPipelines,
+            TextureSampleType, TextureView, UniformBuffer,
+        },
+        renderer::{RenderDevice, RenderQueue},
+        view::ExtractedWindows,
+        Extract,
+    },
+};
+use wgpu::{FilterMode, SamplerDescriptor, TextureFormat};
+
+use super::{
+    bind_group_tracker::{BindGroupTracker, TrackedEntries},
+    fullscreen,
+    post_process::PostProcessSettings,
+    MainTextures, WindowTargets,
+};
+
+pub const TONEMAPPING_SHADER_HANDLE: Handle<Shader> =
+    Handle::weak_from_u128(310945275730716359205123790651427930217);
+
+pub const HDR_TEXTURE_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+pub const SURFACE_TEXTURE_FORMAT: TextureFormat = TextureFormat::Bgra8UnormSrgb;
+
+/// Format the applier pass renders into.
+pub fn main_pass_format(hdr: bool) -> TextureFormat {
+    if hdr {
+        HDR_TEXTURE_FORMAT
+    } else {
+        SURFACE_TEXTURE_FORMAT
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum TonemappingOperator {
+    Reinhard,
+    #[default]
+    Aces,
+    AgX,
+}
+
+impl TonemappingOperator {
+    fn shader_def(&self) -> &'static str {
+        match self {
+            TonemappingOperator::Reinhard => "TONEMAP_REINHARD",
+            TonemappingOperator::Aces => "TONEMAP_ACES",
+            TonemappingOperator::AgX => "TONEMAP_AGX",
+        }
+    }
+}
+
+#[derive(Resource, Clone, Debug)]
+pub struct HdrSettings {
+    pub enabled: bool,
+    pub operator: TonemappingOperator,
+    /// Exposure in stops, the HDR color is scaled by `2^exposure` before tonemapping.
+    pub exposure: f32,
+}
+
+impl Default for HdrSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            operator: TonemappingOperator::default(),
+            exposure: 0.0,
+        }
+    }
+}
+
+pub struct TonemappingPlugin;
+
+impl Plugin for TonemappingPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<HdrSettings>()
+            .add_systems(Update, handle_tonemapping_input);
+    }
+}
+
+const EXPOSURE_STEP: f32 = 0.05;
+
+fn handle_tonemapping_input(
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    mut settings: ResMut<HdrSettings>,
+) {
+    if keyboard_input.just_pressed(KeyCode::KeyH) {
+        settings.enabled = !settings.enabled;
+    }
+    if keyboard_input.just_pressed(KeyCode::Digit1) {
+        settings.operator = TonemappingOperator::Reinhard;
+    }
+    if keyboard_input.just_pressed(KeyCode::Digit2) {
+        settings.operator = TonemappingOperator::Aces;
+    }
+    if keyboard_input.just_pressed(KeyCode::Digit3) {
+        settings.operator = TonemappingOperator::AgX;
+    }
+    if keyboard_input.pressed(KeyCode::Equal) {
+        settings.exposure += EXPOSURE_STEP;
+    }
+    if keyboard_input.pressed(KeyCode::Minus) {
+        settings.exposure -= EXPOSURE_STEP;
+    }
+}
+
+pub fn extract_hdr_settings(mut commands: Commands, main_settings: Extract<Res<HdrSettings>>) {
+    commands.insert_resource(main_settings.clone());
+}
+
+#[derive(Debug, Clone, ShaderType)]
+pub struct TonemappingUniform {
+    exposure: f32,
+}
+
+#[derive(Resource)]
+pub struct TonemappingBuffer {
+    buf: UniformBuffer<TonemappingUniform>,
+    sampler: Sampler,
+}
+
+impl FromWorld for TonemappingBuffer {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let sampler = render_device.create_sampler(&SamplerDescriptor {
+            label: Some("tonemapping_sampler"),
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            ..Default::default()
+        });
+
+        Self {
+            buf: UniformBuffer::from(TonemappingUniform { exposure: 0.0 }),
+            sampler,
+        }
+    }
+}
+
+impl TonemappingBuffer {
+    pub fn bind_group(
+        &self,
+        render_device: &RenderDevice,
+        bind_groups: &mut BindGroupTracker,
+        source: &TextureView,
+    ) -> Option<BindGroup> {
+        let entries = TrackedEntries::sequential((source, &self.sampler, &self.buf))?;
+        Some(
+            bind_groups.get_or_create("Tonemapping bind group", entries, |entries| {
+                let layout = Self::bind_group_layout(render_device);
+                render_device.create_bind_group("Tonemapping bind group", &layout, entries)
+            }),
+        )
+    }
+
+    pub fn bind_group_layout(render_device: &RenderDevice) -> BindGroupLayout {
+        render_device.create_bind_group_layout(
+            "Tonemapping bind group layout",
+            &BindGroupLayoutEntries::sequential(
+                ShaderStages::FRAGMENT,
+                (
+                    texture_2d(TextureSampleType::Float { filterable: true }),
+                    sampler(SamplerBindingType::Filtering),
+                    uniform_buffer::<TonemappingUniform>(false),
+                ),
+            ),
+        )
+    }
+}
+
+#[derive(Resource)]
+pub struct PreparedTonemapping {
+    pub bind_group: BindGroup,
+}
+
+pub fn prepare_tonemapping_bind_group(
+    mut commands: Commands,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    settings: Res<HdrSettings>,
+    post_process: Res<PostProcessSettings>,
+    main_textures: Res<WindowTargets<MainTextures>>,
+    mut tonemapping: ResMut<TonemappingBuffer>,
+    mut bind_groups: ResMut<BindGroupTracker>,
+) {
+    let Some(main_textures) = main_textures.primary() else {
+        commands.remove_resource::<PreparedTonemapping>();
+        return;
+    };
+    tonemapping.buf.set(TonemappingUniform {
+        exposure: settings.exposure,
+    });
+    tonemapping.buf.write_buffer(&render_device, &render_queue);
+
+    // Reads whatever the last post-processing effect wrote.
+    let source = main_textures.source(post_process.enabled_effects().count());
+    match tonemapping.bind_group(&render_device, &mut bind_groups, source) {
+        Some(bind_group) => commands.insert_resource(PreparedTonemapping { bind_group }),
+        None => commands.remove_resource::<PreparedTonemapping>(),
+    }
+}
+
+#[derive(Resource)]
+pub struct TonemappingPipeline {
+    layout: BindGroupLayout,
+}
+
+impl FromWorld for TonemappingPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+
+        Self {
+            layout: TonemappingBuffer::bind_group_layout(render_device),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TonemappingPipelineKey {
+    /// `None` copies the image without tonemapping, for when HDR is disabled.
+    pub operator: Option<TonemappingOperator>,
+    /// Of the swapchain it writes, which is up to the platform.
+    pub format: TextureFormat,
+}
+
+impl SpecializedRenderPipeline for TonemappingPipeline {
+    type Key = TonemappingPipelineKey;
+
+    fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
+        fullscreen::fullscreen_pipeline_descriptor(
+            "tonemapping_pipeline",
+            TONEMAPPING_SHADER_HANDLE,
+            key.operator
+                .iter()
+                .map(|operator| operator.shader_def().into())
+                .collect(),
+            vec![self.layout.clone()],
+            key.format,
+        )
+    }
+}
+
+/// The specialization of [`TonemappingPipeline`] picked for each window this frame.
+#[derive(Resource, Default)]
+pub struct TonemappingPipelineIds(pub EntityHashMap<CachedRenderPipelineId>);
+
+pub fn queue_tonemapping_pipelines(
+    windows: Res<ExtractedWindows>,
+    pipeline_cache: Res<PipelineCache>,
+    pipeline: Res<TonemappingPipeline>,
+    mut pipelines: ResMut<SpecializedRenderPipelines<TonemappingPipeline>>,
+    settings: Res<HdrSettings>,
+    mut pipeline_ids: ResMut<TonemappingPipelineIds>,
+) {
+    pipeline_ids.0.clear();
+    for (&entity, window) in windows.iter() {
+        let Some(format) = window.swap_chain_texture_format else {
+            continue;
+        };
+        let key = TonemappingPipelineKey {
+            operator: settings.enabled.then_some(settings.operator),
+            format,
+        };
+        let id = pipelines.specialize(&pipeline_cache, &pipeline, key);
+        pipeline_ids.0.insert(entity, id);
+    }
+}
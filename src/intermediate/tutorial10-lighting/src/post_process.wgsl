@@ -0,0 +1,172 @@
+#import applier::fullscreen::FullscreenVertexOutput
+
+// Fragment shader, the vertex stage comes from fullscreen.wgsl. Exactly one of the effect
+// defines is set per pipeline.
+
+struct PostProcess {
+    texel_size: vec2<f32>,
+    znear: f32,
+    zfar: f32,
+    bloom_threshold: f32,
+    bloom_intensity: f32,
+    bloom_radius: f32,
+    vignette_intensity: f32,
+    vignette_radius: f32,
+    vignette_smoothness: f32,
+    chromatic_aberration: f32,
+    focus_distance: f32,
+    focal_range: f32,
+    max_blur: f32,
+};
+
+@group(0) @binding(0)
+var t_source: texture_2d<f32>;
+@group(0) @binding(1)
+var s_source: sampler;
+@group(0) @binding(2)
+var<uniform> post_process: PostProcess;
+@group(0) @binding(3)
+var t_depth: texture_depth_2d;
+
+const GOLDEN_ANGLE: f32 = 2.39996323;
+
+// Points of a spiral that evenly cover the unit disk, for the blurs.
+fn disk_tap(i: u32, count: u32) -> vec2<f32> {
+    let r = sqrt((f32(i) + 0.5) / f32(count));
+    let theta = f32(i) * GOLDEN_ANGLE;
+    return r * vec2<f32>(cos(theta), sin(theta));
+}
+
+fn luminance(color: vec3<f32>) -> f32 {
+    return dot(color, vec3<f32>(0.2126, 0.7152, 0.0722));
+}
+
+fn sample_source(uv: vec2<f32>) -> vec3<f32> {
+    return textureSampleLevel(t_source, s_source, uv, 0.0).rgb;
+}
+
+// The simplified FXAA from Timothy Lottes' original whitepaper.
+const FXAA_REDUCE_MIN: f32 = 0.0078125;
+const FXAA_REDUCE_MUL: f32 = 0.125;
+const FXAA_SPAN_MAX: f32 = 8.0;
+
+// FXAA expects perceptual luma in [0, 1], so compress HDR values first.
+fn fxaa_luma(color: vec3<f32>) -> f32 {
+    let luma = luminance(color);
+    return sqrt(luma / (1.0 + luma));
+}
+
+fn fxaa(uv: vec2<f32>) -> vec3<f32> {
+    let texel = post_process.texel_size;
+    let rgb_m = sample_source(uv);
+    let luma_nw = fxaa_luma(sample_source(uv + vec2<f32>(-1.0, -1.0) * texel));
+    let luma_ne = fxaa_luma(sample_source(uv + vec2<f32>(1.0, -1.0) * texel));
+    let luma_sw = fxaa_luma(sample_source(uv + vec2<f32>(-1.0, 1.0) * texel));
+    let luma_se = fxaa_luma(sample_source(uv + vec2<f32>(1.0, 1.0) * texel));
+    let luma_m = fxaa_luma(rgb_m);
+
+    let luma_min = min(luma_m, min(min(luma_nw, luma_ne), min(luma_sw, luma_se)));
+    let luma_max = max(luma_m, max(max(luma_nw, luma_ne), max(luma_sw, luma_se)));
+
+    var dir = vec2<f32>(
+        -((luma_nw + luma_ne) - (luma_sw + luma_se)),
+        (luma_nw + luma_sw) - (luma_ne + luma_se),
+    );
+    let dir_reduce = max(
+        (luma_nw + luma_ne + luma_sw + luma_se) * (0.25 * FXAA_REDUCE_MUL),
+        FXAA_REDUCE_MIN,
+    );
+    let rcp_dir_min = 1.0 / (min(abs(dir.x), abs(dir.y)) + dir_reduce);
+    dir = clamp(dir * rcp_dir_min, vec2<f32>(-FXAA_SPAN_MAX), vec2<f32>(FXAA_SPAN_MAX)) * texel;
+
+    let rgb_a = 0.5 * (sample_source(uv + dir * (1.0 / 3.0 - 0.5))
+        + sample_source(uv + dir * (2.0 / 3.0 - 0.5)));
+    let rgb_b = rgb_a * 0.5 + 0.25 * (sample_source(uv + dir * -0.5)
+        + sample_source(uv + dir * 0.5));
+    let luma_b = fxaa_luma(rgb_b);
+    if luma_b < luma_min || luma_b > luma_max {
+        return rgb_a;
+    }
+    return rgb_b;
+}
+
+// Single pass bloom: blur everything above the threshold and add it back on top.
+const BLOOM_TAPS: u32 = 32u;
+
+fn bloom(uv: vec2<f32>) -> vec3<f32> {
+    var glow = vec3<f32>(0.0);
+    var total_weight = 0.0;
+    for (var i = 0u; i < BLOOM_TAPS; i++) {
+        let offset = disk_tap(i, BLOOM_TAPS);
+        let weight = exp(-2.0 * dot(offset, offset));
+        let color = sample_source(uv + offset * post_process.bloom_radius * post_process.texel_size);
+        let bright = max(luminance(color) - post_process.bloom_threshold, 0.0);
+        glow += color * (bright / max(luminance(color), 1e-4)) * weight;
+        total_weight += weight;
+    }
+    return sample_source(uv) + glow / total_weight * post_process.bloom_intensity;
+}
+
+fn vignette(uv: vec2<f32>) -> vec3<f32> {
+    let distance = length(uv - vec2<f32>(0.5)) * sqrt(2.0);
+    let falloff = smoothstep(
+        post_process.vignette_radius,
+        post_process.vignette_radius - post_process.vignette_smoothness,
+        distance,
+    );
+    return sample_source(uv) * mix(1.0, falloff, post_process.vignette_intensity);
+}
+
+fn chromatic_aberration(uv: vec2<f32>) -> vec3<f32> {
+    // Grows towards the corners like a real lens.
+    let offset = (uv - vec2<f32>(0.5)) * 2.0 * post_process.chromatic_aberration;
+    return vec3<f32>(
+        sample_source(uv + offset).r,
+        sample_source(uv).g,
+        sample_source(uv - offset).b,
+    );
+}
+
+fn linear_depth(uv: vec2<f32>) -> f32 {
+    let size = vec2<f32>(textureDimensions(t_depth));
+    let coords = clamp(vec2<i32>(uv * size), vec2<i32>(0), vec2<i32>(size) - 1);
+    let depth = textureLoad(t_depth, coords, 0);
+    let near = post_process.znear;
+    let far = post_process.zfar;
+    return near * far / (far - depth * (far - near));
+}
+
+// Gathers a disk whose radius grows with the distance from the focal plane.
+const DOF_TAPS: u32 = 24u;
+
+fn depth_of_field(uv: vec2<f32>) -> vec3<f32> {
+    let distance = abs(linear_depth(uv) - post_process.focus_distance);
+    let coc = clamp(distance / post_process.focal_range, 0.0, 1.0) * post_process.max_blur;
+
+    var color = vec3<f32>(0.0);
+    for (var i = 0u; i < DOF_TAPS; i++) {
+        color += sample_source(uv + disk_tap(i, DOF_TAPS) * coc * post_process.texel_size);
+    }
+    return color / f32(DOF_TAPS);
+}
+
+@fragment
+fn fs_main(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
+#ifdef FXAA
+    let color = fxaa(in.uv);
+#endif
+#ifdef BLOOM
+    let color = bloom(in.uv);
+#endif
+#ifdef VIGNETTE
+    let color = vignette(in.uv);
+#endif
+#ifdef CHROMATIC_ABERRATION
+    let color = chromatic_aberration(in.uv);
+#endif
+#ifdef DEPTH_OF_FIELD
+    let color = depth_of_field(in.uv);
+#endif
+
+    return vec4<f32>(color, 1.0);
+}
@@ -0,0 +1,342 @@
+#import applier::camera::{CameraUniform, remove_jitter}
+#import applier::instancing::{
+    TRANSPARENT_ALPHA_CUTOFF,
+    InstanceInput,
+    VertexInput,
+    model_matrix,
+    normal_matrix,
+}
+#ifdef MOTION_VECTORS
+#import applier::instancing::previous_model_matrix
+#endif
+#import applier::lighting::{
+    LIGHT_KIND_DIRECTIONAL,
+    Light,
+    Lights,
+    blinn_phong,
+    sample_light,
+}
+
+// Vertex shader
+@group(1) @binding(0) // 1.
+var<uniform> camera: CameraUniform;
+#ifdef MOTION_VECTORS
+@group(1) @binding(1)
+var<uniform> previous_camera: CameraUniform;
+#endif
+
+struct ClusterConfig {
+    dimensions: vec3<u32>,
+    light_count: u32,
+    screen_size: vec2<f32>,
+    z_near: f32,
+    z_far: f32,
+    // The first lights, which aren't in any cluster's list.
+    directional_light_count: u32,
+};
+
+#ifdef LIGHTS_STORAGE_BUFFERS
+struct ClusterLightIndexLists {
+    data: array<u32>,
+};
+struct ClusterOffsetsAndCounts {
+    data: array<vec2<u32>>,
+};
+@group(2) @binding(0)
+var<storage> lights: Lights;
+@group(2) @binding(1)
+var<storage> cluster_light_index_lists: ClusterLightIndexLists;
+@group(2) @binding(2)
+var<storage> cluster_offsets_and_counts: ClusterOffsetsAndCounts;
+#else
+struct ClusterLightIndexLists {
+    // Light indices packed as u8, sixteen to a vec4.
+    data: array<vec4<u32>, 1024u>,
+};
+struct ClusterOffsetsAndCounts {
+    // One `offset << 8 | count` per cluster, four to a vec4.
+    data: array<vec4<u32>, 1024u>,
+};
+@group(2) @binding(0)
+var<uniform> lights: Lights;
+@group(2) @binding(1)
+var<uniform> cluster_light_index_lists: ClusterLightIndexLists;
+@group(2) @binding(2)
+var<uniform> cluster_offsets_and_counts: ClusterOffsetsAndCounts;
+#endif
+@group(2) @binding(3)
+var<uniform> cluster_config: ClusterConfig;
+
+struct Shadows {
+    view_projections: array<mat4x4<f32>, 8u>,
+    // View space depth at which each cascade ends.
+    cascade_splits: vec4<f32>,
+    cascade_count: u32,
+    texel_size: f32,
+    depth_bias: f32,
+    normal_bias: f32,
+};
+@group(2) @binding(4)
+var shadow_map: texture_depth_2d_array;
+@group(2) @binding(5)
+var shadow_sampler: sampler_comparison;
+@group(2) @binding(6)
+var<uniform> shadows: Shadows;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+    @location(1) world_normal: vec3<f32>,
+    @location(2) world_position: vec3<f32>,
+    @location(3) view_depth: f32,
+    @location(4) world_tangent: vec4<f32>,
+#ifdef MOTION_VECTORS
+    // Both without jitter, so a still camera produces no motion.
+    @location(5) current_clip_position: vec4<f32>,
+    @location(6) previous_clip_position: vec4<f32>,
+#endif
+#ifdef TEXTURE_ARRAY
+    @location(7) @interpolate(flat) layer: u32,
+#endif
+    @location(8) @interpolate(flat) instance_index: u32,
+}
+
+@vertex
+fn vs_main(
+    model: VertexInput,
+    instance: InstanceInput,
+) -> VertexOutput {
+    let normal_transform = normal_matrix(instance);
+    var out: VertexOutput;
+#ifdef TEXTURE_ARRAY
+    out.tex_coords = model.tex_coords;
+    out.layer = u32(instance.texture.x);
+#else
+    out.tex_coords = instance.texture.xy + model.tex_coords * instance.texture.zw;
+#endif
+    out.instance_index = instance.index;
+    out.world_normal = normal_transform * model.normal;
+    out.world_tangent = vec4<f32>(normal_transform * model.tangent.xyz, model.tangent.w);
+    let world_position = model_matrix(instance) * vec4<f32>(model.position, 1.0);
+    out.world_position = world_position.xyz;
+    out.clip_position = camera.view_proj * world_position;
+    // For a perspective projection w is the distance along the view direction.
+    out.view_depth = out.clip_position.w;
+#ifdef MOTION_VECTORS
+    let previous_world_position = previous_model_matrix(instance) * vec4<f32>(model.position, 1.0);
+    out.current_clip_position = remove_jitter(out.clip_position, camera.jitter);
+    out.previous_clip_position = remove_jitter(
+        previous_camera.view_proj * previous_world_position,
+        previous_camera.jitter,
+    );
+#endif
+    return out;
+}
+
+// Fragment shader
+
+struct Material {
+    alpha_cutoff: f32,
+    // Added to the mip level the hardware picks, negative values keep distant textures sharper.
+    lod_bias: f32,
+};
+
+@group(0) @binding(0)
+var t_diffuse: texture_2d<f32>;
+@group(0) @binding(1)
+var s_diffuse: sampler;
+#ifdef NORMAL_MAP
+@group(0) @binding(2)
+var t_normal: texture_2d<f32>;
+@group(0) @binding(3)
+var s_normal: sampler;
+#endif
+@group(0) @binding(4)
+var<uniform> material: Material;
+#ifdef TEXTURE_ARRAY
+@group(0) @binding(5)
+var t_diffuse_array: texture_2d_array<f32>;
+@group(0) @binding(6)
+var s_diffuse_array: sampler;
+#endif
+
+fn fragment_cluster_index(frag_coord: vec2<f32>, view_depth: f32) -> u32 {
+    let dimensions = cluster_config.dimensions;
+    let tile = clamp(
+        frag_coord / cluster_config.screen_size * vec2<f32>(dimensions.xy),
+        vec2<f32>(0.0),
+        vec2<f32>(dimensions.xy - 1u),
+    );
+    let slice = log(view_depth / cluster_config.z_near)
+        / log(cluster_config.z_far / cluster_config.z_near)
+        * f32(dimensions.z);
+    let z = u32(clamp(slice, 0.0, f32(dimensions.z - 1u)));
+    return (z * dimensions.y + u32(tile.y)) * dimensions.x + u32(tile.x);
+}
+
+fn cluster_offset_and_count(cluster_index: u32) -> vec2<u32> {
+#ifdef LIGHTS_STORAGE_BUFFERS
+    return cluster_offsets_and_counts.data[cluster_index];
+#else
+    let packed = cluster_offsets_and_counts.data[cluster_index >> 2u][cluster_index & 3u];
+    return vec2<u32>(packed >> 8u, packed & 0xffu);
+#endif
+}
+
+fn cluster_light_index(index: u32) -> u32 {
+#ifdef LIGHTS_STORAGE_BUFFERS
+    return cluster_light_index_lists.data[index];
+#else
+    let packed = cluster_light_index_lists.data[index >> 4u][(index >> 2u) & 3u];
+    return (packed >> (8u * (index & 3u))) & 0xffu;
+#endif
+}
+
+// 3x3 PCF over one layer of the shadow map. Returns 1.0 when fully lit.
+fn sample_shadow(layer: u32, world_position: vec3<f32>) -> f32 {
+    let clip = shadows.view_projections[layer] * vec4<f32>(world_position, 1.0);
+    let ndc = clip.xyz / clip.w;
+    if any(abs(ndc.xy) > vec2<f32>(1.0)) || ndc.z > 1.0 || ndc.z < 0.0 {
+        return 1.0;
+    }
+    let uv = ndc.xy * vec2<f32>(0.5, -0.5) + 0.5;
+    let depth = ndc.z - shadows.depth_bias;
+
+    var lit = 0.0;
+    for (var y = -1; y <= 1; y += 1) {
+        for (var x = -1; x <= 1; x += 1) {
+            let offset = vec2<f32>(f32(x), f32(y)) * shadows.texel_size;
+            lit += textureSampleCompareLevel(shadow_map, shadow_sampler, uv + offset, layer, depth);
+        }
+    }
+    return lit / 9.0;
+}
+
+fn shadow_factor(
+    light: Light,
+    world_position: vec3<f32>,
+    normal: vec3<f32>,
+    view_depth: f32,
+) -> f32 {
+    if light.shadow_layer < 0 {
+        return 1.0;
+    }
+    // Push the lookup off the surface a little to fight acne on sloped surfaces.
+    let offset_position = world_position + normal * shadows.normal_bias;
+    var layer = u32(light.shadow_layer);
+    if light.kind == LIGHT_KIND_DIRECTIONAL {
+        var cascade = 0u;
+        while cascade < shadows.cascade_count && view_depth > shadows.cascade_splits[cascade] {
+            cascade += 1u;
+        }
+        if cascade == shadows.cascade_count {
+            return 1.0;
+        }
+        layer += cascade;
+    }
+    return sample_shadow(layer, offset_position);
+}
+
+fn light_contribution(
+    light: Light,
+    normal: vec3<f32>,
+    view_dir: vec3<f32>,
+    world_position: vec3<f32>,
+    view_depth: f32,
+) -> vec3<f32> {
+    let sample = sample_light(light, world_position);
+    let shadow = shadow_factor(light, world_position, normal, view_depth);
+    return blinn_phong(light, normal, view_dir, sample.direction, shadow) * sample.attenuation;
+}
+
+fn fragment_normal(in: VertexOutput) -> vec3<f32> {
+    let normal = normalize(in.world_normal);
+#ifdef NORMAL_MAP
+    // Re-orthogonalize after interpolation, then build the tangent space basis.
+    let tangent = normalize(in.world_tangent.xyz - normal * dot(normal, in.world_tangent.xyz));
+    let bitangent = cross(normal, tangent) * in.world_tangent.w;
+    let tbn = mat3x3<f32>(tangent, bitangent, normal);
+    let tangent_normal = textureSampleBias(t_normal, s_normal, in.tex_coords, material.lod_bias).xyz * 2.0 - 1.0;
+    return normalize(tbn * tangent_normal);
+#else
+    return normal;
+#endif
+}
+
+struct FragmentOutput {
+    @location(0) color: vec4<f32>,
+#ifdef MOTION_VECTORS
+    // How far the surface moved in uv space since the last frame.
+    @location(1) motion_vector: vec2<f32>,
+#endif
+    // For picking, at the same location whether TAA is on or not.
+    @location(2) instance_index: u32,
+};
+
+@fragment
+fn fs_main(in: VertexOutput) -> FragmentOutput {
+#ifdef TEXTURE_ARRAY
+    var object_color = textureSampleBias(
+        t_diffuse_array,
+        s_diffuse_array,
+        in.tex_coords,
+        in.layer,
+        material.lod_bias,
+    );
+#else
+    var object_color = textureSampleBias(t_diffuse, s_diffuse, in.tex_coords, material.lod_bias);
+#endif
+#ifdef ALPHA_MASK
+    if object_color.a < material.alpha_cutoff {
+        discard;
+    }
+    object_color.a = 1.0;
+#endif
+#ifdef ALPHA_BLEND
+    if object_color.a < TRANSPARENT_ALPHA_CUTOFF {
+        discard;
+    }
+#endif
+
+    let normal = fragment_normal(in);
+    let view_dir = normalize(camera.view_position.xyz - in.world_position);
+
+    let cluster = cluster_offset_and_count(
+        fragment_cluster_index(in.clip_position.xy, in.view_depth),
+    );
+    var lighting = vec3<f32>(0.0);
+    for (var i = 0u; i < cluster_config.directional_light_count; i += 1u) {
+        lighting += light_contribution(
+            lights.data[i],
+            normal,
+            view_dir,
+            in.world_position,
+            in.view_depth,
+        );
+    }
+    for (var i = cluster.x; i < cluster.x + cluster.y; i += 1u) {
+        let light = lights.data[cluster_light_index(i)];
+        lighting += light_contribution(
+            light,
+            normal,
+            view_dir,
+            in.world_position,
+            in.view_depth,
+        );
+    }
+
+    var out: FragmentOutput;
+    out.color = vec4<f32>(lighting * object_color.rgb, object_color.a);
+#ifdef PREMULTIPLY_ALPHA
+    out.color = vec4<f32>(out.color.rgb * out.color.a, out.color.a);
+#endif
+#ifdef MOTION_VECTORS
+    let current_ndc = in.current_clip_position.xy / in.current_clip_position.w;
+    let previous_ndc = in.previous_clip_position.xy / in.previous_clip_position.w;
+    out.motion_vector = (current_ndc - previous_ndc) * vec2<f32>(0.5, -0.5);
+#endif
+    // Texels below the cutoff were discarded above, so they don't cover up the index of
+    // whatever is behind them.
+    out.instance_index = in.instance_index;
+    return out;
+}
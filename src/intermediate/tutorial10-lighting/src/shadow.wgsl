@@ -0,0 +1,71 @@
+#import applier::instancing::{InstanceInput, VertexInput, model_matrix}
+
+// Vertex shader
+struct ShadowView {
+    view_proj: mat4x4<f32>,
+};
+@group(0) @binding(0)
+var<uniform> shadow_view: ShadowView;
+
+#ifdef ALPHA_TEST
+
+// Matches `OutlineCoverageUniform`.
+struct Coverage {
+    alpha_cutoff: f32,
+    texture_array: u32,
+};
+
+@group(1) @binding(0)
+var t_coverage: texture_2d_array<f32>;
+@group(1) @binding(1)
+var s_coverage: sampler;
+@group(1) @binding(2)
+var<uniform> coverage: Coverage;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+    @location(1) @interpolate(flat) layer: u32,
+}
+
+@vertex
+fn vs_main(
+    model: VertexInput,
+    instance: InstanceInput,
+) -> VertexOutput {
+    var out: VertexOutput;
+    // The same texel as the instance itself, like in shaders.wgsl.
+    if coverage.texture_array != 0u {
+        out.tex_coords = model.tex_coords;
+        out.layer = u32(instance.texture.x);
+    } else {
+        out.tex_coords = instance.texture.xy + model.tex_coords * instance.texture.zw;
+        out.layer = 0u;
+    }
+    out.clip_position =
+        shadow_view.view_proj * model_matrix(instance) * vec4<f32>(model.position, 1.0);
+    return out;
+}
+
+// Fragment shader
+
+@fragment
+fn fs_main(in: VertexOutput) {
+    // Cutouts the main pass discards shouldn't cast a shadow either.
+    let alpha = textureSample(t_coverage, s_coverage, in.tex_coords, in.layer).a;
+    if alpha < coverage.alpha_cutoff {
+        discard;
+    }
+}
+
+#else
+
+@vertex
+fn vs_main(
+    model: VertexInput,
+    instance: InstanceInput,
+) -> @builtin(position) vec4<f32> {
+    return shadow_view.view_proj * model_matrix(instance) * vec4<f32>(model.position, 1.0);
+}
+
+#endif
@@ -0,0 +1,53 @@
+#import applier::fullscreen::FullscreenVertexOutput
+
+// Fragment shader, the vertex stage comes from fullscreen.wgsl.
+
+struct Taa {
+    // Weight of the current frame, the rest comes from the history.
+    current_weight: f32,
+    // Set when the history holds nothing usable, like on the first frame or after a resize.
+    reset: u32,
+};
+
+@group(0) @binding(0)
+var t_current: texture_2d<f32>;
+@group(0) @binding(1)
+var t_history: texture_2d<f32>;
+@group(0) @binding(2)
+var t_motion_vectors: texture_2d<f32>;
+@group(0) @binding(3)
+var s_linear: sampler;
+@group(0) @binding(4)
+var<uniform> taa: Taa;
+
+@fragment
+fn fs_main(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
+    let size = vec2<i32>(textureDimensions(t_current));
+    let coords = vec2<i32>(in.uv * vec2<f32>(size));
+    let current = textureLoad(t_current, coords, 0).rgb;
+
+    // The history is only trusted as far as it stays inside the range of the current pixel's
+    // neighbours. Anything outside is likely a disocclusion or a lighting change, and clamping
+    // it stops ghosting.
+    var neighbourhood_min = current;
+    var neighbourhood_max = current;
+    for (var y = -1; y <= 1; y += 1) {
+        for (var x = -1; x <= 1; x += 1) {
+            let neighbour_coords = clamp(coords + vec2<i32>(x, y), vec2<i32>(0), size - 1);
+            let neighbour = textureLoad(t_current, neighbour_coords, 0).rgb;
+            neighbourhood_min = min(neighbourhood_min, neighbour);
+            neighbourhood_max = max(neighbourhood_max, neighbour);
+        }
+    }
+
+    let motion_vector = textureLoad(t_motion_vectors, coords, 0).xy;
+    let history_uv = in.uv - motion_vector;
+    let off_screen = any(history_uv < vec2<f32>(0.0)) || any(history_uv > vec2<f32>(1.0));
+    if taa.reset != 0u || off_screen {
+        return vec4<f32>(current, 1.0);
+    }
+
+    let history = textureSampleLevel(t_history, s_linear, history_uv, 0.0).rgb;
+    let clamped_history = clamp(history, neighbourhood_min, neighbourhood_max);
+    return vec4<f32>(mix(clamped_history, current, taa.current_weight), 1.0);
+}
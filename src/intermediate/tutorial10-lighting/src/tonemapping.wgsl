@@ -0,0 +1,81 @@
+#import applier::fullscreen::FullscreenVertexOutput
+
+// Fragment shader, the vertex stage comes from fullscreen.wgsl.
+
+struct Tonemapping {
+    // In stops.
+    exposure: f32,
+};
+
+@group(0) @binding(0)
+var t_hdr: texture_2d<f32>;
+@group(0) @binding(1)
+var s_hdr: sampler;
+@group(0) @binding(2)
+var<uniform> tonemapping: Tonemapping;
+
+fn tonemap_reinhard(color: vec3<f32>) -> vec3<f32> {
+    return color / (1.0 + color);
+}
+
+// Krzysztof Narkowicz's fit of the ACES filmic curve.
+fn tonemap_aces(color: vec3<f32>) -> vec3<f32> {
+    let x = color * 0.6;
+    let a = 2.51;
+    let b = 0.03;
+    let c = 2.43;
+    let d = 0.59;
+    let e = 0.14;
+    return clamp((x * (a * x + b)) / (x * (c * x + d) + e), vec3<f32>(0.0), vec3<f32>(1.0));
+}
+
+// Minimal AgX, fitted by Benjamin Wrensch from Blender's implementation.
+fn agx_contrast(x: vec3<f32>) -> vec3<f32> {
+    let x2 = x * x;
+    let x4 = x2 * x2;
+    return 15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2
+        + 0.1191 * x - 0.00232;
+}
+
+fn tonemap_agx(color: vec3<f32>) -> vec3<f32> {
+    let agx_inset = mat3x3<f32>(
+        0.842479062253094, 0.0423282422610123, 0.0423756549057051,
+        0.0784335999999992, 0.878468636469772, 0.0784336,
+        0.0792237451477643, 0.0791661274605434, 0.879142973793104,
+    );
+    let agx_outset = mat3x3<f32>(
+        1.19687900512017, -0.0528968517574562, -0.0529716355144438,
+        -0.0980208811401368, 1.15190312990417, -0.0980434501171241,
+        -0.0990297440797205, -0.0989611768448433, 1.15107367264116,
+    );
+    let min_ev = -12.47393;
+    let max_ev = 4.026069;
+
+    var x = agx_inset * max(color, vec3<f32>(1e-10));
+    x = clamp(log2(x), vec3<f32>(min_ev), vec3<f32>(max_ev));
+    x = (x - min_ev) / (max_ev - min_ev);
+    x = agx_contrast(x);
+    x = agx_outset * x;
+    // AgX produces display encoded values, the sRGB target expects linear ones.
+    return pow(max(x, vec3<f32>(0.0)), vec3<f32>(2.2));
+}
+
+@fragment
+fn fs_main(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
+    let hdr = textureSample(t_hdr, s_hdr, in.uv);
+    let color = hdr.rgb * exp2(tonemapping.exposure);
+    // Without an operator the pass only copies the post-processed image to the swapchain.
+    var mapped = hdr.rgb;
+
+#ifdef TONEMAP_REINHARD
+    mapped = tonemap_reinhard(color);
+#endif
+#ifdef TONEMAP_ACES
+    mapped = tonemap_aces(color);
+#endif
+#ifdef TONEMAP_AGX
+    mapped = tonemap_agx(color);
+#endif
+
+    return vec4<f32>(mapped, hdr.a);
+}